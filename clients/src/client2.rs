@@ -1,6 +1,7 @@
 use std::str;
 use std::thread;
 use std::sync::mpsc;
+use std::sync::Arc;
 use std::time::Duration;
 use std::net::TcpStream;
 use std::io::{Read, Write, Error};
@@ -10,26 +11,29 @@ use byteorder::{ByteOrder, BigEndian};
 
 use aes_crypt;
 
+use crate::random::Random;
+use crate::secret::SecretBytes;
 
 pub struct Client2 {
-    key: Vec<u8>,
+    key: Arc<SecretBytes>,
+    random: Random,
 }
 
 impl Client2 {
-    pub fn new() -> Self {
-        Self { key: Vec::new() }
+    pub fn new(random: Random) -> Self {
+        Self { key: Arc::new(SecretBytes::new(Vec::new())), random }
     }
 
     pub fn run(&mut self, socket: &str) {
         let mut stream = TcpStream::connect(socket).expect("Could not connect to server");
 
         // Generate cryptographic key
-        self.key = aes_crypt::gen_key().to_vec();
+        self.key = Arc::new(self.random.aes_key());
 
         // Send the key to the server as the first message
         let key_length = self.key.len() as u32;
         let mut key_message = key_length.to_be_bytes().to_vec();
-        key_message.extend(&self.key);
+        key_message.extend(self.key.expose());
         stream.write_all(&key_message).expect("Failed to send key");
 
         // Channel for reading from stdin and sending to server
@@ -45,7 +49,7 @@ impl Client2 {
                 let temp_bytes = input.as_bytes().to_vec();
 
                 // Encrypt temp_bytes here later on
-                let mut encrypted_bytes = aes_crypt::encrypt_ecb(&temp_bytes, &key_clone);
+                let mut encrypted_bytes = aes_crypt::encrypt_ecb(&temp_bytes, key_clone.expose());
                 let mut message_bytes = (4_u32 + encrypted_bytes.len() as u32).to_be_bytes().to_vec();
                 message_bytes.append(&mut encrypted_bytes);
 
@@ -69,7 +73,7 @@ impl Client2 {
         loop {
             if let Ok(response_bytes) = server_rx.try_recv() {
                 // Decrypt response bytes here later on
-                let decrypted_bytes = aes_crypt::decrypt_ecb(&response_bytes, &self.key);
+                let decrypted_bytes = aes_crypt::decrypt_ecb(&response_bytes, self.key.expose());
                 let message = String::from_utf8_lossy(&decrypted_bytes);
                 println!("{}", message);
             }
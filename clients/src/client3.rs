@@ -13,35 +13,44 @@ use aes_crypt;
 use dh;
 use bernie_hmac;
 
+use crate::random::Random;
+use crate::sas::{self, Sas};
+use crate::secret::SecretBytes;
+
 
 pub struct Client3 {
-    key: Arc<Mutex<Vec<u8>>>,
+    key: Arc<Mutex<SecretBytes>>,
+    // Short authentication string of the session, once the key exchange is done
+    sas: Arc<Mutex<Option<Sas>>>,
+    random: Random,
 }
 
 impl Client3 {
-    pub fn new() -> Self {
-        Self { key: Arc::new(Mutex::new(Vec::new())) }
+    pub fn new(random: Random) -> Self {
+        Self { key: Arc::new(Mutex::new(SecretBytes::new(Vec::new()))), sas: Arc::new(Mutex::new(None)), random }
     }
 
     pub fn run(&mut self, socket: &str) {
         let mut stream = TcpStream::connect(socket).expect("Could not connect to server");
 
-        // Generate key pair and send client public key
+        // Generate key pair and commit to the client public key. The key itself is only
+        // sent once the server's key has arrived, see sas.rs
         println!("\n--------------------------------------");
         println!("[+] Generating key pair ...");
-        let key_pair = dh::gen_key_pair();
+        let key_pair = self.random.dh_key_pair();
 
-        println!("[+] Sending public key to server ...");
-        let key_length = key_pair.1.len() as u32;
-        let mut key_message = key_length.to_be_bytes().to_vec();
-        key_message.extend(key_pair.1.clone());
-        stream.write_all(&key_message).expect("Failed to send server public key");
+        println!("[+] Sending commitment to our public key to server ...");
+        let commitment = sas::commitment(&key_pair.1);
+        let mut commitment_message = (commitment.len() as u32).to_be_bytes().to_vec();
+        commitment_message.extend(commitment);
+        stream.write_all(&commitment_message).expect("Failed to send commitment");
 
         // Channel for reading from stdin and sending to server
         let (stdin_tx, stdin_rx) = mpsc::channel::<Vec<u8>>();
 
         // Thread for reading from stdin
         let stdin_tx_clone = stdin_tx.clone();
+        let sas_clone = self.sas.clone();
         let key_clone = self.key.clone();
         thread::spawn(move || {
             loop {
@@ -49,11 +58,17 @@ impl Client3 {
                 std::io::stdin().read_line(&mut input).unwrap();
                 let temp_bytes = input.as_bytes().to_vec();
 
+                // /verify confirms the server's code rather than being sent
+                if sas::parse_verify(&input).is_some() {
+                    sas::verify_server(&mut sas_clone.lock().unwrap());
+                    continue;
+                }
+
                 // Lock and access the key
                 let key_guard = key_clone.lock().unwrap();
 
                 // Encrypt the message
-                let mut encrypted_bytes = aes_crypt::encrypt_ecb(&temp_bytes, &key_guard);
+                let mut encrypted_bytes = aes_crypt::encrypt_ecb(&temp_bytes, key_guard.expose());
 
                 // Construct header prefix and append encrypted bytes to it
                 let mut message_bytes = (4_u32 + encrypted_bytes.len() as u32).to_be_bytes().to_vec();
@@ -69,8 +84,9 @@ impl Client3 {
 
         // Thread within which messages from the server are retreived and messages to the server are sent
         let key_clone = self.key.clone();
+        let sas_clone = self.sas.clone();
         thread::spawn(move || {
-            if let Err(e) = Self::handle_server(stream, stdin_rx, server_tx, key_clone, key_pair) {
+            if let Err(e) = Self::handle_server(stream, stdin_rx, server_tx, key_clone, sas_clone, key_pair) {
                 eprintln!("Error with server: {:?}", e);
             }
             println!("Server disconnected");
@@ -82,7 +98,7 @@ impl Client3 {
                 // Decrypt response bytes and display
                 let key_guard = self.key.lock().unwrap(); // Lock and access the key
                 if !key_guard.is_empty() {
-                    let decrypted_bytes = aes_crypt::decrypt_ecb(&response_bytes, &key_guard);
+                    let decrypted_bytes = aes_crypt::decrypt_ecb(&response_bytes, key_guard.expose());
                     let message = String::from_utf8_lossy(&decrypted_bytes); // Use decrypted bytes
                     println!("{}", message);
                 }
@@ -91,7 +107,7 @@ impl Client3 {
         
     }
 
-    fn handle_server(mut stream: TcpStream, stdin_rx: Receiver<Vec<u8>>, server_tx: Sender<Vec<u8>>, key: Arc<Mutex<Vec<u8>>>, key_pair: (Vec<u8>, Vec<u8>)) -> Result<(), Error> {
+    fn handle_server(mut stream: TcpStream, stdin_rx: Receiver<Vec<u8>>, server_tx: Sender<Vec<u8>>, key: Arc<Mutex<SecretBytes>>, sas: Arc<Mutex<Option<Sas>>>, key_pair: (SecretBytes, Vec<u8>)) -> Result<(), Error> {
         // To store entire messages sent from the client
         let mut dynamic_buffer = Vec::new();
 
@@ -151,20 +167,33 @@ impl Client3 {
                             if first_message {
                                 println!("[*] Received server's public key");
 
+                                // The server's key is fixed now, so the key we committed to can go out
+                                println!("[+] Sending public key to server ...");
+                                let key_length = key_pair.1.len() as u32;
+                                let mut key_message = key_length.to_be_bytes().to_vec();
+                                key_message.extend(key_pair.1.clone());
+                                stream.write_all(&key_message)?;
+
                                 // Use the server's public key to compute the shared secret
                                 println!("[+] Calculating shared secret ...");
                                 let modulus = dh::get_domain_params().0;
-                                let shared_secret = dh::get_secret(&dynamic_buffer.clone(), &key_pair.0, &modulus);
+                                let shared_secret = SecretBytes::new(dh::get_secret(&dynamic_buffer, key_pair.0.expose(), &modulus));
 
                                 // Use SHA-256 as the KDF to compute the final key
                                 println!("[+] Using SHA-256 as KDF to compute final key ...");
-                                let final_key = bernie_hmac::hash(&shared_secret);
+                                let final_key = SecretBytes::new(bernie_hmac::hash(shared_secret.expose()));
+
+                                // Both ends show a code from the key and the two public keys. The codes only
+                                // match if nobody in the middle ran a separate exchange with each end.
+                                let code = Sas::new(&final_key, &sas::key_context(&dynamic_buffer, &key_pair.1));
 
                                 // Set the key member equal to the final key
                                 let mut unlocked_key = key.lock().unwrap();
                                 *unlocked_key = final_key;
 
                                 println!("[*] DH Key Exchange Successful.");
+                                println!("[*] Short authentication string {}, type {} once the server shows the same", code.code(), sas::VERIFY_COMMAND);
+                                *sas.lock().unwrap() = Some(code);
                                 println!("--------------------------------------\n");
 
                                 first_message = false;
@@ -2,7 +2,7 @@ use std::str;
 use std::thread;
 use std::sync::mpsc;
 use std::sync::{Arc, Mutex};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use std::net::TcpStream;
 use std::io::{Read, Write, Error};
 use std::sync::mpsc::{Receiver, Sender};
@@ -13,54 +13,82 @@ use aes_crypt;
 use dh;
 use bernie_hmac;
 
+use crate::random::Random;
+use crate::sas::{self, Sas};
+use crate::secret::SecretBytes;
+use crate::sequence::{self, CLIENT_TO_SERVER, SERVER_TO_CLIENT, SEQUENCE_SIZE};
+use crate::verify;
+
 const MAC_TAG_SIZE: usize = 32;
 
 pub struct Client4 {
-    key: Arc<Mutex<Vec<u8>>>,
+    key: Arc<Mutex<SecretBytes>>,
+    // Sequence number of the next message we send
+    next_sequence: Arc<Mutex<u64>>,
+    // Short authentication string of the session, once the key exchange is done
+    sas: Arc<Mutex<Option<Sas>>>,
+    random: Random,
 }
 
 impl Client4 {
-    pub fn new() -> Self {
-        Self { key: Arc::new(Mutex::new(Vec::new())) }
+    pub fn new(random: Random) -> Self {
+        Self { key: Arc::new(Mutex::new(SecretBytes::new(Vec::new()))), next_sequence: Arc::new(Mutex::new(0)), sas: Arc::new(Mutex::new(None)), random }
     }
 
     pub fn run(&mut self, socket: &str) {
         let mut stream = TcpStream::connect(socket).expect("Could not connect to server");
 
-        // Generate key pair and send client public key
+        // Generate key pair and commit to the client public key. The key itself is only
+        // sent once the server's key has arrived, see sas.rs
         println!("\n--------------------------------------");
         println!("[+] Generating key pair ...");
-        let key_pair = dh::gen_key_pair();
+        let key_pair = self.random.dh_key_pair();
 
-        println!("[+] Sending public key to server ...");
-        let key_length = key_pair.1.len() as u32;
-        let mut key_message = key_length.to_be_bytes().to_vec();
-        key_message.extend(key_pair.1.clone());
-        stream.write_all(&key_message).expect("Failed to send server public key");
+        println!("[+] Sending commitment to our public key to server ...");
+        let commitment = sas::commitment(&key_pair.1);
+        let mut commitment_message = (commitment.len() as u32).to_be_bytes().to_vec();
+        commitment_message.extend(commitment);
+        stream.write_all(&commitment_message).expect("Failed to send commitment");
 
         // Channel for reading from stdin and sending to server
         let (stdin_tx, stdin_rx) = mpsc::channel::<Vec<u8>>();
 
         // Thread for reading from stdin
         let stdin_tx_clone = stdin_tx.clone();
+        let sas_clone = self.sas.clone();
         let key_clone = self.key.clone();
+        let next_sequence_clone = self.next_sequence.clone();
         thread::spawn(move || {
             loop {
                 let mut input = String::new();
                 std::io::stdin().read_line(&mut input).unwrap();
                 let temp_bytes = input.as_bytes().to_vec();
 
+                // /verify confirms the server's code rather than being sent
+                if sas::parse_verify(&input).is_some() {
+                    sas::verify_server(&mut sas_clone.lock().unwrap());
+                    continue;
+                }
+
                 // Lock and access the key
                 let key_guard = key_clone.lock().unwrap();
 
                 // Encrypt the message
-                let mut encrypted_bytes = aes_crypt::encrypt_ecb(&temp_bytes, &key_guard);
+                let mut encrypted_bytes = aes_crypt::encrypt_ecb(&temp_bytes, key_guard.expose());
+
+                // Take the next sequence number
+                let mut next_sequence = next_sequence_clone.lock().unwrap();
+                let sequence = *next_sequence;
+                *next_sequence += 1;
 
-                // Compute the MAC tag
-                let mut mac_tag = bernie_hmac::hmac(&encrypted_bytes, &key_guard);
+                // Compute the MAC tag over the direction, sequence number and ciphertext
+                let mut mac_tag = bernie_hmac::hmac(&sequence::mac_input(CLIENT_TO_SERVER, sequence, &encrypted_bytes), key_guard.expose());
 
                 // Construct message with length header
-                let mut message_bytes = (4_u32 + encrypted_bytes.len() as u32 + mac_tag.len() as u32).to_be_bytes().to_vec();
+                let mut message_bytes = (4_u32 + SEQUENCE_SIZE as u32 + encrypted_bytes.len() as u32 + mac_tag.len() as u32).to_be_bytes().to_vec();
+
+                // Append the sequence number to the message
+                message_bytes.extend_from_slice(&sequence.to_be_bytes());
 
                 // Append encrypted bytes to the message
                 message_bytes.append(&mut encrypted_bytes);
@@ -78,8 +106,9 @@ impl Client4 {
 
         // Thread within which messages from the server are retreived and messages to the server are sent
         let key_clone = self.key.clone();
+        let sas_clone = self.sas.clone();
         thread::spawn(move || {
-            if let Err(e) = Self::handle_server(stream, stdin_rx, server_tx, key_clone, key_pair) {
+            if let Err(e) = Self::handle_server(stream, stdin_rx, server_tx, key_clone, sas_clone, key_pair) {
                 eprintln!("Error with server: {:?}", e);
             }
             println!("Server disconnected");
@@ -91,7 +120,7 @@ impl Client4 {
                 // Decrypt response bytes and display
                 let key_guard = self.key.lock().unwrap(); // Lock and access the key
                 if !key_guard.is_empty() {
-                    let decrypted_bytes = aes_crypt::decrypt_ecb(&response_bytes, &key_guard);
+                    let decrypted_bytes = aes_crypt::decrypt_ecb(&response_bytes, key_guard.expose());
                     let message = String::from_utf8_lossy(&decrypted_bytes); // Use decrypted bytes
                     println!("Server > {}", message);
                 }
@@ -104,8 +133,9 @@ impl Client4 {
         mut stream: TcpStream, 
         stdin_rx: Receiver<Vec<u8>>, 
         server_tx: Sender<Vec<u8>>, 
-        key: Arc<Mutex<Vec<u8>>>, 
-        key_pair: (Vec<u8>, Vec<u8>)
+        key: Arc<Mutex<SecretBytes>>, 
+        sas: Arc<Mutex<Option<Sas>>>,
+        key_pair: (SecretBytes, Vec<u8>)
     ) -> Result<(), std::io::Error> {
         
         // To store entire messages sent from the client
@@ -120,6 +150,9 @@ impl Client4 {
         // To see if key is being sent
         let mut first_message = true;
 
+        // Sequence number of the next message we expect from the server
+        let mut expected_sequence: u64 = 0;
+
         loop {
             // Non-blocking attempt to receive message from stdin and send to server
             if let Ok(bytes) = stdin_rx.try_recv() {
@@ -163,43 +196,69 @@ impl Client4 {
                     // Check if the entire message has been received
                     if let Some(length) = expected_length {
                         if total_received >= length {
+                            // A failure to verify is reported a fixed time after this point
+                            let received = Instant::now();
+
                             // If this is the first message, it is the server sending its public key
                             if first_message {
                                 println!("[*] Received server's public key");
 
+                                // The server's key is fixed now, so the key we committed to can go out
+                                println!("[+] Sending public key to server ...");
+                                let key_length = key_pair.1.len() as u32;
+                                let mut key_message = key_length.to_be_bytes().to_vec();
+                                key_message.extend(key_pair.1.clone());
+                                stream.write_all(&key_message)?;
+
                                 // Use the server's public key to compute the shared secret
                                 println!("[+] Calculating shared secret ...");
                                 let modulus = dh::get_domain_params().0;
-                                let shared_secret = dh::get_secret(&dynamic_buffer.clone(), &key_pair.0, &modulus);
+                                let shared_secret = SecretBytes::new(dh::get_secret(&dynamic_buffer, key_pair.0.expose(), &modulus));
 
                                 // Use SHA-256 as the KDF to compute the final key
                                 println!("[+] Using SHA-256 as KDF to compute final key ...");
-                                let final_key = bernie_hmac::hash(&shared_secret);
+                                let final_key = SecretBytes::new(bernie_hmac::hash(shared_secret.expose()));
+
+                                // Both ends show a code from the key and the two public keys. The codes only
+                                // match if nobody in the middle ran a separate exchange with each end.
+                                let code = Sas::new(&final_key, &sas::key_context(&dynamic_buffer, &key_pair.1));
 
                                 // Set the key member equal to the final key
                                 let mut unlocked_key = key.lock().unwrap();
                                 *unlocked_key = final_key;
 
                                 println!("[*] DH Key Exchange Successful.");
+                                println!("[*] Short authentication string {}, type {} once the server shows the same", code.code(), sas::VERIFY_COMMAND);
+                                *sas.lock().unwrap() = Some(code);
                                 println!("--------------------------------------\n");
 
                                 first_message = false;
                             } else {
-                                // Separate the message from the MAC tag
-                                let (payload, received_mac_tag) = dynamic_buffer.split_at(dynamic_buffer.len() - MAC_TAG_SIZE);
+                                // Separate the sequence number and the message from the MAC tag
+                                if dynamic_buffer.len() < SEQUENCE_SIZE + MAC_TAG_SIZE {
+                                    return Err(verify::authentication_failure(received));
+                                }
+                                let (sequence_bytes, payload_with_tag) = dynamic_buffer.split_at(SEQUENCE_SIZE);
+                                let (payload, received_mac_tag) = payload_with_tag.split_at(payload_with_tag.len() - MAC_TAG_SIZE);
+                                let sequence = BigEndian::read_u64(sequence_bytes);
 
                                 // Retrieve shared key to verify MAC tag
                                 let key_lock = key.lock().unwrap();
 
-                                // Verify the MAC tag
-                                if !bernie_hmac::verify_hmac(&payload, &received_mac_tag, &key_lock) {
-                                    println!("MAC verification failed!");
-                                    return Err(std::io::Error::new(std::io::ErrorKind::Other, "MAC verification failed"));
-                                } else {
-                                    // Send the complete message (excluding length prefix and MAC tag) to the main thread
-                                    println!("\n[+] MAC tag verification successful.");
-                                    println!("--------------------------------------\n");
-                                    server_tx.send(payload.clone().to_vec()).unwrap();
+                                // Verify the MAC tag, which also covers the direction and sequence number
+                                if !verify::verify_hmac(&sequence::mac_input(SERVER_TO_CLIENT, sequence, payload), received_mac_tag, key_lock.expose()) {
+                                    return Err(verify::authentication_failure(received));
+                                }
+
+                                // An authentic message is only accepted if it is the next one sent to us
+                                match sequence::check(sequence, &mut expected_sequence) {
+                                    Ok(()) => {
+                                        // Send the complete message (excluding length prefix, sequence number and MAC tag) to the main thread
+                                        println!("\n[+] MAC tag verification successful.");
+                                        println!("--------------------------------------\n");
+                                        server_tx.send(payload.to_vec()).unwrap();
+                                    }
+                                    Err(e) => println!("[-] {}", e),
                                 }
                             }
 
@@ -215,4 +274,4 @@ impl Client4 {
         }
         Ok(())
     }
-}
\ No newline at end of file
+}
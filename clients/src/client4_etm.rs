@@ -0,0 +1,325 @@
+use std::str;
+use std::thread;
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use std::net::TcpStream;
+use std::io::{Read, Write, Error};
+use std::sync::mpsc::{Receiver, Sender};
+
+use byteorder::{ByteOrder, BigEndian};
+
+use dh;
+use bernie_hmac;
+
+use crate::modes::{self, Mode, IV_SIZE, MAC_TAG_SIZE};
+use crate::random::Random;
+use crate::sas::{self, Sas};
+use crate::secret::SecretBytes;
+use crate::sequence::{self, CLIENT_TO_SERVER, SERVER_TO_CLIENT, SEQUENCE_SIZE};
+use crate::verify;
+
+// Stage 4 with ECB swapped out for CBC or CTR (SP 800-38A). Every message carries a fresh
+// IV and a sequence number, and the MAC is computed over the direction, the sequence
+// number, the length header, the IV and the ciphertext using a key separate from the
+// encryption key.
+pub struct Client4Etm {
+    key: Arc<Mutex<Option<(SecretBytes, SecretBytes)>>>,
+    // Sequence number of the next message we send
+    next_sequence: Arc<Mutex<u64>>,
+    mode: Mode,
+    // Short authentication string of the session, once the key exchange is done
+    sas: Arc<Mutex<Option<Sas>>>,
+    random: Random,
+}
+
+impl Client4Etm {
+    pub fn new(mode: Mode, random: Random) -> Self {
+        Self { key: Arc::new(Mutex::new(None)), next_sequence: Arc::new(Mutex::new(0)), sas: Arc::new(Mutex::new(None)), mode, random }
+    }
+
+    pub fn run(&mut self, socket: &str) {
+        let mut stream = TcpStream::connect(socket).expect("Could not connect to server");
+
+        // Generate key pair and commit to the client public key. The key itself is only
+        // sent once the server's key has arrived, see sas.rs
+        println!("\n--------------------------------------");
+        println!("[+] Generating key pair ...");
+        let key_pair = self.random.dh_key_pair();
+
+        println!("[+] Sending commitment to our public key to server ...");
+        let commitment = sas::commitment(&key_pair.1);
+        let mut commitment_message = (commitment.len() as u32).to_be_bytes().to_vec();
+        commitment_message.extend(commitment);
+        stream.write_all(&commitment_message).expect("Failed to send commitment");
+
+        // Channel for reading from stdin and sending to server
+        let (stdin_tx, stdin_rx) = mpsc::channel::<Vec<u8>>();
+
+        // Thread for reading from stdin
+        let stdin_tx_clone = stdin_tx.clone();
+        let sas_clone = self.sas.clone();
+        let key_clone = self.key.clone();
+        let next_sequence_clone = self.next_sequence.clone();
+        let mode = self.mode;
+        let random = self.random.clone();
+        thread::spawn(move || {
+            loop {
+                let mut input = String::new();
+                std::io::stdin().read_line(&mut input).unwrap();
+                let temp_bytes = input.as_bytes().to_vec();
+
+                // /verify confirms the server's code rather than being sent
+                if sas::parse_verify(&input).is_some() {
+                    sas::verify_server(&mut sas_clone.lock().unwrap());
+                    continue;
+                }
+
+                // Lock and access the keys
+                let key_guard = key_clone.lock().unwrap();
+                let (encryption_key, mac_key) = match key_guard.as_ref() {
+                    Some(keys) => keys,
+                    None => {
+                        println!("[!] Key exchange has not completed yet, message dropped.");
+                        continue;
+                    }
+                };
+
+                // Generate a new IV for each message
+                println!("\n--------------------------------------");
+                println!("[+] Generating unique IV ...");
+                let mut iv = modes::generate_iv(&random);
+
+                // Encrypt the message
+                println!("[+] Encrypting {} bytes with {} ...", temp_bytes.len(), mode.name());
+                let mut encrypted_bytes = modes::encrypt(mode, &temp_bytes, &iv, encryption_key.expose());
+
+                // Take the next sequence number
+                let mut next_sequence = next_sequence_clone.lock().unwrap();
+                let sequence = *next_sequence;
+                *next_sequence += 1;
+
+                // Construct the length header
+                let header = (4_u32 + SEQUENCE_SIZE as u32 + iv.len() as u32 + encrypted_bytes.len() as u32 + MAC_TAG_SIZE as u32).to_be_bytes().to_vec();
+
+                // Compute the MAC tag over the direction, sequence number, header, IV and ciphertext
+                println!("[+] Computing HMAC-SHA256 over direction, record #{}, header, IV and ciphertext ...", sequence);
+                println!("--------------------------------------");
+                let mut authenticated = sequence::mac_prefix(CLIENT_TO_SERVER, sequence);
+                authenticated.extend_from_slice(&header);
+                let mut mac_tag = modes::compute_tag(&authenticated, &iv, &encrypted_bytes, mac_key.expose());
+
+                // Construct message from the header
+                let mut message_bytes = header;
+
+                // Append the sequence number to the message
+                message_bytes.extend_from_slice(&sequence.to_be_bytes());
+
+                // Append the IV to the message
+                message_bytes.append(&mut iv);
+
+                // Append encrypted bytes to the message
+                message_bytes.append(&mut encrypted_bytes);
+
+                // Append the MAC tag to the message
+                message_bytes.append(&mut mac_tag);
+
+                // Send message_bytes through the stdin channel
+                stdin_tx_clone.send(message_bytes.clone()).unwrap();
+            }
+        });
+
+        // Channel for communicating from server handler thread to main thread
+        let (server_tx, server_rx) = mpsc::channel::<Vec<u8>>();
+
+        // Thread within which messages from the server are retreived and messages to the server are sent
+        let key_clone = self.key.clone();
+        let sas_clone = self.sas.clone();
+        let mode = self.mode;
+        thread::spawn(move || {
+            if let Err(e) = Self::handle_server(stream, stdin_rx, server_tx, key_clone, sas_clone, key_pair, mode) {
+                eprintln!("Error with server: {:?}", e);
+            }
+            println!("Server disconnected");
+        });
+
+        // Main loop to keep the client running and process server responses
+        loop {
+            if let Ok(response_bytes) = server_rx.try_recv() {
+                // response_bytes has already been verified and decrypted in handle_server
+                let message = String::from_utf8_lossy(&response_bytes);
+                println!("Server > {}", message);
+            }
+        }
+        
+    }
+
+    fn handle_server(
+        mut stream: TcpStream, 
+        stdin_rx: Receiver<Vec<u8>>, 
+        server_tx: Sender<Vec<u8>>, 
+        key: Arc<Mutex<Option<(SecretBytes, SecretBytes)>>>, 
+        sas: Arc<Mutex<Option<Sas>>>,
+        key_pair: (SecretBytes, Vec<u8>),
+        mode: Mode
+    ) -> Result<(), std::io::Error> {
+        
+        // To store entire messages sent from the client
+        let mut dynamic_buffer = Vec::new();
+
+        // Server messages will be prepended with the total length of the message
+        let mut expected_length: Option<usize> = None;
+
+        // To keep track of how many bytes out of the expected lenth have been received
+        let mut total_received = 0;
+
+        // To see if key is being sent
+        let mut first_message = true;
+
+        // Sequence number of the next message we expect from the server
+        let mut expected_sequence: u64 = 0;
+
+        loop {
+            // Non-blocking attempt to receive message from stdin and send to server
+            if let Ok(bytes) = stdin_rx.try_recv() {
+                // This method will continuously call write until there is no more data
+                // to be written or an ErrorKind::Iterrupted occurs.
+                stream.write_all(&bytes)?;
+            }
+
+            // Allows for 1 second of blocking while trying to read from the stream
+            stream.set_read_timeout(Some(Duration::new(1, 0)))?;
+
+            // The server will be sending a message whose total length will be prepended to the message
+            // as the first 4 bytes. If the server's message is larger than the segment size we are expecting
+            // we will continue to read in bytes until we have read in the message length's number of bytes
+
+            // Temporary buffer
+            let mut buffer = [0_u8; 512];
+
+            // Attempt to read bytes sent from server
+            match stream.read(&mut buffer) {
+                Ok(0) => break, // Connection closed by client
+                Ok(bytes_read) => {
+                    // Read the length prefix if it's not already set and we have enough bytes
+                    if expected_length.is_none() && bytes_read >= 4 {
+                        // This branch is taken at the beginning of a new message
+
+                        // read_u32 will only read in the first 4 bytes
+                        expected_length = Some(BigEndian::read_u32(&buffer) as usize);
+
+                        // Ensure only the part of the buffer after the length prefix is added
+                        if bytes_read > 4 {
+                            dynamic_buffer.extend_from_slice(&buffer[4..bytes_read]);
+                        }
+                    } else {
+                        // Otherwise, keep adding the message segments to the buffer
+                        dynamic_buffer.extend_from_slice(&buffer[..bytes_read]);
+                    }
+
+                    total_received += bytes_read;
+
+                    // Check if the entire message has been received
+                    if let Some(length) = expected_length {
+                        if total_received >= length {
+                            // A failure to verify is reported a fixed time after this point
+                            let received = Instant::now();
+
+                            // If this is the first message, it is the server sending its public key
+                            if first_message {
+                                println!("[*] Received server's public key");
+
+                                // The server's key is fixed now, so the key we committed to can go out
+                                println!("[+] Sending public key to server ...");
+                                let key_length = key_pair.1.len() as u32;
+                                let mut key_message = key_length.to_be_bytes().to_vec();
+                                key_message.extend(key_pair.1.clone());
+                                stream.write_all(&key_message)?;
+
+                                // Use the server's public key to compute the shared secret
+                                println!("[+] Calculating shared secret ...");
+                                let modulus = dh::get_domain_params().0;
+                                let shared_secret = SecretBytes::new(dh::get_secret(&dynamic_buffer, key_pair.0.expose(), &modulus));
+
+                                // Use SHA-256 as the KDF to compute the final key
+                                println!("[+] Using SHA-256 as KDF to compute final key ...");
+                                let final_key = SecretBytes::new(bernie_hmac::hash(shared_secret.expose()));
+
+                                // Both ends show a code from the key and the two public keys. The codes only
+                                // match if nobody in the middle ran a separate exchange with each end.
+                                let code = Sas::new(&final_key, &sas::key_context(&dynamic_buffer, &key_pair.1));
+
+                                // Split the final key into separate encryption and MAC keys
+                                println!("[+] Deriving separate encryption and MAC keys ...");
+                                let etm_keys = modes::derive_etm_keys(final_key.expose());
+
+                                // Set the key member equal to the derived keys
+                                let mut unlocked_key = key.lock().unwrap();
+                                *unlocked_key = Some(etm_keys);
+
+                                println!("[*] DH Key Exchange Successful.");
+                                println!("[*] Short authentication string {}, type {} once the server shows the same", code.code(), sas::VERIFY_COMMAND);
+                                *sas.lock().unwrap() = Some(code);
+                                println!("--------------------------------------\n");
+
+                                first_message = false;
+                            } else {
+                                // Separate the sequence number, the IV and the ciphertext from the MAC tag
+                                println!("--------------------------------------");
+                                println!("[+] {} bytes received.", total_received);
+                                if dynamic_buffer.len() < SEQUENCE_SIZE + IV_SIZE + MAC_TAG_SIZE {
+                                    return Err(verify::authentication_failure(received));
+                                }
+                                let (sequence_bytes, rest) = dynamic_buffer.split_at(SEQUENCE_SIZE);
+                                let (iv, payload_with_tag) = rest.split_at(IV_SIZE);
+                                let (payload, received_mac_tag) = payload_with_tag.split_at(payload_with_tag.len() - MAC_TAG_SIZE);
+                                let sequence = BigEndian::read_u64(sequence_bytes);
+
+                                // The MAC also covers the direction and the length header which was stripped off the buffer
+                                let mut authenticated = sequence::mac_prefix(SERVER_TO_CLIENT, sequence);
+                                authenticated.extend_from_slice(&(length as u32).to_be_bytes());
+
+                                // Retrieve shared keys to verify MAC tag
+                                let key_lock = key.lock().unwrap();
+                                let (encryption_key, mac_key) = key_lock.as_ref()
+                                    .ok_or_else(|| std::io::Error::other("Message before key exchange"))?;
+
+                                // Verify the MAC tag before touching the ciphertext
+                                if !modes::verify_tag(&authenticated, iv, payload, received_mac_tag, mac_key.expose()) {
+                                    return Err(verify::authentication_failure(received));
+                                }
+                                println!("[+] MAC tag verification successful.");
+
+                                // An authentic message is only accepted if it is the next one sent to us
+                                if let Err(e) = sequence::check(sequence, &mut expected_sequence) {
+                                    println!("[-] {}", e);
+                                    println!("--------------------------------------\n");
+                                } else {
+                                    // Only now is the ciphertext decrypted
+                                    println!("[+] Decrypting record #{} ({} bytes) with {} ...", sequence, payload.len(), mode.name());
+                                    match modes::decrypt(mode, payload, iv, encryption_key.expose()) {
+                                        Some(message) => {
+                                            // Send the decrypted message to the main thread
+                                            println!("--------------------------------------\n");
+                                            server_tx.send(message).unwrap();
+                                        }
+                                        None => {
+                                            return Err(verify::authentication_failure(received));
+                                        }
+                                    }
+                                }
+                            }
+
+                            dynamic_buffer.clear();
+                            expected_length = None;
+                            total_received = 0;
+                        }
+                    }
+                }
+                Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => continue,
+                Err(_) => break, // Error or disconnection has occured
+            }
+        }
+        Ok(())
+    }
+}
\ No newline at end of file
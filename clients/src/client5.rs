@@ -2,85 +2,171 @@ use std::str;
 use std::thread;
 use std::sync::mpsc;
 use std::sync::{Arc, Mutex};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use std::net::TcpStream;
 use std::io::{Read, Write, Error};
 use std::sync::mpsc::{Receiver, Sender};
 
-use byteorder::{ByteOrder, BigEndian};
-use rand::{Rng, thread_rng};
-
-use aes_crypt;
 use dh;
 
-const MAC_TAG_SIZE: usize = 16; // 16 bytes or 128 bits
-const IV_SIZE: usize = 12; // 12 bytes or 96 bits
+use crate::config::SessionConfig;
+use crate::handshake::{self, ClientHello, ServerHello, Transcript};
+use crate::mlkem;
+use crate::proof::{self, SignedMessage};
+use crate::ratchet::Ratchet;
+use crate::random::{KeyPair, Random};
+use crate::secret::SecretBytes;
+use crate::sas::{self, Sas};
+use crate::record::{self, ContentType, PaddingPolicy, Record, RecordError, RecordLayer, Side};
+use crate::suites::{self, CipherSuite};
+use crate::ticket::{self, SessionTicket};
+use crate::verify;
 
 pub struct Client5 {
-    key: Arc<Mutex<Vec<u8>>>,
+    key: Arc<Mutex<Option<RecordLayer>>>,
+    // Short authentication string of the session, once the handshake is done
+    sas: Arc<Mutex<Option<Sas>>>,
+    config: SessionConfig,
+    random: Random,
+}
+
+// The connection to the server as its thread sees it: the stream, the records sealed by
+// the stdin thread, the channel the server's messages go to the main thread on and the
+// session state shared with the stdin thread
+struct Connection {
+    stream: TcpStream,
+    stdin_rx: Receiver<Vec<u8>>,
+    server_tx: Sender<Vec<u8>>,
+    key: Arc<Mutex<Option<RecordLayer>>>,
+    sas: Arc<Mutex<Option<Sas>>>,
+}
+
+// Keys for a session whose ServerHello has arrived but whose Finished has not
+struct PendingHandshake {
+    record_layer: RecordLayer,
+    server_finished: Vec<u8>,
+    client_finished: Vec<u8>,
+}
+
+impl PendingHandshake {
+    fn new(record_layer: RecordLayer, final_key: &SecretBytes, transcript: &Transcript) -> Self {
+        Self {
+            record_layer,
+            server_finished: handshake::finished(final_key, Side::Server, transcript),
+            client_finished: handshake::finished(final_key, Side::Client, transcript),
+        }
+    }
 }
 
 impl Client5 {
-    pub fn new() -> Self {
-        Self { key: Arc::new(Mutex::new(Vec::new())) }
+    pub fn new(random: Random) -> Self {
+        Self::with_config(SessionConfig::default(), random)
+    }
+
+    // The suites this client offers in its ClientHello, most preferred first
+    pub fn with_suites(suites: Vec<CipherSuite>, random: Random) -> Self {
+        Self::with_config(SessionConfig { suites, ..SessionConfig::default() }, random)
+    }
+
+    pub fn with_config(config: SessionConfig, random: Random) -> Self {
+        Self { key: Arc::new(Mutex::new(None)), sas: Arc::new(Mutex::new(None)), config, random }
+    }
+
+    // Keying material exported from the session, None until the handshake has finished
+    pub fn export_keying_material(&self, label: &str, context: &[u8], length: usize) -> Option<SecretBytes> {
+        self.key.lock().unwrap().as_ref()?.export_keying_material(label, context, length)
     }
 
     pub fn run(&mut self, socket: &str) {
         let mut stream = TcpStream::connect(socket).expect("Could not connect to server");
-
-        // Generate key pair and send client public key
         println!("\n--------------------------------------");
-        println!("[+] Generating key pair ...");
-        let key_pair = dh::gen_key_pair();
 
-        println!("[+] Sending public key to server ...");
-        let key_length = key_pair.1.len() as u32;
-        let mut key_message = key_length.to_be_bytes().to_vec();
-        key_message.extend(key_pair.1.clone());
-        stream.write_all(&key_message).expect("Failed to send server public key");
+        // A ticket saved by an earlier run lets us skip the DH exchange. It is only offered
+        // while it is valid and for a suite we are still willing to use.
+        let resumption = self.config.ticket_file.as_ref()
+            .and_then(|path| std::fs::read(path).ok())
+            .and_then(|bytes| SessionTicket::from_bytes(&bytes))
+            .filter(|session_ticket| !session_ticket.is_expired(&self.random) && self.config.allowed_suites().contains(&session_ticket.suite));
+
+        let (key_pair, kem_key_pair, client_hello) = match &resumption {
+            Some(session_ticket) => {
+                // The nonce takes the place of the public key so the new keys are fresh
+                println!("[+] Sending ClientHello with a session ticket for {} ...", session_ticket.suite.name());
+                let client_hello = ClientHello {
+                    suites: self.config.allowed_suites(),
+                    ratchet: false,
+                    kem_public_key: None,
+                    ticket: Some(session_ticket.ticket.clone()),
+                    signed: self.config.signing_key.is_some(),
+                    gcm_tag_size: self.config.gcm_tag_size,
+                    padded: self.config.padding != PaddingPolicy::None,
+                    committed: false,
+                    public_key: self.random.bytes(ticket::NONCE_SIZE),
+                }.to_bytes();
+                (None, None, client_hello)
+            }
+            None => {
+                let (key_pair, kem_key_pair, client_hello) = Self::full_client_hello(&self.config, &self.random);
+                (Some(key_pair), kem_key_pair, client_hello)
+            }
+        };
+        stream.write_all(&record::encode_frame(ContentType::ClientHello, 0, &client_hello)).expect("Failed to send ClientHello");
+
+        // Every handshake message sent or received, in order
+        let mut transcript = Transcript::new();
+        transcript.add(&client_hello);
 
         // Channel for reading from stdin and sending to server
         let (stdin_tx, stdin_rx) = mpsc::channel::<Vec<u8>>();
 
         // Thread for reading from stdin
         let stdin_tx_clone = stdin_tx.clone();
+        let sas_clone = self.sas.clone();
         let key_clone = self.key.clone();
+        let signing_key = self.config.signing_key.clone();
+        let random = self.random.clone();
         thread::spawn(move || {
             loop {
                 let mut input = String::new();
                 std::io::stdin().read_line(&mut input).unwrap();
                 let temp_bytes = input.as_bytes().to_vec();
 
-                // Lock and access the key
-                let key_guard = key_clone.lock().unwrap();
-
-                // Generate a new IV for each message
-                println!("\n--------------------------------------");
-                println!("[+] Generating unique IV ...");
-                let mut iv = generate_iv();
+                // /verify confirms the server's code rather than being sent
+                if sas::parse_verify(&input).is_some() {
+                    sas::verify_server(&mut sas_clone.lock().unwrap());
+                    continue;
+                }
 
-                // Initialize the additional authenticated data (if any)
-                let aad: Vec<u8> = Vec::new();
+                // Lock and access the record layer
+                let mut key_guard = key_clone.lock().unwrap();
+                let record_layer = match key_guard.as_mut() {
+                    Some(record_layer) => record_layer,
+                    None => {
+                        println!("[!] Handshake has not completed yet, message dropped.");
+                        continue;
+                    }
+                };
 
-                // Encrypt the message and retrieve the ciphertext and authentication tag
-                println!("[+] Encrypting {} bytes with AES-256-GCM ...", temp_bytes.len());
+                // Protect the message with the negotiated suite
+                println!("\n--------------------------------------");
+                let mut sequence = record_layer.write_sequence();
+                if record_layer.update_due() {
+                    println!("[+] Sending KeyUpdate as record #{} and ratcheting sending key to epoch {} ...", sequence, record_layer.write_epoch() + 1);
+                    sequence += 1;
+                }
+                // With signed messages on, what gets encrypted is the message signed for its record
+                let mut message = temp_bytes.clone();
+                if let Some(key_pair) = signing_key.as_ref().filter(|_| record_layer.signed_messages()) {
+                    println!("[+] Signing message for record #{} with key {} ...", sequence, proof::fingerprint(&key_pair.1));
+                    message = SignedMessage::sign(Side::Client, sequence, record_layer.channel_binding().unwrap().expose(), &temp_bytes, key_pair, &random).to_bytes();
+                }
+                println!("[+] Encrypting {} bytes as record #{} with {} ...", message.len(), sequence, record_layer.suite().name());
                 println!("--------------------------------------");
-                let (mut encrypted_bytes, mut auth_tag) = aes_crypt::encrypt_gcm(&temp_bytes, &iv, &aad, &key_guard, MAC_TAG_SIZE * 8);
-
-                // Construct message with length header
-                let mut message_bytes = (4_u32 + encrypted_bytes.len() as u32 + auth_tag.len() as u32 + IV_SIZE as u32).to_be_bytes().to_vec();
-
-                // Append encrypted bytes to the message
-                message_bytes.append(&mut encrypted_bytes);
-
-                // Append the authentication tag to the message
-                message_bytes.append(&mut auth_tag);
-
-                // Append the IV to the message
-                message_bytes.append(&mut iv);
-
-                // Send message_bytes through the stdin channel
-                stdin_tx_clone.send(message_bytes.clone()).unwrap();
+                match record_layer.seal(&message) {
+                    // Send message_bytes through the stdin channel
+                    Ok(message_bytes) => stdin_tx_clone.send(message_bytes).unwrap(),
+                    Err(e) => println!("[!] Could not send message: {}", e),
+                }
             }
         });
 
@@ -89,8 +175,12 @@ impl Client5 {
 
         // Thread within which messages from the server are retreived and messages to the server are sent
         let key_clone = self.key.clone();
+        let sas_clone = self.sas.clone();
+        let config = self.config.clone();
+        let random = self.random.clone();
         thread::spawn(move || {
-            if let Err(e) = Self::handle_server(stream, stdin_rx, server_tx, key_clone, key_pair) {
+            let connection = Connection { stream, stdin_rx, server_tx, key: key_clone, sas: sas_clone };
+            if let Err(e) = Self::handle_server(connection, key_pair, kem_key_pair, resumption, config, transcript, random) {
                 eprintln!("Error with server: {:?}", e);
             }
             println!("Server disconnected");
@@ -107,25 +197,86 @@ impl Client5 {
         
     }
 
+    // Generates our key pairs and builds a ClientHello for a handshake without a ticket
+    fn full_client_hello(config: &SessionConfig, random: &Random) -> (KeyPair, Option<KeyPair>, Vec<u8>) {
+        // Generate key pair and commit to the public key alongside the suites we support.
+        // The key itself is only sent once the server's key has arrived, see sas.rs
+        println!("[+] Generating key pair ...");
+        let key_pair = random.dh_key_pair();
+
+        // For the hybrid exchange the server encapsulates a second secret to this key
+        let kem_key_pair = if config.hybrid {
+            println!("[+] Generating ML-KEM-768 key pair ...");
+            Some(mlkem::key_pair(random).expect("Failed to generate ML-KEM key pair"))
+        } else {
+            None
+        };
+
+        // Suites below our minimum are left out of the offer altogether
+        println!("[+] Sending ClientHello offering: {}", suites::describe(&config.allowed_suites()));
+        if config.ratchet {
+            println!("[+] Requesting Double Ratchet for per-message keys");
+        }
+        if config.hybrid {
+            println!("[+] Requesting hybrid ML-KEM-768 + DH key exchange");
+        }
+        if config.gcm_tag_size < record::GCM_TAG_SIZE {
+            println!("[+] Accepting GCM tags down to {} bits", config.gcm_tag_size * 8);
+        }
+        if config.padding != PaddingPolicy::None {
+            println!("[+] Requesting record padding, sending records with {}", config.padding);
+        }
+        if let Some(key_pair) = &config.signing_key {
+            println!("[+] Requesting signed messages, signing with key {}", proof::fingerprint(&key_pair.1));
+        }
+        println!("[+] Committing to our public key, which is revealed after the ServerHello");
+        let client_hello = ClientHello {
+            suites: config.allowed_suites(),
+            ratchet: config.ratchet,
+            kem_public_key: kem_key_pair.as_ref().map(|(_, kem_public_key)| kem_public_key.clone()),
+            ticket: None,
+            signed: config.signing_key.is_some(),
+            gcm_tag_size: config.gcm_tag_size,
+            padded: config.padding != PaddingPolicy::None,
+            committed: true,
+            public_key: sas::commitment(&key_pair.1),
+        }.to_bytes();
+        (key_pair, kem_key_pair, client_hello)
+    }
+
+    // The short authentication string comes from the final key and the hello transcript,
+    // so a man in the middle, who runs a separate handshake with each end, shows the two
+    // ends different codes
+    fn show_code(sas: &Arc<Mutex<Option<Sas>>>, final_key: &SecretBytes, transcript: &Transcript) {
+        let code = Sas::new(final_key, &transcript.hash());
+        println!("[*] Short authentication string {}, type {} once the server shows the same", code.code(), sas::VERIFY_COMMAND);
+        *sas.lock().unwrap() = Some(code);
+    }
+
     fn handle_server(
-        mut stream: TcpStream, 
-        stdin_rx: Receiver<Vec<u8>>, 
-        server_tx: Sender<Vec<u8>>, 
-        key: Arc<Mutex<Vec<u8>>>, 
-        key_pair: (Vec<u8>, Vec<u8>)
+        connection: Connection,
+        mut key_pair: Option<KeyPair>,
+        mut kem_key_pair: Option<KeyPair>,
+        mut resumption: Option<SessionTicket>,
+        config: SessionConfig,
+        mut transcript: Transcript,
+        random: Random
     ) -> Result<(), std::io::Error> {
+        let Connection { mut stream, stdin_rx, server_tx, key, sas } = connection;
         
-        // To store entire messages sent from the client
+        // Bytes received from the server which have not yet been handled. Every frame is
+        // prepended with its total length, so a frame may arrive split across several
+        // reads or several frames may arrive in a single read
         let mut dynamic_buffer = Vec::new();
 
-        // Server messages will be prepended with the total length of the message
-        let mut expected_length: Option<usize> = None;
+        // Secret the next session can resume from, known once the handshake is done
+        let mut resumption_secret: Option<SecretBytes> = None;
 
-        // To keep track of how many bytes out of the expected lenth have been received
-        let mut total_received = 0;
+        // Key the server signs its messages with, once the first one has arrived
+        let mut server_signing_key = None;
 
-        // To see if key is being sent
-        let mut first_message = true;
+        // Keys held back until the server's Finished has been checked
+        let mut pending: Option<PendingHandshake> = None;
 
         loop {
             // Non-blocking attempt to receive message from stdin and send to server
@@ -138,10 +289,6 @@ impl Client5 {
             // Allows for 1 second of blocking while trying to read from the stream
             stream.set_read_timeout(Some(Duration::new(1, 0)))?;
 
-            // The server will be sending a message whose total length will be prepended to the message
-            // as the first 4 bytes. If the server's message is larger than the segment size we are expecting
-            // we will continue to read in bytes until we have read in the message length's number of bytes
-
             // Temporary buffer
             let mut buffer = [0_u8; 512];
 
@@ -149,80 +296,296 @@ impl Client5 {
             match stream.read(&mut buffer) {
                 Ok(0) => break, // Connection closed by client
                 Ok(bytes_read) => {
-                    // Read the length prefix if it's not already set and we have enough bytes
-                    if expected_length.is_none() && bytes_read >= 4 {
-                        // This branch is taken at the beginning of a new message
+                    dynamic_buffer.extend_from_slice(&buffer[..bytes_read]);
+
+                    // Handle every complete frame received so far
+                    loop {
+                        // A failure to verify is reported a fixed time after this point
+                        let received = Instant::now();
+
+                        // A frame that can not be parsed, too long or with an unknown type,
+                        // fails the same way as a record that does not verify
+                        let frame = match record::take_frame(&mut dynamic_buffer) {
+                            Ok(Some(frame)) => frame,
+                            Ok(None) => break,
+                            Err(_) => {
+                                let error = verify::authentication_failure(received);
+                                stream.write_all(&record::alert_frame())?;
+                                return Err(error);
+                            }
+                        };
 
-                        // read_u32 will only read in the first 4 bytes
-                        expected_length = Some(BigEndian::read_u32(&buffer) as usize);
+                        match frame.content_type {
+                            // The first message is the server's chosen suite and its public key
+                            ContentType::ServerHello => {
+                                if key.lock().unwrap().is_some() || pending.is_some() {
+                                    return Err(std::io::Error::other("Unexpected ServerHello"));
+                                }
 
-                        // Ensure only the part of the buffer after the length prefix is added
-                        if bytes_read > 4 {
-                            dynamic_buffer.extend_from_slice(&buffer[4..bytes_read]);
-                        }
-                    } else {
-                        // Otherwise, keep adding the message segments to the buffer
-                        dynamic_buffer.extend_from_slice(&buffer[..bytes_read]);
-                    }
+                                let server_hello = ServerHello::from_bytes(&frame.body)
+                                    .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidData, "Malformed ServerHello"))?;
 
-                    total_received += bytes_read;
+                                // The server may only pick a suite we actually offered, which rules out
+                                // anything below our minimum
+                                if !config.allowed_suites().contains(&server_hello.suite) {
+                                    println!("[!] Server selected a suite we did not offer: {}", server_hello.suite.name());
+                                    return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "Server selected an unoffered suite"));
+                                }
+                                if server_hello.ratchet && !config.ratchet {
+                                    return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "Server enabled a ratchet we did not ask for"));
+                                }
+                                if server_hello.kem_ciphertext.is_some() && kem_key_pair.is_none() {
+                                    return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "Server sent an ML-KEM ciphertext we did not ask for"));
+                                }
+                                // Asking for the hybrid exchange means not settling for DH alone, so
+                                // a server that declines it ends the handshake
+                                if server_hello.kem_ciphertext.is_none() && kem_key_pair.is_some() {
+                                    println!("[!] Server declined the hybrid ML-KEM-768 + DH key exchange, aborting");
+                                    return Err(std::io::Error::new(std::io::ErrorKind::PermissionDenied, "Server declined the hybrid key exchange"));
+                                }
+                                // The tag may be no shorter than we accept, and only GCM has one to shorten
+                                let tag_too_short = server_hello.gcm_tag_size < config.gcm_tag_size;
+                                let tag_without_gcm = server_hello.suite != CipherSuite::Aes256Gcm && server_hello.gcm_tag_size != record::GCM_TAG_SIZE;
+                                if tag_too_short || tag_without_gcm {
+                                    return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "Server chose a GCM tag length we do not accept"));
+                                }
+                                if server_hello.padded && config.padding == PaddingPolicy::None {
+                                    return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "Server enabled record padding we did not ask for"));
+                                }
+                                if server_hello.signed && config.signing_key.is_none() {
+                                    return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "Server enabled signed messages we did not ask for"));
+                                }
+                                // A server that will not resume has to say so with TicketDeclined
+                                if server_hello.resumed != resumption.is_some() {
+                                    return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "Server did not answer our ticket"));
+                                }
+                                transcript.add(&frame.body);
+                                match (server_hello.signed, &config.signing_key) {
+                                    (true, _) => println!("[*] Server accepted signed messages"),
+                                    (false, Some(_)) => println!("[!] Server declined signed messages, they will be sent unsigned"),
+                                    (false, None) => {}
+                                }
+                                if config.padding != PaddingPolicy::None && !server_hello.padded {
+                                    println!("[!] Server declined record padding, record lengths will show message lengths");
+                                }
 
-                    // Check if the entire message has been received
-                    if let Some(length) = expected_length {
-                        if total_received >= length {
-                            // If this is the first message, it is the server sending its public key
-                            if first_message {
-                                println!("[*] Received server's public key");
+                                // Resuming, the final key comes from the ticket's secret and both nonces
+                                if let Some(session_ticket) = &resumption {
+                                    if server_hello.suite != session_ticket.suite || server_hello.ratchet || server_hello.kem_ciphertext.is_some() {
+                                        return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "Resumed session does not match the ticket"));
+                                    }
+                                    println!("[*] Server accepted our session ticket, resuming with {}", server_hello.suite.name());
+                                    println!("[+] Using SHA-256 as KDF to compute final key from the resumption secret ...");
+                                    let final_key = handshake::derive_key(&session_ticket.resumption_secret, &transcript);
+                                    resumption_secret = Some(ticket::resumption_secret(&final_key));
+                                    let mut record_layer = RecordLayer::new(server_hello.suite, &final_key, Side::Client, config.rekey, random.clone())
+                                        .with_gcm_tag_size(server_hello.gcm_tag_size);
+                                    if server_hello.signed {
+                                        record_layer = record_layer.with_signed_messages();
+                                    }
+                                    if server_hello.padded {
+                                        record_layer = record_layer.with_padding(config.padding.clone());
+                                    }
+                                    println!("[*] Channel binding {}", record_layer.channel_binding().unwrap());
+                                    pending = Some(PendingHandshake::new(record_layer, &final_key, &transcript));
+
+                                    println!("[*] Session Resumed without a DH exchange. Final key {}", final_key);
+                                    Self::show_code(&sas, &final_key, &transcript);
+                                    println!("--------------------------------------\n");
+                                    continue;
+                                }
+                                let key_pair = key_pair.as_ref().unwrap();
+
+                                println!("[*] Received ServerHello selecting: {}", server_hello.suite.name());
+                                if server_hello.gcm_tag_size < record::GCM_TAG_SIZE {
+                                    println!("[*] Server chose {}-bit GCM tags", server_hello.gcm_tag_size * 8);
+                                }
+                                if server_hello.ratchet {
+                                    println!("[*] Server accepted Double Ratchet for per-message keys");
+                                }
+                                if server_hello.kem_ciphertext.is_some() {
+                                    println!("[*] Server accepted hybrid ML-KEM-768 + DH key exchange");
+                                }
+
+                                // The server's key is fixed now, so the key we committed to can go out
+                                println!("[+] Sending KeyReveal with the public key our ClientHello committed to ...");
+                                stream.write_all(&record::encode_frame(ContentType::KeyReveal, 0, &key_pair.1))?;
+                                transcript.add(&key_pair.1);
 
                                 // Use the server's public key to compute the shared secret
                                 println!("[+] Calculating shared secret ...");
                                 let modulus = dh::get_domain_params().0;
-                                let shared_secret = dh::get_secret(&dynamic_buffer.clone(), &key_pair.0, &modulus);
+                                let mut shared_secret = SecretBytes::new(dh::get_secret(&server_hello.public_key, key_pair.0.expose(), &modulus));
+
+                                // Recover the ML-KEM secret from the server's ciphertext and mix it in
+                                if let (Some(kem_ciphertext), Some((decapsulation_key, _))) = (&server_hello.kem_ciphertext, &kem_key_pair) {
+                                    println!("[+] Decapsulating ML-KEM shared secret and combining it with the DH secret ...");
+                                    let kem_secret = mlkem::decapsulate(decapsulation_key, kem_ciphertext)
+                                        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e.to_string()))?;
+                                    shared_secret = handshake::hybrid_secret(&shared_secret, &kem_secret);
+                                }
 
-                                // Use SHA-256 as the KDF to compute the final key
+                                // Use SHA-256 over the secret and the transcript as the KDF to compute the final key
                                 println!("[+] Using SHA-256 as KDF to compute final key ...");
-                                let final_key = bernie_hmac::hash(&shared_secret);
+                                let final_key = handshake::derive_key(&shared_secret, &transcript);
+                                resumption_secret = Some(ticket::resumption_secret(&final_key));
+
+                                // The server's public key is its first ratchet key
+                                let mut record_layer = RecordLayer::new(server_hello.suite, &final_key, Side::Client, config.rekey, random.clone())
+                                    .with_gcm_tag_size(server_hello.gcm_tag_size);
+                                if server_hello.ratchet {
+                                    record_layer = record_layer.with_ratchet(Ratchet::initiate(&final_key, server_hello.public_key.clone(), random.clone()));
+                                }
+                                if server_hello.signed {
+                                    record_layer = record_layer.with_signed_messages();
+                                }
+                                if server_hello.padded {
+                                    record_layer = record_layer.with_padding(config.padding.clone());
+                                }
 
-                                // Set the key member equal to the final key
-                                let mut unlocked_key = key.lock().unwrap();
-                                *unlocked_key = final_key;
+                                // The record layer is only put to use once the server's Finished checks out
+                                println!("[*] Channel binding {}", record_layer.channel_binding().unwrap());
+                                pending = Some(PendingHandshake::new(record_layer, &final_key, &transcript));
 
-                                println!("[*] DH Key Exchange Successful.");
+                                // Only a fingerprint of the key is printed, so both ends can be compared by eye
+                                let exchange = if server_hello.kem_ciphertext.is_some() { "Hybrid ML-KEM-768 + DH" } else { "DH" };
+                                println!("[*] {} Key Exchange Successful. Final key {}", exchange, final_key);
+                                Self::show_code(&sas, &final_key, &transcript);
                                 println!("--------------------------------------\n");
-
-                                first_message = false;
-                            } else {
-                                // Separate the message from the MAC tag and IV
+                            }
+                            // The server's Finished shows it saw the same hellos we did
+                            ContentType::Finished => {
+                                let handshake = pending.take()
+                                    .ok_or_else(|| std::io::Error::other("Unexpected Finished"))?;
                                 println!("--------------------------------------");
-                                println!("[+] {} bytes received.", total_received);
-                                let (payload_with_tag, iv) = dynamic_buffer.split_at(dynamic_buffer.len() - IV_SIZE);
-                                let (payload, auth_tag) = payload_with_tag.split_at(payload_with_tag.len() - MAC_TAG_SIZE);
-
-                                // Retrieve shared key 
-                                let key_lock = key.lock().unwrap();
-
-                                println!("[+] Decrypting {} bytes with AES-256-GCM ...", payload.len());
-
-                                // Decrypt and verify - Using empty AAD for now - Need to update later
-                                let temp_aad: Vec<u8> = Vec::new();
-                                let (message, result) = aes_crypt::decrypt_gcm(payload, iv, &temp_aad, auth_tag, &key_lock);
+                                if !verify::constant_time_eq(&handshake.server_finished, &frame.body) {
+                                    let error = verify::authentication_failure(received);
+                                    stream.write_all(&record::alert_frame())?;
+                                    return Err(error);
+                                }
+                                println!("[*] Server Finished verified, both sides saw the same offer and selection");
+                                println!("[+] Sending Finished over the hello transcript ...");
+                                stream.write_all(&record::encode_frame(ContentType::Finished, 0, &handshake.client_finished))?;
 
-                                // Check result
-                                if result {
-                                    // Send the complete message (excluding length prefix, MAC tag, and IV) to the main thread
-                                    println!("[+] AES-GCM authentication and decryption successful.");
-                                    println!("--------------------------------------\n");
-                                    server_tx.send(message.clone().to_vec()).unwrap();    
-                                } else {
-                                    println!("[!] AES-GCM authentication failed: Data integrity cannot be verified.");
-                                    println!("--------------------------------------\n");
-                                    return Err(std::io::Error::new(std::io::ErrorKind::Other, "MAC verification failed"));          
+                                // Set the key member equal to the record layer built from the final key
+                                *key.lock().unwrap() = Some(handshake.record_layer);
+                                println!("--------------------------------------\n");
+                            }
+                            ContentType::ApplicationData | ContentType::KeyUpdate => {
+                                println!("--------------------------------------");
+                                println!("[+] {} bytes received.", frame.body.len() + record::HEADER_SIZE);
+
+                                // Retrieve the record layer
+                                let mut key_lock = key.lock().unwrap();
+                                let record_layer = key_lock.as_mut()
+                                    .ok_or_else(|| std::io::Error::other("Application data before handshake"))?;
+
+                                println!("[+] Decrypting record #{} ({} bytes) with {} ...", frame.sequence, frame.body.len(), record_layer.suite().name());
+                                let ratchet_steps = record_layer.ratchet_steps();
+                                match record_layer.open(&frame) {
+                                    Ok(Record::ApplicationData(message)) => {
+                                        if record_layer.ratchet_steps() != ratchet_steps {
+                                            println!("[*] Server sent a new ratchet key, DH ratchet step #{} replaced both chains", record_layer.ratchet_steps().unwrap());
+                                        }
+
+                                        // A signed message is only delivered once its signature checks out
+                                        let message = if record_layer.signed_messages() {
+                                            let signed_message = match proof::open_signed(&message, Side::Server, frame.sequence, record_layer.channel_binding().unwrap().expose(), &mut server_signing_key) {
+                                                Some(signed_message) => signed_message,
+                                                None => {
+                                                    let error = verify::authentication_failure(received);
+                                                    stream.write_all(&record::alert_frame())?;
+                                                    return Err(error);
+                                                }
+                                            };
+                                            println!("[*] Message signed by server key {}", signed_message.signer());
+                                            if let Some(path) = &config.proof_file {
+                                                match signed_message.export(path) {
+                                                    Ok(()) => println!("[+] Proof appended to {}", path),
+                                                    Err(e) => println!("[!] Could not write proof to {}: {}", path, e),
+                                                }
+                                            }
+                                            signed_message.content
+                                        } else {
+                                            message
+                                        };
+
+                                        // Send the decrypted message to the main thread
+                                        println!("[+] Authentication and decryption successful.");
+                                        println!("--------------------------------------\n");
+                                        server_tx.send(message).unwrap();
+                                    }
+                                    Ok(Record::KeyUpdate) => {
+                                        println!("[*] Server updated its sending key, receiving key ratcheted to epoch {}", record_layer.read_epoch());
+                                        println!("--------------------------------------\n");
+                                    }
+                                    // A replayed or reordered record is authentic but is dropped without being
+                                    // delivered. The connection itself is left open.
+                                    Err(e @ RecordError::Replay { .. }) | Err(e @ RecordError::OutOfOrder { .. }) => {
+                                        println!("[!] {}", e);
+                                        println!("--------------------------------------\n");
+                                    }
+                                    // Anything else gets the same alert after the same delay,
+                                    // whichever check the record failed
+                                    Err(_) => {
+                                        let error = verify::authentication_failure(received);
+                                        stream.write_all(&record::alert_frame())?;
+                                        return Err(error);
+                                    }
                                 }
                             }
-
-                            dynamic_buffer.clear();
-                            expected_length = None;
-                            total_received = 0;
+                            // The server could not use our ticket, so start over with a full handshake
+                            ContentType::TicketDeclined if resumption.is_some() && key.lock().unwrap().is_none() => {
+                                println!("[!] Server declined our session ticket, falling back to a full handshake");
+                                if let Some(path) = &config.ticket_file {
+                                    let _ = std::fs::remove_file(path);
+                                }
+                                resumption = None;
+
+                                let (new_key_pair, new_kem_key_pair, client_hello) = Self::full_client_hello(&config, &random);
+                                key_pair = Some(new_key_pair);
+                                kem_key_pair = new_kem_key_pair;
+                                transcript = Transcript::new();
+                                transcript.add(&client_hello);
+                                stream.write_all(&record::encode_frame(ContentType::ClientHello, 0, &client_hello))?;
+                            }
+                            // A ticket for our next connection, protected under the session keys
+                            ContentType::NewSessionTicket => {
+                                let mut key_lock = key.lock().unwrap();
+                                let record_layer = key_lock.as_mut()
+                                    .ok_or_else(|| std::io::Error::other("Session ticket before handshake"))?;
+
+                                let body = match record_layer.open_ticket(&frame) {
+                                    Ok(body) => body,
+                                    Err(_) => {
+                                        let error = verify::authentication_failure(received);
+                                        stream.write_all(&record::alert_frame())?;
+                                        return Err(error);
+                                    }
+                                };
+                                let secret = resumption_secret.take()
+                                    .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidData, "More than one session ticket"))?;
+                                let session_ticket = SessionTicket::from_new_session_ticket(&body, record_layer.suite(), secret, &random)
+                                    .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidData, "Malformed session ticket"))?;
+
+                                match &config.ticket_file {
+                                    Some(path) => match std::fs::write(path, session_ticket.to_bytes()) {
+                                        Ok(_) => println!("[*] Received session ticket, saved to {} for the next connection", path),
+                                        Err(e) => println!("[!] Could not save session ticket to {}: {}", path, e),
+                                    },
+                                    None => println!("[*] Received session ticket, no ticket file is configured so it is not kept"),
+                                }
+                                println!("--------------------------------------\n");
+                            }
+                            ContentType::Alert => {
+                                println!("[!] Server sent an alert: {}", verify::AUTHENTICATION_ALERT);
+                                println!("--------------------------------------\n");
+                                return Err(std::io::Error::new(std::io::ErrorKind::ConnectionAborted, "Alert received"));
+                            }
+                            // Hellos meant for the other side and the SRP messages of the PAKE stage
+                            other => {
+                                return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, format!("Unexpected {:?}", other)));
+                            }
                         }
                     }
                 }
@@ -233,10 +596,3 @@ impl Client5 {
         Ok(())
     }
 }
-
-fn generate_iv() -> Vec<u8> {
-    let mut rng = thread_rng();
-    let mut iv = vec![0u8; IV_SIZE];
-    rng.fill(iv.as_mut_slice());
-    iv
-}
\ No newline at end of file
@@ -0,0 +1,226 @@
+use std::str;
+use std::thread;
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use std::net::TcpStream;
+use std::io::{Read, Write, Error};
+use std::sync::mpsc::{Receiver, Sender};
+
+use crate::noise::{self, HandshakeState, NoiseConfig, NoiseError, TransportState};
+use crate::proof;
+use crate::random::Random;
+use crate::record::{self, ContentType};
+use crate::verify;
+
+// Stage 5 with the hand-rolled DH handshake replaced by a Noise handshake, with the
+// pattern and static keys taken from the NoiseConfig. The client is always the
+// initiator. Application data is sent under the two CipherStates the handshake ends with.
+pub struct Client5Noise {
+    key: Arc<Mutex<Option<TransportState>>>,
+    config: NoiseConfig,
+    random: Random,
+}
+
+impl Client5Noise {
+    pub fn new(config: NoiseConfig, random: Random) -> Self {
+        Self { key: Arc::new(Mutex::new(None)), config, random }
+    }
+
+    pub fn run(&mut self, socket: &str) {
+        let mut stream = TcpStream::connect(socket).expect("Could not connect to server");
+
+        println!("\n--------------------------------------");
+        println!("[*] Initiating {}", noise::protocol_name(self.config.pattern));
+        if let (true, Some((_, public_key))) = (self.config.pattern.needs_static_key(true), &self.config.static_key) {
+            println!("[*] Static public key {}", proof::fingerprint(public_key));
+        }
+        let mut handshake = HandshakeState::new(&self.config, true, self.random.clone()).expect("Invalid Noise configuration");
+
+        // Every pattern starts with a message from the initiator
+        println!("[+] Sending {} handshake message ...", self.config.pattern.name());
+        let message = handshake.write_message(&[]).expect("Failed to write Noise handshake message");
+        stream.write_all(&record::encode_frame(ContentType::NoiseHandshake, 0, &message)).expect("Failed to send Noise handshake message");
+
+        // Channel for reading from stdin and sending to server
+        let (stdin_tx, stdin_rx) = mpsc::channel::<Vec<u8>>();
+
+        // Thread for reading from stdin
+        let stdin_tx_clone = stdin_tx.clone();
+        let key_clone = self.key.clone();
+        thread::spawn(move || {
+            loop {
+                let mut input = String::new();
+                std::io::stdin().read_line(&mut input).unwrap();
+                let temp_bytes = input.as_bytes().to_vec();
+
+                // Lock and access the transport keys
+                let mut key_guard = key_clone.lock().unwrap();
+                let transport = match key_guard.as_mut() {
+                    Some(transport) => transport,
+                    None => {
+                        println!("[!] Handshake has not completed yet, message dropped.");
+                        continue;
+                    }
+                };
+
+                println!("\n--------------------------------------");
+                let nonce = transport.sending.nonce();
+                println!("[+] Encrypting {} bytes with nonce {} ...", temp_bytes.len(), nonce);
+                println!("--------------------------------------");
+                match transport.sending.encrypt_with_ad(&[], &temp_bytes) {
+                    // Send message_bytes through the stdin channel
+                    Ok(ciphertext) => stdin_tx_clone.send(record::encode_frame(ContentType::ApplicationData, nonce, &ciphertext)).unwrap(),
+                    Err(e) => println!("[!] Could not send message: {}", e),
+                }
+            }
+        });
+
+        // Channel for communicating from server handler thread to main thread
+        let (server_tx, server_rx) = mpsc::channel::<Vec<u8>>();
+
+        // Thread within which messages from the server are retreived and messages to the server are sent
+        let key_clone = self.key.clone();
+        thread::spawn(move || {
+            if let Err(e) = Self::handle_server(stream, stdin_rx, server_tx, key_clone, handshake) {
+                eprintln!("Error with server: {:?}", e);
+            }
+            println!("Server disconnected");
+        });
+
+        // Main loop to keep the client running and process server responses
+        loop {
+            if let Ok(response_bytes) = server_rx.try_recv() {
+                // response_bytes has already been decrypted in handle_server
+                let message = String::from_utf8_lossy(&response_bytes);
+                println!("Server > {}", message);
+            }
+        }
+    }
+
+    fn handle_server(
+        mut stream: TcpStream,
+        stdin_rx: Receiver<Vec<u8>>,
+        server_tx: Sender<Vec<u8>>,
+        key: Arc<Mutex<Option<TransportState>>>,
+        handshake: HandshakeState
+    ) -> Result<(), std::io::Error> {
+
+        // Bytes received from the server which have not yet been handled
+        let mut dynamic_buffer = Vec::new();
+
+        // Taken once the handshake has finished and the transport keys are in key
+        let mut handshake = Some(handshake);
+
+        loop {
+            // Non-blocking attempt to receive message from stdin and send to server
+            if let Ok(bytes) = stdin_rx.try_recv() {
+                stream.write_all(&bytes)?;
+            }
+
+            // Allows for 1 second of blocking while trying to read from the stream
+            stream.set_read_timeout(Some(Duration::new(1, 0)))?;
+
+            // Temporary buffer
+            let mut buffer = [0_u8; 512];
+
+            // Attempt to read bytes sent from server
+            match stream.read(&mut buffer) {
+                Ok(0) => break, // Connection closed by server
+                Ok(bytes_read) => {
+                    dynamic_buffer.extend_from_slice(&buffer[..bytes_read]);
+
+                    // Handle every complete frame received so far
+                    loop {
+                        // A failure to verify is reported a fixed time after this point
+                        let received = Instant::now();
+
+                        // A frame that can not be parsed, too long or with an unknown type,
+                        // fails the same way as a record that does not verify
+                        let frame = match record::take_frame(&mut dynamic_buffer) {
+                            Ok(Some(frame)) => frame,
+                            Ok(None) => break,
+                            Err(_) => {
+                                let error = verify::authentication_failure(received);
+                                stream.write_all(&record::alert_frame())?;
+                                return Err(error);
+                            }
+                        };
+
+                        match frame.content_type {
+                            ContentType::NoiseHandshake => {
+                                let state = handshake.as_mut()
+                                    .ok_or_else(|| std::io::Error::other("Unexpected NoiseHandshake"))?;
+
+                                println!("[*] Received {} handshake message ({} bytes)", state.pattern().name(), frame.body.len());
+                                match state.read_message(&frame.body) {
+                                    Ok(_) => {}
+                                    Err(NoiseError::OutOfTurn) => return Err(NoiseError::OutOfTurn.into()),
+                                    Err(_) => {
+                                        let error = verify::authentication_failure(received);
+                                        stream.write_all(&record::alert_frame())?;
+                                        return Err(error);
+                                    }
+                                }
+
+                                // XX ends with a third message from us
+                                if !state.is_finished() {
+                                    println!("[+] Sending {} handshake message ...", state.pattern().name());
+                                    let message = state.write_message(&[])?;
+                                    stream.write_all(&record::encode_frame(ContentType::NoiseHandshake, 0, &message))?;
+                                }
+
+                                if state.is_finished() {
+                                    if let Some(remote_static) = state.remote_static() {
+                                        println!("[*] Server static key {}", proof::fingerprint(remote_static));
+                                    }
+                                    let transport = state.split()?;
+                                    println!("[*] Noise Handshake Successful. Handshake hash {}", proof::fingerprint(&transport.handshake_hash));
+                                    println!("--------------------------------------\n");
+                                    *key.lock().unwrap() = Some(transport);
+                                    handshake = None;
+                                }
+                            }
+                            ContentType::ApplicationData => {
+                                println!("--------------------------------------");
+                                println!("[+] {} bytes received.", frame.body.len() + record::HEADER_SIZE);
+
+                                // Retrieve the transport keys
+                                let mut key_lock = key.lock().unwrap();
+                                let transport = key_lock.as_mut()
+                                    .ok_or_else(|| std::io::Error::other("Application data before handshake"))?;
+
+                                // The nonce is implicit, so a replayed or reordered message fails to decrypt
+                                println!("[+] Decrypting message with nonce {} ...", transport.receiving.nonce());
+                                match transport.receiving.decrypt_with_ad(&[], &frame.body) {
+                                    Ok(message) => {
+                                        // Send the decrypted message to the main thread
+                                        println!("[+] Authentication and decryption successful.");
+                                        println!("--------------------------------------\n");
+                                        server_tx.send(message).unwrap();
+                                    }
+                                    Err(_) => {
+                                        let error = verify::authentication_failure(received);
+                                        stream.write_all(&record::alert_frame())?;
+                                        return Err(error);
+                                    }
+                                }
+                            }
+                            ContentType::Alert => {
+                                println!("[!] Server sent an alert: {}", verify::AUTHENTICATION_ALERT);
+                                println!("--------------------------------------\n");
+                                return Err(std::io::Error::new(std::io::ErrorKind::ConnectionAborted, "Alert received"));
+                            }
+                            other => {
+                                return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, format!("Unexpected {:?}", other)));
+                            }
+                        }
+                    }
+                }
+                Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => continue,
+                Err(_) => break, // Error or disconnection has occured
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,266 @@
+use std::str;
+use std::thread;
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use std::net::TcpStream;
+use std::io::{Read, Write, Error};
+use std::sync::mpsc::{Receiver, Sender};
+
+use crate::handshake::{self, Transcript};
+use crate::random::Random;
+use crate::secret::SecretBytes;
+use crate::record::{self, ContentType, Record, RecordError, RecordLayer, RekeyPolicy, Side};
+use crate::srp::{SrpClient, SrpClientHello, SrpServerHello, SrpSession};
+use crate::suites::CipherSuite;
+use crate::verify;
+
+// Stage 5 with the DH handshake replaced by SRP-6a, for pairing a client and server that
+// share nothing but a passphrase. Once both sides have proven they derived the same
+// session key, it is turned into the final key for the AES-GCM record layer of stage 5.
+pub struct Client5Pake {
+    key: Arc<Mutex<Option<RecordLayer>>>,
+    identity: String,
+    password: SecretBytes,
+    random: Random,
+}
+
+impl Client5Pake {
+    pub fn new(identity: &str, password: &str, random: Random) -> Self {
+        Self {
+            key: Arc::new(Mutex::new(None)),
+            identity: identity.to_string(),
+            password: SecretBytes::new(password.as_bytes().to_vec()),
+            random,
+        }
+    }
+
+    pub fn run(&mut self, socket: &str) {
+        let mut stream = TcpStream::connect(socket).expect("Could not connect to server");
+
+        // A = g^a, sent along with the identity the server should look up
+        println!("\n--------------------------------------");
+        println!("[+] Generating ephemeral key ...");
+        let client = SrpClient::new(&self.identity, self.password.duplicate(), &self.random);
+
+        println!("[+] Sending SRP ClientHello for identity '{}' ...", client.identity());
+        let client_hello = SrpClientHello { identity: self.identity.clone(), public_key: client.public_key().to_vec() }.to_bytes();
+        stream.write_all(&record::encode_frame(ContentType::SrpClientHello, 0, &client_hello)).expect("Failed to send SrpClientHello");
+
+        // Both hello messages, in order
+        let mut transcript = Transcript::new();
+        transcript.add(&client_hello);
+
+        // Channel for reading from stdin and sending to server
+        let (stdin_tx, stdin_rx) = mpsc::channel::<Vec<u8>>();
+
+        // Thread for reading from stdin
+        let stdin_tx_clone = stdin_tx.clone();
+        let key_clone = self.key.clone();
+        thread::spawn(move || {
+            loop {
+                let mut input = String::new();
+                std::io::stdin().read_line(&mut input).unwrap();
+                let temp_bytes = input.as_bytes().to_vec();
+
+                // Lock and access the record layer
+                let mut key_guard = key_clone.lock().unwrap();
+                let record_layer = match key_guard.as_mut() {
+                    Some(record_layer) => record_layer,
+                    None => {
+                        println!("[!] Handshake has not completed yet, message dropped.");
+                        continue;
+                    }
+                };
+
+                println!("\n--------------------------------------");
+                let mut sequence = record_layer.write_sequence();
+                if record_layer.update_due() {
+                    println!("[+] Sending KeyUpdate as record #{} and ratcheting sending key to epoch {} ...", sequence, record_layer.write_epoch() + 1);
+                    sequence += 1;
+                }
+                println!("[+] Encrypting {} bytes as record #{} with {} ...", temp_bytes.len(), sequence, record_layer.suite().name());
+                println!("--------------------------------------");
+                match record_layer.seal(&temp_bytes) {
+                    // Send message_bytes through the stdin channel
+                    Ok(message_bytes) => stdin_tx_clone.send(message_bytes).unwrap(),
+                    Err(e) => println!("[!] Could not send message: {}", e),
+                }
+            }
+        });
+
+        // Channel for communicating from server handler thread to main thread
+        let (server_tx, server_rx) = mpsc::channel::<Vec<u8>>();
+
+        // Thread within which messages from the server are retreived and messages to the server are sent
+        let key_clone = self.key.clone();
+        let random = self.random.clone();
+        thread::spawn(move || {
+            if let Err(e) = Self::handle_server(stream, stdin_rx, server_tx, key_clone, client, transcript, random) {
+                eprintln!("Error with server: {:?}", e);
+            }
+            println!("Server disconnected");
+        });
+
+        // Main loop to keep the client running and process server responses
+        loop {
+            if let Ok(response_bytes) = server_rx.try_recv() {
+                // response_bytes has already been decrypted in handle_server
+                let message = String::from_utf8_lossy(&response_bytes);
+                println!("Server > {}", message);
+            }
+        }
+    }
+
+    fn handle_server(
+        mut stream: TcpStream,
+        stdin_rx: Receiver<Vec<u8>>,
+        server_tx: Sender<Vec<u8>>,
+        key: Arc<Mutex<Option<RecordLayer>>>,
+        client: SrpClient,
+        mut transcript: Transcript,
+        random: Random
+    ) -> Result<(), std::io::Error> {
+
+        // Bytes received from the server which have not yet been handled
+        let mut dynamic_buffer = Vec::new();
+
+        // Set once our proof has been sent, until the server's proof has been checked
+        let mut pending_session: Option<SrpSession> = None;
+
+        loop {
+            // Non-blocking attempt to receive message from stdin and send to server
+            if let Ok(bytes) = stdin_rx.try_recv() {
+                stream.write_all(&bytes)?;
+            }
+
+            // Allows for 1 second of blocking while trying to read from the stream
+            stream.set_read_timeout(Some(Duration::new(1, 0)))?;
+
+            // Temporary buffer
+            let mut buffer = [0_u8; 512];
+
+            // Attempt to read bytes sent from server
+            match stream.read(&mut buffer) {
+                Ok(0) => break, // Connection closed by server
+                Ok(bytes_read) => {
+                    dynamic_buffer.extend_from_slice(&buffer[..bytes_read]);
+
+                    // Handle every complete frame received so far
+                    loop {
+                        // A failure to verify is reported a fixed time after this point
+                        let received = Instant::now();
+
+                        // A frame that can not be parsed, too long or with an unknown type,
+                        // fails the same way as a record that does not verify
+                        let frame = match record::take_frame(&mut dynamic_buffer) {
+                            Ok(Some(frame)) => frame,
+                            Ok(None) => break,
+                            Err(_) => {
+                                let error = verify::authentication_failure(received);
+                                stream.write_all(&record::alert_frame())?;
+                                return Err(error);
+                            }
+                        };
+
+                        match frame.content_type {
+                            // The server sends the salt for our identity and B = k * v + g^b
+                            ContentType::SrpServerHello => {
+                                if pending_session.is_some() || key.lock().unwrap().is_some() {
+                                    return Err(std::io::Error::other("Unexpected SrpServerHello"));
+                                }
+
+                                let server_hello = SrpServerHello::from_bytes(&frame.body)
+                                    .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidData, "Malformed SrpServerHello"))?;
+                                transcript.add(&frame.body);
+                                println!("[*] Received SRP ServerHello with salt and B");
+
+                                // S = (B - k * g^x)^(a + u * x)
+                                println!("[+] Calculating shared secret from B and the password ...");
+                                let session = match client.finish(&server_hello.salt, &server_hello.public_key) {
+                                    Some(session) => session,
+                                    None => {
+                                        let error = verify::authentication_failure(received);
+                                        stream.write_all(&record::alert_frame())?;
+                                        return Err(error);
+                                    }
+                                };
+
+                                // M1 shows the server that we know the password
+                                println!("[+] Sending client proof ...");
+                                stream.write_all(&record::encode_frame(ContentType::SrpClientProof, 0, session.client_proof()))?;
+                                pending_session = Some(session);
+                            }
+                            // M2 shows the server holds the verifier for our password
+                            ContentType::SrpServerProof => {
+                                let session = pending_session.take()
+                                    .ok_or_else(|| std::io::Error::other("Unexpected SrpServerProof"))?;
+
+                                println!("[+] Verifying the server's proof ...");
+                                if !session.verify_server_proof(&frame.body) {
+                                    let error = verify::authentication_failure(received);
+                                    stream.write_all(&record::alert_frame())?;
+                                    return Err(error);
+                                }
+
+                                // The session key and the transcript give the final key for the record layer
+                                println!("[+] Using SHA-256 as KDF to compute final key ...");
+                                let final_key = handshake::derive_key(&session.session_key, &transcript);
+                                let record_layer = RecordLayer::new(CipherSuite::Aes256Gcm, &final_key, Side::Client, RekeyPolicy::default(), random.clone());
+                                *key.lock().unwrap() = Some(record_layer);
+
+                                println!("[*] SRP Key Exchange Successful. Final key {}", final_key);
+                                println!("--------------------------------------\n");
+                            }
+                            ContentType::ApplicationData | ContentType::KeyUpdate => {
+                                println!("--------------------------------------");
+                                println!("[+] {} bytes received.", frame.body.len() + record::HEADER_SIZE);
+
+                                // Retrieve the record layer
+                                let mut key_lock = key.lock().unwrap();
+                                let record_layer = key_lock.as_mut()
+                                    .ok_or_else(|| std::io::Error::other("Application data before handshake"))?;
+
+                                println!("[+] Decrypting record #{} ({} bytes) with {} ...", frame.sequence, frame.body.len(), record_layer.suite().name());
+                                match record_layer.open(&frame) {
+                                    Ok(Record::ApplicationData(message)) => {
+                                        // Send the decrypted message to the main thread
+                                        println!("[+] Authentication and decryption successful.");
+                                        println!("--------------------------------------\n");
+                                        server_tx.send(message).unwrap();
+                                    }
+                                    Ok(Record::KeyUpdate) => {
+                                        println!("[*] Server updated its sending key, receiving key ratcheted to epoch {}", record_layer.read_epoch());
+                                        println!("--------------------------------------\n");
+                                    }
+                                    // A replayed or reordered record is authentic but is dropped without being
+                                    // delivered. The connection itself is left open.
+                                    Err(e @ RecordError::Replay { .. }) | Err(e @ RecordError::OutOfOrder { .. }) => {
+                                        println!("[!] {}", e);
+                                        println!("--------------------------------------\n");
+                                    }
+                                    Err(_) => {
+                                        let error = verify::authentication_failure(received);
+                                        stream.write_all(&record::alert_frame())?;
+                                        return Err(error);
+                                    }
+                                }
+                            }
+                            ContentType::Alert => {
+                                println!("[!] Server sent an alert: {}", verify::AUTHENTICATION_ALERT);
+                                println!("--------------------------------------\n");
+                                return Err(std::io::Error::new(std::io::ErrorKind::ConnectionAborted, "Alert received"));
+                            }
+                            other => {
+                                return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, format!("Unexpected {:?}", other)));
+                            }
+                        }
+                    }
+                }
+                Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => continue,
+                Err(_) => break, // Error or disconnection has occured
+            }
+        }
+        Ok(())
+    }
+}
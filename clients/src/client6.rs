@@ -0,0 +1,459 @@
+use std::str;
+use std::thread;
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use std::net::TcpStream;
+use std::io::{Read, Write, Error};
+use std::sync::mpsc::{Receiver, Sender};
+
+use dh;
+
+use crate::certificate::{self, Certificate};
+use crate::config::SessionConfig;
+use crate::handshake::{ClientHello, ServerHello, Transcript};
+use crate::random::{KeyPair, Random};
+use crate::secret::SecretBytes;
+use crate::record::{self, ContentType, Record, RecordError, RecordLayer, Side};
+use crate::suites;
+use crate::tls::{self, HandshakeType, KeySchedule, TrafficSecrets};
+use crate::verify;
+
+// Stage 5 grown into a TLS 1.3-style handshake. The client only goes ahead once the
+// server has shown a certificate for the expected name from the trusted CA, signed the
+// transcript with the certificate's key and sent a Finished that matches our transcript.
+// Handshake messages and application data are protected under separate keys from the key
+// schedule in tls.rs. A server that asks for client authentication gets our certificate
+// and a signature over the transcript, if we were given a key to authenticate with.
+//
+// Only the suites, the minimum suite and the rekey policy of the SessionConfig apply
+// here. The ratchet and the hybrid exchange are never requested.
+pub struct Client6 {
+    key: Arc<Mutex<Option<RecordLayer>>>,
+    config: SessionConfig,
+    // Name the server's certificate must be issued to
+    server_name: String,
+    // Public key of the only CA whose certificates are accepted
+    ca_public_key: Vec<u8>,
+    credentials: Option<Arc<ClientCredentials>>,
+    random: Random,
+}
+
+// What the client authenticates with when the server asks
+struct ClientCredentials {
+    certificate: Certificate,
+    key_pair: KeyPair,
+}
+
+// The connection to the server as its thread sees it: the stream, the records sealed by
+// the stdin thread, the channel the server's messages go to the main thread on, the
+// record layer shared with the stdin thread and our key pair from the ClientHello
+struct Connection {
+    stream: TcpStream,
+    stdin_rx: Receiver<Vec<u8>>,
+    server_tx: Sender<Vec<u8>>,
+    key: Arc<Mutex<Option<RecordLayer>>>,
+    key_pair: KeyPair,
+}
+
+// Where the client is between the ServerHello and its own Finished
+struct PendingHandshake {
+    schedule: KeySchedule,
+    secrets: TrafficSecrets,
+    // Under the handshake traffic keys
+    handshake_layer: RecordLayer,
+    // The next message the server must send
+    expected: HandshakeType,
+    // Taken from the certificate once it has been checked
+    server_public_key: Option<Vec<u8>>,
+    // Set by a CertificateRequest, which can only come before the server's Certificate
+    certificate_requested: bool,
+}
+
+impl Client6 {
+    pub fn new(server_name: &str, ca_public_key: Vec<u8>, random: Random) -> Self {
+        Self::with_config(SessionConfig::default(), server_name, ca_public_key, random)
+    }
+
+    pub fn with_config(config: SessionConfig, server_name: &str, ca_public_key: Vec<u8>, random: Random) -> Self {
+        Self { key: Arc::new(Mutex::new(None)), config, server_name: server_name.to_string(), ca_public_key, credentials: None, random }
+    }
+
+    // Certificate and matching key pair to send if the server asks for client
+    // authentication. A server with an authorized keys file only looks at the key, so the
+    // certificate can be Certificate::unsigned.
+    pub fn with_client_certificate(mut self, certificate: Certificate, key_pair: KeyPair) -> Self {
+        self.credentials = Some(Arc::new(ClientCredentials { certificate, key_pair }));
+        self
+    }
+
+    // Keying material exported from the session, None until the handshake has finished
+    pub fn export_keying_material(&self, label: &str, context: &[u8], length: usize) -> Option<SecretBytes> {
+        self.key.lock().unwrap().as_ref()?.export_keying_material(label, context, length)
+    }
+
+    pub fn run(&mut self, socket: &str) {
+        let mut stream = TcpStream::connect(socket).expect("Could not connect to server");
+
+        // Generate key pair and send client public key along with the suites we support
+        println!("\n--------------------------------------");
+        println!("[+] Generating key pair ...");
+        let key_pair = self.random.dh_key_pair();
+
+        println!("[+] Sending ClientHello offering: {}", suites::describe(&self.config.allowed_suites()));
+        let client_hello = ClientHello {
+            suites: self.config.allowed_suites(),
+            ratchet: false,
+            kem_public_key: None,
+            ticket: None,
+            signed: false,
+            gcm_tag_size: record::GCM_TAG_SIZE,
+            padded: false,
+            committed: false,
+            public_key: key_pair.1.clone(),
+        }.to_bytes();
+        stream.write_all(&record::encode_frame(ContentType::ClientHello, 0, &client_hello)).expect("Failed to send ClientHello");
+
+        // Every handshake message sent or received, in order
+        let mut transcript = Transcript::new();
+        transcript.add(&client_hello);
+
+        // Channel for reading from stdin and sending to server
+        let (stdin_tx, stdin_rx) = mpsc::channel::<Vec<u8>>();
+
+        // Thread for reading from stdin
+        let stdin_tx_clone = stdin_tx.clone();
+        let key_clone = self.key.clone();
+        thread::spawn(move || {
+            loop {
+                let mut input = String::new();
+                std::io::stdin().read_line(&mut input).unwrap();
+                let temp_bytes = input.as_bytes().to_vec();
+
+                // Lock and access the record layer
+                let mut key_guard = key_clone.lock().unwrap();
+                let record_layer = match key_guard.as_mut() {
+                    Some(record_layer) => record_layer,
+                    None => {
+                        println!("[!] Handshake has not completed yet, message dropped.");
+                        continue;
+                    }
+                };
+
+                // Protect the message under the application traffic keys
+                println!("\n--------------------------------------");
+                let mut sequence = record_layer.write_sequence();
+                if record_layer.update_due() {
+                    println!("[+] Sending KeyUpdate as record #{} and ratcheting sending key to epoch {} ...", sequence, record_layer.write_epoch() + 1);
+                    sequence += 1;
+                }
+                println!("[+] Encrypting {} bytes as record #{} with {} ...", temp_bytes.len(), sequence, record_layer.suite().name());
+                println!("--------------------------------------");
+                match record_layer.seal(&temp_bytes) {
+                    // Send message_bytes through the stdin channel
+                    Ok(message_bytes) => stdin_tx_clone.send(message_bytes).unwrap(),
+                    Err(e) => println!("[!] Could not send message: {}", e),
+                }
+            }
+        });
+
+        // Channel for communicating from server handler thread to main thread
+        let (server_tx, server_rx) = mpsc::channel::<Vec<u8>>();
+
+        // Thread within which messages from the server are retreived and messages to the server are sent
+        let key_clone = self.key.clone();
+        let config = self.config.clone();
+        let server_name = self.server_name.clone();
+        let ca_public_key = self.ca_public_key.clone();
+        let credentials = self.credentials.clone();
+        let random = self.random.clone();
+        thread::spawn(move || {
+            let connection = Connection { stream, stdin_rx, server_tx, key: key_clone, key_pair };
+            if let Err(e) = Self::handle_server(connection, config, server_name, ca_public_key, credentials, transcript, random) {
+                eprintln!("Error with server: {:?}", e);
+            }
+            println!("Server disconnected");
+        });
+
+        // Main loop to keep the client running and process server responses
+        loop {
+            if let Ok(response_bytes) = server_rx.try_recv() {
+                // response_bytes has already been decrypted in handle_server
+                let message = String::from_utf8_lossy(&response_bytes);
+                println!("Server > {}", message);
+            }
+        }
+    }
+
+    fn handle_server(
+        connection: Connection,
+        config: SessionConfig,
+        server_name: String,
+        ca_public_key: Vec<u8>,
+        credentials: Option<Arc<ClientCredentials>>,
+        mut transcript: Transcript,
+        random: Random
+    ) -> Result<(), std::io::Error> {
+        let Connection { mut stream, stdin_rx, server_tx, key, key_pair } = connection;
+
+        // Bytes received from the server which have not yet been handled
+        let mut dynamic_buffer = Vec::new();
+
+        // Set between the ServerHello and our Finished
+        let mut pending: Option<PendingHandshake> = None;
+
+        loop {
+            // Non-blocking attempt to receive message from stdin and send to server
+            if let Ok(bytes) = stdin_rx.try_recv() {
+                stream.write_all(&bytes)?;
+            }
+
+            // Allows for 1 second of blocking while trying to read from the stream
+            stream.set_read_timeout(Some(Duration::new(1, 0)))?;
+
+            // Temporary buffer
+            let mut buffer = [0_u8; 512];
+
+            // Attempt to read bytes sent from server
+            match stream.read(&mut buffer) {
+                Ok(0) => break, // Connection closed by server
+                Ok(bytes_read) => {
+                    dynamic_buffer.extend_from_slice(&buffer[..bytes_read]);
+
+                    // Handle every complete frame received so far
+                    loop {
+                        // A failure to verify is reported a fixed time after this point
+                        let received = Instant::now();
+
+                        // A frame that can not be parsed, too long or with an unknown type,
+                        // fails the same way as a record that does not verify
+                        let frame = match record::take_frame(&mut dynamic_buffer) {
+                            Ok(Some(frame)) => frame,
+                            Ok(None) => break,
+                            Err(_) => {
+                                let error = verify::authentication_failure(received);
+                                stream.write_all(&record::alert_frame())?;
+                                return Err(error);
+                            }
+                        };
+
+                        match frame.content_type {
+                            // The server's chosen suite and its public key, the last message in the clear
+                            ContentType::ServerHello => {
+                                if pending.is_some() || key.lock().unwrap().is_some() {
+                                    return Err(std::io::Error::other("Unexpected ServerHello"));
+                                }
+
+                                let server_hello = ServerHello::from_bytes(&frame.body)
+                                    .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidData, "Malformed ServerHello"))?;
+
+                                // The server may only pick a suite we actually offered
+                                if !config.allowed_suites().contains(&server_hello.suite) {
+                                    println!("[!] Server selected a suite we did not offer: {}", server_hello.suite.name());
+                                    return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "Server selected an unoffered suite"));
+                                }
+                                if server_hello.ratchet || server_hello.kem_ciphertext.is_some() || server_hello.resumed {
+                                    return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "Server enabled a feature we did not ask for"));
+                                }
+                                transcript.add(&frame.body);
+                                println!("[*] Received ServerHello selecting: {}", server_hello.suite.name());
+
+                                // Use the server's public key to compute the shared secret
+                                println!("[+] Calculating shared secret ...");
+                                let modulus = dh::get_domain_params().0;
+                                let shared_secret = SecretBytes::new(dh::get_secret(&server_hello.public_key, key_pair.0.expose(), &modulus));
+
+                                // The rest of the server's flight is encrypted under the handshake traffic keys
+                                println!("[+] Deriving handshake traffic keys ...");
+                                let schedule = KeySchedule::new(&shared_secret);
+                                let secrets = schedule.handshake_traffic(&transcript.hash());
+                                let handshake_layer = RecordLayer::from_traffic_secrets(
+                                    server_hello.suite,
+                                    secrets.client.duplicate(),
+                                    secrets.server.duplicate(),
+                                    Side::Client,
+                                    config.rekey,
+                                    random.clone(),
+                                );
+                                pending = Some(PendingHandshake {
+                                    schedule,
+                                    secrets,
+                                    handshake_layer,
+                                    expected: HandshakeType::Certificate,
+                                    server_public_key: None,
+                                    certificate_requested: false,
+                                });
+                            }
+                            // One of the server's encrypted handshake messages
+                            ContentType::ApplicationData if pending.is_some() => {
+                                let handshake = pending.as_mut().unwrap();
+
+                                let message = match handshake.handshake_layer.open(&frame) {
+                                    Ok(Record::ApplicationData(message)) => message,
+                                    _ => {
+                                        let error = verify::authentication_failure(received);
+                                        stream.write_all(&record::alert_frame())?;
+                                        return Err(error);
+                                    }
+                                };
+                                let (handshake_type, body) = tls::decode_message(&message)
+                                    .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidData, "Malformed handshake message"))?;
+                                let request_allowed = handshake.expected == HandshakeType::Certificate && !handshake.certificate_requested;
+                                if handshake_type != handshake.expected && !(handshake_type == HandshakeType::CertificateRequest && request_allowed) {
+                                    return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, format!("Unexpected {:?}", handshake_type)));
+                                }
+
+                                // Signatures and Finished cover the transcript before the message itself
+                                let transcript_hash = transcript.hash();
+                                let verified = match handshake_type {
+                                    HandshakeType::CertificateRequest => {
+                                        println!("[*] Received encrypted CertificateRequest, the server wants to authenticate us");
+                                        handshake.certificate_requested = true;
+                                        true
+                                    }
+                                    HandshakeType::Certificate => {
+                                        println!("[*] Received encrypted Certificate");
+                                        match Certificate::from_bytes(body) {
+                                            Some(certificate) if certificate.subject == server_name && certificate.verify(&ca_public_key) => {
+                                                println!("[*] Certificate for \"{}\" verified against the trusted CA \"{}\"", certificate.subject, certificate.issuer);
+                                                handshake.server_public_key = Some(certificate.public_key);
+                                                handshake.expected = HandshakeType::CertificateVerify;
+                                                true
+                                            }
+                                            _ => false,
+                                        }
+                                    }
+                                    HandshakeType::CertificateVerify => {
+                                        println!("[*] Received encrypted CertificateVerify");
+                                        let server_public_key = handshake.server_public_key.as_ref().unwrap();
+                                        handshake.expected = HandshakeType::Finished;
+                                        certificate::verify(server_public_key, &tls::certificate_verify_input(Side::Server, &transcript_hash), body)
+                                    }
+                                    HandshakeType::Finished => {
+                                        println!("[*] Received encrypted Finished");
+                                        tls::verify_finished(&handshake.secrets.server, &transcript_hash, body)
+                                    }
+                                };
+
+                                // A certificate from the wrong CA or for the wrong name, a bad
+                                // signature and a bad Finished all get the same alert
+                                if !verified {
+                                    let error = verify::authentication_failure(received);
+                                    stream.write_all(&record::alert_frame())?;
+                                    return Err(error);
+                                }
+                                transcript.add(&message);
+
+                                if handshake_type == HandshakeType::Finished {
+                                    let mut handshake = pending.take().unwrap();
+                                    println!("[*] Server signature and Finished verified");
+
+                                    // The application keys cover the transcript up to the server's Finished
+                                    let transcript_hash = transcript.hash();
+
+                                    if handshake.certificate_requested {
+                                        let message = match &credentials {
+                                            Some(credentials) => {
+                                                println!("[+] Sending encrypted Certificate for \"{}\" ...", credentials.certificate.subject);
+                                                tls::encode_message(HandshakeType::Certificate, &credentials.certificate.to_bytes())
+                                            }
+                                            None => {
+                                                println!("[!] No client certificate to send, the server will most likely refuse us");
+                                                tls::encode_message(HandshakeType::Certificate, &[])
+                                            }
+                                        };
+                                        transcript.add(&message);
+                                        stream.write_all(&handshake.handshake_layer.seal(&message)?)?;
+
+                                        if let Some(credentials) = &credentials {
+                                            // Signing the transcript shows we hold the certificate's private key
+                                            println!("[+] Signing transcript and sending encrypted CertificateVerify ...");
+                                            let signature = certificate::sign(&credentials.key_pair, &tls::certificate_verify_input(Side::Client, &transcript.hash()), &random);
+                                            let message = tls::encode_message(HandshakeType::CertificateVerify, &signature);
+                                            transcript.add(&message);
+                                            stream.write_all(&handshake.handshake_layer.seal(&message)?)?;
+                                        }
+                                    }
+
+                                    // Our Finished covers everything we sent as well
+                                    println!("[+] Sending encrypted Finished ...");
+                                    let verify_data = tls::finished(&handshake.secrets.client, &transcript.hash());
+                                    let message = tls::encode_message(HandshakeType::Finished, &verify_data);
+                                    transcript.add(&message);
+                                    stream.write_all(&handshake.handshake_layer.seal(&message)?)?;
+
+                                    println!("[+] Deriving application traffic keys ...");
+                                    let application_secrets = handshake.schedule.application_traffic(&transcript_hash);
+                                    println!("[*] Application traffic keys: client {} server {}", application_secrets.client, application_secrets.server);
+                                    let record_layer = RecordLayer::from_traffic_secrets(
+                                        handshake.handshake_layer.suite(),
+                                        application_secrets.client,
+                                        application_secrets.server,
+                                        Side::Client,
+                                        config.rekey,
+                                        random.clone(),
+                                    ).with_exporter_secret(handshake.schedule.exporter_master(&transcript_hash));
+                                    let channel_binding = record_layer.channel_binding().unwrap();
+                                    *key.lock().unwrap() = Some(record_layer);
+
+                                    println!("[*] Handshake Successful.");
+                                    println!("[*] Channel binding {}", channel_binding);
+                                    println!("--------------------------------------\n");
+                                }
+                            }
+                            ContentType::ApplicationData | ContentType::KeyUpdate => {
+                                println!("--------------------------------------");
+                                println!("[+] {} bytes received.", frame.body.len() + record::HEADER_SIZE);
+
+                                // Retrieve the record layer
+                                let mut key_lock = key.lock().unwrap();
+                                let record_layer = key_lock.as_mut()
+                                    .ok_or_else(|| std::io::Error::other("Application data before handshake"))?;
+
+                                println!("[+] Decrypting record #{} ({} bytes) with {} ...", frame.sequence, frame.body.len(), record_layer.suite().name());
+                                match record_layer.open(&frame) {
+                                    Ok(Record::ApplicationData(message)) => {
+                                        // Send the decrypted message to the main thread
+                                        println!("[+] Authentication and decryption successful.");
+                                        println!("--------------------------------------\n");
+                                        server_tx.send(message).unwrap();
+                                    }
+                                    Ok(Record::KeyUpdate) => {
+                                        println!("[*] Server updated its sending key, receiving key ratcheted to epoch {}", record_layer.read_epoch());
+                                        println!("--------------------------------------\n");
+                                    }
+                                    // A replayed or reordered record is authentic but is dropped without being
+                                    // delivered. The connection itself is left open.
+                                    Err(e @ RecordError::Replay { .. })
+                                    | Err(e @ RecordError::OutOfOrder { .. }) => {
+                                        println!("[!] {}", e);
+                                        println!("--------------------------------------\n");
+                                    }
+                                    // Anything else gets the same alert after the same delay,
+                                    // whichever check the record failed
+                                    Err(_) => {
+                                        let error = verify::authentication_failure(received);
+                                        stream.write_all(&record::alert_frame())?;
+                                        return Err(error);
+                                    }
+                                }
+                            }
+                            ContentType::Alert => {
+                                println!("[!] Server sent an alert: {}", verify::AUTHENTICATION_ALERT);
+                                println!("--------------------------------------\n");
+                                return Err(std::io::Error::new(std::io::ErrorKind::ConnectionAborted, "Alert received"));
+                            }
+                            // Hellos meant for the other side and the messages of the other handshakes
+                            other => {
+                                return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, format!("Unexpected {:?}", other)));
+                            }
+                        }
+                    }
+                }
+                Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => continue,
+                Err(_) => break, // Error or disconnection has occured
+            }
+        }
+        Ok(())
+    }
+}
@@ -1,37 +1,144 @@
 #![allow(dead_code)]
 #![allow(unused_imports)]
+// The dependencies are imported by name in every module that uses them
+#![allow(clippy::single_component_path_imports)]
 
 mod client1;
 mod client2;
 mod client3;
 mod client4;
+mod client4_etm;
 mod client5;
+mod client5_noise;
+mod client5_pake;
+mod client6;
+
+use common::{certificate, config, drbg, handshake, hex, identity, kdf, keystore, mlkem, modes, noise, proof, random, ratchet, record, sas, secret, sequence, srp, suites, ticket, tls, verify};
+
+use std::sync::Arc;
 
 use crate::client1::Client1;
 use crate::client2::Client2;
 use crate::client3::Client3;
 use crate::client4::Client4;
+use crate::client4_etm::Client4Etm;
 use crate::client5::Client5;
+use crate::client5_noise::Client5Noise;
+use crate::client5_pake::Client5Pake;
+use crate::client6::Client6;
+
+use crate::certificate::Certificate;
+use crate::config::SessionConfig;
+use crate::modes::Mode;
+use crate::noise::{NoiseConfig, Pattern};
+use crate::random::Random;
 
 
 fn main() {
+    // `clients verify-proofs <file>` checks the signed messages exported to a proof file
+    // instead of starting a stage
+    let args: Vec<String> = std::env::args().collect();
+    if args.get(1).map(String::as_str) == Some("verify-proofs") {
+        let path = args.get(2).expect("verify-proofs takes the path of a proof file");
+        std::process::exit(if proof::verify_proofs(path) { 0 } else { 1 });
+    }
+
+    // `clients keygen ...` manages the encrypted keystores identity keys are kept in
+    if args.get(1).map(String::as_str) == Some("keygen") {
+        std::process::exit(if keystore::keygen(&args[2..]) { 0 } else { 1 });
+    }
+
+    // One DRBG supplies the randomness for every stage. It is seeded from the operating
+    // system unless --seed <hex> is given, which replays the same session byte for byte
+    let random = match seed_argument() {
+        Some(seed) => Random::seeded(&seed, b"client"),
+        None => Random::new(),
+    };
+    println!("[*] Randomness from {}", random.name());
+    if random.is_seeded() {
+        println!("[!] Replay mode: keys are derived from the --seed value and are not secret");
+    }
+
+    // `clients fs-demo <address>` connects stage 5 to the address, normally `servers wiretap`
+    // or `servers impersonate` in front of `servers fs-demo`. The client asks for signed
+    // messages with a throwaway key so that the ephemeral server puts its long-term key to use.
+    if args.get(1).map(String::as_str) == Some("fs-demo") {
+        let socket = args.get(2).expect("fs-demo takes the address to connect to");
+        let config = SessionConfig { signing_key: Some(Arc::new(random.dh_key_pair())), ..SessionConfig::default() };
+        let mut c5_demo = Client5::with_config(config, random.clone());
+        c5_demo.run(socket);
+        return;
+    }
+
     let socket1 = "10.0.0.189:8888";
     let c1 = Client1::new();
     c1.run(socket1);
 
     // let socket2 = "127.0.0.1:8899";
-    // let mut c2 = Client2::new();
+    // let mut c2 = Client2::new(random.clone());
     // c2.run(socket2);
 
     // let socket3 = "127.0.0.1:9988";
-    // let mut c3 = Client3::new();
+    // let mut c3 = Client3::new(random.clone());
     // c3.run(socket3);
 
     // let socket4 = "127.0.0.1:9999";
-    // let mut c4 = Client4::new();
+    // let mut c4 = Client4::new(random.clone());
     // c4.run(socket4);
 
+    // let socket4_cbc = "127.0.0.1:9990";
+    // let mut c4_cbc = Client4Etm::new(Mode::Cbc, random.clone());
+    // c4_cbc.run(socket4_cbc);
+
+    // let socket4_ctr = "127.0.0.1:9991";
+    // let mut c4_ctr = Client4Etm::new(Mode::Ctr, random.clone());
+    // c4_ctr.run(socket4_ctr);
+
     // let socket5 = "127.0.0.1:9898";
-    // let mut c5 = Client5::new();
+    // let mut c5 = Client5::new(random.clone());
     // c5.run(socket5);
-}
\ No newline at end of file
+
+    // Signed messages. Each message carries a signature by our long-term key, made with
+    // `clients keygen create client.keystore identity`, and the server's signed messages
+    // are kept in server_proofs.txt for verify-proofs
+    // let signing_key = keystore::unlock("client.keystore", "identity", &random).expect("Failed to unlock client.keystore");
+    // let config = SessionConfig {
+    //     signing_key: Some(Arc::new(signing_key)),
+    //     proof_file: Some("server_proofs.txt".to_string()),
+    //     ..SessionConfig::default()
+    // };
+    // let mut c5_signed = Client5::with_config(config, random.clone());
+    // c5_signed.run(socket5);
+
+    // let socket5_noise = "127.0.0.1:9897";
+    // let mut noise_config = NoiseConfig::new(Pattern::XX);
+    // noise_config.static_key = Some(random.dh_key_pair());
+    // noise_config.remote_static = std::fs::read("noise_server.pub").ok();
+    // let mut c5_noise = Client5Noise::new(noise_config, random.clone());
+    // c5_noise.run(socket5_noise);
+
+    // let socket5_pake = "127.0.0.1:9899";
+    // let mut c5_pake = Client5Pake::new("operator", "correct horse battery staple", random.clone());
+    // c5_pake.run(socket5_pake);
+
+    // let socket6 = "127.0.0.1:9896";
+    // let ca_public_key = std::fs::read("ca.pub").expect("Failed to read ca.pub");
+    // let mut c6 = Client6::new("localhost", ca_public_key, random.clone());
+    // c6.run(socket6);
+
+    // Stage 6 with client authentication. A server with an authorized keys file only needs
+    // our key, a server with a client CA needs the certificate it issued in client.cert
+    // let client_key = keystore::unlock("client.keystore", "identity", &random).expect("Failed to unlock client.keystore");
+    // let client_certificate = Certificate::unsigned("alice", &client_key.1);
+    // let client_certificate = Certificate::from_bytes(&std::fs::read("client.cert").expect("Failed to read client.cert")).expect("Malformed client.cert");
+    // let mut c6_auth = Client6::new("localhost", ca_public_key, random.clone()).with_client_certificate(client_certificate, client_key);
+    // c6_auth.run(socket6);
+}
+
+// The value following --seed, if the flag was given
+fn seed_argument() -> Option<Vec<u8>> {
+    let args: Vec<String> = std::env::args().collect();
+    let position = args.iter().position(|arg| arg == "--seed")?;
+    let seed = args.get(position + 1).and_then(|hex| random::parse_seed(hex));
+    Some(seed.expect("--seed takes a seed in hex"))
+}
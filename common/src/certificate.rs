@@ -0,0 +1,193 @@
+// Certificates for the stage-6 handshake, signed with Schnorr signatures over the MODP
+// group from the dh library so that signing keys are ordinary DH key pairs (x, y = g^x).
+//
+// A signature on a message m is (r, s) where
+//
+//     r = g^k mod p,   e = H(r | y | m),   s = k + e * x mod (p - 1)
+//
+// for a fresh random k, and it verifies when g^s = r * y^e mod p. Exponents are reduced
+// mod p - 1, which is a multiple of the order of g, so verification does not need to know
+// the order of the subgroup. k is drawn with 64 bits more than p - 1 has so that s is
+// uniform and says nothing about x.
+//
+// A certificate binds a subject name to a public key and is signed by the private key of
+// a certificate authority. There is only one level: clients are given the CA's public key
+// up front and accept any certificate the CA signed for the name they are connecting to.
+// A server that authenticates its clients can do the same with a client CA, see identity.rs.
+
+use byteorder::{ByteOrder, BigEndian};
+use num_bigint::BigUint;
+
+use dh;
+use bernie_hmac;
+
+use crate::random::Random;
+use crate::secret::SecretBytes;
+
+// Prefix of every signed certificate body, so that a signature made for a
+// CertificateVerify can never pass as a certificate or the other way around
+const CERTIFICATE_CONTEXT: &[u8] = b"seccom certificate";
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Certificate {
+    pub subject: String,
+    pub issuer: String,
+    pub public_key: Vec<u8>,
+    pub signature: Vec<u8>,
+}
+
+impl Certificate {
+    // A certificate nobody signed, which only carries a public key. It is for servers that
+    // check client keys against an authorized keys file rather than a CA.
+    pub fn unsigned(subject: &str, public_key: &[u8]) -> Self {
+        Self { subject: subject.to_string(), issuer: String::new(), public_key: public_key.to_vec(), signature: Vec::new() }
+    }
+
+    // Layout: subject | issuer | public key | signature, each prefixed with a 2 byte length
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.to_be_signed();
+        put_field(&mut bytes, &self.signature);
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let mut rest = bytes;
+        let subject = String::from_utf8(take_field(&mut rest)?.to_vec()).ok()?;
+        let issuer = String::from_utf8(take_field(&mut rest)?.to_vec()).ok()?;
+        let public_key = take_field(&mut rest)?.to_vec();
+        let signature = take_field(&mut rest)?.to_vec();
+        if !rest.is_empty() {
+            return None;
+        }
+        Some(Self { subject, issuer, public_key, signature })
+    }
+
+    // True if the CA with this public key signed the certificate
+    pub fn verify(&self, ca_public_key: &[u8]) -> bool {
+        let mut message = CERTIFICATE_CONTEXT.to_vec();
+        message.extend_from_slice(&self.to_be_signed());
+        verify(ca_public_key, &message, &self.signature)
+    }
+
+    // Every field except the signature
+    fn to_be_signed(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        put_field(&mut bytes, self.subject.as_bytes());
+        put_field(&mut bytes, self.issuer.as_bytes());
+        put_field(&mut bytes, &self.public_key);
+        bytes
+    }
+}
+
+pub struct CertificateAuthority {
+    name: String,
+    key_pair: (SecretBytes, Vec<u8>),
+}
+
+impl CertificateAuthority {
+    pub fn new(name: &str, random: &Random) -> Self {
+        Self { name: name.to_string(), key_pair: random.dh_key_pair() }
+    }
+
+    // The key clients need in order to trust what this CA signs
+    pub fn public_key(&self) -> &[u8] {
+        &self.key_pair.1
+    }
+
+    pub fn issue(&self, subject: &str, public_key: &[u8], random: &Random) -> Certificate {
+        let mut certificate = Certificate {
+            subject: subject.to_string(),
+            issuer: self.name.clone(),
+            public_key: public_key.to_vec(),
+            signature: Vec::new(),
+        };
+        let mut message = CERTIFICATE_CONTEXT.to_vec();
+        message.extend_from_slice(&certificate.to_be_signed());
+        certificate.signature = sign(&self.key_pair, &message, random);
+        certificate
+    }
+}
+
+// Signature layout: r | s, each as long as the modulus
+pub fn sign(key_pair: &(SecretBytes, Vec<u8>), message: &[u8], random: &Random) -> Vec<u8> {
+    let (modulus, generator) = group();
+    let order = &modulus - 1_u32;
+    let size = element_size();
+
+    let nonce = SecretBytes::new(random.bytes(size + 8));
+    let k = BigUint::from_bytes_be(nonce.expose()) % &order;
+    let r = pad(&generator.modpow(&k, &modulus).to_bytes_be(), size);
+    let e = challenge(&r, &key_pair.1, message);
+    let x = BigUint::from_bytes_be(key_pair.0.expose());
+    let s = (k + e * x) % &order;
+
+    let mut signature = r;
+    signature.extend(pad(&s.to_bytes_be(), size));
+    signature
+}
+
+pub fn verify(public_key: &[u8], message: &[u8], signature: &[u8]) -> bool {
+    let (modulus, generator) = group();
+    let size = element_size();
+    if signature.len() != 2 * size {
+        return false;
+    }
+    let (r_bytes, s_bytes) = signature.split_at(size);
+
+    let y = BigUint::from_bytes_be(public_key);
+    let r = BigUint::from_bytes_be(r_bytes);
+    let one = BigUint::from(1_u32);
+    let upper = &modulus - 1_u32;
+    if y <= one || y >= upper || r <= one || r >= modulus {
+        return false;
+    }
+
+    let e = challenge(r_bytes, public_key, message);
+    let s = BigUint::from_bytes_be(s_bytes);
+    let left = generator.modpow(&s, &modulus);
+    let right = (r * y.modpow(&e, &modulus)) % &modulus;
+    left == right
+}
+
+// e = H(r | y | m), with r and y padded to the length of the modulus
+fn challenge(r: &[u8], public_key: &[u8], message: &[u8]) -> BigUint {
+    let size = element_size();
+    let mut input = pad(r, size);
+    input.extend(pad(public_key, size));
+    input.extend_from_slice(message);
+    BigUint::from_bytes_be(&bernie_hmac::hash(&input))
+}
+
+fn group() -> (BigUint, BigUint) {
+    let (modulus, generator) = dh::get_domain_params();
+    (BigUint::from_bytes_be(&modulus), BigUint::from_bytes_be(&generator))
+}
+
+// Length of the modulus in bytes
+fn element_size() -> usize {
+    dh::get_domain_params().0.len()
+}
+
+fn pad(bytes: &[u8], size: usize) -> Vec<u8> {
+    let mut padded = vec![0_u8; size.saturating_sub(bytes.len())];
+    padded.extend_from_slice(bytes);
+    padded
+}
+
+fn put_field(bytes: &mut Vec<u8>, field: &[u8]) {
+    bytes.extend_from_slice(&(field.len() as u16).to_be_bytes());
+    bytes.extend_from_slice(field);
+}
+
+fn take_field<'a>(bytes: &mut &'a [u8]) -> Option<&'a [u8]> {
+    if bytes.len() < 2 {
+        return None;
+    }
+    let length = BigEndian::read_u16(bytes) as usize;
+    if bytes.len() < 2 + length {
+        return None;
+    }
+    let field = &bytes[2..2 + length];
+    *bytes = &bytes[2 + length..];
+    Some(field)
+}
@@ -0,0 +1,74 @@
+// Settings for a stage-5 connection, shared by the client and the server
+
+use std::sync::Arc;
+
+use crate::record::{self, PaddingPolicy, RekeyPolicy};
+use crate::secret::SecretBytes;
+use crate::suites::CipherSuite;
+use crate::ticket::TicketPolicy;
+
+#[derive(Clone)]
+pub struct SessionConfig {
+    // Suites a client offers or a server accepts, most preferred first
+    pub suites: Vec<CipherSuite>,
+    // Weakest suite this side will use. Anything weaker is neither offered nor accepted,
+    // so an attacker who cuts the offer down can only make the handshake fail.
+    pub minimum_suite: CipherSuite,
+    // When the record layer replaces its traffic keys
+    pub rekey: RekeyPolicy,
+    // Shortest GCM tag this side accepts, in bytes, from record::GCM_TAG_SIZES. The
+    // session uses the longer of the client's and the server's. Tags of 8 or 4 bytes save
+    // bandwidth but limit how much a key can protect, see record.rs.
+    pub gcm_tag_size: usize,
+    // Padding added to the records this side sends. A client with any policy other than
+    // None asks for padded records, and a server pads by its own policy once a client has.
+    pub padding: PaddingPolicy,
+    // Ask for (client) or allow (server) the Double Ratchet, which gives every record
+    // its own key at the cost of a DH operation each time the conversation turns around
+    pub ratchet: bool,
+    // Ask for (client) or allow (server) the hybrid ML-KEM-768 + DH key exchange, at the
+    // cost of about 2.3 KB more handshake traffic. A client asking for it will not connect
+    // to a server that declines it.
+    pub hybrid: bool,
+    // Lifetime of the session tickets a server issues and how often it changes the key
+    // sealing them
+    pub tickets: TicketPolicy,
+    // File a client keeps its session ticket in between runs. The client resumes from the
+    // ticket in it if there is one and saves each new ticket there. None turns resumption off.
+    pub ticket_file: Option<String>,
+    // Long-term key pair chat messages are signed with. A client with a key asks for
+    // signed messages and a server with one allows them. None turns signing off.
+    pub signing_key: Option<Arc<(SecretBytes, Vec<u8>)>>,
+    // File the peer's signed messages are appended to as proofs once they verify
+    pub proof_file: Option<String>,
+    // DH key pair a server uses in every handshake instead of a fresh one. Whoever learns
+    // its private key later can decrypt every session recorded with it, which is what the
+    // forward secrecy demo in the servers' wiretap.rs shows. None keeps the exchange ephemeral.
+    pub static_key: Option<Arc<(SecretBytes, Vec<u8>)>>,
+}
+
+impl SessionConfig {
+    // The configured suites that meet the minimum, in the same order
+    pub fn allowed_suites(&self) -> Vec<CipherSuite> {
+        self.suites.iter().copied().filter(|suite| suite.at_least(self.minimum_suite)).collect()
+    }
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        Self {
+            suites: CipherSuite::all(),
+            minimum_suite: CipherSuite::Aes256EcbHmacSha256,
+            rekey: RekeyPolicy::default(),
+            gcm_tag_size: record::GCM_TAG_SIZE,
+            padding: PaddingPolicy::None,
+            ratchet: false,
+            hybrid: false,
+            tickets: TicketPolicy::default(),
+            ticket_file: None,
+            signing_key: None,
+            proof_file: None,
+            static_key: None,
+        }
+    }
+}
@@ -0,0 +1,469 @@
+// Deterministic random bit generators from NIST SP 800-90A Rev. 1: HMAC_DRBG with
+// SHA-256 (section 10.1.2) and CTR_DRBG with AES-256 and no derivation function
+// (section 10.2.1). Both provide 256 bits of security strength.
+//
+// A DRBG turns a seed from an entropy source into as many output bytes as are asked
+// for. DrbgSource pairs one with the operating system's entropy source, reseeds it
+// when its reseed counter runs out (or before every request when prediction
+// resistance is on) and runs the known-answer health tests of section 11.3 before
+// handing out anything. For replaying a session it can instead be instantiated from a
+// fixed seed, in which case it never touches the entropy source.
+
+use std::fmt;
+
+use rand::RngCore;
+use rand::rngs::OsRng;
+
+use aes_crypt;
+use bernie_hmac;
+
+use crate::hex::from_hex;
+use crate::secret::SecretBytes;
+
+// 256 bits
+pub const SECURITY_STRENGTH: usize = 32;
+
+// Generate requests allowed between reseeds. Both mechanisms allow up to 2^48.
+pub const RESEED_INTERVAL: u64 = 1 << 48;
+
+// Largest single generate request, 2^19 bits for both mechanisms
+pub const MAX_REQUEST_SIZE: usize = 1 << 16;
+
+// Bytes of personalization string mixed in by DrbgSource, so that this program's
+// instantiations differ from any other DRBG seeded with the same entropy
+const PERSONALIZATION: &[u8] = b"seccom drbg";
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum DrbgError {
+    // The reseed counter has passed RESEED_INTERVAL and fresh entropy is needed
+    ReseedRequired,
+    // More than MAX_REQUEST_SIZE bytes were asked for in one request
+    RequestTooLarge,
+    // The entropy source failed or repeated itself
+    EntropySource,
+    // A DRBG instantiated from a fixed seed has no entropy source to reseed from
+    Seeded,
+    // A known-answer test produced the wrong output
+    HealthTest(&'static str),
+}
+
+impl fmt::Display for DrbgError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DrbgError::ReseedRequired => write!(f, "DRBG must be reseeded"),
+            DrbgError::RequestTooLarge => write!(f, "DRBG request too large"),
+            DrbgError::EntropySource => write!(f, "Entropy source failure"),
+            DrbgError::Seeded => write!(f, "DRBG instantiated from a fixed seed can not be reseeded"),
+            DrbgError::HealthTest(name) => write!(f, "{} failed its health test", name),
+        }
+    }
+}
+
+impl std::error::Error for DrbgError {}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mechanism {
+    HmacSha256,
+    CtrAes256,
+}
+
+impl Mechanism {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Mechanism::HmacSha256 => "HMAC_DRBG (SHA-256)",
+            Mechanism::CtrAes256 => "CTR_DRBG (AES-256, no df)",
+        }
+    }
+
+    // Bytes of entropy input taken from the entropy source at instantiation and at
+    // every reseed. CTR_DRBG without a derivation function needs a full seed length
+    // of full-entropy input.
+    fn entropy_size(&self) -> usize {
+        match self {
+            Mechanism::HmacSha256 => SECURITY_STRENGTH,
+            Mechanism::CtrAes256 => CTR_SEED_SIZE,
+        }
+    }
+
+    // Bytes of nonce taken at instantiation, half the security strength for HMAC_DRBG.
+    // CTR_DRBG without a derivation function takes no nonce.
+    fn nonce_size(&self) -> usize {
+        match self {
+            Mechanism::HmacSha256 => SECURITY_STRENGTH / 2,
+            Mechanism::CtrAes256 => 0,
+        }
+    }
+}
+
+// The instantiate, reseed and generate functions shared by both mechanisms
+pub trait Drbg {
+    fn reseed(&mut self, entropy: &[u8], additional_input: &[u8]);
+    fn generate(&mut self, output: &mut [u8], additional_input: &[u8]) -> Result<(), DrbgError>;
+}
+
+pub fn instantiate(mechanism: Mechanism, entropy: &[u8], nonce: &[u8], personalization: &[u8]) -> Box<dyn Drbg + Send> {
+    match mechanism {
+        Mechanism::HmacSha256 => Box::new(HmacDrbg::instantiate(entropy, nonce, personalization)),
+        Mechanism::CtrAes256 => Box::new(CtrDrbg::instantiate(entropy, personalization)),
+    }
+}
+
+// HMAC_DRBG internal state (section 10.1.2.1)
+pub struct HmacDrbg {
+    key: SecretBytes,
+    value: SecretBytes,
+    reseed_counter: u64,
+}
+
+impl HmacDrbg {
+    // Section 10.1.2.3: seed_material = entropy_input || nonce || personalization_string
+    pub fn instantiate(entropy: &[u8], nonce: &[u8], personalization: &[u8]) -> Self {
+        let mut drbg = Self {
+            key: SecretBytes::new(vec![0x00; SECURITY_STRENGTH]),
+            value: SecretBytes::new(vec![0x01; SECURITY_STRENGTH]),
+            reseed_counter: 1,
+        };
+        drbg.update(&[entropy, nonce, personalization]);
+        drbg
+    }
+
+    // Section 10.1.2.2. The provided data is passed in pieces so the seed material never
+    // has to be copied into a single buffer.
+    fn update(&mut self, provided_data: &[&[u8]]) {
+        for round in [0x00_u8, 0x01] {
+            // K = HMAC(K, V || round || provided_data) and V = HMAC(K, V)
+            let length = SECURITY_STRENGTH + 1 + provided_data.iter().map(|data| data.len()).sum::<usize>();
+            let mut input = Vec::with_capacity(length);
+            input.extend_from_slice(self.value.expose());
+            input.push(round);
+            for data in provided_data {
+                input.extend_from_slice(data);
+            }
+            let input = SecretBytes::new(input);
+            self.key = SecretBytes::new(bernie_hmac::hmac(input.expose(), self.key.expose()));
+            self.value = SecretBytes::new(bernie_hmac::hmac(self.value.expose(), self.key.expose()));
+
+            // The second round only happens when there is provided data
+            if provided_data.iter().all(|data| data.is_empty()) {
+                break;
+            }
+        }
+    }
+}
+
+impl Drbg for HmacDrbg {
+    // Section 10.1.2.4: seed_material = entropy_input || additional_input
+    fn reseed(&mut self, entropy: &[u8], additional_input: &[u8]) {
+        self.update(&[entropy, additional_input]);
+        self.reseed_counter = 1;
+    }
+
+    // Section 10.1.2.5
+    fn generate(&mut self, output: &mut [u8], additional_input: &[u8]) -> Result<(), DrbgError> {
+        if output.len() > MAX_REQUEST_SIZE {
+            return Err(DrbgError::RequestTooLarge);
+        }
+        if self.reseed_counter > RESEED_INTERVAL {
+            return Err(DrbgError::ReseedRequired);
+        }
+        if !additional_input.is_empty() {
+            self.update(&[additional_input]);
+        }
+
+        // V = HMAC(K, V), concatenated until enough bytes are available
+        for chunk in output.chunks_mut(SECURITY_STRENGTH) {
+            self.value = SecretBytes::new(bernie_hmac::hmac(self.value.expose(), self.key.expose()));
+            chunk.copy_from_slice(&self.value.expose()[..chunk.len()]);
+        }
+
+        self.update(&[additional_input]);
+        self.reseed_counter += 1;
+        Ok(())
+    }
+}
+
+const CTR_KEY_SIZE: usize = 32;
+const CTR_BLOCK_SIZE: usize = 16;
+const CTR_SEED_SIZE: usize = CTR_KEY_SIZE + CTR_BLOCK_SIZE;
+
+// CTR_DRBG internal state (section 10.2.1.1)
+pub struct CtrDrbg {
+    key: SecretBytes,
+    value: SecretBytes,
+    reseed_counter: u64,
+}
+
+impl CtrDrbg {
+    // Section 10.2.1.3.1: seed_material = entropy_input XOR personalization_string, with
+    // the personalization string padded with zeros to the seed length
+    pub fn instantiate(entropy: &[u8], personalization: &[u8]) -> Self {
+        let mut drbg = Self {
+            key: SecretBytes::new(vec![0x00; CTR_KEY_SIZE]),
+            value: SecretBytes::new(vec![0x00; CTR_BLOCK_SIZE]),
+            reseed_counter: 1,
+        };
+        drbg.update(&seed_material(entropy, personalization));
+        drbg
+    }
+
+    // Section 10.2.1.2: encrypt successive counter values to fill a seed length, XOR in
+    // the provided data and split the result into the new key and V
+    fn update(&mut self, provided_data: &SecretBytes) {
+        let mut temp = self.keystream(CTR_SEED_SIZE);
+        for (byte, data) in temp.iter_mut().zip(provided_data.expose()) {
+            *byte ^= data;
+        }
+        let value = temp.split_off(CTR_KEY_SIZE);
+        self.key = SecretBytes::new(temp);
+        self.value = SecretBytes::new(value);
+    }
+
+    // V = (V + 1) mod 2^128 followed by E(Key, V), block after block
+    fn keystream(&mut self, length: usize) -> Vec<u8> {
+        let mut value = self.value.expose().to_vec();
+        let mut output = Vec::with_capacity(length + CTR_BLOCK_SIZE);
+        while output.len() < length {
+            for byte in value.iter_mut().rev() {
+                *byte = byte.wrapping_add(1);
+                if *byte != 0 {
+                    break;
+                }
+            }
+            output.extend_from_slice(&aes_crypt::encrypt_ecb(&value, self.key.expose())[..CTR_BLOCK_SIZE]);
+        }
+        self.value = SecretBytes::new(value);
+        output.truncate(length);
+        output
+    }
+}
+
+impl Drbg for CtrDrbg {
+    // Section 10.2.1.4.1: seed_material = entropy_input XOR additional_input
+    fn reseed(&mut self, entropy: &[u8], additional_input: &[u8]) {
+        self.update(&seed_material(entropy, additional_input));
+        self.reseed_counter = 1;
+    }
+
+    // Section 10.2.1.5.1
+    fn generate(&mut self, output: &mut [u8], additional_input: &[u8]) -> Result<(), DrbgError> {
+        if output.len() > MAX_REQUEST_SIZE {
+            return Err(DrbgError::RequestTooLarge);
+        }
+        if self.reseed_counter > RESEED_INTERVAL {
+            return Err(DrbgError::ReseedRequired);
+        }
+
+        // Without a derivation function the additional input is padded to the seed length
+        let additional_input = seed_material(&[0_u8; CTR_SEED_SIZE], additional_input);
+        if additional_input.expose().iter().any(|&byte| byte != 0) {
+            self.update(&additional_input);
+        }
+
+        let keystream = SecretBytes::new(self.keystream(output.len()));
+        output.copy_from_slice(keystream.expose());
+
+        self.update(&additional_input);
+        self.reseed_counter += 1;
+        Ok(())
+    }
+}
+
+// Input XOR data, where data is no longer than the seed length and is padded with zeros
+fn seed_material(input: &[u8], data: &[u8]) -> SecretBytes {
+    let mut material = input[..CTR_SEED_SIZE].to_vec();
+    for (byte, data) in material.iter_mut().zip(data.iter().take(CTR_SEED_SIZE)) {
+        *byte ^= data;
+    }
+    SecretBytes::new(material)
+}
+
+// A DRBG instantiated from the operating system's entropy source, which is the form the
+// rest of the program draws its randomness from
+pub struct DrbgSource {
+    mechanism: Mechanism,
+    drbg: Box<dyn Drbg + Send>,
+    // Reseed before every request, so an attacker who learns the internal state can not
+    // predict any output produced after that point (section 8.8)
+    prediction_resistance: bool,
+    // Last block read from the entropy source, for the continuous test. None when the
+    // DRBG was instantiated from a fixed seed and has no entropy source.
+    last_entropy: Option<SecretBytes>,
+}
+
+impl DrbgSource {
+    pub fn new(mechanism: Mechanism, prediction_resistance: bool) -> Result<Self, DrbgError> {
+        self_test()?;
+
+        let mut last_entropy = SecretBytes::new(Vec::new());
+        let entropy = get_entropy(mechanism.entropy_size(), &mut last_entropy)?;
+        let nonce = get_entropy(mechanism.nonce_size(), &mut last_entropy)?;
+        let drbg = instantiate(mechanism, entropy.expose(), nonce.expose(), PERSONALIZATION);
+
+        Ok(Self { mechanism, drbg, prediction_resistance, last_entropy: Some(last_entropy) })
+    }
+
+    // Instantiates from a fixed seed instead of the entropy source, so the same seed and
+    // personalization always give the same output. Only meant for replaying sessions: the
+    // output is no more secret than the seed, and the DRBG can never reseed, so it stops
+    // with ReseedRequired once the reseed counter runs out.
+    pub fn seeded(mechanism: Mechanism, seed: &[u8], personalization: &[u8]) -> Result<Self, DrbgError> {
+        self_test()?;
+
+        let seed_material = hash_df(seed, mechanism.entropy_size() + mechanism.nonce_size());
+        let (entropy, nonce) = seed_material.expose().split_at(mechanism.entropy_size());
+        let mut full_personalization = PERSONALIZATION.to_vec();
+        full_personalization.extend_from_slice(personalization);
+        let drbg = instantiate(mechanism, entropy, nonce, &full_personalization);
+
+        Ok(Self { mechanism, drbg, prediction_resistance: false, last_entropy: None })
+    }
+
+    pub fn mechanism(&self) -> Mechanism {
+        self.mechanism
+    }
+
+    pub fn is_seeded(&self) -> bool {
+        self.last_entropy.is_none()
+    }
+
+    pub fn reseed(&mut self, additional_input: &[u8]) -> Result<(), DrbgError> {
+        let last_entropy = self.last_entropy.as_mut().ok_or(DrbgError::Seeded)?;
+        let entropy = get_entropy(self.mechanism.entropy_size(), last_entropy)?;
+        self.drbg.reseed(entropy.expose(), additional_input);
+        Ok(())
+    }
+
+    // Fills the output, splitting it into requests no larger than MAX_REQUEST_SIZE
+    pub fn fill(&mut self, output: &mut [u8]) -> Result<(), DrbgError> {
+        for chunk in output.chunks_mut(MAX_REQUEST_SIZE) {
+            if self.prediction_resistance {
+                self.reseed(&[])?;
+            }
+            match self.drbg.generate(chunk, &[]) {
+                Err(DrbgError::ReseedRequired) if !self.is_seeded() => {
+                    self.reseed(&[])?;
+                    self.drbg.generate(chunk, &[])?;
+                }
+                result => result?,
+            }
+        }
+        Ok(())
+    }
+}
+
+// Reads from the operating system's entropy source. As a continuous test, a read that
+// starts with the same bytes as the previous one is treated as a failed source.
+fn get_entropy(length: usize, last_entropy: &mut SecretBytes) -> Result<SecretBytes, DrbgError> {
+    let mut entropy = vec![0_u8; length];
+    OsRng.try_fill_bytes(&mut entropy).map_err(|_| DrbgError::EntropySource)?;
+    let entropy = SecretBytes::new(entropy);
+
+    let compared = length.min(last_entropy.len());
+    if compared > 0 && entropy.expose()[..compared] == last_entropy.expose()[..compared] {
+        return Err(DrbgError::EntropySource);
+    }
+    *last_entropy = entropy.duplicate();
+    Ok(entropy)
+}
+
+// Output size of SHA-256, the hash used by Hash_df
+const HASH_SIZE: usize = 32;
+
+// Hash_df (section 10.3.1) with SHA-256, which spreads a seed of any length over the
+// entropy input and nonce
+fn hash_df(input: &[u8], length: usize) -> SecretBytes {
+    let bits = (length as u32 * 8).to_be_bytes();
+    let mut output = Vec::with_capacity(length.div_ceil(HASH_SIZE) * HASH_SIZE);
+    let mut counter = 1_u8;
+    while output.len() < length {
+        // Hash(counter || no_of_bits_to_return || input_string)
+        let mut hash_input = Vec::with_capacity(1 + bits.len() + input.len());
+        hash_input.push(counter);
+        hash_input.extend_from_slice(&bits);
+        hash_input.extend_from_slice(input);
+        let hash_input = SecretBytes::new(hash_input);
+        output.extend(bernie_hmac::hash(hash_input.expose()));
+        counter += 1;
+    }
+    output.truncate(length);
+    SecretBytes::new(output)
+}
+
+// Known-answer tests of the instantiate, generate and reseed functions (section 11.3),
+// plus a check that an exhausted reseed counter is refused. All vectors are COUNT 0 from
+// the CAVP DRBG test files, without prediction resistance:
+//
+// - HMAC_DRBG SHA-256 from drbgvectors_no_reseed, with additional input, of which only
+//   the second 128-byte output is given
+// - HMAC_DRBG SHA-256 from drbgvectors_pr_false, without personalization string or
+//   additional input. The DRBG is reseeded with EntropyInputReseed after instantiation
+//   and only the second output is given.
+// - CTR_DRBG AES-256 no df from drbgvectors_no_reseed (384-bit entropy input, no nonce,
+//   personalization string or additional input)
+// - CTR_DRBG AES-256 no df from drbgvectors_pr_false, reseeded as the HMAC_DRBG case
+pub fn self_test() -> Result<(), DrbgError> {
+    let mut hmac_drbg = HmacDrbg::instantiate(
+        &from_hex("d3cc4d1acf3dde0c4bd2290d262337042dc632948223d3a2eaab87da44295fbd").unwrap(),
+        &from_hex("0109b0e729f457328aa18569a9224921").unwrap(),
+        &[],
+    );
+    let mut output = vec![0_u8; 128];
+    hmac_drbg.generate(&mut output, &from_hex("3c311848183c9a212a26f27f8c6647e40375e466a0857cc39c4e47575d53f1f6").unwrap())?;
+    hmac_drbg.generate(&mut output, &from_hex("fcb9abd19ccfbccef88c9c39bfb3dd7b1c12266c9808992e305bc3cff566e4e4").unwrap())?;
+    if output != from_hex(HMAC_DRBG_EXPECTED).unwrap() {
+        return Err(DrbgError::HealthTest(Mechanism::HmacSha256.name()));
+    }
+
+    let mut hmac_drbg = HmacDrbg::instantiate(&from_hex(HMAC_DRBG_RESEED_ENTROPY).unwrap(), &from_hex(HMAC_DRBG_RESEED_NONCE).unwrap(), &[]);
+    hmac_drbg.reseed(&from_hex(HMAC_DRBG_ENTROPY_RESEED).unwrap(), &[]);
+    let mut output = vec![0_u8; 128];
+    hmac_drbg.generate(&mut output, &[])?;
+    hmac_drbg.generate(&mut output, &[])?;
+    if output != from_hex(HMAC_DRBG_RESEED_EXPECTED).unwrap() {
+        return Err(DrbgError::HealthTest(Mechanism::HmacSha256.name()));
+    }
+
+    let mut ctr_drbg = CtrDrbg::instantiate(&from_hex(CTR_DRBG_ENTROPY).unwrap(), &[]);
+    let mut output = vec![0_u8; 64];
+    ctr_drbg.generate(&mut output, &[])?;
+    ctr_drbg.generate(&mut output, &[])?;
+    if output != from_hex(CTR_DRBG_EXPECTED).unwrap() {
+        return Err(DrbgError::HealthTest(Mechanism::CtrAes256.name()));
+    }
+
+    let mut ctr_drbg = CtrDrbg::instantiate(&from_hex(CTR_DRBG_RESEED_ENTROPY).unwrap(), &[]);
+    ctr_drbg.reseed(&from_hex(CTR_DRBG_ENTROPY_RESEED).unwrap(), &[]);
+    ctr_drbg.generate(&mut output, &[])?;
+    ctr_drbg.generate(&mut output, &[])?;
+    if output != from_hex(CTR_DRBG_RESEED_EXPECTED).unwrap() {
+        return Err(DrbgError::HealthTest(Mechanism::CtrAes256.name()));
+    }
+
+    // Error handling: once the reseed interval has passed, generate must refuse
+    ctr_drbg.reseed_counter = RESEED_INTERVAL + 1;
+    if ctr_drbg.generate(&mut output, &[]) != Err(DrbgError::ReseedRequired) {
+        return Err(DrbgError::HealthTest(Mechanism::CtrAes256.name()));
+    }
+    Ok(())
+}
+
+const HMAC_DRBG_EXPECTED: &str = "9c7b758b212cd0fcecd5daa489821712e3cdea4467b560ef5ddc24ab47749a1f1ffdbbb118f4e62fcfca3371b8fbfc5b0646b83e06bfbbab5fac30ea09ea2bc76f1ea568c9be0444b2cc90517b20ca825f2d0eccd88e7175538b85d90ab390183ca6395535d34473af6b5a5b88f5a59ee7561573337ea819da0dcc3573a22974";
+const HMAC_DRBG_RESEED_ENTROPY: &str = "06032cd5eed33f39265f49ecb142c511da9aff2af71203bffaf34a9ca5bd9c0d";
+const HMAC_DRBG_RESEED_NONCE: &str = "0e66f71edc43e42a45ad3c6fc6cdc4df";
+const HMAC_DRBG_ENTROPY_RESEED: &str = "01920a4e669ed3a85ae8a33b35a74ad7fb2a6bb4cf395ce00334a9c9a5a5d552";
+const HMAC_DRBG_RESEED_EXPECTED: &str = "76fc79fe9b50beccc991a11b5635783a83536add03c157fb30645e611c2898bb2b1bc215000209208cd506cb28da2a51bdb03826aaf2bd2335d576d519160842e7158ad0949d1a9ec3e66ea1b1a064b005de914eac2e9d4f2d72a8616a80225422918250ff66a41bd2f864a6a38cc5b6499dc43f7f2bd09e1e0f8f5885935124";
+const CTR_DRBG_ENTROPY: &str = "df5d73faa468649edda33b5cca79b0b05600419ccb7a879ddfec9db32ee494e5531b51de16a30f769262474c73bec010";
+const CTR_DRBG_EXPECTED: &str = "d1c07cd95af8a7f11012c84ce48bb8cb87189e99d40fccb1771c619bdf82ab2280b1dc2f2581f39164f7ac0c510494b3a43c41b7db17514c87b107ae793e01c5";
+const CTR_DRBG_RESEED_ENTROPY: &str = "e4bc23c5089a19d86f4119cb3fa08c0a4991e0a1def17e101e4c14d9c323460a7c2fb58e0b086c6c57b55f56cae25bad";
+const CTR_DRBG_ENTROPY_RESEED: &str = "fd85a836bba85019881e8c6bad23c9061adc75477659acaea8e4a01dfe07a1832dad1c136f59d70f8653a5dc118663d6";
+const CTR_DRBG_RESEED_EXPECTED: &str = "b2cb8905c05e5950ca31895096be29ea3d5a3b82b269495554eb80fe07de43e193b9e7c3ece73b80e062b1c1f68202fbb1c52a040ea2478864295282234aaada";
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn known_answer_tests_pass() {
+        assert_eq!(self_test(), Ok(()));
+    }
+}
@@ -0,0 +1,302 @@
+// Hello messages exchanged at the start of a stage-5 connection. The client lists the
+// cipher suites it supports alongside its DH public key, and the server answers with the
+// suite it picked alongside its own DH public key. Both hellos are recorded in a
+// transcript which is mixed into the final key, so the negotiated suite is bound to
+// the keys protecting the rest of the session.
+//
+// The transcript holds the hellos exactly as they were sent, including suite ids this
+// build does not know, so the whole offer is covered and not just the part we parsed.
+// Once the final key is known each side sends a Finished, an HMAC over the transcript
+// under a key expanded from the final key. If anything in either hello was changed on
+// the way, say an offer cut down to its weakest suite, the two transcripts differ and
+// the Finished check fails before any application data is sent.
+//
+// Both hellos also carry a flags byte for optional features. A feature is only used if
+// the client asked for it and the server echoed it back.
+//
+// With the hybrid flag the client also sends an ML-KEM-768 encapsulation key and the
+// server answers with a ciphertext encapsulated to it. Both shared secrets then go into
+// the KDF, so the final key stays safe as long as either DH or ML-KEM does.
+//
+// With the resume flag the client presents a session ticket from an earlier connection,
+// and both hellos carry a fresh nonce where the DH public key would be. If the server
+// accepts the ticket it echoes the flag and no DH is done at all. Otherwise it answers
+// with a TicketDeclined frame and the client starts over with a full ClientHello.
+//
+// With the signed flag every chat message also carries a signature by the sender's
+// long-term key (see proof.rs). The server only echoes it if it has a key to sign with.
+//
+// With the short-tag flag the flags byte is followed by a GCM tag length in bytes. In
+// the ClientHello it is the shortest tag the client accepts, and in the ServerHello the
+// length both sides use, which is the longer of the client's and the server's shortest.
+// Without the flag GCM tags are the full 16 bytes.
+//
+// With the padded flag every record carries an inner content type followed by zero
+// padding (see record.rs). The server always echoes it, since stripping padding costs
+// nothing, and each side then pads the records it sends by its own policy.
+//
+// With the committed flag, which only the ClientHello has, the client sends a hash of its
+// DH public key in place of the key (see sas.rs). The key itself follows in a KeyReveal
+// frame once the ServerHello has arrived, and goes into the transcript after both hellos.
+
+use byteorder::{ByteOrder, BigEndian};
+
+use bernie_hmac;
+
+use crate::kdf;
+use crate::mlkem;
+use crate::record::{self, Side};
+use crate::secret::SecretBytes;
+use crate::suites::CipherSuite;
+
+// Protect records under the Double Ratchet rather than the traffic keys
+const FLAG_RATCHET: u8 = 0x01;
+// Add an ML-KEM-768 encapsulation to the DH exchange
+const FLAG_HYBRID: u8 = 0x02;
+// Resume the session a ticket was issued for instead of doing a DH exchange
+const FLAG_RESUME: u8 = 0x04;
+// Sign every chat message with the sender's long-term key
+const FLAG_SIGNED: u8 = 0x08;
+// Use GCM tags shorter than 16 bytes
+const FLAG_SHORT_TAG: u8 = 0x10;
+// Pad records to hide the length of their content
+const FLAG_PADDED: u8 = 0x20;
+// The DH public key is held back behind a commitment until the ServerHello has been sent
+const FLAG_COMMITTED: u8 = 0x40;
+
+pub struct ClientHello {
+    pub suites: Vec<CipherSuite>,
+    pub ratchet: bool,
+    // ML-KEM-768 encapsulation key, present when asking for the hybrid exchange
+    pub kem_public_key: Option<Vec<u8>>,
+    // Session ticket, present when asking to resume
+    pub ticket: Option<Vec<u8>>,
+    pub signed: bool,
+    // Shortest GCM tag the client accepts, in bytes
+    pub gcm_tag_size: usize,
+    pub padded: bool,
+    // Set when public_key holds a commitment to the DH public key rather than the key
+    pub committed: bool,
+    // DH public key, its commitment, or the client's nonce when resuming
+    pub public_key: Vec<u8>,
+}
+
+impl ClientHello {
+    // Layout: suite count (1 byte) | suite ids (2 bytes each) | flags (1 byte) | GCM tag
+    // length (1 byte, short tag only) | ML-KEM encapsulation key (hybrid only) | ticket
+    // length (2 bytes) and ticket (resume only) | DH public key, commitment or nonce
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![self.suites.len() as u8];
+        for suite in &self.suites {
+            bytes.extend_from_slice(&suite.id().to_be_bytes());
+        }
+        let mut flags = flags(self.ratchet, self.kem_public_key.is_some(), self.ticket.is_some(), self.signed, self.gcm_tag_size, self.padded);
+        if self.committed {
+            flags |= FLAG_COMMITTED;
+        }
+        bytes.push(flags);
+        push_tag_field(&mut bytes, self.gcm_tag_size);
+        if let Some(kem_public_key) = &self.kem_public_key {
+            bytes.extend_from_slice(kem_public_key);
+        }
+        if let Some(ticket) = &self.ticket {
+            bytes.extend_from_slice(&(ticket.len() as u16).to_be_bytes());
+            bytes.extend_from_slice(ticket);
+        }
+        bytes.extend_from_slice(&self.public_key);
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let count = *bytes.first()? as usize;
+        let ids_end = 1 + count * 2;
+        if bytes.len() <= ids_end + 1 {
+            return None;
+        }
+
+        // Suites this build does not know about are skipped rather than rejected
+        let suites = bytes[1..ids_end]
+            .chunks(2)
+            .filter_map(|id| CipherSuite::from_id(BigEndian::read_u16(id)))
+            .collect();
+
+        let ratchet = bytes[ids_end] & FLAG_RATCHET != 0;
+        let signed = bytes[ids_end] & FLAG_SIGNED != 0;
+        let padded = bytes[ids_end] & FLAG_PADDED != 0;
+        let committed = bytes[ids_end] & FLAG_COMMITTED != 0;
+        let (gcm_tag_size, rest) = split_tag_field(&bytes[ids_end + 1..], bytes[ids_end])?;
+        let (kem_public_key, rest) = split_kem_field(rest, bytes[ids_end], mlkem::ENCAPSULATION_KEY_SIZE)?;
+        let (ticket, public_key) = split_ticket_field(&rest, bytes[ids_end])?;
+        Some(Self { suites, ratchet, kem_public_key, ticket, signed, gcm_tag_size, padded, committed, public_key })
+    }
+}
+
+pub struct ServerHello {
+    pub suite: CipherSuite,
+    pub ratchet: bool,
+    // ML-KEM-768 ciphertext, present when the hybrid exchange was accepted
+    pub kem_ciphertext: Option<Vec<u8>>,
+    // Set when the server accepted the client's ticket
+    pub resumed: bool,
+    pub signed: bool,
+    // GCM tag length both sides use, in bytes
+    pub gcm_tag_size: usize,
+    pub padded: bool,
+    // DH public key, or the server's nonce when resuming
+    pub public_key: Vec<u8>,
+}
+
+impl ServerHello {
+    // Layout: selected suite id (2 bytes) | flags (1 byte) | GCM tag length (1 byte,
+    // short tag only) | ML-KEM ciphertext (hybrid only) | DH public key or nonce
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.suite.id().to_be_bytes().to_vec();
+        bytes.push(flags(self.ratchet, self.kem_ciphertext.is_some(), self.resumed, self.signed, self.gcm_tag_size, self.padded));
+        push_tag_field(&mut bytes, self.gcm_tag_size);
+        if let Some(kem_ciphertext) = &self.kem_ciphertext {
+            bytes.extend_from_slice(kem_ciphertext);
+        }
+        bytes.extend_from_slice(&self.public_key);
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() <= 3 {
+            return None;
+        }
+        let suite = CipherSuite::from_id(BigEndian::read_u16(bytes))?;
+        let ratchet = bytes[2] & FLAG_RATCHET != 0;
+        let resumed = bytes[2] & FLAG_RESUME != 0;
+        let signed = bytes[2] & FLAG_SIGNED != 0;
+        let padded = bytes[2] & FLAG_PADDED != 0;
+        let (gcm_tag_size, rest) = split_tag_field(&bytes[3..], bytes[2])?;
+        let (kem_ciphertext, public_key) = split_kem_field(rest, bytes[2], mlkem::CIPHERTEXT_SIZE)?;
+        Some(Self { suite, ratchet, kem_ciphertext, resumed, signed, gcm_tag_size, padded, public_key })
+    }
+}
+
+fn flags(ratchet: bool, hybrid: bool, resume: bool, signed: bool, gcm_tag_size: usize, padded: bool) -> u8 {
+    let mut flags = 0;
+    if ratchet {
+        flags |= FLAG_RATCHET;
+    }
+    if hybrid {
+        flags |= FLAG_HYBRID;
+    }
+    if resume {
+        flags |= FLAG_RESUME;
+    }
+    if signed {
+        flags |= FLAG_SIGNED;
+    }
+    if gcm_tag_size < record::GCM_TAG_SIZE {
+        flags |= FLAG_SHORT_TAG;
+    }
+    if padded {
+        flags |= FLAG_PADDED;
+    }
+    flags
+}
+
+fn push_tag_field(bytes: &mut Vec<u8>, gcm_tag_size: usize) {
+    if gcm_tag_size < record::GCM_TAG_SIZE {
+        bytes.push(gcm_tag_size as u8);
+    }
+}
+
+// With the short-tag flag set, the tag length comes first. Only the lengths SP 800-38D
+// allows are accepted.
+fn split_tag_field(bytes: &[u8], flags: u8) -> Option<(usize, &[u8])> {
+    if flags & FLAG_SHORT_TAG == 0 {
+        return Some((record::GCM_TAG_SIZE, bytes));
+    }
+    let (&size, rest) = bytes.split_first()?;
+    let size = size as usize;
+    if size >= record::GCM_TAG_SIZE || !record::GCM_TAG_SIZES.contains(&size) {
+        return None;
+    }
+    Some((size, rest))
+}
+
+// With the hybrid flag set, the fixed-size ML-KEM field comes before the DH public key
+fn split_kem_field(bytes: &[u8], flags: u8, size: usize) -> Option<(Option<Vec<u8>>, Vec<u8>)> {
+    if flags & FLAG_HYBRID == 0 {
+        return Some((None, bytes.to_vec()));
+    }
+    if bytes.len() <= size {
+        return None;
+    }
+    Some((Some(bytes[..size].to_vec()), bytes[size..].to_vec()))
+}
+
+// With the resume flag set, the length-prefixed ticket comes before the nonce
+fn split_ticket_field(bytes: &[u8], flags: u8) -> Option<(Option<Vec<u8>>, Vec<u8>)> {
+    if flags & FLAG_RESUME == 0 {
+        return Some((None, bytes.to_vec()));
+    }
+    if bytes.len() < 2 {
+        return None;
+    }
+    let length = BigEndian::read_u16(bytes) as usize;
+    if bytes.len() <= 2 + length {
+        return None;
+    }
+    Some((Some(bytes[2..2 + length].to_vec()), bytes[2 + length..].to_vec()))
+}
+
+// Running record of every handshake message in the order it was sent
+pub struct Transcript {
+    messages: Vec<u8>,
+}
+
+impl Transcript {
+    pub fn new() -> Self {
+        Self { messages: Vec::new() }
+    }
+
+    pub fn add(&mut self, message: &[u8]) {
+        self.messages.extend_from_slice(&(message.len() as u32).to_be_bytes());
+        self.messages.extend_from_slice(message);
+    }
+
+    pub fn hash(&self) -> Vec<u8> {
+        bernie_hmac::hash(&self.messages)
+    }
+}
+
+impl Default for Transcript {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Input to the KDF for the hybrid exchange: the DH secret followed by the ML-KEM secret.
+// The ML-KEM secret is always 32 bytes and comes last, so the split is unambiguous.
+pub fn hybrid_secret(dh_secret: &SecretBytes, kem_secret: &SecretBytes) -> SecretBytes {
+    let mut secret = Vec::with_capacity(dh_secret.len() + kem_secret.len());
+    secret.extend_from_slice(dh_secret.expose());
+    secret.extend_from_slice(kem_secret.expose());
+    SecretBytes::new(secret)
+}
+
+// verify_data = HMAC(finished key, transcript hash), where the finished key is expanded
+// from the final key with a label for the sender
+pub fn finished(final_key: &SecretBytes, sender: Side, transcript: &Transcript) -> Vec<u8> {
+    let label = match sender {
+        Side::Client => "client finished",
+        Side::Server => "server finished",
+    };
+    let finished_key = SecretBytes::new(kdf::expand_label(final_key.expose(), label, &[], kdf::HASH_SIZE));
+    bernie_hmac::hmac(&transcript.hash(), finished_key.expose())
+}
+
+// Use SHA-256 over the shared secret and the transcript hash as the KDF
+pub fn derive_key(shared_secret: &SecretBytes, transcript: &Transcript) -> SecretBytes {
+    // Sized up front so that growing the vector can not leave a stray copy of the secret behind
+    let transcript_hash = transcript.hash();
+    let mut kdf_input = Vec::with_capacity(shared_secret.len() + transcript_hash.len());
+    kdf_input.extend_from_slice(shared_secret.expose());
+    kdf_input.extend_from_slice(&transcript_hash);
+    let kdf_input = SecretBytes::new(kdf_input);
+    SecretBytes::new(bernie_hmac::hash(kdf_input.expose()))
+}
@@ -0,0 +1,17 @@
+// Hex encoding, for everything that is written out or read back as text: keys and seeds,
+// proofs, captures and fingerprints, and the known answers of the self-tests.
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+// None for an odd length or anything other than hex digits
+pub fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
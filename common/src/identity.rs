@@ -0,0 +1,99 @@
+// Client authentication for stage 6. A server that asks for it only keeps a client whose
+// Certificate it accepts and whose CertificateVerify signature over the transcript checks
+// out, and from then on knows the client by the identity below rather than by its
+// socket address.
+//
+// There are two ways to accept a client's key. An authorized keys file lists the keys
+// the server accepts by name, in the spirit of SSH, and the client sends its bare public
+// key (as with the raw public keys of RFC 7250). A client CA instead signs a certificate
+// for each client, and the server accepts any certificate it signed, using the subject
+// as the identity.
+//
+// An authorized keys file has one key per line:
+//
+//     <name> <public key in hex>
+//
+// Blank lines and lines starting with # are skipped. `keygen authorize` adds lines.
+
+use std::io::{Error, ErrorKind, Write};
+
+use crate::certificate::Certificate;
+use crate::hex;
+use crate::proof;
+
+#[derive(Clone)]
+pub enum ClientAuth {
+    // Any client that finishes the handshake, known by its socket address
+    None,
+    // Clients holding one of these keys, known by the name listed with it
+    AuthorizedKeys(Vec<(String, Vec<u8>)>),
+    // Clients with a certificate from the CA with this public key, known by its subject
+    ClientCa(Vec<u8>),
+}
+
+impl ClientAuth {
+    pub fn authorized_keys(path: &str) -> Result<Self, Error> {
+        Ok(ClientAuth::AuthorizedKeys(read_authorized_keys(path)?))
+    }
+
+    pub fn required(&self) -> bool {
+        !matches!(self, ClientAuth::None)
+    }
+
+    // The identity of the client that sent this certificate, None if it is not accepted.
+    // The certificate only names a key, the CertificateVerify still has to show the client
+    // holds it.
+    pub fn identify(&self, certificate: &Certificate) -> Option<String> {
+        match self {
+            ClientAuth::None => None,
+            ClientAuth::AuthorizedKeys(keys) => keys.iter()
+                .find(|(_, public_key)| *public_key == certificate.public_key)
+                .map(|(name, _)| name.clone()),
+            ClientAuth::ClientCa(ca_public_key) if certificate.verify(ca_public_key) => Some(certificate.subject.clone()),
+            ClientAuth::ClientCa(_) => None,
+        }
+    }
+}
+
+impl std::fmt::Display for ClientAuth {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ClientAuth::None => write!(f, "none"),
+            ClientAuth::AuthorizedKeys(keys) => write!(f, "{} authorized key(s)", keys.len()),
+            ClientAuth::ClientCa(ca_public_key) => write!(f, "certificates from the client CA {}", proof::fingerprint(ca_public_key)),
+        }
+    }
+}
+
+pub fn read_authorized_keys(path: &str) -> Result<Vec<(String, Vec<u8>)>, Error> {
+    let contents = std::fs::read_to_string(path)?;
+    let mut keys = Vec::new();
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut words = line.split_whitespace();
+        let key = match (words.next(), words.next().and_then(hex::from_hex), words.next()) {
+            (Some(name), Some(public_key), None) if !public_key.is_empty() => (name.to_string(), public_key),
+            _ => return Err(Error::new(ErrorKind::InvalidData, format!("{} line {}: expected <name> <public key in hex>", path, number + 1))),
+        };
+        if keys.iter().any(|(name, _)| *name == key.0) {
+            return Err(Error::new(ErrorKind::InvalidData, format!("{} line {}: {} is listed twice", path, number + 1, key.0)));
+        }
+        keys.push(key);
+    }
+    Ok(keys)
+}
+
+// Appends a key to an authorized keys file, creating the file if needed
+pub fn authorize(path: &str, name: &str, public_key: &[u8]) -> Result<(), Error> {
+    if name.is_empty() || name.contains(char::is_whitespace) || name.starts_with('#') {
+        return Err(Error::new(ErrorKind::InvalidInput, "Names in an authorized keys file can not contain whitespace or start with #"));
+    }
+    if std::path::Path::new(path).exists() && read_authorized_keys(path)?.iter().any(|(listed, _)| listed == name) {
+        return Err(Error::new(ErrorKind::AlreadyExists, format!("{} is already listed in {}", name, path)));
+    }
+    let mut file = std::fs::OpenOptions::new().create(true).append(true).open(path)?;
+    writeln!(file, "{} {}", name, hex::to_hex(public_key))
+}
@@ -0,0 +1,81 @@
+// HKDF (RFC 5869, the extract-then-expand KDF of SP 800-56C) built on HMAC-SHA256 from
+// bernie_hmac. The record layer uses it as its key schedule to turn the handshake's
+// final key into separate keys and IVs for each direction of the connection. PBKDF2 at
+// the end derives the keys that keystore files are sealed under.
+
+use bernie_hmac;
+
+pub const HASH_SIZE: usize = 32;
+
+// PRK = HMAC(salt, IKM). An empty salt is replaced by a block of zeros as the RFC requires.
+pub fn extract(salt: &[u8], ikm: &[u8]) -> Vec<u8> {
+    if salt.is_empty() {
+        bernie_hmac::hmac(ikm, &[0_u8; HASH_SIZE])
+    } else {
+        bernie_hmac::hmac(ikm, salt)
+    }
+}
+
+// T(i) = HMAC(PRK, T(i - 1) | info | i), concatenated until length bytes are available
+pub fn expand(prk: &[u8], info: &[u8], length: usize) -> Vec<u8> {
+    assert!(length <= 255 * HASH_SIZE, "HKDF can not expand to more than 255 blocks");
+
+    let mut okm = Vec::with_capacity(length);
+    let mut previous: Vec<u8> = Vec::new();
+    let mut counter = 1_u8;
+    while okm.len() < length {
+        let mut input = previous;
+        input.extend_from_slice(info);
+        input.push(counter);
+        previous = bernie_hmac::hmac(&input, prk);
+        okm.extend_from_slice(&previous);
+        counter += 1;
+    }
+    okm.truncate(length);
+    okm
+}
+
+// Expands a secret for one purpose. The label and context are length prefixed in the same
+// way as the HkdfLabel structure of TLS 1.3 so that no two purposes share an info string.
+pub fn expand_label(secret: &[u8], label: &str, context: &[u8], length: usize) -> Vec<u8> {
+    let full_label = format!("seccom {}", label);
+
+    let mut info = (length as u16).to_be_bytes().to_vec();
+    info.push(full_label.len() as u8);
+    info.extend_from_slice(full_label.as_bytes());
+    info.push(context.len() as u8);
+    info.extend_from_slice(context);
+
+    expand(secret, &info, length)
+}
+
+// Keying material for the application, as the exporters of RFC 5705 and RFC 8446 give.
+// Each label gets a secret of its own and the context is hashed into the last expansion,
+// so different labels or contexts give unrelated outputs.
+pub fn export(exporter_secret: &[u8], label: &str, context: &[u8], length: usize) -> Vec<u8> {
+    let label_secret = expand_label(exporter_secret, label, &bernie_hmac::hash(&[]), HASH_SIZE);
+    expand_label(&label_secret, "exporter", &bernie_hmac::hash(context), length)
+}
+
+// PBKDF2 (RFC 8018 section 5.2, SP 800-132) with HMAC-SHA256 as the PRF. Turns a
+// passphrase into a key, and the iteration count sets how much work every guess at the
+// passphrase costs.
+pub fn pbkdf2(passphrase: &[u8], salt: &[u8], iterations: u32, length: usize) -> Vec<u8> {
+    let mut output = Vec::with_capacity(length.div_ceil(HASH_SIZE) * HASH_SIZE);
+    let mut block_index = 1_u32;
+    while output.len() < length {
+        // T(i) = U(1) ^ U(2) ^ ... ^ U(c), where U(1) = HMAC(P, S | i) and U(j) = HMAC(P, U(j - 1))
+        let mut input = salt.to_vec();
+        input.extend_from_slice(&block_index.to_be_bytes());
+        let mut u = bernie_hmac::hmac(&input, passphrase);
+        let mut block = u.clone();
+        for _ in 1..iterations {
+            u = bernie_hmac::hmac(&u, passphrase);
+            block.iter_mut().zip(u.iter()).for_each(|(byte, u_byte)| *byte ^= u_byte);
+        }
+        output.append(&mut block);
+        block_index += 1;
+    }
+    output.truncate(length);
+    output
+}
@@ -0,0 +1,394 @@
+// Encrypted files for long-term identity keys, such as the signing keys of proof.rs, the
+// certificate keys of stage 6 and Noise static keys.
+//
+// A keystore holds any number of named DH key pairs. The public keys are stored in the
+// clear, so a keystore can be listed and its public keys exported without the
+// passphrase. Each private key is sealed with AES-256-GCM under a key derived from the
+// passphrase with PBKDF2-HMAC-SHA256, using a random salt and an iteration count stored
+// in the file.
+//
+// File layout:
+//
+//     magic "SECCOMKS" (8 bytes) | version (1 byte) | iterations (4 bytes) | salt (16 bytes)
+//     | check IV (12 bytes) | check tag (16 bytes) | entry count (2 bytes) | entries
+//
+// and each entry is
+//
+//     name length (1 byte) | name | public key length (2 bytes) | public key | IV (12 bytes)
+//     | sealed private key length (2 bytes) | sealed private key and tag
+//
+// The check tag is a GCM tag over the header with nothing encrypted. It lets a wrong
+// passphrase be told apart from a damaged file even when the keystore is empty, and it
+// covers the salt and iteration count. The tag of each entry covers its name and public
+// key as well as the private key, so a private key can not be swapped onto another name.
+
+use std::io::{BufRead, Error, ErrorKind, Write};
+
+use byteorder::{ByteOrder, BigEndian};
+
+use aes_crypt;
+
+use crate::identity;
+use crate::kdf;
+use crate::proof;
+use crate::random::Random;
+use crate::record;
+use crate::secret::SecretBytes;
+
+const MAGIC: &[u8] = b"SECCOMKS";
+const VERSION: u8 = 1;
+const SALT_SIZE: usize = 16;
+const IV_SIZE: usize = 12;
+const KEY_SIZE: usize = 32;
+// Size of everything before the entries
+const HEADER_SIZE: usize = 8 + 1 + 4 + SALT_SIZE + IV_SIZE + record::GCM_TAG_SIZE + 2;
+
+// Iterations for new keystores and new passphrases. OWASP's current guidance for
+// PBKDF2-HMAC-SHA256 is 600,000, far above the 1,000 minimum of SP 800-132.
+pub const DEFAULT_ITERATIONS: u32 = 600_000;
+
+// The fields in front of the entries
+struct Header {
+    iterations: u32,
+    salt: Vec<u8>,
+    check_iv: Vec<u8>,
+    check_tag: Vec<u8>,
+}
+
+// A named key pair as it sits in the file, with the private key still sealed
+struct Entry {
+    name: String,
+    public_key: Vec<u8>,
+    iv: Vec<u8>,
+    sealed_private_key: Vec<u8>,
+}
+
+impl Entry {
+    fn associated_data(name: &str, public_key: &[u8]) -> Vec<u8> {
+        let mut aad = vec![name.len() as u8];
+        aad.extend_from_slice(name.as_bytes());
+        aad.extend_from_slice(&(public_key.len() as u16).to_be_bytes());
+        aad.extend_from_slice(public_key);
+        aad
+    }
+}
+
+// An unlocked keystore. The key derived from the passphrase is held for as long as the
+// keystore is, so keys can be added and opened without asking again.
+pub struct Keystore {
+    iterations: u32,
+    salt: Vec<u8>,
+    key: SecretBytes,
+    entries: Vec<Entry>,
+    random: Random,
+}
+
+impl Keystore {
+    // An empty keystore protected by the passphrase
+    pub fn create(passphrase: &SecretBytes, random: Random) -> Self {
+        let salt = random.bytes(SALT_SIZE);
+        let key = derive_key(passphrase, &salt, DEFAULT_ITERATIONS);
+        Self { iterations: DEFAULT_ITERATIONS, salt, key, entries: Vec::new(), random }
+    }
+
+    // Reads and unlocks a keystore. A wrong passphrase is reported as such, any other
+    // problem with the file as a damaged keystore.
+    pub fn open(path: &str, passphrase: &SecretBytes, random: Random) -> Result<Self, Error> {
+        let bytes = std::fs::read(path)?;
+        let (header, entries) = parse(&bytes)
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, "Damaged keystore"))?;
+
+        // The check tag covers everything up to and including its IV
+        let key = derive_key(passphrase, &header.salt, header.iterations);
+        let checked = &bytes[..HEADER_SIZE - record::GCM_TAG_SIZE - 2];
+        if record::open_gcm(&[], &header.check_iv, checked, &header.check_tag, key.expose()).is_none() {
+            return Err(Error::new(ErrorKind::PermissionDenied, "Wrong passphrase"));
+        }
+        Ok(Self { iterations: header.iterations, salt: header.salt, key, entries, random })
+    }
+
+    pub fn names(&self) -> Vec<&str> {
+        self.entries.iter().map(|entry| entry.name.as_str()).collect()
+    }
+
+    // Seals the key pair into the keystore under a name not already in use
+    pub fn add(&mut self, name: &str, key_pair: &(SecretBytes, Vec<u8>)) -> Result<(), Error> {
+        if name.is_empty() || name.len() > u8::MAX as usize {
+            return Err(Error::new(ErrorKind::InvalidInput, "Key names are 1 to 255 bytes long"));
+        }
+        if self.entries.iter().any(|entry| entry.name == name) {
+            return Err(Error::new(ErrorKind::AlreadyExists, format!("The keystore already has a key named {}", name)));
+        }
+
+        let iv = self.random.bytes(IV_SIZE);
+        let aad = Entry::associated_data(name, &key_pair.1);
+        let (mut sealed_private_key, mut tag) = aes_crypt::encrypt_gcm(key_pair.0.expose(), &iv, &aad, self.key.expose(), record::GCM_TAG_SIZE * 8);
+        sealed_private_key.append(&mut tag);
+        self.entries.push(Entry { name: name.to_string(), public_key: key_pair.1.clone(), iv, sealed_private_key });
+        Ok(())
+    }
+
+    // The key pair stored under the name, None if there is none or it fails to verify
+    pub fn key_pair(&self, name: &str) -> Option<(SecretBytes, Vec<u8>)> {
+        let entry = self.entries.iter().find(|entry| entry.name == name)?;
+        if entry.sealed_private_key.len() < record::GCM_TAG_SIZE {
+            return None;
+        }
+        let (ciphertext, tag) = entry.sealed_private_key.split_at(entry.sealed_private_key.len() - record::GCM_TAG_SIZE);
+        let aad = Entry::associated_data(&entry.name, &entry.public_key);
+        let private_key = record::open_gcm(ciphertext, &entry.iv, &aad, tag, self.key.expose())?;
+        Some((SecretBytes::new(private_key), entry.public_key.clone()))
+    }
+
+    // Reseals every private key under a key derived from the new passphrase with a fresh
+    // salt. Nothing changes if any entry fails to open under the old passphrase.
+    pub fn change_passphrase(&mut self, passphrase: &SecretBytes) -> Result<(), Error> {
+        let mut key_pairs = Vec::with_capacity(self.entries.len());
+        for entry in &self.entries {
+            let key_pair = self.key_pair(&entry.name)
+                .ok_or_else(|| Error::new(ErrorKind::InvalidData, format!("Key {} is damaged", entry.name)))?;
+            key_pairs.push((entry.name.clone(), key_pair));
+        }
+
+        self.salt = self.random.bytes(SALT_SIZE);
+        self.iterations = DEFAULT_ITERATIONS;
+        self.key = derive_key(passphrase, &self.salt, self.iterations);
+        self.entries.clear();
+        for (name, key_pair) in &key_pairs {
+            self.add(name, key_pair)?;
+        }
+        Ok(())
+    }
+
+    // Writes the keystore to a temporary file next to the target and then renames it
+    // over the target, so an interrupted save never leaves half a keystore behind
+    pub fn save(&self, path: &str) -> Result<(), Error> {
+        let mut bytes = MAGIC.to_vec();
+        bytes.push(VERSION);
+        bytes.extend_from_slice(&self.iterations.to_be_bytes());
+        bytes.extend_from_slice(&self.salt);
+        bytes.extend_from_slice(&self.random.bytes(IV_SIZE));
+        let (_, check_tag) = aes_crypt::encrypt_gcm(&[], &bytes[bytes.len() - IV_SIZE..], &bytes, self.key.expose(), record::GCM_TAG_SIZE * 8);
+        bytes.extend_from_slice(&check_tag);
+        bytes.extend_from_slice(&(self.entries.len() as u16).to_be_bytes());
+
+        for entry in &self.entries {
+            bytes.extend_from_slice(&Entry::associated_data(&entry.name, &entry.public_key));
+            bytes.extend_from_slice(&entry.iv);
+            bytes.extend_from_slice(&(entry.sealed_private_key.len() as u16).to_be_bytes());
+            bytes.extend_from_slice(&entry.sealed_private_key);
+        }
+
+        let temporary_path = format!("{}.tmp", path);
+        std::fs::write(&temporary_path, &bytes)?;
+        std::fs::rename(&temporary_path, path)
+    }
+}
+
+// The names and public keys in a keystore, which are readable without the passphrase
+pub fn public_keys(path: &str) -> Result<Vec<(String, Vec<u8>)>, Error> {
+    let bytes = std::fs::read(path)?;
+    let (_, entries) = parse(&bytes)
+        .ok_or_else(|| Error::new(ErrorKind::InvalidData, "Damaged keystore"))?;
+    Ok(entries.into_iter().map(|entry| (entry.name, entry.public_key)).collect())
+}
+
+// Asks for the keystore's passphrase and opens one key pair from it, for a stage that
+// needs its identity key at start-up
+pub fn unlock(path: &str, name: &str, random: &Random) -> Result<(SecretBytes, Vec<u8>), Error> {
+    let passphrase = read_passphrase(&format!("Passphrase for {}", path))?;
+    let keystore = Keystore::open(path, &passphrase, random.clone())?;
+    keystore.key_pair(name)
+        .ok_or_else(|| Error::new(ErrorKind::NotFound, format!("No key named {} in {}", name, path)))
+}
+
+// The keygen subcommand:
+//
+//     keygen create <keystore> <name>           generate a key pair and add it, creating the keystore if needed
+//     keygen list <keystore>                    show the name and fingerprint of every key
+//     keygen export <keystore> <name> <file>    write the public key to a file
+//     keygen passwd <keystore>                  change the passphrase
+//     keygen authorize <file> <name> <key>      add a public key written by export to an
+//                                               authorized keys file for stage 6
+//
+// Returns false if the command failed.
+pub fn keygen(args: &[String]) -> bool {
+    let arg = |index: usize| args.get(index).map(String::as_str);
+    let result = match (arg(0), arg(1), arg(2), arg(3)) {
+        (Some("create"), Some(path), Some(name), None) => create_key(path, name),
+        (Some("list"), Some(path), None, None) => list_keys(path),
+        (Some("export"), Some(path), Some(name), Some(file)) => export_key(path, name, file),
+        (Some("passwd"), Some(path), None, None) => change_passphrase(path),
+        (Some("authorize"), Some(file), Some(name), Some(key)) => authorize_key(file, name, key),
+        _ => Err(Error::new(ErrorKind::InvalidInput, "Usage: keygen create <keystore> <name> | list <keystore> | export <keystore> <name> <file> | passwd <keystore> | authorize <file> <name> <key>")),
+    };
+    match result {
+        Ok(()) => true,
+        Err(e) => {
+            println!("[!] {}", e);
+            false
+        }
+    }
+}
+
+fn create_key(path: &str, name: &str) -> Result<(), Error> {
+    // Identity keys always come from the operating system, never from a --seed
+    let random = Random::new();
+    let mut keystore = if std::path::Path::new(path).exists() {
+        Keystore::open(path, &read_passphrase(&format!("Passphrase for {}", path))?, random.clone())?
+    } else {
+        println!("[*] Creating keystore {}", path);
+        Keystore::create(&read_new_passphrase()?, random.clone())
+    };
+
+    println!("[+] Generating key pair {} ...", name);
+    keystore.add(name, &random.dh_key_pair())?;
+    keystore.save(path)?;
+    let (_, public_key) = keystore.key_pair(name).unwrap();
+    println!("[*] Added {} with fingerprint {} to {}", name, proof::fingerprint(&public_key), path);
+    Ok(())
+}
+
+fn list_keys(path: &str) -> Result<(), Error> {
+    let entries = public_keys(path)?;
+    println!("[*] {} holds {} key(s)", path, entries.len());
+    for (name, public_key) in entries {
+        println!("    {}  {}", proof::fingerprint(&public_key), name);
+    }
+    Ok(())
+}
+
+fn export_key(path: &str, name: &str, file: &str) -> Result<(), Error> {
+    let public_key = public_keys(path)?
+        .into_iter()
+        .find(|(entry_name, _)| entry_name == name)
+        .map(|(_, public_key)| public_key)
+        .ok_or_else(|| Error::new(ErrorKind::NotFound, format!("No key named {} in {}", name, path)))?;
+    std::fs::write(file, &public_key)?;
+    println!("[*] Public key of {} ({}) written to {}", name, proof::fingerprint(&public_key), file);
+    Ok(())
+}
+
+fn authorize_key(file: &str, name: &str, key: &str) -> Result<(), Error> {
+    let public_key = std::fs::read(key)?;
+    identity::authorize(file, name, &public_key)?;
+    println!("[*] Authorized {} ({}) in {}", name, proof::fingerprint(&public_key), file);
+    Ok(())
+}
+
+fn change_passphrase(path: &str) -> Result<(), Error> {
+    let mut keystore = Keystore::open(path, &read_passphrase("Current passphrase")?, Random::new())?;
+    keystore.change_passphrase(&read_new_passphrase()?)?;
+    keystore.save(path)?;
+    println!("[*] Passphrase changed, {} key(s) resealed", keystore.names().len());
+    Ok(())
+}
+
+// Reads a line from stdin as a passphrase. It is echoed, since the standard library can
+// not turn the terminal's echo off.
+fn read_passphrase(prompt: &str) -> Result<SecretBytes, Error> {
+    print!("{}: ", prompt);
+    std::io::stdout().flush()?;
+    let mut line = String::new();
+    std::io::stdin().lock().read_line(&mut line)?;
+    let passphrase = SecretBytes::new(line.trim_end_matches(['\r', '\n']).as_bytes().to_vec());
+    line.clear();
+    Ok(passphrase)
+}
+
+// Asks for a new passphrase twice
+fn read_new_passphrase() -> Result<SecretBytes, Error> {
+    let passphrase = read_passphrase("New passphrase")?;
+    if passphrase.is_empty() {
+        return Err(Error::new(ErrorKind::InvalidInput, "The passphrase can not be empty"));
+    }
+    if read_passphrase("Repeat the new passphrase")?.expose() != passphrase.expose() {
+        return Err(Error::new(ErrorKind::InvalidInput, "The passphrases do not match"));
+    }
+    Ok(passphrase)
+}
+
+fn derive_key(passphrase: &SecretBytes, salt: &[u8], iterations: u32) -> SecretBytes {
+    SecretBytes::new(kdf::pbkdf2(passphrase.expose(), salt, iterations, KEY_SIZE))
+}
+
+// Splits a keystore file into its header and entries
+fn parse(bytes: &[u8]) -> Option<(Header, Vec<Entry>)> {
+    if bytes.len() < HEADER_SIZE || &bytes[..MAGIC.len()] != MAGIC || bytes[MAGIC.len()] != VERSION {
+        return None;
+    }
+    let mut rest = &bytes[MAGIC.len() + 1..];
+    let iterations = BigEndian::read_u32(take(&mut rest, 4)?);
+    let salt = take(&mut rest, SALT_SIZE)?.to_vec();
+    let check_iv = take(&mut rest, IV_SIZE)?.to_vec();
+    let check_tag = take(&mut rest, record::GCM_TAG_SIZE)?.to_vec();
+    let count = BigEndian::read_u16(take(&mut rest, 2)?) as usize;
+    if iterations == 0 {
+        return None;
+    }
+
+    let mut entries = Vec::with_capacity(count);
+    for _ in 0..count {
+        let name_length = *take(&mut rest, 1)?.first()? as usize;
+        let name = String::from_utf8(take(&mut rest, name_length)?.to_vec()).ok()?;
+        let key_length = BigEndian::read_u16(take(&mut rest, 2)?) as usize;
+        let public_key = take(&mut rest, key_length)?.to_vec();
+        let iv = take(&mut rest, IV_SIZE)?.to_vec();
+        let sealed_length = BigEndian::read_u16(take(&mut rest, 2)?) as usize;
+        let sealed_private_key = take(&mut rest, sealed_length)?.to_vec();
+        entries.push(Entry { name, public_key, iv, sealed_private_key });
+    }
+    if !rest.is_empty() {
+        return None;
+    }
+    Some((Header { iterations, salt, check_iv, check_tag }, entries))
+}
+
+fn take<'a>(bytes: &mut &'a [u8], length: usize) -> Option<&'a [u8]> {
+    if bytes.len() < length {
+        return None;
+    }
+    let (field, rest) = bytes.split_at(length);
+    *bytes = rest;
+    Some(field)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The SP 800-132 minimum, so the tests do not spend a minute in PBKDF2
+    const TEST_ITERATIONS: u32 = 1_000;
+
+    fn saved_keystore(path: &str, passphrase: &SecretBytes, key_pair: &(SecretBytes, Vec<u8>), random: &Random) {
+        let salt = random.bytes(SALT_SIZE);
+        let key = derive_key(passphrase, &salt, TEST_ITERATIONS);
+        let mut keystore = Keystore { iterations: TEST_ITERATIONS, salt, key, entries: Vec::new(), random: random.clone() };
+        keystore.add("alice", key_pair).unwrap();
+        keystore.save(path).unwrap();
+    }
+
+    #[test]
+    fn wrong_passphrase_is_rejected() {
+        let random = Random::new();
+        let path = std::env::temp_dir().join(format!("keystore-test-{}", std::process::id()));
+        let path = path.to_str().unwrap();
+        let passphrase = SecretBytes::new(b"correct horse battery staple".to_vec());
+        let key_pair = random.dh_key_pair();
+        saved_keystore(path, &passphrase, &key_pair, &random);
+
+        let wrong = SecretBytes::new(b"incorrect horse battery staple".to_vec());
+        assert_eq!(Keystore::open(path, &wrong, random.clone()).err().map(|e| e.kind()), Some(ErrorKind::PermissionDenied));
+        assert_eq!(Keystore::open(path, &SecretBytes::new(Vec::new()), random.clone()).err().map(|e| e.kind()), Some(ErrorKind::PermissionDenied));
+
+        let keystore = Keystore::open(path, &passphrase, random.clone()).unwrap();
+        assert_eq!(keystore.key_pair("alice").unwrap().0.expose(), key_pair.0.expose());
+
+        // A changed salt is a wrong key rather than a damaged file, since the check tag covers it
+        let mut bytes = std::fs::read(path).unwrap();
+        bytes[MAGIC.len() + 1 + 4] ^= 1;
+        std::fs::write(path, &bytes).unwrap();
+        assert_eq!(Keystore::open(path, &passphrase, random).err().map(|e| e.kind()), Some(ErrorKind::PermissionDenied));
+        std::fs::remove_file(path).unwrap();
+    }
+}
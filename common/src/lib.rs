@@ -0,0 +1,27 @@
+// Everything the clients and servers have in common: the handshakes, the record layer and
+// the primitives behind them, randomness, and the keys and certificates of both sides.
+// The stages themselves live in the two binaries.
+
+pub mod certificate;
+pub mod config;
+pub mod drbg;
+pub mod handshake;
+pub mod hex;
+pub mod identity;
+pub mod kdf;
+pub mod keystore;
+pub mod mlkem;
+pub mod modes;
+pub mod noise;
+pub mod proof;
+pub mod random;
+pub mod ratchet;
+pub mod record;
+pub mod sas;
+pub mod secret;
+pub mod sequence;
+pub mod srp;
+pub mod suites;
+pub mod ticket;
+pub mod tls;
+pub mod verify;
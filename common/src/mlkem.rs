@@ -0,0 +1,616 @@
+// ML-KEM-768 from NIST FIPS 203, the module-lattice key encapsulation mechanism
+// standardised from Kyber. Security category 3, roughly AES-192.
+//
+// One side generates a key pair and sends the encapsulation key. The other side uses it
+// to encapsulate a fresh 32-byte shared secret and sends back the ciphertext, which only
+// the holder of the decapsulation key can turn back into the same secret. Unlike DH the
+// hard problem (Module-LWE) is not known to be solvable with a quantum computer.
+//
+// Decapsulation re-encrypts the recovered message and compares the result with the
+// ciphertext it was given. A mismatch yields a pseudorandom secret derived from z rather
+// than an error (implicit rejection), so a tampered ciphertext just leads to a key the
+// peer does not share.
+//
+// The known-answer self test runs once, before the first key pair or encapsulation.
+
+use std::fmt;
+use std::sync::OnceLock;
+
+use sha3::{Digest, Sha3_256, Sha3_512, Shake128, Shake256};
+use sha3::digest::{ExtendableOutput, Update, XofReader};
+use zeroize::Zeroizing;
+
+use crate::hex::to_hex;
+use crate::random::Random;
+use crate::secret::SecretBytes;
+use crate::verify;
+
+// Parameter set ML-KEM-768 (FIPS 203, table 2)
+const N: usize = 256;
+const Q: u32 = 3329;
+const K: usize = 3;
+const ETA1: usize = 2;
+const ETA2: usize = 2;
+const DU: usize = 10;
+const DV: usize = 4;
+
+// 128^-1 mod q, the scaling left over by the inverse NTT
+const N_INVERSE: u32 = 3303;
+
+pub const ENCAPSULATION_KEY_SIZE: usize = 384 * K + 32;
+pub const DECAPSULATION_KEY_SIZE: usize = 768 * K + 96;
+pub const CIPHERTEXT_SIZE: usize = 32 * (DU * K + DV);
+pub const SHARED_SECRET_SIZE: usize = 32;
+
+// Seeds d and z of the key generation, and the message m of an encapsulation
+const SEED_SIZE: usize = 32;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum MlKemError {
+    // Wrong length, or coefficients that are not reduced mod q (FIPS 203, section 7.2)
+    InvalidEncapsulationKey,
+    // Wrong length, or the embedded hash does not match the embedded encapsulation key
+    InvalidDecapsulationKey,
+    InvalidCiphertext,
+    SelfTest,
+}
+
+impl fmt::Display for MlKemError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MlKemError::InvalidEncapsulationKey => write!(f, "invalid ML-KEM encapsulation key"),
+            MlKemError::InvalidDecapsulationKey => write!(f, "invalid ML-KEM decapsulation key"),
+            MlKemError::InvalidCiphertext => write!(f, "invalid ML-KEM ciphertext"),
+            MlKemError::SelfTest => write!(f, "ML-KEM-768 failed its self test"),
+        }
+    }
+}
+
+// Coefficients in [0, q)
+type Poly = [u32; N];
+type PolyVec = [Poly; K];
+
+// ML-KEM.KeyGen (algorithm 19). Returns the decapsulation key, which stays here, and the
+// encapsulation key, which is sent to the peer.
+pub fn key_pair(random: &Random) -> Result<(SecretBytes, Vec<u8>), MlKemError> {
+    checked_self_test()?;
+    let d = random.secret(SEED_SIZE);
+    let z = random.secret(SEED_SIZE);
+    Ok(key_pair_from_seeds(d.expose(), z.expose()))
+}
+
+// ML-KEM.Encaps (algorithm 20). Returns the shared secret and the ciphertext for the peer.
+pub fn encapsulate(encapsulation_key: &[u8], random: &Random) -> Result<(SecretBytes, Vec<u8>), MlKemError> {
+    checked_self_test()?;
+    check_encapsulation_key(encapsulation_key)?;
+    let m = random.secret(SEED_SIZE);
+    Ok(encapsulate_with(encapsulation_key, m.expose()))
+}
+
+// ML-KEM.Decaps (algorithm 21)
+pub fn decapsulate(decapsulation_key: &SecretBytes, ciphertext: &[u8]) -> Result<SecretBytes, MlKemError> {
+    let dk = decapsulation_key.expose();
+    if dk.len() != DECAPSULATION_KEY_SIZE || H(&dk[384 * K..768 * K + 32]) != dk[768 * K + 32..768 * K + 64] {
+        return Err(MlKemError::InvalidDecapsulationKey);
+    }
+    if ciphertext.len() != CIPHERTEXT_SIZE {
+        return Err(MlKemError::InvalidCiphertext);
+    }
+    Ok(decapsulate_unchecked(dk, ciphertext))
+}
+
+// ML-KEM.KeyGen_internal (algorithm 16): dk = dk_pke | ek | H(ek) | z
+fn key_pair_from_seeds(d: &[u8], z: &[u8]) -> (SecretBytes, Vec<u8>) {
+    let (encapsulation_key, pke_key) = pke_key_gen(d);
+    let mut decapsulation_key = Vec::with_capacity(DECAPSULATION_KEY_SIZE);
+    decapsulation_key.extend_from_slice(pke_key.expose());
+    decapsulation_key.extend_from_slice(&encapsulation_key);
+    decapsulation_key.extend_from_slice(&H(&encapsulation_key));
+    decapsulation_key.extend_from_slice(z);
+    (SecretBytes::new(decapsulation_key), encapsulation_key)
+}
+
+// ML-KEM.Encaps_internal (algorithm 17): (K, r) = G(m | H(ek))
+fn encapsulate_with(encapsulation_key: &[u8], m: &[u8]) -> (SecretBytes, Vec<u8>) {
+    let (shared_secret, r) = G(&[m, &H(encapsulation_key)[..]]);
+    let ciphertext = pke_encrypt(encapsulation_key, m, r.expose());
+    (shared_secret, ciphertext)
+}
+
+// ML-KEM.Decaps_internal (algorithm 18)
+fn decapsulate_unchecked(dk: &[u8], ciphertext: &[u8]) -> SecretBytes {
+    let pke_key = &dk[..384 * K];
+    let encapsulation_key = &dk[384 * K..768 * K + 32];
+    let h = &dk[768 * K + 32..768 * K + 64];
+    let z = &dk[768 * K + 64..];
+
+    let m = pke_decrypt(pke_key, ciphertext);
+    let (shared_secret, r) = G(&[m.expose(), h]);
+    let rejection_secret = J(&[z, ciphertext]);
+
+    // The ciphertext is only accepted if encrypting the recovered message reproduces it.
+    // The choice between the two secrets is made without branching on the comparison.
+    let reencrypted = pke_encrypt(encapsulation_key, m.expose(), r.expose());
+    let mask = 0_u8.wrapping_sub(verify::constant_time_eq(&reencrypted, ciphertext) as u8);
+    let selected = shared_secret.expose()
+        .iter()
+        .zip(rejection_secret.expose())
+        .map(|(accept, reject)| (accept & mask) | (reject & !mask))
+        .collect();
+    SecretBytes::new(selected)
+}
+
+// Modulus check of FIPS 203 section 7.2: every 12-bit coefficient of ek must be below q,
+// which is the same as it surviving a decode and re-encode unchanged
+fn check_encapsulation_key(encapsulation_key: &[u8]) -> Result<(), MlKemError> {
+    if encapsulation_key.len() != ENCAPSULATION_KEY_SIZE {
+        return Err(MlKemError::InvalidEncapsulationKey);
+    }
+    let t_hat = &encapsulation_key[..384 * K];
+    for chunk in t_hat.chunks(384) {
+        if byte_encode(&byte_decode(chunk, 12), 12) != chunk {
+            return Err(MlKemError::InvalidEncapsulationKey);
+        }
+    }
+    Ok(())
+}
+
+// K-PKE.KeyGen (algorithm 13). Returns ek = ByteEncode12(t_hat) | rho and the secret
+// dk_pke = ByteEncode12(s_hat).
+fn pke_key_gen(d: &[u8]) -> (Vec<u8>, SecretBytes) {
+    let (rho, sigma) = G(&[d, &[K as u8][..]]);
+    let a_hat = sample_matrix(rho.expose(), false);
+
+    let mut counter = 0;
+    let mut s_hat = Zeroizing::new([[0_u32; N]; K]);
+    let mut e_hat = Zeroizing::new([[0_u32; N]; K]);
+    for s in s_hat.iter_mut() {
+        *s = sample_poly_cbd(&prf(sigma.expose(), counter, ETA1), ETA1);
+        ntt(s);
+        counter += 1;
+    }
+    for e in e_hat.iter_mut() {
+        *e = sample_poly_cbd(&prf(sigma.expose(), counter, ETA1), ETA1);
+        ntt(e);
+        counter += 1;
+    }
+
+    // t_hat = A_hat * s_hat + e_hat
+    let mut encapsulation_key = Vec::with_capacity(ENCAPSULATION_KEY_SIZE);
+    for i in 0..K {
+        let t = add(&inner_product(&a_hat[i], &s_hat), &e_hat[i]);
+        encapsulation_key.extend_from_slice(&byte_encode(&t, 12));
+    }
+    encapsulation_key.extend_from_slice(rho.expose());
+
+    let mut pke_key = Vec::with_capacity(384 * K);
+    for s in s_hat.iter() {
+        pke_key.extend_from_slice(&byte_encode(s, 12));
+    }
+    (encapsulation_key, SecretBytes::new(pke_key))
+}
+
+// K-PKE.Encrypt (algorithm 14)
+fn pke_encrypt(encapsulation_key: &[u8], m: &[u8], r: &[u8]) -> Vec<u8> {
+    let mut t_hat = [[0_u32; N]; K];
+    for (t, chunk) in t_hat.iter_mut().zip(encapsulation_key[..384 * K].chunks(384)) {
+        *t = byte_decode(chunk, 12);
+    }
+    let a_hat_transposed = sample_matrix(&encapsulation_key[384 * K..], true);
+
+    let mut counter = 0;
+    let mut y_hat = Zeroizing::new([[0_u32; N]; K]);
+    let mut e1 = Zeroizing::new([[0_u32; N]; K]);
+    for y in y_hat.iter_mut() {
+        *y = sample_poly_cbd(&prf(r, counter, ETA1), ETA1);
+        ntt(y);
+        counter += 1;
+    }
+    for e in e1.iter_mut() {
+        *e = sample_poly_cbd(&prf(r, counter, ETA2), ETA2);
+        counter += 1;
+    }
+    let e2 = Zeroizing::new(sample_poly_cbd(&prf(r, counter, ETA2), ETA2));
+
+    // u = NTT^-1(A_hat^T * y_hat) + e1
+    let mut ciphertext = Vec::with_capacity(CIPHERTEXT_SIZE);
+    for i in 0..K {
+        let mut u = inner_product(&a_hat_transposed[i], &y_hat);
+        inverse_ntt(&mut u);
+        ciphertext.extend_from_slice(&byte_encode(&compress(&add(&u, &e1[i]), DU), DU));
+    }
+
+    // v = NTT^-1(t_hat^T * y_hat) + e2 + Decompress1(m)
+    let mut v = inner_product(&t_hat, &y_hat);
+    inverse_ntt(&mut v);
+    let mu = decompress(&byte_decode(m, 1), 1);
+    ciphertext.extend_from_slice(&byte_encode(&compress(&add(&add(&v, &e2), &mu), DV), DV));
+    ciphertext
+}
+
+// K-PKE.Decrypt (algorithm 15)
+fn pke_decrypt(pke_key: &[u8], ciphertext: &[u8]) -> SecretBytes {
+    let (c1, c2) = ciphertext.split_at(32 * DU * K);
+
+    let mut u_hat = [[0_u32; N]; K];
+    for (u, chunk) in u_hat.iter_mut().zip(c1.chunks(32 * DU)) {
+        *u = decompress(&byte_decode(chunk, DU), DU);
+        ntt(u);
+    }
+    let v = decompress(&byte_decode(c2, DV), DV);
+
+    let mut s_hat = Zeroizing::new([[0_u32; N]; K]);
+    for (s, chunk) in s_hat.iter_mut().zip(pke_key.chunks(384)) {
+        *s = byte_decode(chunk, 12);
+    }
+
+    // w = v - NTT^-1(s_hat^T * u_hat)
+    let mut product = Zeroizing::new(inner_product(&s_hat, &u_hat));
+    inverse_ntt(&mut product);
+    let w = Zeroizing::new(subtract(&v, &product));
+    SecretBytes::new(byte_encode(&compress(&w, 1), 1))
+}
+
+// Every entry of A_hat is sampled from SHAKE128(rho | j | i). Encryption needs A_hat^T,
+// which is the same matrix sampled with the indices swapped.
+fn sample_matrix(rho: &[u8], transposed: bool) -> [PolyVec; K] {
+    let mut a_hat = [[[0_u32; N]; K]; K];
+    for (i, row) in a_hat.iter_mut().enumerate() {
+        for (j, entry) in row.iter_mut().enumerate() {
+            let (first, second) = if transposed { (i, j) } else { (j, i) };
+            *entry = sample_ntt(rho, first as u8, second as u8);
+        }
+    }
+    a_hat
+}
+
+// SampleNTT (algorithm 7): rejection sampling of 12-bit values below q
+fn sample_ntt(rho: &[u8], first: u8, second: u8) -> Poly {
+    let mut xof = Shake128::default();
+    xof.update(rho);
+    xof.update(&[first, second]);
+    let mut reader = xof.finalize_xof();
+
+    let mut a = [0_u32; N];
+    let mut count = 0;
+    let mut c = [0_u8; 3];
+    while count < N {
+        reader.read(&mut c);
+        let d1 = c[0] as u32 + 256 * (c[1] as u32 & 0x0f);
+        let d2 = (c[1] as u32 >> 4) + 16 * c[2] as u32;
+        if d1 < Q {
+            a[count] = d1;
+            count += 1;
+        }
+        if d2 < Q && count < N {
+            a[count] = d2;
+            count += 1;
+        }
+    }
+    a
+}
+
+// SamplePolyCBD (algorithm 8): each coefficient is the difference of two sums of eta bits
+fn sample_poly_cbd(bytes: &[u8], eta: usize) -> Poly {
+    let bit = |index: usize| ((bytes[index / 8] >> (index % 8)) & 1) as u32;
+    let mut f = [0_u32; N];
+    for (i, coefficient) in f.iter_mut().enumerate() {
+        let x: u32 = (0..eta).map(|j| bit(2 * i * eta + j)).sum();
+        let y: u32 = (0..eta).map(|j| bit(2 * i * eta + eta + j)).sum();
+        *coefficient = (x + Q - y) % Q;
+    }
+    f
+}
+
+// Number-theoretic transform (algorithm 9), in place
+fn ntt(f: &mut Poly) {
+    let mut i = 1;
+    let mut len = 128;
+    while len >= 2 {
+        for start in (0..N).step_by(2 * len) {
+            let zeta = ZETAS[i];
+            i += 1;
+            for j in start..start + len {
+                let t = zeta * f[j + len] % Q;
+                f[j + len] = (f[j] + Q - t) % Q;
+                f[j] = (f[j] + t) % Q;
+            }
+        }
+        len /= 2;
+    }
+}
+
+// Inverse NTT (algorithm 10), in place
+fn inverse_ntt(f: &mut Poly) {
+    let mut i = 127;
+    let mut len = 2;
+    while len <= 128 {
+        for start in (0..N).step_by(2 * len) {
+            let zeta = ZETAS[i];
+            i -= 1;
+            for j in start..start + len {
+                let t = f[j];
+                f[j] = (t + f[j + len]) % Q;
+                f[j + len] = zeta * ((f[j + len] + Q - t) % Q) % Q;
+            }
+        }
+        len *= 2;
+    }
+    for coefficient in f.iter_mut() {
+        *coefficient = *coefficient * N_INVERSE % Q;
+    }
+}
+
+// MultiplyNTTs (algorithm 11): 128 products of degree-one polynomials mod X^2 - gamma
+fn multiply_ntts(f: &Poly, g: &Poly) -> Poly {
+    let mut h = [0_u32; N];
+    for i in 0..N / 2 {
+        let (a0, a1, b0, b1) = (f[2 * i], f[2 * i + 1], g[2 * i], g[2 * i + 1]);
+        h[2 * i] = (a0 * b0 % Q + a1 * b1 % Q * GAMMAS[i]) % Q;
+        h[2 * i + 1] = (a0 * b1 + a1 * b0) % Q;
+    }
+    h
+}
+
+// Sum over the K products of matching entries, all in the NTT domain
+fn inner_product(a: &PolyVec, b: &PolyVec) -> Poly {
+    a.iter()
+        .zip(b.iter())
+        .fold([0_u32; N], |sum, (x, y)| add(&sum, &multiply_ntts(x, y)))
+}
+
+fn add(f: &Poly, g: &Poly) -> Poly {
+    let mut h = [0_u32; N];
+    for i in 0..N {
+        h[i] = (f[i] + g[i]) % Q;
+    }
+    h
+}
+
+fn subtract(f: &Poly, g: &Poly) -> Poly {
+    let mut h = [0_u32; N];
+    for i in 0..N {
+        h[i] = (f[i] + Q - g[i]) % Q;
+    }
+    h
+}
+
+// Compress_d: round(2^d / q * x) mod 2^d
+fn compress(f: &Poly, d: usize) -> Poly {
+    let mut h = [0_u32; N];
+    for i in 0..N {
+        h[i] = (((f[i] << (d + 1)) + Q) / (2 * Q)) & ((1 << d) - 1);
+    }
+    h
+}
+
+// Decompress_d: round(q / 2^d * y)
+fn decompress(f: &Poly, d: usize) -> Poly {
+    let mut h = [0_u32; N];
+    for i in 0..N {
+        h[i] = (f[i] * Q + (1 << (d - 1))) >> d;
+    }
+    h
+}
+
+// ByteEncode_d (algorithm 5): d bits per coefficient, least significant bit first
+fn byte_encode(f: &Poly, d: usize) -> Vec<u8> {
+    let mut bytes = vec![0_u8; 32 * d];
+    for (i, coefficient) in f.iter().enumerate() {
+        for j in 0..d {
+            let index = i * d + j;
+            bytes[index / 8] |= (((coefficient >> j) & 1) as u8) << (index % 8);
+        }
+    }
+    bytes
+}
+
+// ByteDecode_d (algorithm 6). For d = 12 the values are reduced mod q.
+fn byte_decode(bytes: &[u8], d: usize) -> Poly {
+    let mut f = [0_u32; N];
+    for (i, coefficient) in f.iter_mut().enumerate() {
+        for j in 0..d {
+            let index = i * d + j;
+            *coefficient |= (((bytes[index / 8] >> (index % 8)) & 1) as u32) << j;
+        }
+        if d == 12 {
+            *coefficient %= Q;
+        }
+    }
+    f
+}
+
+// PRF_eta(s, b) = SHAKE256(s | b), 64 * eta bytes
+fn prf(seed: &[u8], counter: u8, eta: usize) -> Zeroizing<Vec<u8>> {
+    let mut output = Zeroizing::new(vec![0_u8; 64 * eta]);
+    let mut xof = Shake256::default();
+    xof.update(seed);
+    xof.update(&[counter]);
+    xof.finalize_xof().read(&mut output);
+    output
+}
+
+// H = SHA3-256
+#[allow(non_snake_case)]
+fn H(input: &[u8]) -> Vec<u8> {
+    Sha3_256::digest(input).to_vec()
+}
+
+// G = SHA3-512 over the concatenated parts, split into two 32-byte halves
+#[allow(non_snake_case)]
+fn G(parts: &[&[u8]]) -> (SecretBytes, SecretBytes) {
+    let mut hasher = Sha3_512::new();
+    for part in parts {
+        Digest::update(&mut hasher, part);
+    }
+    let mut output = hasher.finalize().to_vec();
+    let second = SecretBytes::new(output.split_off(32));
+    (SecretBytes::new(output), second)
+}
+
+// J = SHAKE256 over the concatenated parts, 32 bytes
+#[allow(non_snake_case)]
+fn J(parts: &[&[u8]]) -> SecretBytes {
+    let mut xof = Shake256::default();
+    for part in parts {
+        xof.update(part);
+    }
+    let mut output = vec![0_u8; SHARED_SECRET_SIZE];
+    xof.finalize_xof().read(&mut output);
+    SecretBytes::new(output)
+}
+
+// zeta^BitRev7(i) and zeta^(2 * BitRev7(i) + 1) for zeta = 17, the 256th root of unity mod q
+const ZETAS: [u32; 128] = powers_of_zeta(false);
+const GAMMAS: [u32; 128] = powers_of_zeta(true);
+
+const fn powers_of_zeta(gamma: bool) -> [u32; 128] {
+    let mut table = [0_u32; 128];
+    let mut i = 0;
+    while i < 128 {
+        let reversed = (i as u8).reverse_bits() >> 1;
+        let exponent = if gamma { 2 * reversed as u32 + 1 } else { reversed as u32 };
+        let mut power = 1;
+        let mut e = 0;
+        while e < exponent {
+            power = power * 17 % Q;
+            e += 1;
+        }
+        table[i] = power;
+        i += 1;
+    }
+    table
+}
+
+fn checked_self_test() -> Result<(), MlKemError> {
+    static RESULT: OnceLock<Result<(), MlKemError>> = OnceLock::new();
+    match RESULT.get_or_init(self_test) {
+        Ok(()) => Ok(()),
+        Err(_) => Err(MlKemError::SelfTest),
+    }
+}
+
+// Known answers laid out like the ACVP ML-KEM tests, each compared in full:
+// keyGen from fixed seeds d and z, encapsulation of a fixed message m, decapsulation of
+// that ciphertext, and decapsulation of a copy with its last bit flipped, which must take
+// the implicit rejection path.
+pub fn self_test() -> Result<(), MlKemError> {
+    let d: Vec<u8> = (0..32).collect();
+    let z: Vec<u8> = (32..64).collect();
+    let m: Vec<u8> = (64..96).collect();
+
+    // keyGen: (d, z) -> (ek, dk). The decapsulation key is dk_PKE | ek | H(ek) | z, as
+    // FIPS 203 Algorithm 16 assembles it, so only dk_PKE is stored separately.
+    let (decapsulation_key, encapsulation_key) = key_pair_from_seeds(&d, &z);
+    let expected_decapsulation_key = [SELF_TEST_DK_PKE, SELF_TEST_EK, &to_hex(&H(&encapsulation_key)), &to_hex(&z)].concat();
+    if to_hex(&encapsulation_key) != SELF_TEST_EK || to_hex(decapsulation_key.expose()) != expected_decapsulation_key {
+        return Err(MlKemError::SelfTest);
+    }
+
+    // encapsulation: (ek, m) -> (c, K)
+    let (shared_secret, mut ciphertext) = encapsulate_with(&encapsulation_key, &m);
+    if to_hex(&ciphertext) != SELF_TEST_CT || to_hex(shared_secret.expose()) != SELF_TEST_SHARED_SECRET {
+        return Err(MlKemError::SelfTest);
+    }
+
+    // decapsulation: (dk, c) -> K, for the valid ciphertext and for the modified one
+    if to_hex(decapsulate(&decapsulation_key, &ciphertext)?.expose()) != SELF_TEST_SHARED_SECRET {
+        return Err(MlKemError::SelfTest);
+    }
+    *ciphertext.last_mut().unwrap() ^= 1;
+    if to_hex(decapsulate(&decapsulation_key, &ciphertext)?.expose()) != SELF_TEST_REJECTION_SECRET {
+        return Err(MlKemError::SelfTest);
+    }
+    Ok(())
+}
+
+// Answers for d = 00..1f, z = 20..3f and m = 40..5f, produced with OpenSSL 3.5's FIPS 203
+// implementation (genpkey with the seed d | z, pkeyutl -encap with ikme m, -decap). The
+// rejection secret also matches J(z | c') computed directly with SHAKE256.
+const SELF_TEST_DK_PKE: &str = "\
+    27d2a77f33756f61208ef113abe82595873d4abc730e5b5d679529bf6a4ceb6383427231a8612f41550515acba52e48e\
+    ad8b942833bbe6865d13d14a79d2c5c3e07f0a056d8de7aadfcaba058c493c80b37cab8c562753bb3ba6b6ec8297f885\
+    eaa7540d530015a84406e55b1366b577e236ce58a26d8a1eb5a44d542323c2167d9bf4a47f985699ca05bae43b8dec61\
+    7f02380a3890afd4b8c7ec7ede26553a025f3ce5bc5d7a62130304235cb1ad4836b566b5b863bd9bdb45a2844a7047b6\
+    c8d383e448525e040b4dc8a2b48c6c37c96d62d43f3fd88e2881c40a205c9e248f652b592781a779f86880f2a147b678\
+    63f391cc1a5a908c0095e07212291e2ef8a36eb9a9c0c6073225b34703a4af049382c47573da68fde9245ad444e31b1f\
+    bdb521f1f61f37bc0cef292067e670d28a1ffd904f6f1190a996918a13037a6cabf3c373bf8296cd37ab33ba7746809c\
+    c3f8ade1b3639bd57bfcc69650aaaf1de198fc4c0463299e52c461780cc428fc5d04a5c51850cba6c2a5274340675793\
+    dda09be44c29e6395c65f85d2a0a7c6df411e6911b1f2cb6c351cd2e875f51b638be776097e93e2f2b2f83da0beef4aa\
+    85ba9e763ab64502a0ca5222e9eab5b3b7088ed52060e8c8269b943a71ab0ae1c5b1b687d2e019cf8036bcf9bf6e7bac\
+    3aaa36e41660faa4540f2648cd93a189ec5c2dea70bacaaa4ffc906f90810ea1b67bf24f2c78cf6ba881aaea61c0652b\
+    ff95b1bae4426d1773b9cc2ca82c21e38c636e3b1c523244986b0be8a83f5dd5cf2d54762fb3c5ebf59b8e885302b1ce\
+    47033edf760f4e029be40b6d566b19dd758acd5c7412878131244f90172c53f26663c21d905301d48baf91c917cc7779\
+    e9d8802cc10d89a3705099a2ad3a3a8896743c1144698093be257dacb66dc785228b912c8d965d14aa28342c3ac4a93f\
+    efa532b20945ddc1020139c14d638b908c4ddde9a0645b95b2e4414d40bb79f04413830f15a873c28bb7059c27410020\
+    15f20408f058e715b0bf995b5380b7dd325a056ab97e659a2be0cdf6c33731c683a634b771e8c92a139aee4bb0e49c70\
+    77321d42fc199f7c1f298ca625d223a5c263a03cc48159b7812665b78637e4e18720b2c29a6b99f42766a4cbc4dc508b\
+    a94ba83b89c3a5c78f8bb26bbd9b79beb8c8182490f5793ee5b96013b74b7e169e29d162f1315464ea7d72436d89b755\
+    161192c81cc2dd1c8b8bba795ef426ee1cc01c37aaa37b2cff8b0a378b47cbd0b4d49398cfc2712959699fa0bd8cd846\
+    66acc61f541b84fa96b9c854e4e75e9144addb44b8566a57dfbb545ce423c03346f2b2c1a91780d152a8de1a4d4c9cac\
+    de7392c996888cc2399c02c38b3353adf8acab283924da00a05b76e738c72c930d6cba09ae168990faa1fef2226e7808\
+    61d416eff402f4f759fc648ab1f97100109087f96e4b148d2cb31e4805314ea0cd95fb023eac0d989474ba4201d7b41d\
+    26f5394b217eea5b34b71a8b37931c0e594271e0b7c733257240233e7ba735603e425a87dee77079e37cb28a21764594\
+    ce5350d8da2b62a07174943032ec89c98809c73b6423d30c1d283a766a64d89703c3d629b497828d48320c346210797a";
+const SELF_TEST_EK: &str = "\
+    298aa10d423c8dda069d02bc59e6cdf03a096b8b3da4cab9b80ca4a14907672ccef1ec4faf234a0bc5b7e9d473f2b313\
+    3b3b26a1d175cb67a7805919699c02f76531b99c5f89180704bb4ca4535c5b8972679c660a07c5e514b87009c862eb8f\
+    5157695efb3fc40a9def6b81c1cc02a249ae4f094ad0d9bd3485c1c1c68080520a7c8c632032cee738154e5c5176c07d\
+    a56024776a430fe76eacf665a3f7b832102215bc82f10939c8355704336a8fac1d81e4bb0485aa5d7c74d6b59bbe5c5e\
+    972a0d8bac411b55b5d5557cd680a1a8f71b4eb86bc48c9a0509731a54bd9d7290b27963e4372dc9b199cfdcac0b01ac\
+    d28a62395112e4c43648d622c48c8234d01440e8cc376c927f23a5afc9ac0474c662274e424525c8552ece3b3fe26516\
+    de901bc7d515bde89558e626c95c80b93342f8010004f39e6c6c94871c5e344cab3966c835f9a96a59afd31c40286b38\
+    b1c1a78470bab947518934453ce86736a919f1f5a6d510a86f5454fc3980cb5c765bd2bd5f7b36b1410d6635c8ceb47c\
+    4dda0d76a28eac939c71c3024804866c71626658442163c2c22117e50acefce6378a985652302a4ef0c2ce0cc716b779\
+    6e2b6b2e3777dfa1ac3da259a31b5a9b530f8cb638a81a62ac301849abaf95a7301bda30068909bfdb7e67dbccbb38a5\
+    551a25b1a3a0f685748ad5753d8880f0016c627486166384c5571fe2365900364d038311e2d875db366686932b5ec602\
+    430a369e87a6ef5c338786657825bd4c057aceb923eb0935e6905e63b4ced7f80857a773dd64b150d26612ea9ac12052\
+    db2017bf1843ccb4b3281b690dc728adfa85c00281b8e3c09287335f856b4fc2892f69a2f57921ada01914c40988662d\
+    57769662a786351b9b66493dab79594d986de2100d65ba0ff4ea58b81538d24a4435a258fac25404aa7f41f658b13850\
+    65e158dcb60115732720f40459aaac15e406953a90ac52997d1ccd070060efc65db9e653354467fad56ec713c86e7540\
+    c423acf2669f52fa6f4ac6888d871ef3e847c029a8aafbb92e17b24aa079b1f419ba6175b442afb11909d4a56b70a033\
+    5b28739218aa7c9348e2c3c2f3eb3d15a41e6417c0dd94bfeb21419b311a7bb13a180bbe833218a9a6b17447cc85f225\
+    859587a73077049acbcfd44d0f025438e15d1538270d586e1bf83192a9459cf63c0e972f85297679831ecf121509851c\
+    b8340f6f107b0fa1a0efd1b36a8189bc085c4f5cb784e553f41b918f80397ce1956f785bee377ca9aa8be6998ada30c2\
+    6b7c3d8c6b55254cc96203b20c42aee0ac4e1ebb408e49a9e3f879d0ab0785eb7025425d1305a2299c015e120d163b0e\
+    19494ce57253d0246d182745cb8197ab7438b3c1bb7972bec5a306eba3567855c014699fef65ae54c770a0d85c18400c\
+    f642aedc660777ba4b138502bd5a7812f621f84a48296b98dd4322b6f15828b8a8f0e00a8ba44a53c3a8b143571b0740\
+    abd567daf1cde9c79c204b6d5e259d1766a31bbbcb4e6a05cf4502176b301c1c2f41247750157bcec85e809b30a4d60d\
+    7747cdd0f5b99aa8c826987517793aaa8080a0b124a8558df72bbe37b75f4edbb6be8216d6c633fb2b2280e25113d869\
+    5e43481c3eeb397eb192505229b67a201ea893c3e2cb32da8bc342fa4dea0578";
+const SELF_TEST_CT: &str = "\
+    695a60d9c79f08343ed9ff5802582063c2ca3a648e543d924affbb39ef4de656591f0d7689e6626be7ea7fedaf134e2c\
+    27c6797c73a5edaf16808f141c8afcf31614e8ab665379573e4d0a2037cbf776048167ba53576001a2596402cf24b5d4\
+    5362bc893ceaef3599f76b10812e626002e66db5c5b0f2b9a7080e32db68dcc8d04c24f8461a58bb7e47efe670d740ad\
+    8af9820033845ef5f880f26f0e00adb2abef876f5270477ebbb02de6787ce72ca8785fb181f46c3ff7ae3787c25c68cc\
+    ceefb3551875b9d77c4d439b6050eb382aacf9e744227e8c46e0a9a55838ea7034f5b4bcb61f1023a80186e795f4b3d8\
+    ae93988994224fa2d83e21711670da01e2b3e272f81616c0bc88cc46f641d16e0d0c0924cf4a4a5c1a9128c226d4918a\
+    a39bef94199dfffa33876ef0bfa0d9560d25f5ba08068d5271f32d2f9d88bcf53c7dcf811a8d5efe617f5e05700d3478\
+    d3cb7932528d1bceb240198a4cf8752caea3d387f00759a1356b7a5bf1838d26c3573e92e69f0f57c06e8c25459eb83e\
+    12cdd75f541a81ce710eafce2984783f30e37b327ff93b72297c6cd8c78c185ad53864952069d7d6c3bc633ae5e1a592\
+    5855df0b7e714bbde245f68822e0950c23c96d6111753a6ed0c46cce437f53b6bb708c1a3e25979733198d9879e3237e\
+    769471f922e579f37cfd641d29bdcfdbaa81edae09aeb046366e0376d04282d17778a8d54774e8c9be3c822b1e90cd88\
+    95abc1db8951b7687f63fee50ec43faf23730b15189e7c982b22d896a972da3c2ee529bb5fe63630c9c2ddfb9d1e4263\
+    a3d49af2832053d97efa2bd1782f25d7b864d6fb3708bfb9d4bc6c2cc6458d4f1459995db387e8b503825a4496c73525\
+    2aa630a1bcaa7a2674727396dcaf67030b53473951651dc26c22476bfd11d33206af0ff035ed035e34716c905e8ddf04\
+    3a4cdae145238d8f612dbcb75e879653bb9e2657dab58b944ff34f977fe15ce907f6814a5f92338774e6f2ab5257d249\
+    17decdd158c6d4594189f42a9b7fa9159a8af6aa825ba904654e08c894901298ffb27239ddea8283dd45b876036c0aec\
+    f03583ba444529757444c857fff6e4f8ed48f8a180adea54979a678f16dc6ac8edcc8e72ed08e96082f0ff4520dc635d\
+    4a846a3026fd86a48b1297e0cdfc06008793e783bde1c3fc6a71871e66b1feb560495817aabbdc59f0149f3e76add9b5\
+    bd6ce34734de7593ed607efb84c6e732960c744c908a9cb8947375a55b55fa2f0cd6742b75c10f65522d3844bed9b05b\
+    d441bbbea17cfbabdaef9847a0edd9c8329a762e34e5396014d88b4d344f250aaddefd917bb2120d1169c79cb09f59ba\
+    d21850752c1099fff98b71bcdaab76f7063323e78faa521cd243f74ddc7f7775aa79960622e13580a6831e69bb7f2321\
+    d141d35da88317719078d4db319f308594c26836503f62362c40005022937c1298a928c040879661349a7b5362d0a75f\
+    2893b97a2600d5337239a70a6b64a457e6dfd5c74d462e7e790bb9ef3cee1461";
+const SELF_TEST_SHARED_SECRET: &str = "9cddd089ffe70e3996e76f7c8d06746df34d07e8657bc0fcf2bb0e1c3084aea1";
+const SELF_TEST_REJECTION_SECRET: &str = "1f39ae51991196b33dbc7c6031f9f35fd3347d577ebb4dea93028bcd9ab5dabe";
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn known_answer_tests_pass() {
+        assert_eq!(self_test(), Ok(()));
+    }
+}
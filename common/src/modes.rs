@@ -0,0 +1,214 @@
+// CBC and CTR modes of operation (NIST SP 800-38A) built on the AES block cipher from
+// aes_crypt, paired with HMAC-SHA256 in encrypt-then-MAC order.
+//
+// aes_crypt only exposes the raw block cipher through its ECB routines, which pad their
+// input. Encrypting a single block and keeping the first 16 bytes gives the raw forward
+// cipher. For the inverse cipher, the block is followed by the encryption of a full
+// PKCS#7 padding block and only the first 16 bytes of the ECB decryption are kept. That
+// first block is the raw inverse whether ECB strips the PKCS#7 block, strips trailing
+// zero bytes (the padding block has none) or strips nothing at all.
+//
+// Before either mode is first used, the CBC-AES256 and CTR-AES256 examples of SP 800-38A
+// Appendix F are run in both directions, so a change in how aes_crypt pads is caught
+// before it can touch a message.
+
+use std::sync::OnceLock;
+
+use aes_crypt;
+use bernie_hmac;
+
+use crate::hex::from_hex;
+use crate::random::Random;
+use crate::secret::SecretBytes;
+use crate::verify;
+
+pub const BLOCK_SIZE: usize = 16;
+pub const IV_SIZE: usize = 16;
+pub const MAC_TAG_SIZE: usize = 32;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mode {
+    // Cipher Block Chaining with a random IV and PKCS#7 padding
+    Cbc,
+    // Counter mode with a random initial counter block, no padding required
+    Ctr,
+}
+
+impl Mode {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Mode::Cbc => "AES-256-CBC",
+            Mode::Ctr => "AES-256-CTR",
+        }
+    }
+}
+
+// CBC needs an unpredictable IV and CTR needs a counter block that is never reused under
+// the same key, so a fresh random value is drawn for every message in either mode
+pub fn generate_iv(random: &Random) -> Vec<u8> {
+    random.bytes(IV_SIZE)
+}
+
+// Derives independent encryption and MAC keys from the DH-derived key, so the same key is
+// never used with both AES and HMAC
+pub fn derive_etm_keys(key: &[u8]) -> (SecretBytes, SecretBytes) {
+    let encryption_key = bernie_hmac::hmac(b"seccom etm encryption key", key);
+    let mac_key = bernie_hmac::hmac(b"seccom etm mac key", key);
+    (SecretBytes::new(encryption_key), SecretBytes::new(mac_key))
+}
+
+pub fn encrypt(mode: Mode, plaintext: &[u8], iv: &[u8], key: &[u8]) -> Vec<u8> {
+    checked_self_test();
+    match mode {
+        Mode::Cbc => encrypt_cbc(plaintext, iv, key),
+        Mode::Ctr => apply_ctr(plaintext, iv, key),
+    }
+}
+
+// None is returned if the CBC padding is invalid. Callers only decrypt after the MAC
+// has been verified, so this can not be turned into a padding oracle.
+pub fn decrypt(mode: Mode, ciphertext: &[u8], iv: &[u8], key: &[u8]) -> Option<Vec<u8>> {
+    checked_self_test();
+    match mode {
+        Mode::Cbc => decrypt_cbc(ciphertext, iv, key),
+        Mode::Ctr => Some(apply_ctr(ciphertext, iv, key)),
+    }
+}
+
+// The MAC covers the frame header, the IV and the ciphertext
+pub fn compute_tag(header: &[u8], iv: &[u8], ciphertext: &[u8], mac_key: &[u8]) -> Vec<u8> {
+    let mut mac_input = header.to_vec();
+    mac_input.extend_from_slice(iv);
+    mac_input.extend_from_slice(ciphertext);
+    bernie_hmac::hmac(&mac_input, mac_key)
+}
+
+pub fn verify_tag(header: &[u8], iv: &[u8], ciphertext: &[u8], tag: &[u8], mac_key: &[u8]) -> bool {
+    let mut mac_input = header.to_vec();
+    mac_input.extend_from_slice(iv);
+    mac_input.extend_from_slice(ciphertext);
+    verify::verify_hmac(&mac_input, tag, mac_key)
+}
+
+fn encrypt_cbc(plaintext: &[u8], iv: &[u8], key: &[u8]) -> Vec<u8> {
+    // PKCS#7 padding always adds between 1 and 16 bytes
+    let padding = BLOCK_SIZE - plaintext.len() % BLOCK_SIZE;
+    let mut padded = plaintext.to_vec();
+    padded.extend(std::iter::repeat_n(padding as u8, padding));
+    chain_cbc(&padded, iv, key)
+}
+
+fn decrypt_cbc(ciphertext: &[u8], iv: &[u8], key: &[u8]) -> Option<Vec<u8>> {
+    if ciphertext.is_empty() || !ciphertext.len().is_multiple_of(BLOCK_SIZE) {
+        return None;
+    }
+    let mut plaintext = unchain_cbc(ciphertext, iv, key);
+
+    // Remove the PKCS#7 padding
+    let padding = *plaintext.last()? as usize;
+    if padding == 0 || padding > BLOCK_SIZE || plaintext[plaintext.len() - padding..].iter().any(|&b| b as usize != padding) {
+        return None;
+    }
+    plaintext.truncate(plaintext.len() - padding);
+    Some(plaintext)
+}
+
+// C_1 = E(P_1 ^ IV), C_j = E(P_j ^ C_(j-1)), over whole blocks
+fn chain_cbc(blocks: &[u8], iv: &[u8], key: &[u8]) -> Vec<u8> {
+    let mut ciphertext = Vec::with_capacity(blocks.len());
+    let mut previous = iv.to_vec();
+    for block in blocks.chunks(BLOCK_SIZE) {
+        let input: Vec<u8> = block.iter().zip(previous.iter()).map(|(p, c)| p ^ c).collect();
+        previous = encrypt_block(&input, key);
+        ciphertext.extend_from_slice(&previous);
+    }
+    ciphertext
+}
+
+// P_1 = D(C_1) ^ IV, P_j = D(C_j) ^ C_(j-1), over whole blocks
+fn unchain_cbc(ciphertext: &[u8], iv: &[u8], key: &[u8]) -> Vec<u8> {
+    let mut plaintext = Vec::with_capacity(ciphertext.len());
+    let mut previous = iv;
+    for block in ciphertext.chunks(BLOCK_SIZE) {
+        let output = decrypt_block(block, key);
+        plaintext.extend(output.iter().zip(previous.iter()).map(|(d, c)| d ^ c));
+        previous = block;
+    }
+    plaintext
+}
+
+// O_j = E(T_j), C_j = P_j ^ O_j where T_1 is the IV and each following counter block is
+// the previous one incremented in its low 64 bits (the standard incrementing function).
+// Encryption and decryption are the same operation.
+fn apply_ctr(data: &[u8], iv: &[u8], key: &[u8]) -> Vec<u8> {
+    let mut counter_block = iv.to_vec();
+    let mut output = Vec::with_capacity(data.len());
+    for chunk in data.chunks(BLOCK_SIZE) {
+        let keystream = encrypt_block(&counter_block, key);
+        output.extend(chunk.iter().zip(keystream.iter()).map(|(d, k)| d ^ k));
+        increment_counter(&mut counter_block);
+    }
+    output
+}
+
+fn increment_counter(counter_block: &mut [u8]) {
+    for byte in counter_block[BLOCK_SIZE / 2..].iter_mut().rev() {
+        *byte = byte.wrapping_add(1);
+        if *byte != 0 {
+            break;
+        }
+    }
+}
+
+// Forward cipher on a single block
+fn encrypt_block(block: &[u8], key: &[u8]) -> Vec<u8> {
+    aes_crypt::encrypt_ecb(block, key)[..BLOCK_SIZE].to_vec()
+}
+
+// Inverse cipher on a single block
+fn decrypt_block(block: &[u8], key: &[u8]) -> Vec<u8> {
+    let mut input = block.to_vec();
+    input.extend(encrypt_block(&[BLOCK_SIZE as u8; BLOCK_SIZE], key));
+    aes_crypt::decrypt_ecb(&input, key)[..BLOCK_SIZE].to_vec()
+}
+
+// The self test runs once per process. A block cipher that gives wrong answers must not
+// be used at all, so a failure ends the process rather than a single message.
+fn checked_self_test() {
+    static RESULT: OnceLock<bool> = OnceLock::new();
+    assert!(*RESULT.get_or_init(self_test), "AES-256-CBC/CTR known-answer test failed");
+}
+
+// SP 800-38A Appendix F: F.2.5 and F.2.6 (CBC-AES256.Encrypt and .Decrypt), then F.5.5 and
+// F.5.6 (CTR-AES256.Encrypt and .Decrypt). All four examples share the key and the four
+// plaintext blocks.
+pub fn self_test() -> bool {
+    let key = from_hex(SP800_38A_KEY).unwrap();
+    let plaintext = from_hex(SP800_38A_PLAINTEXT).unwrap();
+
+    let cbc_iv = from_hex("000102030405060708090a0b0c0d0e0f").unwrap();
+    let cbc_ciphertext = from_hex(SP800_38A_CBC_CIPHERTEXT).unwrap();
+    if chain_cbc(&plaintext, &cbc_iv, &key) != cbc_ciphertext || unchain_cbc(&cbc_ciphertext, &cbc_iv, &key) != plaintext {
+        return false;
+    }
+
+    let ctr_counter_block = from_hex("f0f1f2f3f4f5f6f7f8f9fafbfcfdfeff").unwrap();
+    let ctr_ciphertext = from_hex(SP800_38A_CTR_CIPHERTEXT).unwrap();
+    apply_ctr(&plaintext, &ctr_counter_block, &key) == ctr_ciphertext
+        && apply_ctr(&ctr_ciphertext, &ctr_counter_block, &key) == plaintext
+}
+
+const SP800_38A_KEY: &str = "603deb1015ca71be2b73aef0857d77811f352c073b6108d72d9810a30914dff4";
+const SP800_38A_PLAINTEXT: &str = "6bc1bee22e409f96e93d7e117393172aae2d8a571e03ac9c9eb76fac45af8e5130c81c46a35ce411e5fbc1191a0a52eff69f2445df4f9b17ad2b417be66c3710";
+const SP800_38A_CBC_CIPHERTEXT: &str = "f58c4c04d6e5f1ba779eabfb5f7bfbd69cfc4e967edb808d679f777bc6702c7d39f23369a9d9bacfa530e26304231461b2eb05e2c39be9fcda6c19078c6a9d1b";
+const SP800_38A_CTR_CIPHERTEXT: &str = "601ec313775789a5b7a7f504bbf3d228f443e3ca4d62b59aca84e990cacaf5c52b0930daa23de94ce87017ba2d84988ddfc9c58db67aada613c2dd08457941a6";
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn known_answer_tests_pass() {
+        assert!(self_test());
+    }
+}
@@ -0,0 +1,600 @@
+// The Noise Protocol Framework (revision 34) with the NN, NK, XX and IK handshake
+// patterns, built on this project's primitives: the MODP group from the dh library,
+// AES-256-GCM from aes_crypt and SHA-256 with HKDF from bernie_hmac.
+//
+// A pattern is a fixed sequence of messages, each a list of tokens. "e" and "s" send an
+// ephemeral or static public key, and "ee", "es", "se" and "ss" mix a DH result into the
+// chaining key. Once the first DH is done, static keys and payloads are encrypted, and
+// every key and payload is hashed into h, so both sides end with the same handshake hash
+// only if they saw the same messages.
+//
+//     NN:  -> e                  XX:  -> e
+//          <- e, ee                   <- e, ee, s, es
+//                                     -> s, se
+//     NK:  <- s                  IK:  <- s
+//          ...                        ...
+//          -> e, es                   -> e, es, s, ss
+//          <- e, ee                   <- e, ee, se
+//
+// NN authenticates nobody. NK authenticates the responder to an initiator that already
+// knows its static key, XX exchanges both static keys during the handshake, and IK does
+// the same as XX in one round trip when the initiator knows the responder's key up front.
+
+use std::fmt;
+use std::collections::VecDeque;
+
+use num_bigint::BigUint;
+
+use aes_crypt;
+use bernie_hmac;
+use dh;
+
+use crate::kdf;
+use crate::random::Random;
+use crate::record;
+use crate::secret::SecretBytes;
+
+pub const HASH_SIZE: usize = 32;
+pub const TAG_SIZE: usize = record::GCM_TAG_SIZE;
+pub const MAX_MESSAGE_SIZE: usize = 65535;
+
+const NONCE_SIZE: usize = 12;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Pattern {
+    NN,
+    NK,
+    XX,
+    IK,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Token {
+    E,
+    S,
+    EE,
+    ES,
+    SE,
+    SS,
+}
+
+impl Pattern {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Pattern::NN => "NN",
+            Pattern::NK => "NK",
+            Pattern::XX => "XX",
+            Pattern::IK => "IK",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Pattern> {
+        match name {
+            "NN" => Some(Pattern::NN),
+            "NK" => Some(Pattern::NK),
+            "XX" => Some(Pattern::XX),
+            "IK" => Some(Pattern::IK),
+            _ => None,
+        }
+    }
+
+    // Whether the initiator must know the responder's static key before the handshake
+    fn responder_premessage(&self) -> bool {
+        matches!(self, Pattern::NK | Pattern::IK)
+    }
+
+    // Whether the initiator (or responder) uses a static key of its own
+    pub fn needs_static_key(&self, initiator: bool) -> bool {
+        match self {
+            Pattern::NN => false,
+            Pattern::NK => !initiator,
+            Pattern::XX | Pattern::IK => true,
+        }
+    }
+
+    fn messages(&self) -> &'static [&'static [Token]] {
+        match self {
+            Pattern::NN => &[&[Token::E], &[Token::E, Token::EE]],
+            Pattern::NK => &[&[Token::E, Token::ES], &[Token::E, Token::EE]],
+            Pattern::XX => &[&[Token::E], &[Token::E, Token::EE, Token::S, Token::ES], &[Token::S, Token::SE]],
+            Pattern::IK => &[&[Token::E, Token::ES, Token::S, Token::SS], &[Token::E, Token::EE, Token::SE]],
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum NoiseError {
+    // The pattern needs a key that was not configured
+    MissingKey(&'static str),
+    // A message was written or read out of turn, or after the handshake finished
+    OutOfTurn,
+    // A message was too short, too long or failed to decrypt
+    InvalidMessage,
+    // A public key was not an element of the group other than 0, 1 and p - 1
+    InvalidPublicKey,
+    // The peer's static key is not the one configured for it
+    RemoteStaticMismatch,
+    // 2^64 - 1 messages have been sent with one key
+    NonceExhausted,
+}
+
+impl fmt::Display for NoiseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            NoiseError::MissingKey(key) => write!(f, "Noise pattern needs a {} key", key),
+            NoiseError::OutOfTurn => write!(f, "Noise message out of turn"),
+            NoiseError::InvalidMessage => write!(f, "Invalid Noise message"),
+            NoiseError::InvalidPublicKey => write!(f, "Invalid Noise public key"),
+            NoiseError::RemoteStaticMismatch => write!(f, "Peer's static key does not match the configured key"),
+            NoiseError::NonceExhausted => write!(f, "Noise nonce exhausted"),
+        }
+    }
+}
+
+impl std::error::Error for NoiseError {}
+
+impl From<NoiseError> for std::io::Error {
+    fn from(error: NoiseError) -> Self {
+        std::io::Error::new(std::io::ErrorKind::InvalidData, error)
+    }
+}
+
+// Settings for one side of a Noise handshake
+pub struct NoiseConfig {
+    pub pattern: Pattern,
+    // Our long-term key pair. Needed by the responder in NK, XX and IK and by the
+    // initiator in XX and IK.
+    pub static_key: Option<(SecretBytes, Vec<u8>)>,
+    // The peer's long-term public key. The initiator must have it for NK and IK. When it
+    // is set and the peer sends its static key during the handshake, the two must match.
+    pub remote_static: Option<Vec<u8>>,
+    // Data both sides agree on beforehand, hashed in before the first message
+    pub prologue: Vec<u8>,
+}
+
+impl NoiseConfig {
+    pub fn new(pattern: Pattern) -> Self {
+        Self { pattern, static_key: None, remote_static: None, prologue: Vec::new() }
+    }
+}
+
+// A key and the counter used as its nonce
+pub struct CipherState {
+    key: Option<SecretBytes>,
+    nonce: u64,
+}
+
+impl CipherState {
+    fn new() -> Self {
+        Self { key: None, nonce: 0 }
+    }
+
+    fn initialize_key(&mut self, key: SecretBytes) {
+        self.key = Some(key);
+        self.nonce = 0;
+    }
+
+    pub fn has_key(&self) -> bool {
+        self.key.is_some()
+    }
+
+    // Nonce the next message will be sent or expected with
+    pub fn nonce(&self) -> u64 {
+        self.nonce
+    }
+
+    // Output is the ciphertext followed by the tag. Without a key the plaintext is
+    // returned unchanged, as in the first messages of a handshake.
+    pub fn encrypt_with_ad(&mut self, ad: &[u8], plaintext: &[u8]) -> Result<Vec<u8>, NoiseError> {
+        let key = match &self.key {
+            Some(key) => key,
+            None => return Ok(plaintext.to_vec()),
+        };
+        if self.nonce == u64::MAX {
+            return Err(NoiseError::NonceExhausted);
+        }
+        let (mut ciphertext, tag) = aes_crypt::encrypt_gcm(plaintext, &gcm_nonce(self.nonce), ad, key.expose(), TAG_SIZE * 8);
+        ciphertext.extend_from_slice(&tag);
+        self.nonce += 1;
+        Ok(ciphertext)
+    }
+
+    // The nonce only advances when the message decrypts
+    pub fn decrypt_with_ad(&mut self, ad: &[u8], ciphertext: &[u8]) -> Result<Vec<u8>, NoiseError> {
+        let key = match &self.key {
+            Some(key) => key,
+            None => return Ok(ciphertext.to_vec()),
+        };
+        if self.nonce == u64::MAX {
+            return Err(NoiseError::NonceExhausted);
+        }
+        if ciphertext.len() < TAG_SIZE {
+            return Err(NoiseError::InvalidMessage);
+        }
+        let (body, tag) = ciphertext.split_at(ciphertext.len() - TAG_SIZE);
+        let plaintext = record::open_gcm(body, &gcm_nonce(self.nonce), ad, tag, key.expose())
+            .ok_or(NoiseError::InvalidMessage)?;
+        self.nonce += 1;
+        Ok(plaintext)
+    }
+}
+
+// The chaining key ck, the handshake hash h and the CipherState keyed from ck
+pub struct SymmetricState {
+    cipher: CipherState,
+    chaining_key: SecretBytes,
+    hash: Vec<u8>,
+}
+
+impl SymmetricState {
+    fn new(protocol_name: &str) -> Self {
+        // A name of up to HASH_SIZE bytes is used directly, padded with zeros
+        let hash = if protocol_name.len() <= HASH_SIZE {
+            let mut hash = protocol_name.as_bytes().to_vec();
+            hash.resize(HASH_SIZE, 0);
+            hash
+        } else {
+            bernie_hmac::hash(protocol_name.as_bytes())
+        };
+        Self { cipher: CipherState::new(), chaining_key: SecretBytes::new(hash.clone()), hash }
+    }
+
+    fn mix_key(&mut self, input_key_material: &SecretBytes) {
+        let (chaining_key, key) = hkdf(&self.chaining_key, input_key_material);
+        self.chaining_key = chaining_key;
+        self.cipher.initialize_key(key);
+    }
+
+    fn mix_hash(&mut self, data: &[u8]) {
+        let mut input = self.hash.clone();
+        input.extend_from_slice(data);
+        self.hash = bernie_hmac::hash(&input);
+    }
+
+    fn encrypt_and_hash(&mut self, plaintext: &[u8]) -> Result<Vec<u8>, NoiseError> {
+        let ciphertext = self.cipher.encrypt_with_ad(&self.hash, plaintext)?;
+        self.mix_hash(&ciphertext);
+        Ok(ciphertext)
+    }
+
+    fn decrypt_and_hash(&mut self, ciphertext: &[u8]) -> Result<Vec<u8>, NoiseError> {
+        let plaintext = self.cipher.decrypt_with_ad(&self.hash, ciphertext)?;
+        self.mix_hash(ciphertext);
+        Ok(plaintext)
+    }
+
+    // Two CipherStates for the transport phase, initiator to responder first
+    fn split(&self) -> (CipherState, CipherState) {
+        let (first, second) = hkdf(&self.chaining_key, &SecretBytes::new(Vec::new()));
+        let mut initiator_to_responder = CipherState::new();
+        let mut responder_to_initiator = CipherState::new();
+        initiator_to_responder.initialize_key(first);
+        responder_to_initiator.initialize_key(second);
+        (initiator_to_responder, responder_to_initiator)
+    }
+}
+
+pub struct HandshakeState {
+    symmetric: SymmetricState,
+    pattern: Pattern,
+    initiator: bool,
+    // s and e are our key pairs, rs and re the peer's public keys
+    s: Option<(SecretBytes, Vec<u8>)>,
+    e: Option<(SecretBytes, Vec<u8>)>,
+    rs: Option<Vec<u8>>,
+    re: Option<Vec<u8>>,
+    // Set when rs must match the key the peer sends
+    pinned_remote_static: Option<Vec<u8>>,
+    // Messages still to be written or read, the next one first
+    messages: VecDeque<&'static [Token]>,
+    random: Random,
+}
+
+impl HandshakeState {
+    pub fn new(config: &NoiseConfig, initiator: bool, random: Random) -> Result<Self, NoiseError> {
+        let pattern = config.pattern;
+        let s = config.static_key.as_ref().map(|(private_key, public_key)| (private_key.duplicate(), pad(public_key)));
+        if pattern.needs_static_key(initiator) && s.is_none() {
+            return Err(NoiseError::MissingKey("local static"));
+        }
+        let remote_static = config.remote_static.as_ref().map(|public_key| pad(public_key));
+        if initiator && pattern.responder_premessage() && remote_static.is_none() {
+            return Err(NoiseError::MissingKey("remote static"));
+        }
+
+        let mut symmetric = SymmetricState::new(&protocol_name(pattern));
+        symmetric.mix_hash(&config.prologue);
+
+        // The responder's static key is known to both sides before NK and IK start
+        let mut rs = None;
+        if pattern.responder_premessage() {
+            if initiator {
+                rs = remote_static.clone();
+                symmetric.mix_hash(rs.as_ref().unwrap());
+            } else {
+                symmetric.mix_hash(&s.as_ref().unwrap().1);
+            }
+        }
+
+        Ok(Self {
+            symmetric,
+            pattern,
+            initiator,
+            s,
+            e: None,
+            rs,
+            re: None,
+            pinned_remote_static: remote_static,
+            messages: pattern.messages().iter().copied().collect(),
+            random,
+        })
+    }
+
+    pub fn pattern(&self) -> Pattern {
+        self.pattern
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.messages.is_empty()
+    }
+
+    // Whether the next handshake message is ours to write
+    pub fn is_my_turn(&self) -> bool {
+        let written = self.pattern.messages().len() - self.messages.len();
+        written.is_multiple_of(2) == self.initiator
+    }
+
+    // The peer's static public key, once it is known
+    pub fn remote_static(&self) -> Option<&[u8]> {
+        self.rs.as_deref()
+    }
+
+    pub fn handshake_hash(&self) -> &[u8] {
+        &self.symmetric.hash
+    }
+
+    pub fn write_message(&mut self, payload: &[u8]) -> Result<Vec<u8>, NoiseError> {
+        if self.is_finished() || !self.is_my_turn() {
+            return Err(NoiseError::OutOfTurn);
+        }
+        let tokens = self.messages.pop_front().unwrap();
+
+        let mut message = Vec::new();
+        for token in tokens {
+            match token {
+                Token::E => {
+                    let (private_key, public_key) = self.random.dh_key_pair();
+                    let public_key = pad(&public_key);
+                    self.symmetric.mix_hash(&public_key);
+                    message.extend_from_slice(&public_key);
+                    self.e = Some((private_key, public_key));
+                }
+                Token::S => {
+                    let public_key = self.s.as_ref().unwrap().1.clone();
+                    message.extend_from_slice(&self.symmetric.encrypt_and_hash(&public_key)?);
+                }
+                _ => self.mix_dh(*token)?,
+            }
+        }
+        message.extend_from_slice(&self.symmetric.encrypt_and_hash(payload)?);
+
+        if message.len() > MAX_MESSAGE_SIZE {
+            return Err(NoiseError::InvalidMessage);
+        }
+        Ok(message)
+    }
+
+    pub fn read_message(&mut self, message: &[u8]) -> Result<Vec<u8>, NoiseError> {
+        if self.is_finished() || self.is_my_turn() {
+            return Err(NoiseError::OutOfTurn);
+        }
+        if message.len() > MAX_MESSAGE_SIZE {
+            return Err(NoiseError::InvalidMessage);
+        }
+        let tokens = self.messages.pop_front().unwrap();
+
+        let mut rest = message;
+        for token in tokens {
+            match token {
+                Token::E => {
+                    let public_key = take(&mut rest, dh_size())?;
+                    check_public_key(&public_key)?;
+                    self.symmetric.mix_hash(&public_key);
+                    self.re = Some(public_key);
+                }
+                Token::S => {
+                    let length = dh_size() + if self.symmetric.cipher.has_key() { TAG_SIZE } else { 0 };
+                    let public_key = self.symmetric.decrypt_and_hash(&take(&mut rest, length)?)?;
+                    check_public_key(&public_key)?;
+                    if let Some(pinned) = &self.pinned_remote_static {
+                        if *pinned != public_key {
+                            return Err(NoiseError::RemoteStaticMismatch);
+                        }
+                    }
+                    self.rs = Some(public_key);
+                }
+                _ => self.mix_dh(*token)?,
+            }
+        }
+        self.symmetric.decrypt_and_hash(rest)
+    }
+
+    // Our sending and receiving CipherStates for the transport phase
+    pub fn split(&self) -> Result<TransportState, NoiseError> {
+        if !self.is_finished() {
+            return Err(NoiseError::OutOfTurn);
+        }
+        let (initiator_to_responder, responder_to_initiator) = self.symmetric.split();
+        let (sending, receiving) = if self.initiator {
+            (initiator_to_responder, responder_to_initiator)
+        } else {
+            (responder_to_initiator, initiator_to_responder)
+        };
+        Ok(TransportState { sending, receiving, handshake_hash: self.symmetric.hash.clone() })
+    }
+
+    // "es" is DH(e, rs) for the initiator and DH(s, re) for the responder, and "se" the
+    // other way round, so both sides compute the same value
+    fn mix_dh(&mut self, token: Token) -> Result<(), NoiseError> {
+        let (local, remote) = match (token, self.initiator) {
+            (Token::EE, _) => (&self.e, &self.re),
+            (Token::SS, _) => (&self.s, &self.rs),
+            (Token::ES, true) | (Token::SE, false) => (&self.e, &self.rs),
+            (Token::ES, false) | (Token::SE, true) => (&self.s, &self.re),
+            _ => unreachable!("not a DH token"),
+        };
+        let local = local.as_ref().ok_or(NoiseError::MissingKey("local"))?;
+        let remote = remote.as_ref().ok_or(NoiseError::MissingKey("remote"))?;
+        let shared_secret = diffie_hellman(&local.0, remote);
+        self.symmetric.mix_key(&shared_secret);
+        Ok(())
+    }
+}
+
+// The two directions of a finished handshake
+pub struct TransportState {
+    pub sending: CipherState,
+    pub receiving: CipherState,
+    // h at the end of the handshake, which identifies the session
+    pub handshake_hash: Vec<u8>,
+}
+
+// Noise_<pattern>_<DH>_<cipher>_<hash>. The DH name gives the size of the MODP group.
+pub fn protocol_name(pattern: Pattern) -> String {
+    format!("Noise_{}_MODP{}_AESGCM_SHA256", pattern.name(), dh_size() * 8)
+}
+
+// DHLEN: public keys and DH outputs are always the length of the modulus
+fn dh_size() -> usize {
+    dh::get_domain_params().0.len()
+}
+
+fn diffie_hellman(private_key: &SecretBytes, public_key: &[u8]) -> SecretBytes {
+    let modulus = dh::get_domain_params().0;
+    let shared_secret = SecretBytes::new(dh::get_secret(public_key, private_key.expose(), &modulus));
+    SecretBytes::new(pad(shared_secret.expose()))
+}
+
+// Left pads a key or DH output with zeros to DHLEN
+fn pad(value: &[u8]) -> Vec<u8> {
+    let mut padded = vec![0_u8; dh_size().saturating_sub(value.len())];
+    padded.extend_from_slice(value);
+    padded
+}
+
+// Rejects 0, 1, p - 1 and anything at least p, which would force the DH result into a
+// group of size at most two
+fn check_public_key(public_key: &[u8]) -> Result<(), NoiseError> {
+    let modulus = BigUint::from_bytes_be(&dh::get_domain_params().0);
+    let value = BigUint::from_bytes_be(public_key);
+    if value <= BigUint::from(1_u8) || value >= modulus - 1_u8 {
+        return Err(NoiseError::InvalidPublicKey);
+    }
+    Ok(())
+}
+
+// HKDF(ck, ikm) with two outputs. Noise's HKDF is HMAC-SHA256 extract and expand with an
+// empty info string, so the project's HKDF gives the same bytes.
+fn hkdf(chaining_key: &SecretBytes, input_key_material: &SecretBytes) -> (SecretBytes, SecretBytes) {
+    let temp_key = SecretBytes::new(kdf::extract(chaining_key.expose(), input_key_material.expose()));
+    let mut output = kdf::expand(temp_key.expose(), &[], 2 * HASH_SIZE);
+    let second = SecretBytes::new(output.split_off(HASH_SIZE));
+    (SecretBytes::new(output), second)
+}
+
+// 32 bits of zeros followed by the counter, big-endian
+fn gcm_nonce(nonce: u64) -> Vec<u8> {
+    let mut iv = vec![0_u8; NONCE_SIZE - 8];
+    iv.extend_from_slice(&nonce.to_be_bytes());
+    iv
+}
+
+fn take(rest: &mut &[u8], length: usize) -> Result<Vec<u8>, NoiseError> {
+    if rest.len() < length {
+        return Err(NoiseError::InvalidMessage);
+    }
+    let (taken, remaining) = rest.split_at(length);
+    *rest = remaining;
+    Ok(taken.to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Runs the handshake to the end, with a payload in every message, and returns both
+    // sides' transport states
+    fn handshake(initiator: &NoiseConfig, responder: &NoiseConfig) -> Result<(TransportState, TransportState), NoiseError> {
+        let random = Random::new();
+        let mut initiator = HandshakeState::new(initiator, true, random.clone())?;
+        let mut responder = HandshakeState::new(responder, false, random)?;
+        while !initiator.is_finished() {
+            let (writer, reader) = if initiator.is_my_turn() { (&mut initiator, &mut responder) } else { (&mut responder, &mut initiator) };
+            let message = writer.write_message(b"payload")?;
+            assert_eq!(reader.read_message(&message)?, b"payload");
+        }
+        assert!(responder.is_finished());
+        assert_eq!(initiator.handshake_hash(), responder.handshake_hash());
+        Ok((initiator.split()?, responder.split()?))
+    }
+
+    fn config(pattern: Pattern, static_key: Option<&(SecretBytes, Vec<u8>)>, remote_static: Option<&[u8]>) -> NoiseConfig {
+        let mut config = NoiseConfig::new(pattern);
+        config.static_key = static_key.map(|(private_key, public_key)| (private_key.duplicate(), public_key.clone()));
+        config.remote_static = remote_static.map(|public_key| public_key.to_vec());
+        config
+    }
+
+    fn exchange(initiator: &mut TransportState, responder: &mut TransportState) {
+        let ciphertext = initiator.sending.encrypt_with_ad(&[], b"to responder").unwrap();
+        assert_eq!(responder.receiving.decrypt_with_ad(&[], &ciphertext).unwrap(), b"to responder");
+        let ciphertext = responder.sending.encrypt_with_ad(&[], b"to initiator").unwrap();
+        assert_eq!(initiator.receiving.decrypt_with_ad(&[], &ciphertext).unwrap(), b"to initiator");
+    }
+
+    #[test]
+    fn handshakes_round_trip() {
+        let random = Random::new();
+        let initiator_key = random.dh_key_pair();
+        let responder_key = random.dh_key_pair();
+
+        let (mut initiator, mut responder) = handshake(
+            &config(Pattern::XX, Some(&initiator_key), None),
+            &config(Pattern::XX, Some(&responder_key), None),
+        ).unwrap();
+        exchange(&mut initiator, &mut responder);
+
+        let (mut initiator, mut responder) = handshake(
+            &config(Pattern::NK, None, Some(&responder_key.1)),
+            &config(Pattern::NK, Some(&responder_key), None),
+        ).unwrap();
+        exchange(&mut initiator, &mut responder);
+
+        let (mut initiator, mut responder) = handshake(
+            &config(Pattern::IK, Some(&initiator_key), Some(&responder_key.1)),
+            &config(Pattern::IK, Some(&responder_key), None),
+        ).unwrap();
+        exchange(&mut initiator, &mut responder);
+    }
+
+    #[test]
+    fn unexpected_remote_static_is_rejected() {
+        let random = Random::new();
+        let responder_key = random.dh_key_pair();
+        let other_key = random.dh_key_pair();
+
+        // XX sends the responder's key in the handshake, where it must match the pinned one
+        let result = handshake(
+            &config(Pattern::XX, Some(&random.dh_key_pair()), Some(&other_key.1)),
+            &config(Pattern::XX, Some(&responder_key), None),
+        );
+        assert_eq!(result.err(), Some(NoiseError::RemoteStaticMismatch));
+
+        // NK mixes the expected key in up front, so a different responder can not read the first message
+        let result = handshake(
+            &config(Pattern::NK, None, Some(&other_key.1)),
+            &config(Pattern::NK, Some(&responder_key), None),
+        );
+        assert_eq!(result.err(), Some(NoiseError::InvalidMessage));
+    }
+}
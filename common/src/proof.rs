@@ -0,0 +1,239 @@
+// Signed chat messages for non-repudiation. A MAC or GCM tag only shows that someone
+// holding the session key wrote a message, and both ends hold it, so neither can prove to
+// anyone else what the other said. With signed messages on, each message also carries a
+// signature by the sender's long-term key over
+//
+//     context | sender | sequence | timestamp | channel binding | content
+//
+// The channel binding is the session's exporter value from record.rs, so a signed message
+// lifted out of one session is refused in any other, even at the same sequence number.
+//
+// using the Schnorr signatures from certificate.rs. The receiver checks it before the
+// message is shown and can append it to a proof file. Each line of the file is one
+// message in hex, with the signer's public key included, so it can be checked later by
+// someone who was never part of the session:
+//
+//     servers verify-proofs <file>
+//
+// The proof shows which key signed the message. Tying that key to a person is up to
+// whoever checks it, for example by comparing the fingerprint printed here with one the
+// signer published.
+
+use std::fs::OpenOptions;
+use std::io::Write;
+
+use byteorder::{ByteOrder, BigEndian};
+
+use bernie_hmac;
+use dh;
+
+use crate::certificate;
+use crate::hex::{from_hex, to_hex};
+use crate::random::Random;
+use crate::record::{Side, CHANNEL_BINDING_SIZE};
+use crate::secret::SecretBytes;
+
+// Keeps a message signature from being taken for a certificate or a CertificateVerify
+const SIGNATURE_CONTEXT: &[u8] = b"seccom signed message";
+
+// Number of SHA-256 bytes shown in a key fingerprint
+const FINGERPRINT_SIZE: usize = 8;
+
+pub struct SignedMessage {
+    pub sender: Side,
+    // Sequence number of the record the message was sent in
+    pub sequence: u64,
+    // Milliseconds since the Unix epoch, by the sender's clock
+    pub timestamp: u64,
+    // Channel binding of the session the message was sent in
+    pub channel_binding: Vec<u8>,
+    pub content: Vec<u8>,
+    pub public_key: Vec<u8>,
+    pub signature: Vec<u8>,
+}
+
+impl SignedMessage {
+    pub fn sign(sender: Side, sequence: u64, channel_binding: &[u8], content: &[u8], key_pair: &(SecretBytes, Vec<u8>), random: &Random) -> Self {
+        let mut message = Self {
+            sender,
+            sequence,
+            timestamp: random.unix_time().as_millis() as u64,
+            channel_binding: channel_binding.to_vec(),
+            content: content.to_vec(),
+            public_key: key_pair.1.clone(),
+            signature: Vec::new(),
+        };
+        message.signature = certificate::sign(key_pair, &message.signed_bytes(), random);
+        message
+    }
+
+    pub fn verify(&self) -> bool {
+        certificate::verify(&self.public_key, &self.signed_bytes(), &self.signature)
+    }
+
+    // Short form of the signer's public key for comparing by eye
+    pub fn signer(&self) -> String {
+        fingerprint(&self.public_key)
+    }
+
+    // Layout: sender (1 byte) | sequence (8 bytes) | timestamp (8 bytes) | channel binding
+    // (32 bytes) | content length (4 bytes) | content | public key length (2 bytes) | public
+    // key | signature
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.fields();
+        bytes.extend_from_slice(&(self.public_key.len() as u16).to_be_bytes());
+        bytes.extend_from_slice(&self.public_key);
+        bytes.extend_from_slice(&self.signature);
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < 21 + CHANNEL_BINDING_SIZE {
+            return None;
+        }
+        let sender = match bytes[0] {
+            0 => Side::Client,
+            1 => Side::Server,
+            _ => return None,
+        };
+        let sequence = BigEndian::read_u64(&bytes[1..9]);
+        let timestamp = BigEndian::read_u64(&bytes[9..17]);
+        let (channel_binding, rest) = bytes[17..].split_at(CHANNEL_BINDING_SIZE);
+        let content_length = BigEndian::read_u32(rest) as usize;
+        let rest = &rest[4..];
+        if rest.len() < content_length + 2 {
+            return None;
+        }
+        let (content, rest) = rest.split_at(content_length);
+        let key_length = BigEndian::read_u16(rest) as usize;
+        let rest = &rest[2..];
+        if rest.len() <= key_length {
+            return None;
+        }
+        let (public_key, signature) = rest.split_at(key_length);
+        Some(Self {
+            sender,
+            sequence,
+            timestamp,
+            channel_binding: channel_binding.to_vec(),
+            content: content.to_vec(),
+            public_key: public_key.to_vec(),
+            signature: signature.to_vec(),
+        })
+    }
+
+    // Appends the message to a proof file as one line of hex
+    pub fn export(&self, path: &str) -> std::io::Result<()> {
+        let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+        writeln!(file, "{}", to_hex(&self.to_bytes()))
+    }
+
+    fn fields(&self) -> Vec<u8> {
+        let mut bytes = vec![match self.sender { Side::Client => 0, Side::Server => 1 }];
+        bytes.extend_from_slice(&self.sequence.to_be_bytes());
+        bytes.extend_from_slice(&self.timestamp.to_be_bytes());
+        bytes.extend_from_slice(&self.channel_binding);
+        bytes.extend_from_slice(&(self.content.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(&self.content);
+        bytes
+    }
+
+    fn signed_bytes(&self) -> Vec<u8> {
+        let mut bytes = SIGNATURE_CONTEXT.to_vec();
+        bytes.extend(self.fields());
+        bytes
+    }
+}
+
+// Reads a signed message that arrived in the peer's record #sequence. The signature must
+// hold, the message must say it came from the peer and was sent in that record of this
+// session, and it must be signed with the same key as the peer's earlier messages.
+// peer_key is that key, None until the first message has arrived.
+pub fn open_signed(bytes: &[u8], peer: Side, sequence: u64, channel_binding: &[u8], peer_key: &mut Option<Vec<u8>>) -> Option<SignedMessage> {
+    let message = SignedMessage::from_bytes(bytes)?;
+    if message.sender != peer || message.sequence != sequence || message.channel_binding != channel_binding || !message.verify() {
+        return None;
+    }
+    match peer_key {
+        Some(key) if *key != message.public_key => return None,
+        Some(_) => {}
+        None => *peer_key = Some(message.public_key.clone()),
+    }
+    Some(message)
+}
+
+// The verify-proofs subcommand. Prints every proof in the file and whether its signature
+// holds, and returns false if any line is malformed or fails to verify.
+pub fn verify_proofs(path: &str) -> bool {
+    let contents = match std::fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) => {
+            println!("[!] Could not read {}: {}", path, e);
+            return false;
+        }
+    };
+
+    let mut all_valid = true;
+    for (index, line) in contents.lines().enumerate().filter(|(_, line)| !line.trim().is_empty()) {
+        println!("--------------------------------------");
+        let message = match from_hex(line.trim()).and_then(|bytes| SignedMessage::from_bytes(&bytes)) {
+            Some(message) => message,
+            None => {
+                println!("[!] Line {}: not a signed message", index + 1);
+                all_valid = false;
+                continue;
+            }
+        };
+
+        let sender = match message.sender { Side::Client => "client", Side::Server => "server" };
+        println!("[*] Line {}: record #{} from the {}, signed by key {}", index + 1, message.sequence, sender, message.signer());
+        println!("[*] Sent at {}.{:03} (seconds since the Unix epoch)", message.timestamp / 1000, message.timestamp % 1000);
+        println!("[*] Channel binding {}", to_hex(&message.channel_binding));
+        println!("[*] Content: {}", String::from_utf8_lossy(&message.content).trim_end());
+        if message.verify() {
+            println!("[+] Signature valid");
+        } else {
+            println!("[!] Signature INVALID");
+            all_valid = false;
+        }
+    }
+    println!("--------------------------------------");
+    all_valid
+}
+
+// Short form of a public key for comparing by eye, used for every key the stages print.
+// The key is left padded to the length of the modulus first, as Noise pads to DHLEN, so a
+// key has one fingerprint however many leading zeros it was encoded with.
+pub fn fingerprint(public_key: &[u8]) -> String {
+    let size = dh::get_domain_params().0.len();
+    let mut padded = vec![0_u8; size.saturating_sub(public_key.len())];
+    padded.extend_from_slice(public_key);
+    to_hex(&bernie_hmac::hash(&padded)[..FINGERPRINT_SIZE])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signed_message_is_bound_to_its_session() {
+        let random = Random::new();
+        let key_pair = random.dh_key_pair();
+        let channel_binding = random.bytes(CHANNEL_BINDING_SIZE);
+        let bytes = SignedMessage::sign(Side::Client, 3, &channel_binding, b"hello", &key_pair, &random).to_bytes();
+
+        let mut peer_key = None;
+        let message = open_signed(&bytes, Side::Client, 3, &channel_binding, &mut peer_key).unwrap();
+        assert_eq!(message.content, b"hello");
+
+        // The same message replayed into another session, at another position or back at its sender
+        let other_binding = random.bytes(CHANNEL_BINDING_SIZE);
+        assert!(open_signed(&bytes, Side::Client, 3, &other_binding, &mut None).is_none());
+        assert!(open_signed(&bytes, Side::Client, 4, &channel_binding, &mut None).is_none());
+        assert!(open_signed(&bytes, Side::Server, 3, &channel_binding, &mut None).is_none());
+
+        // A later message from the same session signed with another key
+        let bytes = SignedMessage::sign(Side::Client, 4, &channel_binding, b"hello", &random.dh_key_pair(), &random).to_bytes();
+        assert!(open_signed(&bytes, Side::Client, 4, &channel_binding, &mut peer_key).is_none());
+    }
+}
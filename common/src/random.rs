@@ -0,0 +1,115 @@
+// The single source of randomness for every stage. main instantiates one DRBG and hands
+// a clone of this handle to each client or server, which draws its keys, DH exponents
+// and IVs from it instead of from thread_rng or the libraries' own generators.
+//
+// Because nothing else produces random bytes, a DRBG instantiated from a fixed seed
+// makes a whole session reproducible: given the same seed on both ends and the same
+// input in the same order, every byte on the wire comes out the same. The wall clock
+// would still leak into ticket expiries and message timestamps, so the handle also
+// tells the time, and a seeded one stops its clock at REPLAY_TIME.
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use dh;
+
+use crate::drbg::{DrbgSource, Mechanism};
+use crate::hex::from_hex;
+use crate::secret::SecretBytes;
+
+pub const AES_KEY_SIZE: usize = 32;
+
+// Twice the 128-bit security strength targeted, as SP 800-56A Rev. 3 section 5.6.1.1.4
+// asks of private keys in the safe-prime groups
+pub const DH_PRIVATE_KEY_SIZE: usize = 32;
+
+// The time a seeded handle reports, in seconds since the Unix epoch (2024-01-01 00:00 UTC)
+pub const REPLAY_TIME: u64 = 1_704_067_200;
+
+// A DH or ML-KEM key pair as (private, public)
+pub type KeyPair = (SecretBytes, Vec<u8>);
+
+#[derive(Clone)]
+pub struct Random {
+    source: Arc<Mutex<DrbgSource>>,
+    // Time since the Unix epoch to report instead of the system clock's
+    clock: Option<Duration>,
+}
+
+impl Random {
+    // HMAC_DRBG seeded from the operating system, without prediction resistance
+    pub fn new() -> Self {
+        Self::with_mechanism(Mechanism::HmacSha256, false)
+    }
+
+    pub fn with_mechanism(mechanism: Mechanism, prediction_resistance: bool) -> Self {
+        let source = DrbgSource::new(mechanism, prediction_resistance).expect("Could not instantiate the DRBG");
+        Self { source: Arc::new(Mutex::new(source)), clock: None }
+    }
+
+    // Replay mode. The personalization string keeps the two ends of a session from
+    // drawing the same bytes when they are given the same seed.
+    pub fn seeded(seed: &[u8], personalization: &[u8]) -> Self {
+        let source = DrbgSource::seeded(Mechanism::HmacSha256, seed, personalization).expect("Could not instantiate the DRBG");
+        Self { source: Arc::new(Mutex::new(source)), clock: Some(Duration::from_secs(REPLAY_TIME)) }
+    }
+
+    pub fn is_seeded(&self) -> bool {
+        self.source.lock().unwrap().is_seeded()
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.source.lock().unwrap().mechanism().name()
+    }
+
+    // Time since the Unix epoch, fixed at REPLAY_TIME in replay mode so that tickets and
+    // timestamps come out the same on every run
+    pub fn unix_time(&self) -> Duration {
+        self.clock.unwrap_or_else(|| SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default())
+    }
+
+    // A DRBG that fails its checks can not be worked around, so this panics rather than
+    // handing back output that may not be random
+    pub fn fill(&self, output: &mut [u8]) {
+        self.source.lock().unwrap().fill(output).expect("DRBG failure");
+    }
+
+    pub fn bytes(&self, length: usize) -> Vec<u8> {
+        let mut output = vec![0_u8; length];
+        self.fill(&mut output);
+        output
+    }
+
+    pub fn secret(&self, length: usize) -> SecretBytes {
+        SecretBytes::new(self.bytes(length))
+    }
+
+    pub fn aes_key(&self) -> SecretBytes {
+        self.secret(AES_KEY_SIZE)
+    }
+
+    // DH key pair as (private, public) where public = g^private mod p. The exponent is
+    // drawn here and the dh library only does the modular exponentiation.
+    pub fn dh_key_pair(&self) -> KeyPair {
+        let (modulus, generator) = dh::get_domain_params();
+        let private_key = loop {
+            let candidate = self.secret(DH_PRIVATE_KEY_SIZE);
+            if candidate.expose().iter().any(|&byte| byte != 0) {
+                break candidate;
+            }
+        };
+        let public_key = dh::get_secret(&generator, private_key.expose(), &modulus);
+        (private_key, public_key)
+    }
+}
+
+impl Default for Random {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Parses a seed given as hex on the command line
+pub fn parse_seed(hex: &str) -> Option<Vec<u8>> {
+    from_hex(hex).filter(|seed| !seed.is_empty())
+}
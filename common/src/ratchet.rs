@@ -0,0 +1,300 @@
+// Double Ratchet (as specified by Signal) for stage-5 sessions that ask for per-message
+// forward secrecy.
+//
+// Each direction has a chain key that is pushed through HMAC once per message. The
+// output is that message's key and the chain key is overwritten, so a key taken from the
+// current state can not be walked back to earlier messages. Whenever a message arrives
+// carrying a new ratchet public key from the peer, both chains are replaced by mixing a
+// fresh DH output into the root key, so a leaked state also heals once the conversation
+// changes direction.
+//
+// The server starts from its handshake key pair and an initial sending chain so it can
+// talk first. The client starts with a new ratchet key pair, so its very first message
+// moves both sides onto DH-derived chains.
+
+use std::collections::VecDeque;
+
+use byteorder::{ByteOrder, BigEndian};
+
+use dh;
+use bernie_hmac;
+
+use crate::kdf;
+use crate::random::Random;
+use crate::record::RecordError;
+use crate::secret::SecretBytes;
+
+// Most message keys derived ahead within a single chain. A peer claiming a huge message
+// number could otherwise make us derive keys without end.
+pub const MAX_SKIP: u32 = 1000;
+
+// Most skipped message keys held at once across all chains. The oldest go first.
+pub const MAX_SKIPPED_KEYS: usize = 2000;
+
+// Number of the peer's old ratchet public keys remembered, so a late record from a chain
+// that has been replaced is recognised rather than mistaken for a new ratchet step
+const MAX_RETIRED_KEYS: usize = 16;
+
+// Sent in front of every record protected under the ratchet
+pub struct RatchetHeader {
+    // Sender's current ratchet public key
+    pub public_key: Vec<u8>,
+    // Number of messages sent in the sender's previous sending chain
+    pub previous_chain_length: u32,
+    // Position of this message in the sender's current sending chain
+    pub message_number: u32,
+}
+
+impl RatchetHeader {
+    // Layout: key length (2 bytes) | ratchet public key | previous chain length (4 bytes) | message number (4 bytes)
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = (self.public_key.len() as u16).to_be_bytes().to_vec();
+        bytes.extend_from_slice(&self.public_key);
+        bytes.extend_from_slice(&self.previous_chain_length.to_be_bytes());
+        bytes.extend_from_slice(&self.message_number.to_be_bytes());
+        bytes
+    }
+
+    // Returns the header along with the number of bytes it took up
+    pub fn from_bytes(bytes: &[u8]) -> Option<(Self, usize)> {
+        if bytes.len() < 2 {
+            return None;
+        }
+        let key_length = BigEndian::read_u16(bytes) as usize;
+        let length = 2 + key_length + 8;
+        if bytes.len() < length {
+            return None;
+        }
+
+        let public_key = bytes[2..2 + key_length].to_vec();
+        let previous_chain_length = BigEndian::read_u32(&bytes[2 + key_length..]);
+        let message_number = BigEndian::read_u32(&bytes[2 + key_length + 4..]);
+        Some((Self { public_key, previous_chain_length, message_number }, length))
+    }
+}
+
+// A message key derived ahead of time for a message that has not arrived yet
+struct SkippedKey {
+    public_key: Vec<u8>,
+    message_number: u32,
+    message_key: SecretBytes,
+}
+
+pub struct Ratchet {
+    root_key: SecretBytes,
+    // Our current ratchet key pair as (private, public)
+    key_pair: (SecretBytes, Vec<u8>),
+    // The peer's current ratchet public key, None until the server hears from the client
+    remote_public_key: Option<Vec<u8>>,
+    sending_chain: SecretBytes,
+    receiving_chain: Option<SecretBytes>,
+    // Messages sent in the current sending chain, received in the current receiving
+    // chain, and sent in the previous sending chain
+    sent: u32,
+    received: u32,
+    previous_sent: u32,
+    skipped: VecDeque<SkippedKey>,
+    retired_keys: VecDeque<Vec<u8>>,
+    // Number of DH ratchet steps taken so far
+    steps: u64,
+    // Source of the ratchet key pairs
+    random: Random,
+}
+
+impl Ratchet {
+    // Client side. The server's handshake public key is its first ratchet key.
+    pub fn initiate(final_key: &SecretBytes, server_public_key: Vec<u8>, random: Random) -> Self {
+        let root_key = SecretBytes::new(kdf::expand_label(final_key.expose(), "ratchet root", &[], kdf::HASH_SIZE));
+        let server_chain = SecretBytes::new(kdf::expand_label(final_key.expose(), "server chain", &[], kdf::HASH_SIZE));
+
+        let key_pair = random.dh_key_pair();
+        let (root_key, sending_chain) = kdf_root(&root_key, &dh_output(&key_pair, &server_public_key));
+
+        Self {
+            root_key,
+            key_pair,
+            remote_public_key: Some(server_public_key),
+            sending_chain,
+            receiving_chain: Some(server_chain),
+            sent: 0,
+            received: 0,
+            previous_sent: 0,
+            skipped: VecDeque::new(),
+            retired_keys: VecDeque::new(),
+            steps: 0,
+            random,
+        }
+    }
+
+    // Server side. The handshake key pair is kept as the first ratchet key pair.
+    pub fn respond(final_key: &SecretBytes, key_pair: (SecretBytes, Vec<u8>), random: Random) -> Self {
+        let root_key = SecretBytes::new(kdf::expand_label(final_key.expose(), "ratchet root", &[], kdf::HASH_SIZE));
+        let server_chain = SecretBytes::new(kdf::expand_label(final_key.expose(), "server chain", &[], kdf::HASH_SIZE));
+
+        Self {
+            root_key,
+            key_pair,
+            remote_public_key: None,
+            sending_chain: server_chain,
+            receiving_chain: None,
+            sent: 0,
+            received: 0,
+            previous_sent: 0,
+            skipped: VecDeque::new(),
+            retired_keys: VecDeque::new(),
+            steps: 0,
+            random,
+        }
+    }
+
+    pub fn steps(&self) -> u64 {
+        self.steps
+    }
+
+    // Copy taken before each received record is handled, so the state only moves forward
+    // once the record has been authenticated
+    pub fn duplicate(&self) -> Self {
+        Self {
+            root_key: self.root_key.duplicate(),
+            key_pair: (self.key_pair.0.duplicate(), self.key_pair.1.clone()),
+            remote_public_key: self.remote_public_key.clone(),
+            sending_chain: self.sending_chain.duplicate(),
+            receiving_chain: self.receiving_chain.as_ref().map(|chain| chain.duplicate()),
+            sent: self.sent,
+            received: self.received,
+            previous_sent: self.previous_sent,
+            skipped: self.skipped.iter().map(|skipped| SkippedKey {
+                public_key: skipped.public_key.clone(),
+                message_number: skipped.message_number,
+                message_key: skipped.message_key.duplicate(),
+            }).collect(),
+            retired_keys: self.retired_keys.clone(),
+            steps: self.steps,
+            random: self.random.clone(),
+        }
+    }
+
+    // Advances the sending chain and returns the header to send along with the key for
+    // the message
+    pub fn next_sending_key(&mut self) -> (RatchetHeader, SecretBytes) {
+        let (message_key, next_chain) = kdf_chain(&self.sending_chain);
+        self.sending_chain = next_chain;
+
+        let header = RatchetHeader {
+            public_key: self.key_pair.1.clone(),
+            previous_chain_length: self.previous_sent,
+            message_number: self.sent,
+        };
+        self.sent += 1;
+        (header, message_key)
+    }
+
+    // Finds the key for a received message. Keys for messages skipped on the way are
+    // cached so those messages can still be read if they turn up later. The sequence
+    // number is only used to report errors.
+    pub fn receiving_key(&mut self, header: &RatchetHeader, sequence: u64) -> Result<SecretBytes, RecordError> {
+        if let Some(message_key) = self.take_skipped(header) {
+            return Ok(message_key);
+        }
+        if self.retired_keys.contains(&header.public_key) {
+            return Err(RecordError::MessageKeyUnavailable { sequence });
+        }
+
+        // A new public key means the peer has taken a DH ratchet step. Whatever is left
+        // of the old receiving chain is cached before it is replaced.
+        if self.remote_public_key.as_ref() != Some(&header.public_key) {
+            self.skip_message_keys(header.previous_chain_length)?;
+            self.dh_step(&header.public_key);
+        }
+
+        // Anything below the chain position has been used already or was dropped from the cache
+        if header.message_number < self.received {
+            return Err(RecordError::MessageKeyUnavailable { sequence });
+        }
+        self.skip_message_keys(header.message_number)?;
+
+        let receiving_chain = self.receiving_chain.as_ref().ok_or(RecordError::Malformed)?;
+        let (message_key, next_chain) = kdf_chain(receiving_chain);
+        self.receiving_chain = Some(next_chain);
+        self.received += 1;
+        Ok(message_key)
+    }
+
+    // Message keys are single use, so a cached key is removed as it is handed out
+    fn take_skipped(&mut self, header: &RatchetHeader) -> Option<SecretBytes> {
+        let position = self.skipped.iter().position(|skipped| {
+            skipped.public_key == header.public_key && skipped.message_number == header.message_number
+        })?;
+        self.skipped.remove(position).map(|skipped| skipped.message_key)
+    }
+
+    fn skip_message_keys(&mut self, until: u32) -> Result<(), RecordError> {
+        let (mut receiving_chain, remote_public_key) = match (&self.receiving_chain, &self.remote_public_key) {
+            (Some(chain), Some(public_key)) => (chain.duplicate(), public_key.clone()),
+            _ => return Ok(()),
+        };
+        if until > self.received.saturating_add(MAX_SKIP) {
+            return Err(RecordError::SkippedKeyLimit);
+        }
+
+        while self.received < until {
+            let (message_key, next_chain) = kdf_chain(&receiving_chain);
+            receiving_chain = next_chain;
+            self.skipped.push_back(SkippedKey {
+                public_key: remote_public_key.clone(),
+                message_number: self.received,
+                message_key,
+            });
+            if self.skipped.len() > MAX_SKIPPED_KEYS {
+                self.skipped.pop_front();
+            }
+            self.received += 1;
+        }
+        self.receiving_chain = Some(receiving_chain);
+        Ok(())
+    }
+
+    // The receiving chain is rebuilt from DH(our key, their new key), then a new key pair
+    // is generated and the sending chain is rebuilt from DH(our new key, their new key)
+    fn dh_step(&mut self, remote_public_key: &[u8]) {
+        self.previous_sent = self.sent;
+        self.sent = 0;
+        self.received = 0;
+        if let Some(retired_key) = self.remote_public_key.replace(remote_public_key.to_vec()) {
+            self.retired_keys.push_back(retired_key);
+            if self.retired_keys.len() > MAX_RETIRED_KEYS {
+                self.retired_keys.pop_front();
+            }
+        }
+
+        let (root_key, receiving_chain) = kdf_root(&self.root_key, &dh_output(&self.key_pair, remote_public_key));
+        self.key_pair = self.random.dh_key_pair();
+        let (root_key, sending_chain) = kdf_root(&root_key, &dh_output(&self.key_pair, remote_public_key));
+
+        self.root_key = root_key;
+        self.receiving_chain = Some(receiving_chain);
+        self.sending_chain = sending_chain;
+        self.steps += 1;
+    }
+}
+
+fn dh_output(key_pair: &(SecretBytes, Vec<u8>), remote_public_key: &[u8]) -> SecretBytes {
+    let modulus = dh::get_domain_params().0;
+    SecretBytes::new(dh::get_secret(remote_public_key, key_pair.0.expose(), &modulus))
+}
+
+// HKDF with the root key as salt and the DH output as input keying material, split into
+// the next root key and a new chain key
+fn kdf_root(root_key: &SecretBytes, dh_output: &SecretBytes) -> (SecretBytes, SecretBytes) {
+    let prk = SecretBytes::new(kdf::extract(root_key.expose(), dh_output.expose()));
+    let mut output = kdf::expand_label(prk.expose(), "ratchet step", &[], 2 * kdf::HASH_SIZE);
+    let chain_key = output.split_off(kdf::HASH_SIZE);
+    (SecretBytes::new(output), SecretBytes::new(chain_key))
+}
+
+// Message key = HMAC(chain key, 0x01) and next chain key = HMAC(chain key, 0x02)
+fn kdf_chain(chain_key: &SecretBytes) -> (SecretBytes, SecretBytes) {
+    let message_key = bernie_hmac::hmac(&[0x01], chain_key.expose());
+    let next_chain = bernie_hmac::hmac(&[0x02], chain_key.expose());
+    (SecretBytes::new(message_key), SecretBytes::new(next_chain))
+}
@@ -0,0 +1,954 @@
+// Framing and record protection for stage-5 connections.
+//
+// Every frame on the wire has the layout
+//
+//     length (4 bytes) | version (1 byte) | content type (1 byte) | sequence (8 bytes) | body
+//
+// where the length counts the whole frame including itself. Application data bodies
+// are protected with whichever cipher suite was negotiated during the handshake, and
+// the sequence number counts the records sent in one direction. Handshake messages are
+// sent before any records and always carry sequence number 0.
+//
+// When the session negotiated record padding, every protected record carries
+//
+//     content | content type (1 byte) | zero padding
+//
+// as its plaintext, as TLSInnerPlaintext does in TLS 1.3. The sender picks how much
+// padding to add from its PaddingPolicy, so a record's length no longer gives away how
+// long the message in it is. No content type is zero, so after the record has been
+// authenticated the receiver strips trailing zeros and the last byte left is the type.
+// It must match the type in the frame header.
+
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use byteorder::{ByteOrder, BigEndian};
+
+use aes_crypt;
+use bernie_hmac;
+
+use crate::kdf;
+use crate::modes::{self, Mode};
+use crate::random::Random;
+use crate::ratchet::{Ratchet, RatchetHeader};
+use crate::secret::SecretBytes;
+use crate::suites::CipherSuite;
+use crate::verify;
+
+pub const PROTOCOL_VERSION: u8 = 1;
+pub const HEADER_SIZE: usize = 14;
+pub const MAX_FRAME_SIZE: usize = 1 << 16;
+
+const KEY_SIZE: usize = 32; // 32 bytes or 256 bits
+pub const GCM_TAG_SIZE: usize = 16; // 16 bytes or 128 bits
+// Every GCM tag length SP 800-38D allows, in bytes, longest first
+pub const GCM_TAG_SIZES: [usize; 7] = [16, 15, 14, 13, 12, 8, 4];
+const IV_SIZE: usize = 12; // 12 bytes or 96 bits
+const HMAC_TAG_SIZE: usize = 32;
+
+// Padding never takes a record's plaintext past this many bytes, the record size limit of
+// TLS, so a padded record always fits in a frame. Longer content is sent as it is.
+const MAX_PADDED_SIZE: usize = 1 << 14;
+
+// Exporter label for binding an application login to the session (tls-exporter in RFC 9266)
+pub const CHANNEL_BINDING_LABEL: &str = "EXPORTER-Channel-Binding";
+pub const CHANNEL_BINDING_SIZE: usize = 32;
+
+// SP 800-38D Appendix C. Under a 64 or 32 bit tag a key may only be used for so many
+// authenticated decryptions, and fewer the longer the records are. Each entry is the
+// longest record allowed (ciphertext and additional data, in bytes) and the number of
+// decryptions allowed under one key as long as no record is longer than that.
+const GCM_64_BIT_LIMITS: [(usize, u64); 6] = [
+    (1 << 15, 1 << 32),
+    (1 << 17, 1 << 29),
+    (1 << 19, 1 << 26),
+    (1 << 21, 1 << 23),
+    (1 << 23, 1 << 20),
+    (1 << 25, 1 << 17),
+];
+const GCM_32_BIT_LIMITS: [(usize, u64); 6] = [
+    (1 << 1, 1 << 22),
+    (1 << 2, 1 << 20),
+    (1 << 3, 1 << 18),
+    (1 << 4, 1 << 15),
+    (1 << 5, 1 << 13),
+    (1 << 6, 1 << 11),
+];
+
+// Alert description, numbered as bad_record_mac is in TLS
+const BAD_RECORD_MAC: u8 = 20;
+
+// Direction labels bound into every record's authenticated header
+const CLIENT_TO_SERVER: u8 = 0;
+const SERVER_TO_CLIENT: u8 = 1;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ContentType {
+    ClientHello = 1,
+    ServerHello = 2,
+    // The SRP exchange of the password-authenticated stage, in the order they are sent
+    SrpClientHello = 3,
+    SrpServerHello = 4,
+    SrpClientProof = 5,
+    SrpServerProof = 6,
+    // Every message of a Noise handshake, whichever pattern is in use
+    NoiseHandshake = 7,
+    // A session ticket from the server, protected like application data
+    NewSessionTicket = 8,
+    // Sent in the clear when the server will not resume from the ticket it was shown
+    TicketDeclined = 9,
+    // Sent in the clear by each side of a stage-5 handshake to confirm the hello transcript
+    Finished = 10,
+    // Sent in the clear by the client of a stage-5 handshake after the ServerHello, with the
+    // DH public key its ClientHello committed to
+    KeyReveal = 11,
+    // Sent in the clear just before closing the connection over a record that failed to verify
+    Alert = 21,
+    ApplicationData = 23,
+    KeyUpdate = 24,
+}
+
+impl ContentType {
+    pub fn from_u8(value: u8) -> Option<ContentType> {
+        match value {
+            1 => Some(ContentType::ClientHello),
+            2 => Some(ContentType::ServerHello),
+            3 => Some(ContentType::SrpClientHello),
+            4 => Some(ContentType::SrpServerHello),
+            5 => Some(ContentType::SrpClientProof),
+            6 => Some(ContentType::SrpServerProof),
+            7 => Some(ContentType::NoiseHandshake),
+            8 => Some(ContentType::NewSessionTicket),
+            9 => Some(ContentType::TicketDeclined),
+            10 => Some(ContentType::Finished),
+            11 => Some(ContentType::KeyReveal),
+            21 => Some(ContentType::Alert),
+            23 => Some(ContentType::ApplicationData),
+            24 => Some(ContentType::KeyUpdate),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum RecordError {
+    // The frame could not be parsed
+    Malformed,
+    // The MAC or AEAD tag did not verify
+    Authentication,
+    // The 64-bit sequence number for a direction has been used up
+    SequenceExhausted,
+    // An authentic record which has already been accepted was received again
+    Replay { sequence: u64, expected: u64 },
+    // An authentic record arrived ahead of records which have not been received yet
+    OutOfOrder { sequence: u64, expected: u64 },
+    // Under the ratchet, the key for a record was already used or has left the skipped key
+    // cache. This and SkippedKeyLimit stay inside the record layer, which reports both as
+    // Authentication since the record could not be checked.
+    MessageKeyUnavailable { sequence: u64 },
+    // Under the ratchet, a record claimed more skipped messages than the ratchet will derive keys for
+    SkippedKeyLimit,
+    // A record was too long for the negotiated GCM tag length, or the key has been used as
+    // often as SP 800-38D allows for it
+    GcmLimit { tag_bits: usize },
+}
+
+impl fmt::Display for RecordError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RecordError::Malformed => write!(f, "Malformed record"),
+            RecordError::Authentication => write!(f, "MAC verification failed"),
+            RecordError::SequenceExhausted => write!(f, "Record sequence number exhausted"),
+            RecordError::Replay { sequence, expected } => {
+                write!(f, "Replayed record #{} rejected (expecting #{})", sequence, expected)
+            }
+            RecordError::OutOfOrder { sequence, expected } => {
+                write!(f, "Out of order record #{} rejected (expecting #{})", sequence, expected)
+            }
+            RecordError::MessageKeyUnavailable { sequence } => {
+                write!(f, "No message key for record #{}, it was already used or has expired", sequence)
+            }
+            RecordError::SkippedKeyLimit => write!(f, "Too many skipped messages"),
+            RecordError::GcmLimit { tag_bits } => {
+                write!(f, "Record exceeds the SP 800-38D limits for {}-bit GCM tags", tag_bits)
+            }
+        }
+    }
+}
+
+impl std::error::Error for RecordError {}
+
+impl From<RecordError> for std::io::Error {
+    fn from(error: RecordError) -> Self {
+        std::io::Error::new(std::io::ErrorKind::InvalidData, error)
+    }
+}
+
+pub struct Frame {
+    pub content_type: ContentType,
+    pub sequence: u64,
+    pub body: Vec<u8>,
+}
+
+// The only alert there is. Every record that fails to verify is answered with it, so the
+// peer learns nothing about which check failed.
+pub fn alert_frame() -> Vec<u8> {
+    encode_frame(ContentType::Alert, 0, &[BAD_RECORD_MAC])
+}
+
+pub fn encode_frame(content_type: ContentType, sequence: u64, body: &[u8]) -> Vec<u8> {
+    let mut frame = frame_header(content_type, sequence, body.len());
+    frame.extend_from_slice(body);
+    frame
+}
+
+// Removes the next complete frame from the front of the buffer. Bytes read from the
+// stream are appended to the buffer as they arrive, so it may hold a partial frame
+// (Ok(None) is returned and the bytes stay put) or several frames back to back.
+pub fn take_frame(buffer: &mut Vec<u8>) -> Result<Option<Frame>, RecordError> {
+    if buffer.len() < 4 {
+        return Ok(None);
+    }
+
+    let length = BigEndian::read_u32(buffer) as usize;
+    if !(HEADER_SIZE..=MAX_FRAME_SIZE).contains(&length) {
+        return Err(RecordError::Malformed);
+    }
+    if buffer.len() < length {
+        return Ok(None);
+    }
+
+    let frame: Vec<u8> = buffer.drain(..length).collect();
+    if frame[4] != PROTOCOL_VERSION {
+        return Err(RecordError::Malformed);
+    }
+    let content_type = ContentType::from_u8(frame[5]).ok_or(RecordError::Malformed)?;
+    let sequence = BigEndian::read_u64(&frame[6..HEADER_SIZE]);
+
+    Ok(Some(Frame { content_type, sequence, body: frame[HEADER_SIZE..].to_vec() }))
+}
+
+// How much use a traffic key may see before it is replaced. Limits are checked every time a
+// record is about to be sent, and whichever limit is hit first triggers a key update.
+#[derive(Clone, Copy, Debug)]
+pub struct RekeyPolicy {
+    // Records protected under one key. SP 800-38D caps the number of GCM invocations per
+    // key, and the confidentiality margin of AES-GCM shrinks well before that cap, so the
+    // default follows the 2^24.5 record guidance of RFC 8446 section 5.5 rounded down.
+    pub max_records: u64,
+    // Plaintext bytes protected under one key
+    pub max_bytes: u64,
+    // Time since the key was derived
+    pub max_age: Duration,
+}
+
+impl Default for RekeyPolicy {
+    fn default() -> Self {
+        Self {
+            max_records: 1 << 24,
+            max_bytes: 1 << 34,
+            max_age: Duration::from_secs(60 * 60),
+        }
+    }
+}
+
+// How much zero padding a sender adds to each record when the session uses padding. The
+// length counted is that of the content plus its inner content type byte.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PaddingPolicy {
+    // Only the inner content type is added
+    None,
+    // Up to the next multiple of this many bytes
+    Block(usize),
+    // Up to the smallest of these sizes the content fits in, or to a multiple of the
+    // largest when it fits in none of them
+    Buckets(Vec<usize>),
+    // A random number of bytes between 0 and this many, chosen afresh for every record
+    Random(usize),
+}
+
+impl PaddingPolicy {
+    // Plaintext length a record with this much content is padded to
+    fn padded_length(&self, content_length: usize, random: &Random) -> usize {
+        let length = content_length + 1;
+        let padded = match self {
+            PaddingPolicy::Block(block) if *block > 0 => length.div_ceil(*block) * block,
+            PaddingPolicy::Buckets(sizes) => match sizes.iter().filter(|size| **size >= length).min() {
+                Some(size) => *size,
+                None => match sizes.iter().max() {
+                    Some(largest) if *largest > 0 => length.div_ceil(*largest) * largest,
+                    _ => length,
+                },
+            },
+            // Never more than fits under MAX_PADDED_SIZE, which also keeps maximum + 1 from
+            // overflowing. The modulo bias is then about one in 2^18.
+            PaddingPolicy::Random(maximum) => {
+                let maximum = (*maximum).min(MAX_PADDED_SIZE.saturating_sub(length));
+                length + (BigEndian::read_u32(&random.bytes(4)) as usize) % (maximum + 1)
+            }
+            _ => length,
+        };
+        padded.min(MAX_PADDED_SIZE).max(length)
+    }
+}
+
+impl fmt::Display for PaddingPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PaddingPolicy::None => write!(f, "no padding"),
+            PaddingPolicy::Block(block) => write!(f, "padding to a multiple of {} bytes", block),
+            PaddingPolicy::Buckets(sizes) => {
+                let sizes: Vec<String> = sizes.iter().map(|size| size.to_string()).collect();
+                write!(f, "padding to buckets of {} bytes", sizes.join("/"))
+            }
+            PaddingPolicy::Random(maximum) => write!(f, "up to {} bytes of random padding", maximum),
+        }
+    }
+}
+
+// The inner plaintext of a padded record
+fn pad(content_type: ContentType, content: &[u8], padded_length: usize) -> Vec<u8> {
+    let mut plaintext = Vec::with_capacity(padded_length);
+    plaintext.extend_from_slice(content);
+    plaintext.push(content_type as u8);
+    plaintext.resize(padded_length, 0);
+    plaintext
+}
+
+// Undoes pad once the record has been authenticated. The last non-zero byte is the inner
+// content type, and a record that has none or whose type differs from the header's is
+// malformed.
+fn unpad(content_type: ContentType, mut plaintext: Vec<u8>) -> Result<Vec<u8>, RecordError> {
+    let end = plaintext.iter().rposition(|byte| *byte != 0).ok_or(RecordError::Malformed)?;
+    if plaintext[end] != content_type as u8 {
+        return Err(RecordError::Malformed);
+    }
+    plaintext.truncate(end);
+    Ok(plaintext)
+}
+
+// What an authenticated record turned out to contain
+pub enum Record {
+    ApplicationData(Vec<u8>),
+    // The peer has moved its sending key forward. Records after this one use the new key.
+    KeyUpdate,
+}
+
+// Which end of the connection a record layer belongs to
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Side {
+    Client,
+    Server,
+}
+
+// Keys and sequence number for one direction of the connection
+struct DirectionState {
+    // CLIENT_TO_SERVER or SERVER_TO_CLIENT
+    direction: u8,
+    // Traffic secret the keys below are expanded from. Each key update replaces it with
+    // the next secret in the chain.
+    secret: SecretBytes,
+    key: SecretBytes,
+    mac_key: SecretBytes,
+    // Fixed field of the deterministic GCM nonce (SP 800-38D section 8.2.1)
+    fixed_iv: Vec<u8>,
+    // Number of records protected in this direction so far
+    sequence: u64,
+    // Number of key updates in this direction so far
+    epoch: u64,
+    // Usage of the current key
+    records: u64,
+    bytes: u64,
+    keyed_at: Instant,
+    // Length of the GCM tag in bytes, and for the SP 800-38D limits the number of GCM
+    // operations under the current key and the longest record they covered
+    gcm_tag_size: usize,
+    gcm_uses: u64,
+    longest: usize,
+}
+
+impl DirectionState {
+    // Expands the handshake's final key into the keys for one direction
+    fn derive(final_key: &SecretBytes, direction: u8) -> Self {
+        let label = if direction == CLIENT_TO_SERVER { "client write" } else { "server write" };
+        let secret = SecretBytes::new(kdf::expand_label(final_key.expose(), label, &[], kdf::HASH_SIZE));
+        Self::from_secret(direction, secret, 0, 0)
+    }
+
+    fn from_secret(direction: u8, secret: SecretBytes, sequence: u64, epoch: u64) -> Self {
+        let key = SecretBytes::new(kdf::expand_label(secret.expose(), "key", &[], KEY_SIZE));
+        let fixed_iv = kdf::expand_label(secret.expose(), "iv", &[], IV_SIZE);
+
+        // Every suite with an HMAC keys it separately from the cipher, ECB included
+        let mac_key = SecretBytes::new(kdf::expand_label(secret.expose(), "mac", &[], kdf::HASH_SIZE));
+
+        Self {
+            direction,
+            secret,
+            key,
+            mac_key,
+            fixed_iv,
+            sequence,
+            epoch,
+            records: 0,
+            bytes: 0,
+            keyed_at: Instant::now(),
+            gcm_tag_size: GCM_TAG_SIZE,
+            gcm_uses: 0,
+            longest: 0,
+        }
+    }
+
+    // Ratchets the traffic secret forward through the KDF and re-derives the keys from it.
+    // The old secret can not be recovered from the new one. Sequence numbers carry on
+    // from where they were so replay protection spans key updates.
+    fn update(&mut self) {
+        let next_secret = SecretBytes::new(kdf::expand_label(self.secret.expose(), "traffic upd", &[], kdf::HASH_SIZE));
+        let gcm_tag_size = self.gcm_tag_size;
+        *self = Self::from_secret(self.direction, next_secret, self.sequence, self.epoch + 1);
+        self.gcm_tag_size = gcm_tag_size;
+    }
+
+    // Counts one more GCM operation under the current key, covering a record of the given
+    // length. Refused if the record is too long for the tag or the key has been used as
+    // often as the tag allows.
+    fn use_gcm_key(&mut self, record_length: usize) -> Result<(), RecordError> {
+        let longest = self.longest.max(record_length);
+        let limit = gcm_invocation_limit(self.gcm_tag_size, longest)
+            .ok_or(RecordError::GcmLimit { tag_bits: self.gcm_tag_size * 8 })?;
+        if self.gcm_uses >= limit {
+            return Err(RecordError::GcmLimit { tag_bits: self.gcm_tag_size * 8 });
+        }
+        self.longest = longest;
+        self.gcm_uses += 1;
+        Ok(())
+    }
+
+    // True once only one more record fits under the current key, which is kept for the
+    // KeyUpdate that replaces it
+    fn gcm_limit_reached(&self) -> bool {
+        match gcm_invocation_limit(self.gcm_tag_size, self.longest) {
+            Some(limit) => self.gcm_uses + 1 >= limit,
+            None => true,
+        }
+    }
+
+    fn limits_reached(&self, policy: &RekeyPolicy) -> bool {
+        self.records >= policy.max_records
+            || self.bytes >= policy.max_bytes
+            || self.keyed_at.elapsed() >= policy.max_age
+    }
+
+    // Hands out the next sequence number. The counter is never allowed to wrap since
+    // that would repeat a GCM nonce under the same key.
+    fn next_sequence(&mut self) -> Result<u64, RecordError> {
+        if self.sequence == u64::MAX {
+            return Err(RecordError::SequenceExhausted);
+        }
+        let sequence = self.sequence;
+        self.sequence += 1;
+        Ok(sequence)
+    }
+
+    // The header of a record as it appears on the wire (length, version, type and sequence
+    // number) followed by the direction, which is implied by the key in use rather than
+    // sent. This is the GCM additional authenticated data and the prefix of the HMAC
+    // input, so changing any header bit makes the record fail authentication.
+    fn authenticated_header(&self, content_type: ContentType, sequence: u64, body_length: usize) -> Vec<u8> {
+        let mut header = frame_header(content_type, sequence, body_length);
+        header.push(self.direction);
+        header
+    }
+
+    // Everything the record's MAC or tag covers besides the ciphertext: the authenticated
+    // header followed by the prefix, which is the ratchet header when there is one
+    fn associated_data(&self, content_type: ContentType, sequence: u64, prefix: &[u8], protected_length: usize) -> Vec<u8> {
+        let mut header = self.authenticated_header(content_type, sequence, prefix.len() + protected_length);
+        header.extend_from_slice(prefix);
+        header
+    }
+
+    // Encrypts and authenticates the plaintext under this state's keys. The prefix is sent
+    // unencrypted in front of the result but is still covered by the MAC or tag.
+    fn seal_body(&self, suite: CipherSuite, content_type: ContentType, sequence: u64, prefix: &[u8], plaintext: &[u8], random: &Random) -> Vec<u8> {
+        match suite {
+            CipherSuite::Aes256Gcm => {
+                // Build the nonce from the sequence number
+                let iv = self.nonce(sequence);
+
+                // The record header is the additional authenticated data. GCM ciphertext is
+                // exactly as long as the plaintext, so the length is known up front.
+                let aad = self.associated_data(content_type, sequence, prefix, plaintext.len() + self.gcm_tag_size);
+
+                // Layout: ciphertext | tag, with the tag as long as was negotiated
+                let (mut body, mut auth_tag) = aes_crypt::encrypt_gcm(plaintext, &iv, &aad, self.key.expose(), self.gcm_tag_size * 8);
+                body.append(&mut auth_tag);
+                body
+            }
+            CipherSuite::Aes256EcbHmacSha256 => {
+                // Layout: ciphertext | MAC tag, with the MAC over the header and ciphertext
+                let mut body = aes_crypt::encrypt_ecb(plaintext, self.key.expose());
+                let mut mac_input = self.associated_data(content_type, sequence, prefix, body.len() + HMAC_TAG_SIZE);
+                mac_input.extend_from_slice(&body);
+                let mut mac_tag = bernie_hmac::hmac(&mac_input, self.mac_key.expose());
+                body.append(&mut mac_tag);
+                body
+            }
+            CipherSuite::Aes256CbcHmacSha256 | CipherSuite::Aes256CtrHmacSha256 => {
+                let mode = etm_mode(suite).unwrap();
+
+                // Layout: IV | ciphertext | MAC tag, with the MAC over the header, IV and ciphertext
+                let iv = modes::generate_iv(random);
+                let mut encrypted_bytes = modes::encrypt(mode, plaintext, &iv, self.key.expose());
+                let header = self.associated_data(content_type, sequence, prefix, iv.len() + encrypted_bytes.len() + modes::MAC_TAG_SIZE);
+                let mut mac_tag = modes::compute_tag(&header, &iv, &encrypted_bytes, self.mac_key.expose());
+
+                let mut body = iv;
+                body.append(&mut encrypted_bytes);
+                body.append(&mut mac_tag);
+                body
+            }
+        }
+    }
+
+    // Verifies and decrypts what seal_body produced
+    fn open_body(&self, suite: CipherSuite, content_type: ContentType, sequence: u64, prefix: &[u8], body: &[u8]) -> Result<Vec<u8>, RecordError> {
+        match suite {
+            CipherSuite::Aes256Gcm => {
+                if body.len() < self.gcm_tag_size {
+                    return Err(RecordError::Malformed);
+                }
+
+                // Separate the message from the tag, which must be the negotiated length
+                let (payload, auth_tag) = body.split_at(body.len() - self.gcm_tag_size);
+
+                // The nonce is rebuilt from the record's sequence number
+                let iv = self.nonce(sequence);
+
+                let aad = self.associated_data(content_type, sequence, prefix, body.len());
+                open_gcm(payload, &iv, &aad, auth_tag, self.key.expose()).ok_or(RecordError::Authentication)
+            }
+            CipherSuite::Aes256EcbHmacSha256 => {
+                if body.len() < HMAC_TAG_SIZE {
+                    return Err(RecordError::Malformed);
+                }
+
+                // Separate the message from the MAC tag and verify before decrypting
+                let (payload, mac_tag) = body.split_at(body.len() - HMAC_TAG_SIZE);
+                let mut mac_input = self.associated_data(content_type, sequence, prefix, body.len());
+                mac_input.extend_from_slice(payload);
+                if !verify::verify_hmac(&mac_input, mac_tag, self.mac_key.expose()) {
+                    return Err(RecordError::Authentication);
+                }
+                Ok(aes_crypt::decrypt_ecb(payload, self.key.expose()))
+            }
+            CipherSuite::Aes256CbcHmacSha256 | CipherSuite::Aes256CtrHmacSha256 => {
+                let mode = etm_mode(suite).unwrap();
+                if body.len() < modes::IV_SIZE + modes::MAC_TAG_SIZE {
+                    return Err(RecordError::Malformed);
+                }
+
+                // Separate the IV and the ciphertext from the MAC tag and verify before decrypting
+                let (iv, payload_with_tag) = body.split_at(modes::IV_SIZE);
+                let (payload, mac_tag) = payload_with_tag.split_at(payload_with_tag.len() - modes::MAC_TAG_SIZE);
+                let header = self.associated_data(content_type, sequence, prefix, body.len());
+                if !modes::verify_tag(&header, iv, payload, mac_tag, self.mac_key.expose()) {
+                    return Err(RecordError::Authentication);
+                }
+                modes::decrypt(mode, payload, iv, self.key.expose()).ok_or(RecordError::Malformed)
+            }
+        }
+    }
+
+    // The 64-bit sequence number, left padded to 96 bits, XORed into the fixed field.
+    // Both sides track the sequence number so the nonce is never transmitted.
+    fn nonce(&self, sequence: u64) -> Vec<u8> {
+        let mut nonce = self.fixed_iv.clone();
+        for (byte, counter_byte) in nonce[IV_SIZE - 8..].iter_mut().zip(sequence.to_be_bytes().iter()) {
+            *byte ^= counter_byte;
+        }
+        nonce
+    }
+}
+
+// Record protection state for one connection once the handshake has finished
+pub struct RecordLayer {
+    suite: CipherSuite,
+    write: DirectionState,
+    read: DirectionState,
+    rekey: RekeyPolicy,
+    // Set when the peer updated its key and asked us to update ours in turn
+    update_requested: bool,
+    // Per-message keys when the session negotiated the ratchet
+    ratchet: Option<Ratchet>,
+    // Set when the session negotiated signed chat messages (see proof.rs)
+    signed: bool,
+    // Set when the session negotiated record padding, with how much this side adds to
+    // the records it sends
+    padding: Option<PaddingPolicy>,
+    // Root of export_keying_material. Unlike the traffic keys it never changes during
+    // the session, so both ends export the same values however far they have rekeyed.
+    exporter_secret: Option<SecretBytes>,
+    // Source of the CBC and CTR IVs
+    random: Random,
+}
+
+impl RecordLayer {
+    pub fn new(suite: CipherSuite, final_key: &SecretBytes, side: Side, rekey: RekeyPolicy, random: Random) -> Self {
+        let client_write = DirectionState::derive(final_key, CLIENT_TO_SERVER);
+        let server_write = DirectionState::derive(final_key, SERVER_TO_CLIENT);
+        let exporter_secret = SecretBytes::new(kdf::expand_label(final_key.expose(), "exp master", &[], kdf::HASH_SIZE));
+
+        let (write, read) = match side {
+            Side::Client => (client_write, server_write),
+            Side::Server => (server_write, client_write),
+        };
+        Self { suite, write, read, rekey, update_requested: false, ratchet: None, signed: false, padding: None, exporter_secret: Some(exporter_secret), random }
+    }
+
+    // For a handshake whose key schedule already gives a traffic secret for each
+    // direction, as the stage-6 handshake does. Such a layer has no exporter secret
+    // until one is given with with_exporter_secret.
+    pub fn from_traffic_secrets(suite: CipherSuite, client_secret: SecretBytes, server_secret: SecretBytes, side: Side, rekey: RekeyPolicy, random: Random) -> Self {
+        let client_write = DirectionState::from_secret(CLIENT_TO_SERVER, client_secret, 0, 0);
+        let server_write = DirectionState::from_secret(SERVER_TO_CLIENT, server_secret, 0, 0);
+
+        let (write, read) = match side {
+            Side::Client => (client_write, server_write),
+            Side::Server => (server_write, client_write),
+        };
+        Self { suite, write, read, rekey, update_requested: false, ratchet: None, signed: false, padding: None, exporter_secret: None, random }
+    }
+
+    // Protects records under the ratchet instead of the traffic keys
+    pub fn with_ratchet(mut self, ratchet: Ratchet) -> Self {
+        self.ratchet = Some(ratchet);
+        self
+    }
+
+    // Application data carries a signed message rather than bare content
+    pub fn with_signed_messages(mut self) -> Self {
+        self.signed = true;
+        self
+    }
+
+    pub fn signed_messages(&self) -> bool {
+        self.signed
+    }
+
+    // Records carry an inner content type and are padded by the given policy
+    pub fn with_padding(mut self, policy: PaddingPolicy) -> Self {
+        self.padding = Some(policy);
+        self
+    }
+
+    pub fn padding(&self) -> Option<&PaddingPolicy> {
+        self.padding.as_ref()
+    }
+
+    // Sends and expects GCM tags of this many bytes, one of GCM_TAG_SIZES
+    pub fn with_gcm_tag_size(mut self, gcm_tag_size: usize) -> Self {
+        self.write.gcm_tag_size = gcm_tag_size;
+        self.read.gcm_tag_size = gcm_tag_size;
+        self
+    }
+
+    pub fn gcm_tag_size(&self) -> usize {
+        self.write.gcm_tag_size
+    }
+
+    pub fn with_exporter_secret(mut self, exporter_secret: SecretBytes) -> Self {
+        self.exporter_secret = Some(exporter_secret);
+        self
+    }
+
+    // Keying material derived from the session's master secret for use outside the record
+    // layer. Both ends get the same bytes for the same label and context. None for a layer
+    // without an exporter secret, such as the one protecting the stage-6 handshake.
+    pub fn export_keying_material(&self, label: &str, context: &[u8], length: usize) -> Option<SecretBytes> {
+        let exporter_secret = self.exporter_secret.as_ref()?;
+        Some(SecretBytes::new(kdf::export(exporter_secret.expose(), label, context, length)))
+    }
+
+    // The exporter value for CHANNEL_BINDING_LABEL, printed by both ends after the
+    // handshake so they can be compared
+    pub fn channel_binding(&self) -> Option<SecretBytes> {
+        self.export_keying_material(CHANNEL_BINDING_LABEL, &[], CHANNEL_BINDING_SIZE)
+    }
+
+    // Number of DH ratchet steps taken, None when the ratchet is not in use
+    pub fn ratchet_steps(&self) -> Option<u64> {
+        self.ratchet.as_ref().map(|ratchet| ratchet.steps())
+    }
+
+    pub fn suite(&self) -> CipherSuite {
+        self.suite
+    }
+
+    // Sequence number the next sealed record will carry
+    pub fn write_sequence(&self) -> u64 {
+        self.write.sequence
+    }
+
+    // True if the next call to seal will send a KeyUpdate ahead of the message. The ratchet
+    // already uses a new key for every record, so it never needs one.
+    pub fn update_due(&self) -> bool {
+        let gcm_limit_reached = self.suite == CipherSuite::Aes256Gcm && self.write.gcm_limit_reached();
+        self.ratchet.is_none() && (self.update_requested || self.write.limits_reached(&self.rekey) || gcm_limit_reached)
+    }
+
+    // Number of times the sending key has been updated
+    pub fn write_epoch(&self) -> u64 {
+        self.write.epoch
+    }
+
+    // Number of times the receiving key has been updated
+    pub fn read_epoch(&self) -> u64 {
+        self.read.epoch
+    }
+
+    // Protects the plaintext and returns the bytes to write to the stream. Normally this is
+    // a single application data frame. If the sending key has reached a usage limit, or
+    // the peer asked for an update, a KeyUpdate record sealed under the old key goes out
+    // first and the message follows under the new key. Records already in flight were
+    // sealed under the old key and reach the peer before the KeyUpdate, so none are lost.
+    pub fn seal(&mut self, plaintext: &[u8]) -> Result<Vec<u8>, RecordError> {
+        let mut frames = Vec::new();
+
+        if self.update_due() {
+            // Ask the peer to follow along unless this update is itself the answer to one
+            let request_update = !self.update_requested;
+            frames.extend(self.seal_record(ContentType::KeyUpdate, &[request_update as u8])?);
+            self.write.update();
+            self.update_requested = false;
+        }
+
+        frames.extend(self.seal_record(ContentType::ApplicationData, plaintext)?);
+        Ok(frames)
+    }
+
+    fn seal_record(&mut self, content_type: ContentType, content: &[u8]) -> Result<Vec<u8>, RecordError> {
+        let plaintext = match &self.padding {
+            Some(policy) => pad(content_type, content, policy.padded_length(content.len(), &self.random)),
+            None => content.to_vec(),
+        };
+        let plaintext = plaintext.as_slice();
+
+        // A record the GCM limits refuse must not use up a sequence number, or the peer
+        // would see a gap
+        if self.ratchet.is_none() && self.suite == CipherSuite::Aes256Gcm {
+            self.write.use_gcm_key(gcm_record_length(&[], plaintext.len()))?;
+        }
+        let sequence = self.write.next_sequence()?;
+        self.write.records += 1;
+        self.write.bytes += content.len() as u64;
+
+        // Under the ratchet every record gets keys of its own, expanded from a message key
+        // in the same way as the traffic keys, and the ratchet header goes in front of the body
+        let body = match self.ratchet.as_mut() {
+            Some(ratchet) => {
+                let (header, message_key) = ratchet.next_sending_key();
+                let prefix = header.to_bytes();
+                let mut message_keys = DirectionState::from_secret(self.write.direction, message_key, sequence, 0);
+                message_keys.gcm_tag_size = self.write.gcm_tag_size;
+                if self.suite == CipherSuite::Aes256Gcm {
+                    message_keys.use_gcm_key(gcm_record_length(&prefix, plaintext.len()))?;
+                }
+                let mut body = prefix.clone();
+                body.extend(message_keys.seal_body(self.suite, content_type, sequence, &prefix, plaintext, &self.random));
+                body
+            }
+            None => self.write.seal_body(self.suite, content_type, sequence, &[], plaintext, &self.random),
+        };
+
+        Ok(encode_frame(content_type, sequence, &body))
+    }
+
+    // Protects a session ticket. It takes a sequence number like any other record, so it
+    // can not be replayed or dropped without the peer noticing.
+    pub fn seal_ticket(&mut self, ticket: &[u8]) -> Result<Vec<u8>, RecordError> {
+        self.seal_record(ContentType::NewSessionTicket, ticket)
+    }
+
+    // Verifies and decrypts an application data or KeyUpdate frame
+    pub fn open(&mut self, frame: &Frame) -> Result<Record, RecordError> {
+        if frame.content_type != ContentType::ApplicationData && frame.content_type != ContentType::KeyUpdate {
+            return Err(RecordError::Malformed);
+        }
+        let message = self.open_record(frame)?;
+
+        match frame.content_type {
+            ContentType::ApplicationData => Ok(Record::ApplicationData(message)),
+            _ => {
+                // Every record after this one is protected under the peer's next key
+                self.read.update();
+                if message.first() == Some(&1) {
+                    self.update_requested = true;
+                }
+                Ok(Record::KeyUpdate)
+            }
+        }
+    }
+
+    // Verifies and decrypts a NewSessionTicket frame
+    pub fn open_ticket(&mut self, frame: &Frame) -> Result<Vec<u8>, RecordError> {
+        if frame.content_type != ContentType::NewSessionTicket {
+            return Err(RecordError::Malformed);
+        }
+        self.open_record(frame)
+    }
+
+    // The record is authenticated first and only then is its sequence number compared
+    // against the next one expected, so a Replay or OutOfOrder error always refers to a
+    // genuine record sent by the peer. Under the ratchet the order is not enforced and a
+    // replay finds its message key already gone. Without the key the record can not be
+    // authenticated, so that is reported as an Authentication error like any forgery.
+    fn open_record(&mut self, frame: &Frame) -> Result<Vec<u8>, RecordError> {
+        let sequence = frame.sequence;
+
+        let message = match self.ratchet.as_ref().map(|ratchet| ratchet.duplicate()) {
+            Some(mut ratchet) => {
+                let (header, header_length) = RatchetHeader::from_bytes(&frame.body).ok_or(RecordError::Malformed)?;
+                let (prefix, body) = frame.body.split_at(header_length);
+
+                // Work out the message key on a copy of the ratchet so that a forged record
+                // can not move the real state forward. Why no key could be found says nothing
+                // about who sent the record, so the reason is not passed on.
+                let message_key = ratchet.receiving_key(&header, sequence).map_err(|_| RecordError::Authentication)?;
+                let mut message_keys = DirectionState::from_secret(self.read.direction, message_key, sequence, 0);
+                message_keys.gcm_tag_size = self.read.gcm_tag_size;
+                if self.suite == CipherSuite::Aes256Gcm {
+                    message_keys.use_gcm_key(gcm_record_length(prefix, body.len().saturating_sub(self.read.gcm_tag_size)))?;
+                }
+                let message = message_keys.open_body(self.suite, frame.content_type, sequence, prefix, body)?;
+                self.ratchet = Some(ratchet);
+
+                // Records are accepted in any order since each has its own message key. A
+                // replayed record finds its key already used and is refused by the ratchet.
+                self.read.sequence = self.read.sequence.max(sequence.saturating_add(1));
+                message
+            }
+            None => {
+                // Every decryption counts against the key, forgeries included
+                if self.suite == CipherSuite::Aes256Gcm {
+                    self.read.use_gcm_key(gcm_record_length(&[], frame.body.len().saturating_sub(self.read.gcm_tag_size)))?;
+                }
+                let message = self.read.open_body(self.suite, frame.content_type, sequence, &[], &frame.body)?;
+
+                // Only the next record in order is accepted
+                let expected = self.read.sequence;
+                if sequence < expected {
+                    return Err(RecordError::Replay { sequence, expected });
+                }
+                if sequence > expected {
+                    return Err(RecordError::OutOfOrder { sequence, expected });
+                }
+
+                self.read.next_sequence()?;
+                message
+            }
+        };
+        match self.padding {
+            Some(_) => unpad(frame.content_type, message),
+            None => Ok(message),
+        }
+    }
+}
+
+// GCM decryption with the tag checked here instead of inside aes_crypt::decrypt_gcm. The
+// keystream is the same in both directions, so encrypting the ciphertext recovers the
+// plaintext, and encrypting that plaintext gives the tag the sender must have produced.
+// The expected tag is cut to the length of the one received, which the caller has checked.
+pub fn open_gcm(ciphertext: &[u8], iv: &[u8], aad: &[u8], tag: &[u8], key: &[u8]) -> Option<Vec<u8>> {
+    let (plaintext, _) = aes_crypt::encrypt_gcm(ciphertext, iv, aad, key, tag.len() * 8);
+    let (_, expected_tag) = aes_crypt::encrypt_gcm(&plaintext, iv, aad, key, tag.len() * 8);
+    if verify::constant_time_eq(&expected_tag, tag) {
+        Some(plaintext)
+    } else {
+        None
+    }
+}
+
+// Most GCM operations allowed under one key once a record of this length has been seen,
+// or None if the record is too long for the tag at all. Tags of 96 bits or more are
+// limited by the rekey policy alone.
+fn gcm_invocation_limit(tag_size: usize, record_length: usize) -> Option<u64> {
+    let limits: &[(usize, u64)] = match tag_size {
+        8 => &GCM_64_BIT_LIMITS,
+        4 => &GCM_32_BIT_LIMITS,
+        _ => return Some(u64::MAX),
+    };
+    limits.iter().find(|(longest, _)| record_length <= *longest).map(|(_, invocations)| *invocations)
+}
+
+// Length of a GCM record as SP 800-38D counts it: the additional data (authenticated
+// header and prefix) plus the ciphertext
+fn gcm_record_length(prefix: &[u8], ciphertext_length: usize) -> usize {
+    HEADER_SIZE + 1 + prefix.len() + ciphertext_length
+}
+
+// The mode of operation behind an encrypt-then-MAC suite
+fn etm_mode(suite: CipherSuite) -> Option<Mode> {
+    match suite {
+        CipherSuite::Aes256CbcHmacSha256 => Some(Mode::Cbc),
+        CipherSuite::Aes256CtrHmacSha256 => Some(Mode::Ctr),
+        _ => None,
+    }
+}
+
+// The bytes that precede a body of the given length on the wire
+fn frame_header(content_type: ContentType, sequence: u64, body_length: usize) -> Vec<u8> {
+    let mut header = ((HEADER_SIZE + body_length) as u32).to_be_bytes().to_vec();
+    header.push(PROTOCOL_VERSION);
+    header.push(content_type as u8);
+    header.extend_from_slice(&sequence.to_be_bytes());
+    header
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A client and a server record layer sharing a final key
+    fn pair(suite: CipherSuite) -> (RecordLayer, RecordLayer) {
+        let random = Random::new();
+        let final_key = SecretBytes::new(random.bytes(kdf::HASH_SIZE));
+        let client = RecordLayer::new(suite, &final_key, Side::Client, RekeyPolicy::default(), random.clone());
+        let server = RecordLayer::new(suite, &final_key, Side::Server, RekeyPolicy::default(), random);
+        (client, server)
+    }
+
+    fn frame(mut bytes: Vec<u8>) -> Frame {
+        take_frame(&mut bytes).unwrap().unwrap()
+    }
+
+    #[test]
+    fn records_round_trip() {
+        for suite in CipherSuite::all() {
+            let (mut client, mut server) = pair(suite);
+            match server.open(&frame(client.seal(b"hello").unwrap())).unwrap() {
+                Record::ApplicationData(message) => assert_eq!(message, b"hello"),
+                Record::KeyUpdate => panic!("expected application data"),
+            }
+        }
+    }
+
+    #[test]
+    fn replayed_record_is_rejected() {
+        for suite in CipherSuite::all() {
+            let (mut client, mut server) = pair(suite);
+            let sealed = client.seal(b"hello").unwrap();
+            assert!(server.open(&frame(sealed.clone())).is_ok());
+            assert_eq!(server.open(&frame(sealed)).err(), Some(RecordError::Replay { sequence: 0, expected: 1 }));
+        }
+    }
+
+    #[test]
+    fn reflected_record_is_rejected() {
+        for suite in CipherSuite::all() {
+            let (_, mut server) = pair(suite);
+            let sealed = server.seal(b"hello").unwrap();
+            assert_eq!(server.open(&frame(sealed)).err(), Some(RecordError::Authentication));
+        }
+    }
+}
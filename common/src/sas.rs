@@ -0,0 +1,126 @@
+// Short authentication strings for the stages whose DH exchange authenticates nobody
+// (stages 3 to 5).
+//
+// After the exchange both ends show a six digit code expanded from the final key, with
+// either the hello transcript or both public keys as context. A man in the middle runs a
+// separate exchange with each end and so ends up with two different keys, and the two
+// codes only match by chance. The users read their codes to each other over a channel
+// the attacker does not control, such as a phone call, and type /verify once they match,
+// which records that the peer has been checked.
+//
+// Six digits are only enough if the attacker can not choose its keys after seeing the
+// codes they lead to. Otherwise it finishes one exchange, then keeps trying keys for the
+// other until the second code happens to match the first, which takes about a million
+// DH key pairs. As in ZRTP, the client therefore commits to its public key first: it
+// sends a hash of the key, the server answers with its own key, and only then does the
+// client reveal the key the hash was made from. By the time the attacker learns anything
+// about either code its keys are fixed, and it gets one guess in a million per session.
+
+use std::collections::HashMap;
+
+use byteorder::{ByteOrder, BigEndian};
+
+use bernie_hmac;
+
+use crate::kdf;
+use crate::secret::SecretBytes;
+use crate::verify;
+
+pub const VERIFY_COMMAND: &str = "/verify";
+
+// A commitment is a SHA-256 hash
+pub const COMMITMENT_SIZE: usize = 32;
+
+const CODE_MODULUS: u32 = 1_000_000;
+
+pub struct Sas {
+    code: u32,
+    confirmed: bool,
+}
+
+impl Sas {
+    pub fn new(final_key: &SecretBytes, context: &[u8]) -> Self {
+        let bytes = kdf::expand_label(final_key.expose(), "sas", context, 4);
+        // The modulo bias is a few parts in ten thousand, far too small to help an attacker
+        Self { code: BigEndian::read_u32(&bytes) % CODE_MODULUS, confirmed: false }
+    }
+
+    // The code as two groups of three digits, which are easier to read out
+    pub fn code(&self) -> String {
+        format!("{:03} {:03}", self.code / 1000, self.code % 1000)
+    }
+
+    pub fn is_confirmed(&self) -> bool {
+        self.confirmed
+    }
+
+    pub fn confirm(&mut self) {
+        self.confirmed = true;
+    }
+}
+
+// What the client sends in place of its public key until the server's key has arrived
+pub fn commitment(public_key: &[u8]) -> Vec<u8> {
+    let mut input = b"sas commitment".to_vec();
+    input.extend_from_slice(public_key);
+    bernie_hmac::hash(&input)
+}
+
+// Whether a revealed public key is the one the client committed to
+pub fn opens(commitment: &[u8], public_key: &[u8]) -> bool {
+    verify::constant_time_eq(commitment, &self::commitment(public_key))
+}
+
+// Context for stages 3 and 4, which keep no transcript: both public keys, the server's first
+pub fn key_context(server_public_key: &[u8], client_public_key: &[u8]) -> Vec<u8> {
+    let mut context = Vec::with_capacity(8 + server_public_key.len() + client_public_key.len());
+    context.extend_from_slice(&(server_public_key.len() as u32).to_be_bytes());
+    context.extend_from_slice(server_public_key);
+    context.extend_from_slice(&(client_public_key.len() as u32).to_be_bytes());
+    context.extend_from_slice(client_public_key);
+    bernie_hmac::hash(&context)
+}
+
+// None if the line is not a /verify command, otherwise whatever follows the command
+pub fn parse_verify(line: &str) -> Option<Option<&str>> {
+    let mut words = line.split_whitespace();
+    if words.next() != Some(VERIFY_COMMAND) {
+        return None;
+    }
+    Some(words.next())
+}
+
+// /verify on a server. The client is named by its address, which may be left out while
+// only one client is connected.
+pub fn verify_client(codes: &mut HashMap<String, Sas>, target: Option<&str>) {
+    let address = match target {
+        Some(address) => address.to_string(),
+        None if codes.len() == 1 => codes.keys().next().unwrap().clone(),
+        None => {
+            println!("[!] Name the client to verify: {} <address>", VERIFY_COMMAND);
+            for (address, sas) in codes.iter() {
+                let status = if sas.is_confirmed() { "verified" } else { "not verified" };
+                println!("    {}  {}  {}", address, sas.code(), status);
+            }
+            return;
+        }
+    };
+    match codes.get_mut(&address) {
+        Some(sas) => {
+            sas.confirm();
+            println!("[*] {} verified, its code {} was confirmed to match", address, sas.code());
+        }
+        None => println!("[!] No client at {} has a code to verify", address),
+    }
+}
+
+// /verify on a client, which only has the one server
+pub fn verify_server(code: &mut Option<Sas>) {
+    match code {
+        Some(sas) => {
+            sas.confirm();
+            println!("[*] Server verified, its code {} was confirmed to match", sas.code());
+        }
+        None => println!("[!] The key exchange has not finished, there is no code to verify yet"),
+    }
+}
@@ -0,0 +1,74 @@
+// Holder for key material: DH private keys, shared secrets, derived keys and anything
+// else that would let an observer read the traffic.
+//
+// The bytes are overwritten with zeros when the value is dropped, and the type has no
+// Clone so that copies only come about through an explicit call to duplicate. Debug and
+// Display print a short fingerprint in place of the bytes, which is enough to check that
+// both ends derived the same key without putting the key in a log.
+
+use std::fmt;
+
+use zeroize::Zeroize;
+
+use bernie_hmac;
+
+use crate::hex::to_hex;
+
+// Number of SHA-256 bytes shown in a fingerprint
+const FINGERPRINT_SIZE: usize = 4;
+
+pub struct SecretBytes(Vec<u8>);
+
+impl SecretBytes {
+    // Takes ownership of the bytes without copying them
+    pub fn new(bytes: Vec<u8>) -> Self {
+        Self(bytes)
+    }
+
+    // Read access to the secret, for handing to a cipher or KDF
+    pub fn expose(&self) -> &[u8] {
+        &self.0
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    // A second, independently zeroized copy of the secret
+    pub fn duplicate(&self) -> Self {
+        Self(self.0.clone())
+    }
+
+    // Leading bytes of SHA-256 over the secret, in hex
+    pub fn fingerprint(&self) -> String {
+        to_hex(&bernie_hmac::hash(&self.0)[..FINGERPRINT_SIZE])
+    }
+}
+
+impl From<Vec<u8>> for SecretBytes {
+    fn from(bytes: Vec<u8>) -> Self {
+        Self::new(bytes)
+    }
+}
+
+impl Drop for SecretBytes {
+    fn drop(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+impl fmt::Debug for SecretBytes {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SecretBytes({} bytes, fingerprint {})", self.0.len(), self.fingerprint())
+    }
+}
+
+impl fmt::Display for SecretBytes {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "[redacted {}]", self.fingerprint())
+    }
+}
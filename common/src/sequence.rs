@@ -0,0 +1,83 @@
+// Sequence numbers for the stage 4 messages, which have no record layer of their own.
+//
+// Each side numbers the messages it sends from 0, and the MAC covers the number together
+// with a label for the direction. A recorded message can then neither be replayed to the
+// same peer, whose next expected number has moved on, nor sent back to its sender, whose
+// MAC check expects the other direction.
+
+use std::fmt;
+
+pub const SEQUENCE_SIZE: usize = 8;
+
+// Direction labels mixed into every MAC so that a message can not be reflected back at its sender
+pub const CLIENT_TO_SERVER: u8 = 0;
+pub const SERVER_TO_CLIENT: u8 = 1;
+
+// Why an authentic message was not accepted
+#[derive(Debug, PartialEq, Eq)]
+pub enum SequenceError {
+    // A message we have already accepted
+    Replay { sequence: u64, expected: u64 },
+    // A message ahead of ones we have not seen yet
+    OutOfOrder { sequence: u64, expected: u64 },
+}
+
+impl fmt::Display for SequenceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SequenceError::Replay { sequence, expected } => {
+                write!(f, "Replayed record #{} rejected (expecting #{})", sequence, expected)
+            }
+            SequenceError::OutOfOrder { sequence, expected } => {
+                write!(f, "Out of order record #{} rejected (expecting #{})", sequence, expected)
+            }
+        }
+    }
+}
+
+// Accepts an authenticated message's number only if it is the next one expected, and
+// then moves on to the one after
+pub fn check(sequence: u64, expected: &mut u64) -> Result<(), SequenceError> {
+    if sequence < *expected {
+        return Err(SequenceError::Replay { sequence, expected: *expected });
+    }
+    if sequence > *expected {
+        return Err(SequenceError::OutOfOrder { sequence, expected: *expected });
+    }
+    *expected += 1;
+    Ok(())
+}
+
+// The start of the MAC input for a message: direction | sequence number
+pub fn mac_prefix(direction: u8, sequence: u64) -> Vec<u8> {
+    let mut input = vec![direction];
+    input.extend_from_slice(&sequence.to_be_bytes());
+    input
+}
+
+// The MAC input for an ECB message: direction | sequence number | ciphertext
+pub fn mac_input(direction: u8, sequence: u64, ciphertext: &[u8]) -> Vec<u8> {
+    let mut input = mac_prefix(direction, sequence);
+    input.extend_from_slice(ciphertext);
+    input
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_only_the_next_number() {
+        let mut expected = 0;
+        assert_eq!(check(0, &mut expected), Ok(()));
+        assert_eq!(check(1, &mut expected), Ok(()));
+        assert_eq!(check(1, &mut expected), Err(SequenceError::Replay { sequence: 1, expected: 2 }));
+        assert_eq!(check(3, &mut expected), Err(SequenceError::OutOfOrder { sequence: 3, expected: 2 }));
+        assert_eq!(expected, 2);
+    }
+
+    #[test]
+    fn directions_give_different_mac_inputs() {
+        assert_ne!(mac_input(CLIENT_TO_SERVER, 0, b"record"), mac_input(SERVER_TO_CLIENT, 0, b"record"));
+    }
+}
@@ -0,0 +1,287 @@
+// SRP-6a (RFC 2945, with the multiplier k of RFC 5054) over the MODP group from the dh
+// library, using SHA-256 as the hash throughout.
+//
+// For each identity the server stores a salt and a verifier v = g^x, where x is derived
+// from the password, and never sees the password itself. Each side can only compute the
+// shared secret S if it knows its half (the password on the client, v on the server),
+// and each proves it did by sending a hash of the session key, so a successful exchange
+// authenticates both ends. An eavesdropper learns nothing that lets passwords be tested
+// offline, and an active attacker gets a single guess per connection.
+//
+// BigUint can not be zeroized, so values derived from the password or the private keys
+// only live as BigUint for the length of a calculation and are kept as SecretBytes.
+
+use num_bigint::BigUint;
+
+use dh;
+use bernie_hmac;
+
+use crate::random::{self, Random};
+use crate::secret::SecretBytes;
+use crate::verify;
+
+pub const SALT_SIZE: usize = 16;
+
+// What the server keeps for each identity in place of the password
+pub struct Verifier {
+    pub identity: String,
+    pub salt: Vec<u8>,
+    pub verifier: Vec<u8>,
+}
+
+impl Verifier {
+    // Registration, done once per identity. The password is not needed afterwards.
+    pub fn new(identity: &str, password: &[u8], random: &Random) -> Self {
+        let salt = random.bytes(SALT_SIZE);
+        let (modulus, generator) = group();
+        let x = private_key(identity, password, &salt);
+        let verifier = generator.modpow(&BigUint::from_bytes_be(x.expose()), &modulus).to_bytes_be();
+        Self { identity: identity.to_string(), salt, verifier }
+    }
+
+    // Stand-in for an identity the server does not know. The salt and verifier are fixed
+    // for a given identity and key, so repeated attempts look the same as they would for
+    // a real account, and the exchange fails at the client's proof like a wrong password.
+    pub fn decoy(identity: &str, decoy_key: &SecretBytes) -> Self {
+        let mut salt = bernie_hmac::hmac(&[b"salt:", identity.as_bytes()].concat(), decoy_key.expose());
+        salt.truncate(SALT_SIZE);
+        let x = SecretBytes::new(bernie_hmac::hmac(&[b"verifier:", identity.as_bytes()].concat(), decoy_key.expose()));
+        let (modulus, generator) = group();
+        let verifier = generator.modpow(&BigUint::from_bytes_be(x.expose()), &modulus).to_bytes_be();
+        Self { identity: identity.to_string(), salt, verifier }
+    }
+}
+
+// The result of a completed exchange: the session key K = H(S) and the two proofs
+pub struct SrpSession {
+    pub session_key: SecretBytes,
+    // M1 = H(H(N) XOR H(g) | H(I) | s | A | B | K), sent by the client
+    client_proof: Vec<u8>,
+    // M2 = H(A | M1 | K), sent by the server
+    server_proof: Vec<u8>,
+}
+
+impl SrpSession {
+    fn new(identity: &str, salt: &[u8], client_public_key: &[u8], server_public_key: &[u8], secret: &SecretBytes) -> Self {
+        let (modulus, generator) = group();
+        let session_key = SecretBytes::new(bernie_hmac::hash(secret.expose()));
+
+        let group_hash: Vec<u8> = bernie_hmac::hash(&modulus.to_bytes_be())
+            .iter()
+            .zip(bernie_hmac::hash(&generator.to_bytes_be()))
+            .map(|(n, g)| n ^ g)
+            .collect();
+        let identity_hash = bernie_hmac::hash(identity.as_bytes());
+        let client_proof = hash(&[&group_hash, &identity_hash, salt, client_public_key, server_public_key, session_key.expose()]);
+        let server_proof = hash(&[client_public_key, &client_proof, session_key.expose()]);
+
+        Self { session_key, client_proof, server_proof }
+    }
+
+    pub fn client_proof(&self) -> &[u8] {
+        &self.client_proof
+    }
+
+    pub fn server_proof(&self) -> &[u8] {
+        &self.server_proof
+    }
+
+    pub fn verify_client_proof(&self, proof: &[u8]) -> bool {
+        verify::constant_time_eq(&self.client_proof, proof)
+    }
+
+    pub fn verify_server_proof(&self, proof: &[u8]) -> bool {
+        verify::constant_time_eq(&self.server_proof, proof)
+    }
+}
+
+pub struct SrpClient {
+    identity: String,
+    password: SecretBytes,
+    // a, and A = g^a
+    private_key: SecretBytes,
+    public_key: Vec<u8>,
+}
+
+impl SrpClient {
+    pub fn new(identity: &str, password: SecretBytes, random: &Random) -> Self {
+        let (modulus, generator) = group();
+        let private_key = random.secret(random::DH_PRIVATE_KEY_SIZE);
+        let public_key = generator.modpow(&BigUint::from_bytes_be(private_key.expose()), &modulus).to_bytes_be();
+        Self { identity: identity.to_string(), password, private_key, public_key }
+    }
+
+    pub fn identity(&self) -> &str {
+        &self.identity
+    }
+
+    pub fn public_key(&self) -> &[u8] {
+        &self.public_key
+    }
+
+    // S = (B - k * g^x)^(a + u * x). None if the server's public key is invalid.
+    pub fn finish(&self, salt: &[u8], server_public_key: &[u8]) -> Option<SrpSession> {
+        let (modulus, generator) = group();
+        let b_public = BigUint::from_bytes_be(server_public_key);
+        if &b_public % &modulus == BigUint::ZERO {
+            return None;
+        }
+        let u = scrambler(&self.public_key, server_public_key, &modulus)?;
+
+        let x = BigUint::from_bytes_be(private_key(&self.identity, self.password.expose(), salt).expose());
+        let k = multiplier(&modulus, &generator);
+        let subtrahend = k * generator.modpow(&x, &modulus) % &modulus;
+        let base = (&b_public % &modulus + &modulus - subtrahend) % &modulus;
+        let exponent = BigUint::from_bytes_be(self.private_key.expose()) + u * x;
+        let secret = SecretBytes::new(base.modpow(&exponent, &modulus).to_bytes_be());
+
+        Some(SrpSession::new(&self.identity, salt, &self.public_key, server_public_key, &secret))
+    }
+}
+
+pub struct SrpServer {
+    identity: String,
+    salt: Vec<u8>,
+    verifier: Vec<u8>,
+    // b, and B = k * v + g^b
+    private_key: SecretBytes,
+    public_key: Vec<u8>,
+}
+
+impl SrpServer {
+    pub fn new(verifier: &Verifier, random: &Random) -> Self {
+        let (modulus, generator) = group();
+        let private_key = random.secret(random::DH_PRIVATE_KEY_SIZE);
+        let k = multiplier(&modulus, &generator);
+        let public_key = ((k * BigUint::from_bytes_be(&verifier.verifier)
+            + generator.modpow(&BigUint::from_bytes_be(private_key.expose()), &modulus))
+            % &modulus)
+            .to_bytes_be();
+
+        Self {
+            identity: verifier.identity.clone(),
+            salt: verifier.salt.clone(),
+            verifier: verifier.verifier.clone(),
+            private_key,
+            public_key,
+        }
+    }
+
+    pub fn salt(&self) -> &[u8] {
+        &self.salt
+    }
+
+    pub fn public_key(&self) -> &[u8] {
+        &self.public_key
+    }
+
+    // S = (A * v^u)^b. None if the client's public key is invalid.
+    pub fn finish(&self, client_public_key: &[u8]) -> Option<SrpSession> {
+        let (modulus, _) = group();
+        let a_public = BigUint::from_bytes_be(client_public_key);
+        if &a_public % &modulus == BigUint::ZERO {
+            return None;
+        }
+        let u = scrambler(client_public_key, &self.public_key, &modulus)?;
+
+        let v = BigUint::from_bytes_be(&self.verifier);
+        let base = a_public * v.modpow(&u, &modulus) % &modulus;
+        let secret = SecretBytes::new(base.modpow(&BigUint::from_bytes_be(self.private_key.expose()), &modulus).to_bytes_be());
+
+        Some(SrpSession::new(&self.identity, &self.salt, client_public_key, &self.public_key, &secret))
+    }
+}
+
+// First message of the exchange, sent by the client
+pub struct SrpClientHello {
+    pub identity: String,
+    pub public_key: Vec<u8>,
+}
+
+impl SrpClientHello {
+    // Layout: identity length (1 byte) | identity | A
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![self.identity.len() as u8];
+        bytes.extend_from_slice(self.identity.as_bytes());
+        bytes.extend_from_slice(&self.public_key);
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let identity_end = 1 + *bytes.first()? as usize;
+        if bytes.len() <= identity_end {
+            return None;
+        }
+        let identity = String::from_utf8(bytes[1..identity_end].to_vec()).ok()?;
+        Some(Self { identity, public_key: bytes[identity_end..].to_vec() })
+    }
+}
+
+// The server's answer, carrying the salt for the identity
+pub struct SrpServerHello {
+    pub salt: Vec<u8>,
+    pub public_key: Vec<u8>,
+}
+
+impl SrpServerHello {
+    // Layout: salt length (1 byte) | salt | B
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![self.salt.len() as u8];
+        bytes.extend_from_slice(&self.salt);
+        bytes.extend_from_slice(&self.public_key);
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let salt_end = 1 + *bytes.first()? as usize;
+        if bytes.len() <= salt_end {
+            return None;
+        }
+        Some(Self { salt: bytes[1..salt_end].to_vec(), public_key: bytes[salt_end..].to_vec() })
+    }
+}
+
+// N and g as integers
+fn group() -> (BigUint, BigUint) {
+    let (modulus, generator) = dh::get_domain_params();
+    (BigUint::from_bytes_be(&modulus), BigUint::from_bytes_be(&generator))
+}
+
+// x = H(s | H(I | ":" | P))
+fn private_key(identity: &str, password: &[u8], salt: &[u8]) -> SecretBytes {
+    let inner = SecretBytes::new(hash(&[identity.as_bytes(), b":", password]));
+    SecretBytes::new(hash(&[salt, inner.expose()]))
+}
+
+// k = H(N | PAD(g))
+fn multiplier(modulus: &BigUint, generator: &BigUint) -> BigUint {
+    BigUint::from_bytes_be(&hash(&[&modulus.to_bytes_be(), &pad(generator, modulus)]))
+}
+
+// u = H(PAD(A) | PAD(B)). The exchange is abandoned if u is zero, as the RFC requires.
+fn scrambler(client_public_key: &[u8], server_public_key: &[u8], modulus: &BigUint) -> Option<BigUint> {
+    let client_public_key = pad(&BigUint::from_bytes_be(client_public_key), modulus);
+    let server_public_key = pad(&BigUint::from_bytes_be(server_public_key), modulus);
+    let u = BigUint::from_bytes_be(&hash(&[&client_public_key, &server_public_key]));
+    if u == BigUint::ZERO { None } else { Some(u) }
+}
+
+// Left pads a value with zeros to the length of N
+fn pad(value: &BigUint, modulus: &BigUint) -> Vec<u8> {
+    let length = modulus.to_bytes_be().len();
+    let bytes = value.to_bytes_be();
+    let mut padded = vec![0_u8; length.saturating_sub(bytes.len())];
+    padded.extend_from_slice(&bytes);
+    padded
+}
+
+// SHA-256 over the concatenated parts. Some parts are secret, so the buffer they are
+// concatenated into is zeroized afterwards.
+fn hash(parts: &[&[u8]]) -> Vec<u8> {
+    let mut input = Vec::with_capacity(parts.iter().map(|part| part.len()).sum());
+    for part in parts {
+        input.extend_from_slice(part);
+    }
+    let input = SecretBytes::new(input);
+    bernie_hmac::hash(input.expose())
+}
@@ -0,0 +1,68 @@
+// Cipher suites which a stage-5 connection can negotiate. Each suite names one of the
+// record protection constructions already demonstrated by the earlier stages so that a
+// single server binary can talk to clients with different capabilities.
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum CipherSuite {
+    // AES-256 in Galois/Counter Mode (SP 800-38D), the AEAD used by stage 5
+    Aes256Gcm,
+    // AES-256-CTR followed by HMAC-SHA256 over the header, IV and ciphertext
+    Aes256CtrHmacSha256,
+    // AES-256-CBC followed by HMAC-SHA256 over the header, IV and ciphertext
+    Aes256CbcHmacSha256,
+    // AES-256-ECB followed by HMAC-SHA256 over the ciphertext, the construction used by stage 4
+    Aes256EcbHmacSha256,
+}
+
+impl CipherSuite {
+    // Every suite this build supports, strongest first. This doubles as the default
+    // server policy and the default list of suites a client offers.
+    pub fn all() -> Vec<CipherSuite> {
+        vec![
+            CipherSuite::Aes256Gcm,
+            CipherSuite::Aes256CtrHmacSha256,
+            CipherSuite::Aes256CbcHmacSha256,
+            CipherSuite::Aes256EcbHmacSha256,
+        ]
+    }
+
+    // Two byte identifier used for the suite on the wire
+    pub fn id(&self) -> u16 {
+        match self {
+            CipherSuite::Aes256Gcm => 0x0001,
+            CipherSuite::Aes256EcbHmacSha256 => 0x0002,
+            CipherSuite::Aes256CbcHmacSha256 => 0x0003,
+            CipherSuite::Aes256CtrHmacSha256 => 0x0004,
+        }
+    }
+
+    pub fn from_id(id: u16) -> Option<CipherSuite> {
+        CipherSuite::all().into_iter().find(|suite| suite.id() == id)
+    }
+
+    // True if the suite is as strong as the minimum or stronger, going by the order of all()
+    pub fn at_least(&self, minimum: CipherSuite) -> bool {
+        let rank = |suite: &CipherSuite| CipherSuite::all().iter().position(|other| other == suite);
+        rank(self) <= rank(&minimum)
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            CipherSuite::Aes256Gcm => "AES-256-GCM",
+            CipherSuite::Aes256EcbHmacSha256 => "AES-256-ECB+HMAC-SHA256",
+            CipherSuite::Aes256CbcHmacSha256 => "AES-256-CBC+HMAC-SHA256",
+            CipherSuite::Aes256CtrHmacSha256 => "AES-256-CTR+HMAC-SHA256",
+        }
+    }
+}
+
+// The server walks its own policy in order of preference and picks the first suite
+// that the client also offered. None means the two sides share no suite.
+pub fn select_suite(policy: &[CipherSuite], offered: &[CipherSuite]) -> Option<CipherSuite> {
+    policy.iter().copied().find(|suite| offered.contains(suite))
+}
+
+// Comma separated suite names, used when printing what was offered
+pub fn describe(suites: &[CipherSuite]) -> String {
+    suites.iter().map(|suite| suite.name()).collect::<Vec<&str>>().join(", ")
+}
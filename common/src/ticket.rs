@@ -0,0 +1,213 @@
+// Session tickets for resuming a stage-5 session without a new DH exchange.
+//
+// After a full handshake both sides expand the final key into a resumption secret. The
+// server seals that secret, the suite and an expiry time under a ticket key only it
+// holds and sends the result to the client, so it keeps no state per session. A client
+// that reconnects presents the ticket with a fresh nonce, the server opens it, and both
+// derive the new final key from the resumption secret and a transcript holding both
+// nonces. Someone who copies a ticket off the wire can not use it without the secret.
+//
+// Ticket layout: key id (4 bytes) | IV (12 bytes) | AES-256-GCM ciphertext | tag, with the
+// key id as additional authenticated data. The plaintext is the suite id (2 bytes), the
+// expiry in seconds since the Unix epoch (8 bytes) and the resumption secret.
+//
+// The ticket key is replaced every rotation interval. Old keys are kept until every
+// ticket they sealed has expired, so rotating does not cut off clients holding a
+// ticket that is still valid. All times come from Random's clock, which stands still in
+// replay mode, so a seeded session issues the same ticket on every run.
+
+use std::time::Duration;
+
+use byteorder::{ByteOrder, BigEndian};
+
+use aes_crypt;
+
+use crate::hex::to_hex;
+use crate::kdf;
+use crate::random::Random;
+use crate::record;
+use crate::secret::SecretBytes;
+use crate::suites::CipherSuite;
+
+pub const RESUMPTION_SECRET_SIZE: usize = 32;
+// Size of the fresh value each side sends in place of a DH public key when resuming
+pub const NONCE_SIZE: usize = 32;
+
+const KEY_ID_SIZE: usize = 4;
+const IV_SIZE: usize = 12;
+const PLAINTEXT_SIZE: usize = 2 + 8 + RESUMPTION_SECRET_SIZE;
+
+#[derive(Clone, Copy, Debug)]
+pub struct TicketPolicy {
+    // How long a ticket can be used for after it was issued
+    pub lifetime: Duration,
+    // How long one ticket key is used to seal new tickets
+    pub rotation_interval: Duration,
+}
+
+impl Default for TicketPolicy {
+    fn default() -> Self {
+        Self {
+            lifetime: Duration::from_secs(12 * 60 * 60),
+            rotation_interval: Duration::from_secs(60 * 60),
+        }
+    }
+}
+
+// Expands the final key of a full or resumed handshake into the secret the next
+// resumption starts from
+pub fn resumption_secret(final_key: &SecretBytes) -> SecretBytes {
+    SecretBytes::new(kdf::expand_label(final_key.expose(), "resumption", &[], RESUMPTION_SECRET_SIZE))
+}
+
+struct TicketKey {
+    id: [u8; KEY_ID_SIZE],
+    key: SecretBytes,
+    // Seconds since the Unix epoch
+    created: u64,
+}
+
+impl TicketKey {
+    fn new(random: &Random) -> Self {
+        let mut id = [0_u8; KEY_ID_SIZE];
+        random.fill(&mut id);
+        Self { id, key: random.aes_key(), created: random.unix_time().as_secs() }
+    }
+
+    fn age(&self, random: &Random) -> Duration {
+        Duration::from_secs(random.unix_time().as_secs().saturating_sub(self.created))
+    }
+}
+
+// The server's ticket keys: the one sealing new tickets and the retired ones that can
+// still open tickets issued earlier
+pub struct TicketKeys {
+    current: TicketKey,
+    retired: Vec<TicketKey>,
+    policy: TicketPolicy,
+    random: Random,
+}
+
+impl TicketKeys {
+    pub fn new(policy: TicketPolicy, random: Random) -> Self {
+        Self { current: TicketKey::new(&random), retired: Vec::new(), policy, random }
+    }
+
+    pub fn policy(&self) -> TicketPolicy {
+        self.policy
+    }
+
+    // Id of the key sealing new tickets, in hex
+    pub fn current_key_id(&self) -> String {
+        to_hex(&self.current.id)
+    }
+
+    // Starts sealing tickets under a new key and forgets keys whose tickets have all expired
+    pub fn rotate(&mut self) {
+        let next = TicketKey::new(&self.random);
+        let retired = std::mem::replace(&mut self.current, next);
+        self.retired.push(retired);
+
+        // A retired key stopped sealing at most one interval after it was created, and the
+        // last ticket it sealed expires one lifetime after that
+        let keep_for = self.policy.rotation_interval + self.policy.lifetime;
+        let random = &self.random;
+        self.retired.retain(|key| key.age(random) < keep_for);
+    }
+
+    // Seals a ticket, rotating first if the current key has been in use for a full interval
+    pub fn issue(&mut self, suite: CipherSuite, resumption_secret: &SecretBytes) -> Vec<u8> {
+        if self.current.age(&self.random) >= self.policy.rotation_interval {
+            self.rotate();
+        }
+
+        let expiry = self.random.unix_time().as_secs() + self.policy.lifetime.as_secs();
+        let mut plaintext = Vec::with_capacity(PLAINTEXT_SIZE);
+        plaintext.extend_from_slice(&suite.id().to_be_bytes());
+        plaintext.extend_from_slice(&expiry.to_be_bytes());
+        plaintext.extend_from_slice(resumption_secret.expose());
+        let plaintext = SecretBytes::new(plaintext);
+
+        let iv = self.random.bytes(IV_SIZE);
+        let (ciphertext, tag) = aes_crypt::encrypt_gcm(plaintext.expose(), &iv, &self.current.id, self.current.key.expose(), record::GCM_TAG_SIZE * 8);
+
+        let mut ticket = self.current.id.to_vec();
+        ticket.extend_from_slice(&iv);
+        ticket.extend_from_slice(&ciphertext);
+        ticket.extend_from_slice(&tag);
+        ticket
+    }
+
+    // The suite and resumption secret sealed in the ticket. None if the ticket was not
+    // sealed by one of our keys, has been tampered with or has expired.
+    pub fn open(&self, ticket: &[u8]) -> Option<(CipherSuite, SecretBytes)> {
+        if ticket.len() != KEY_ID_SIZE + IV_SIZE + PLAINTEXT_SIZE + record::GCM_TAG_SIZE {
+            return None;
+        }
+        let (id, rest) = ticket.split_at(KEY_ID_SIZE);
+        let (iv, rest) = rest.split_at(IV_SIZE);
+        let (ciphertext, tag) = rest.split_at(PLAINTEXT_SIZE);
+
+        let key = std::iter::once(&self.current)
+            .chain(self.retired.iter())
+            .find(|key| key.id == id)?;
+        let plaintext = SecretBytes::new(record::open_gcm(ciphertext, iv, id, tag, key.key.expose())?);
+
+        let suite = CipherSuite::from_id(BigEndian::read_u16(plaintext.expose()))?;
+        let expiry = BigEndian::read_u64(&plaintext.expose()[2..10]);
+        if self.random.unix_time().as_secs() >= expiry {
+            return None;
+        }
+        Some((suite, SecretBytes::new(plaintext.expose()[10..].to_vec())))
+    }
+}
+
+// What a client keeps between connections in order to resume
+pub struct SessionTicket {
+    pub suite: CipherSuite,
+    // Seconds since the Unix epoch after which the server will refuse the ticket
+    pub expiry: u64,
+    pub resumption_secret: SecretBytes,
+    pub ticket: Vec<u8>,
+}
+
+impl SessionTicket {
+    // The body of a NewSessionTicket record is the lifetime in seconds (4 bytes) followed by the ticket
+    pub fn new_session_ticket(lifetime: Duration, ticket: &[u8]) -> Vec<u8> {
+        let mut body = (lifetime.as_secs() as u32).to_be_bytes().to_vec();
+        body.extend_from_slice(ticket);
+        body
+    }
+
+    pub fn from_new_session_ticket(body: &[u8], suite: CipherSuite, resumption_secret: SecretBytes, random: &Random) -> Option<Self> {
+        if body.len() <= 4 {
+            return None;
+        }
+        let lifetime = BigEndian::read_u32(body) as u64;
+        Some(Self { suite, expiry: random.unix_time().as_secs() + lifetime, resumption_secret, ticket: body[4..].to_vec() })
+    }
+
+    pub fn is_expired(&self, random: &Random) -> bool {
+        random.unix_time().as_secs() >= self.expiry
+    }
+
+    // Layout for the ticket file: suite id (2 bytes) | expiry (8 bytes) | resumption
+    // secret | ticket. The secret is stored as it is, so the file must be kept private.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.suite.id().to_be_bytes().to_vec();
+        bytes.extend_from_slice(&self.expiry.to_be_bytes());
+        bytes.extend_from_slice(self.resumption_secret.expose());
+        bytes.extend_from_slice(&self.ticket);
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() <= PLAINTEXT_SIZE {
+            return None;
+        }
+        let suite = CipherSuite::from_id(BigEndian::read_u16(bytes))?;
+        let expiry = BigEndian::read_u64(&bytes[2..10]);
+        let resumption_secret = SecretBytes::new(bytes[10..PLAINTEXT_SIZE].to_vec());
+        Some(Self { suite, expiry, resumption_secret, ticket: bytes[PLAINTEXT_SIZE..].to_vec() })
+    }
+}
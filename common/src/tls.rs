@@ -0,0 +1,146 @@
+// Handshake messages and key schedule for stage 6, which follows the TLS 1.3 handshake
+// (RFC 8446) on top of the stage-5 hellos and record layer:
+//
+//     Client                                         Server
+//     ClientHello (suites, DH public key)  -------->
+//                                          <--------  ServerHello (suite, DH public key)
+//                                                    {CertificateRequest*}
+//                                                    {Certificate}
+//                                                    {CertificateVerify}
+//                                          <--------  {Finished}
+//     {Certificate*}
+//     {CertificateVerify*}
+//     {Finished}                           -------->
+//     [Application Data]                   <------->  [Application Data]
+//
+// Messages in {} are protected under the handshake traffic keys and [] under the
+// application traffic keys. Those marked * are only sent when the server asks for client
+// authentication (identity.rs). A client without a key answers with an empty Certificate
+// and no CertificateVerify. The encrypted handshake messages travel in application data
+// records and start with a type byte, so on the wire they look the same as application
+// data. Every message is added to the transcript, and each secret below is bound to the
+// transcript hash at the point it is derived.
+//
+// The key schedule of RFC 8446 section 7.1, without the PSK input:
+//
+//     early secret     = Extract(0, 0)
+//     handshake secret = Extract(Derive(early secret, "derived"), DH secret)
+//         c hs traffic = Derive(handshake secret, "c hs traffic", ClientHello..ServerHello)
+//         s hs traffic = Derive(handshake secret, "s hs traffic", ClientHello..ServerHello)
+//     master secret    = Extract(Derive(handshake secret, "derived"), 0)
+//         c ap traffic = Derive(master secret, "c ap traffic", ClientHello..server Finished)
+//         s ap traffic = Derive(master secret, "s ap traffic", ClientHello..server Finished)
+
+use bernie_hmac;
+
+use crate::kdf;
+use crate::record::Side;
+use crate::secret::SecretBytes;
+use crate::verify;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HandshakeType {
+    Certificate = 11,
+    CertificateRequest = 13,
+    CertificateVerify = 15,
+    Finished = 20,
+}
+
+impl HandshakeType {
+    pub fn from_u8(value: u8) -> Option<HandshakeType> {
+        match value {
+            11 => Some(HandshakeType::Certificate),
+            13 => Some(HandshakeType::CertificateRequest),
+            15 => Some(HandshakeType::CertificateVerify),
+            20 => Some(HandshakeType::Finished),
+            _ => None,
+        }
+    }
+}
+
+// Layout: type (1 byte) | body
+pub fn encode_message(handshake_type: HandshakeType, body: &[u8]) -> Vec<u8> {
+    let mut message = vec![handshake_type as u8];
+    message.extend_from_slice(body);
+    message
+}
+
+pub fn decode_message(message: &[u8]) -> Option<(HandshakeType, &[u8])> {
+    let (&handshake_type, body) = message.split_first()?;
+    Some((HandshakeType::from_u8(handshake_type)?, body))
+}
+
+// What each side signs in its CertificateVerify: 64 spaces, a context string, a zero
+// byte and the transcript hash, laid out as in RFC 8446 section 4.4.3. The context names
+// the signer, so a server's signature can not be replayed as a client's.
+pub fn certificate_verify_input(signer: Side, transcript_hash: &[u8]) -> Vec<u8> {
+    let mut input = vec![0x20_u8; 64];
+    match signer {
+        Side::Client => input.extend_from_slice(b"seccom, client CertificateVerify"),
+        Side::Server => input.extend_from_slice(b"seccom, server CertificateVerify"),
+    }
+    input.push(0);
+    input.extend_from_slice(transcript_hash);
+    input
+}
+
+// The pair of traffic secrets for one phase of the connection
+pub struct TrafficSecrets {
+    pub client: SecretBytes,
+    pub server: SecretBytes,
+}
+
+pub struct KeySchedule {
+    handshake_secret: SecretBytes,
+    master_secret: SecretBytes,
+}
+
+impl KeySchedule {
+    // Runs the schedule as far as the master secret, which needs nothing but the DH secret
+    pub fn new(shared_secret: &SecretBytes) -> Self {
+        let zeros = [0_u8; kdf::HASH_SIZE];
+        let empty_hash = bernie_hmac::hash(&[]);
+
+        let early_secret = SecretBytes::new(kdf::extract(&[], &zeros));
+        let salt = SecretBytes::new(kdf::expand_label(early_secret.expose(), "derived", &empty_hash, kdf::HASH_SIZE));
+        let handshake_secret = SecretBytes::new(kdf::extract(salt.expose(), shared_secret.expose()));
+
+        let salt = SecretBytes::new(kdf::expand_label(handshake_secret.expose(), "derived", &empty_hash, kdf::HASH_SIZE));
+        let master_secret = SecretBytes::new(kdf::extract(salt.expose(), &zeros));
+
+        Self { handshake_secret, master_secret }
+    }
+
+    // Secrets protecting the encrypted handshake messages of both sides
+    pub fn handshake_traffic(&self, transcript_hash: &[u8]) -> TrafficSecrets {
+        derive_pair(&self.handshake_secret, "c hs traffic", "s hs traffic", transcript_hash)
+    }
+
+    // Secrets protecting application data, bound to the transcript up to the server's Finished
+    pub fn application_traffic(&self, transcript_hash: &[u8]) -> TrafficSecrets {
+        derive_pair(&self.master_secret, "c ap traffic", "s ap traffic", transcript_hash)
+    }
+
+    // Root of the keying material the application can export, over the same transcript
+    pub fn exporter_master(&self, transcript_hash: &[u8]) -> SecretBytes {
+        SecretBytes::new(kdf::expand_label(self.master_secret.expose(), "exp master", transcript_hash, kdf::HASH_SIZE))
+    }
+}
+
+// verify_data = HMAC(finished key, transcript hash), where the finished key is expanded
+// from the sender's handshake traffic secret
+pub fn finished(traffic_secret: &SecretBytes, transcript_hash: &[u8]) -> Vec<u8> {
+    let finished_key = SecretBytes::new(kdf::expand_label(traffic_secret.expose(), "finished", &[], kdf::HASH_SIZE));
+    bernie_hmac::hmac(transcript_hash, finished_key.expose())
+}
+
+pub fn verify_finished(traffic_secret: &SecretBytes, transcript_hash: &[u8], verify_data: &[u8]) -> bool {
+    verify::constant_time_eq(&finished(traffic_secret, transcript_hash), verify_data)
+}
+
+fn derive_pair(secret: &SecretBytes, client_label: &str, server_label: &str, transcript_hash: &[u8]) -> TrafficSecrets {
+    TrafficSecrets {
+        client: SecretBytes::new(kdf::expand_label(secret.expose(), client_label, transcript_hash, kdf::HASH_SIZE)),
+        server: SecretBytes::new(kdf::expand_label(secret.expose(), server_label, transcript_hash, kdf::HASH_SIZE)),
+    }
+}
@@ -0,0 +1,45 @@
+// Tag verification for every stage, and the one way a failed verification is reported.
+//
+// Tags are compared in constant time, so how long a comparison takes says nothing about
+// how many leading bytes of a forged tag were right. aes_crypt and bernie_hmac make no
+// such promise for decrypt_gcm and verify_hmac, so neither is used for checking tags.
+//
+// A message that fails to verify, whether it was too short, carried a bad tag or
+// decrypted to bad padding, gets the same alert after the same delay measured from when
+// it arrived. Neither the wording nor the timing says which check it failed.
+
+use std::thread;
+use std::time::{Duration, Instant};
+
+use bernie_hmac;
+
+// Time from receiving a message to reporting that it failed to verify. Long enough to
+// cover the slowest check in any suite.
+pub const FAILURE_DELAY: Duration = Duration::from_millis(100);
+
+pub const AUTHENTICATION_ALERT: &str = "Authentication failed: Data integrity cannot be verified.";
+
+// Every byte is compared wherever the first difference is. Only the lengths, which are
+// public, can end the comparison early.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    let difference = a.iter().zip(b).fold(0_u8, |difference, (x, y)| difference | (x ^ y));
+
+    // black_box keeps the compiler from turning the fold back into an early exit
+    std::hint::black_box(difference) == 0
+}
+
+pub fn verify_hmac(message: &[u8], tag: &[u8], key: &[u8]) -> bool {
+    constant_time_eq(&bernie_hmac::hmac(message, key), tag)
+}
+
+// Waits until FAILURE_DELAY has passed since the message was received, prints the alert
+// and returns the error that closes the connection
+pub fn authentication_failure(received: Instant) -> std::io::Error {
+    thread::sleep(FAILURE_DELAY.saturating_sub(received.elapsed()));
+    println!("[!] {}", AUTHENTICATION_ALERT);
+    println!("--------------------------------------\n");
+    std::io::Error::new(std::io::ErrorKind::InvalidData, AUTHENTICATION_ALERT)
+}
@@ -0,0 +1,82 @@
+#!/bin/bash
+# Forward secrecy demonstration. Records one stage-5 session against a server with a
+# static DH key and one against a server with ephemeral keys, both holding the same
+# long-term key. The key is then "leaked" and the decryption tool is run on both
+# recordings: the static session opens, the ephemeral one does not. Finally the leaked key
+# is turned against its owner: a relay between a client and each server tries to put its
+# own message in the client's mouth, which only works against the static server.
+#
+# Run from the repository root. Uses ports 9898 (server) and 9797 (wiretap and relay).
+set -e
+
+ROOT=$(pwd)
+WORK=$(mktemp -d)
+SERVERS="$ROOT/target/release/servers"
+CLIENTS="$ROOT/target/release/clients"
+
+cargo build --release -q
+
+# Runs one session: server, wiretap and client, with a few scripted chat lines
+record_session() {
+    local variant=$1
+    echo "=== Recording a session with the $variant server"
+
+    # The sleeps keep stdin open until the processes are killed, since the chat loops
+    # treat end of input as an empty message
+    (sleep 3; echo "Server here, the launch code is 0000"; sleep 8) | "$SERVERS" fs-demo "$variant" "$WORK/server.key" > "$WORK/$variant.server.log" 2>&1 &
+    local server=$!
+    sleep 1
+    "$SERVERS" wiretap 127.0.0.1:9797 127.0.0.1:9898 "$WORK/$variant.capture" > "$WORK/$variant.wiretap.log" 2>&1 &
+    local wiretap=$!
+    sleep 0.5
+    (sleep 2; echo "Client here, my password is hunter2"; sleep 8) | "$CLIENTS" fs-demo 127.0.0.1:9797 > "$WORK/$variant.client.log" 2>&1 &
+    local client=$!
+    sleep 7
+
+    kill $client $server $wiretap 2>/dev/null || true
+    wait 2>/dev/null || true
+    echo "    $(grep -c . "$WORK/$variant.capture") frames recorded to $WORK/$variant.capture"
+}
+
+record_session static
+record_session ephemeral
+
+echo
+echo "=== The server's long-term key leaks"
+cp "$WORK/server.key" "$WORK/leaked.key"
+
+for variant in static ephemeral; do
+    echo
+    echo "=== Decrypting the $variant recording with the leaked key"
+    "$SERVERS" decrypt "$WORK/$variant.capture" "$WORK/leaked.key" || true
+done
+
+# Runs one session through the impersonating relay, which holds only the leaked key
+impersonate_session() {
+    local variant=$1
+    echo
+    echo "=== Impersonating the client to the $variant server with the leaked key"
+
+    (sleep 11) | "$SERVERS" fs-demo "$variant" "$WORK/server.key" > "$WORK/$variant.kci.server.log" 2>&1 &
+    local server=$!
+    sleep 1
+    "$SERVERS" impersonate 127.0.0.1:9797 127.0.0.1:9898 "$WORK/leaked.key" "Client here, please wire the funds to the attacker" > "$WORK/$variant.kci.relay.log" 2>&1 &
+    local relay=$!
+    sleep 0.5
+    (sleep 2; echo "Client here, my password is hunter2"; sleep 8) | "$CLIENTS" fs-demo 127.0.0.1:9797 > "$WORK/$variant.kci.client.log" 2>&1 &
+    local client=$!
+    sleep 5
+
+    kill $client $server $relay 2>/dev/null || true
+    wait 2>/dev/null || true
+    echo "    Client sees:  $(grep -m1 "Short authentication string" "$WORK/$variant.kci.client.log" | sed 's/, type.*//')"
+    echo "    Server sees:  $(grep -m1 "Short authentication string" "$WORK/$variant.kci.server.log" | sed 's/, type.*//')"
+    echo "    Client typed: Client here, my password is hunter2"
+    echo "    Server got:   $(grep -m1 "^Client >" "$WORK/$variant.kci.server.log" | sed 's/^Client > //')"
+}
+
+impersonate_session static
+impersonate_session ephemeral
+
+echo
+echo "Logs, captures and keys are in $WORK"
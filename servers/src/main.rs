@@ -1,32 +1,184 @@
 #![allow(dead_code)]
 #![allow(unused_imports)]
+// The dependencies are imported by name in every module that uses them
+#![allow(clippy::single_component_path_imports)]
 
 mod server1;
 mod server2;
 mod server3;
 mod server4;
+mod server4_etm;
 mod server5;
+mod server5_noise;
+mod server5_pake;
+mod server6;
+
+use common::{certificate, config, drbg, handshake, hex, identity, kdf, keystore, mlkem, modes, noise, proof, random, ratchet, record, sas, secret, sequence, srp, suites, ticket, tls, verify};
+mod wiretap;
+
+use std::sync::Arc;
 
 use crate::server1::Server1;
 use crate::server2::Server2;
 use crate::server3::Server3;
 use crate::server4::Server4;
+use crate::server4_etm::Server4Etm;
 use crate::server5::Server5;
+use crate::server5_noise::Server5Noise;
+use crate::server5_pake::Server5Pake;
+use crate::server6::Server6;
+
+use crate::certificate::CertificateAuthority;
+use crate::config::SessionConfig;
+use crate::identity::ClientAuth;
+use crate::modes::Mode;
+use crate::noise::{NoiseConfig, Pattern};
+use crate::random::Random;
+use crate::srp::Verifier;
 
 
 fn main() {
+    // `servers verify-proofs <file>` checks the signed messages exported to a proof file
+    // instead of starting a stage
+    let args: Vec<String> = std::env::args().collect();
+    if args.get(1).map(String::as_str) == Some("verify-proofs") {
+        let path = args.get(2).expect("verify-proofs takes the path of a proof file");
+        std::process::exit(if proof::verify_proofs(path) { 0 } else { 1 });
+    }
+
+    // `servers keygen ...` manages the encrypted keystores identity keys are kept in
+    if args.get(1).map(String::as_str) == Some("keygen") {
+        std::process::exit(if keystore::keygen(&args[2..]) { 0 } else { 1 });
+    }
+
+    // `servers wiretap <listen address> <server address> <capture file>` records a stage-5
+    // session passing through it and `servers decrypt <capture file> <key file>` tries to
+    // decrypt the recording with a leaked private key, for the forward secrecy demo.
+    // `servers impersonate <listen address> <server address> <key file> <message>` relays a
+    // session and uses the leaked key to put words in the client's mouth.
+    if args.get(1).map(String::as_str) == Some("wiretap") {
+        let (listen_address, server_address, capture_path) = match (args.get(2), args.get(3), args.get(4)) {
+            (Some(listen_address), Some(server_address), Some(capture_path)) => (listen_address, server_address, capture_path),
+            _ => panic!("wiretap takes a listen address, a server address and a capture file"),
+        };
+        wiretap::record(listen_address, server_address, capture_path).expect("Wiretap failed");
+        return;
+    }
+    if args.get(1).map(String::as_str) == Some("decrypt") {
+        let capture_path = args.get(2).expect("decrypt takes a capture file and a key file");
+        let key_path = args.get(3).expect("decrypt takes a capture file and a key file");
+        std::process::exit(if wiretap::decrypt(capture_path, key_path) { 0 } else { 1 });
+    }
+    if args.get(1).map(String::as_str) == Some("impersonate") {
+        let (listen_address, server_address, key_path, message) = match (args.get(2), args.get(3), args.get(4), args.get(5)) {
+            (Some(listen_address), Some(server_address), Some(key_path), Some(message)) => (listen_address, server_address, key_path, message),
+            _ => panic!("impersonate takes a listen address, a server address, a key file and a message"),
+        };
+        wiretap::impersonate(listen_address, server_address, key_path, message).expect("Impersonation failed");
+        return;
+    }
+
+    // One DRBG supplies the randomness for every stage. It is seeded from the operating
+    // system unless --seed <hex> is given, which replays the same session byte for byte
+    let random = match seed_argument() {
+        Some(seed) => Random::seeded(&seed, b"server"),
+        None => Random::new(),
+    };
+    println!("[*] Randomness from {}", random.name());
+    if random.is_seeded() {
+        println!("[!] Replay mode: keys are derived from the --seed value and are not secret");
+    }
+
+    // `servers fs-demo static|ephemeral <key file>` runs stage 5 for the forward secrecy
+    // demo with the long-term key in the file, which is created if needed. The static
+    // variant uses it as the DH key of every session. The ephemeral variant makes a fresh
+    // DH key for each session and only signs messages with it.
+    if args.get(1).map(String::as_str) == Some("fs-demo") {
+        let key_path = args.get(3).expect("fs-demo takes static or ephemeral and a key file");
+        let key_pair = Arc::new(wiretap::demo_key(key_path, &random).expect("Failed to read the key file"));
+        let config = match args.get(2).map(String::as_str) {
+            Some("static") => SessionConfig { static_key: Some(key_pair), ..SessionConfig::default() },
+            Some("ephemeral") => SessionConfig { signing_key: Some(key_pair), ..SessionConfig::default() },
+            _ => panic!("fs-demo takes static or ephemeral and a key file"),
+        };
+        let mut s5_demo = Server5::with_config(9898, config, random.clone());
+        s5_demo.run();
+        return;
+    }
+
     // let mut s1 = Server1::new(8888);
     // s1.run();
 
     // let mut s2 = Server2::new(8899);
     // s2.run();
 
-    // let mut s3 = Server3::new(9988);
+    // let mut s3 = Server3::new(9988, random.clone());
     // s3.run();
 
-    // let mut s4 = Server4::new(9999);
+    // let mut s4 = Server4::new(9999, random.clone());
     // s4.run();
 
-    let mut s5 = Server5::new(9898);
+    // let mut s4_cbc = Server4Etm::new(9990, Mode::Cbc, random.clone());
+    // s4_cbc.run();
+
+    // let mut s4_ctr = Server4Etm::new(9991, Mode::Ctr, random.clone());
+    // s4_ctr.run();
+
+    // Only the verifier is kept, the passphrase is not needed once it has been computed
+    // let verifiers = vec![Verifier::new("operator", b"correct horse battery staple", &random)];
+    // let mut s5_pake = Server5Pake::new(9899, verifiers, random.clone());
+    // s5_pake.run();
+
+    // Signed messages for clients that ask for them, with the key made by
+    // `servers keygen create server.keystore identity`. The client's signed messages are
+    // kept in client_proofs.txt for verify-proofs
+    // let signing_key = keystore::unlock("server.keystore", "identity", &random).expect("Failed to unlock server.keystore");
+    // let config = SessionConfig {
+    //     signing_key: Some(Arc::new(signing_key)),
+    //     proof_file: Some("client_proofs.txt".to_string()),
+    //     ..SessionConfig::default()
+    // };
+    // let mut s5_signed = Server5::with_config(9898, config, random.clone());
+    // s5_signed.run();
+
+    // Noise handshakes. The client needs the server's static public key in advance for NK and IK
+    // let mut noise_config = NoiseConfig::new(Pattern::XX);
+    // noise_config.static_key = Some(random.dh_key_pair());
+    // std::fs::write("noise_server.pub", &noise_config.static_key.as_ref().unwrap().1).expect("Failed to write noise_server.pub");
+    // let mut s5_noise = Server5Noise::new(9897, noise_config, random.clone());
+    // s5_noise.run();
+
+    // TLS 1.3-style handshake. The CA's public key goes to the client, which only accepts
+    // certificates the CA issued for "localhost". The certificate key is kept in
+    // server.keystore like the signing key above
+    // let ca = CertificateAuthority::new("SECCOM Demo CA", &random);
+    // std::fs::write("ca.pub", ca.public_key()).expect("Failed to write ca.pub");
+    // let signing_key = keystore::unlock("server.keystore", "localhost", &random).expect("Failed to unlock server.keystore");
+    // let server_certificate = ca.issue("localhost", &signing_key.1, &random);
+    // let mut s6 = Server6::new(9896, server_certificate, signing_key, random.clone());
+    // s6.run();
+
+    // Stage 6 with client authentication, using the certificate and key above. Either keys
+    // listed in authorized_keys are accepted, added with
+    // `servers keygen authorize authorized_keys alice client.pub` after the client ran
+    // `clients keygen export client.keystore identity client.pub`
+    // let client_auth = ClientAuth::authorized_keys("authorized_keys").expect("Failed to read authorized_keys");
+    // or certificates from a client CA, which here issues the client's certificate for "alice"
+    // let client_ca = CertificateAuthority::new("SECCOM Client CA", &random);
+    // let client_public_key = std::fs::read("client.pub").expect("Failed to read client.pub");
+    // std::fs::write("client.cert", client_ca.issue("alice", &client_public_key, &random).to_bytes()).expect("Failed to write client.cert");
+    // let client_auth = ClientAuth::ClientCa(client_ca.public_key().to_vec());
+    // let mut s6_auth = Server6::new(9896, server_certificate, signing_key, random.clone()).with_client_auth(client_auth);
+    // s6_auth.run();
+
+    let mut s5 = Server5::new(9898, random.clone());
     s5.run()
 }
+
+// The value following --seed, if the flag was given
+fn seed_argument() -> Option<Vec<u8>> {
+    let args: Vec<String> = std::env::args().collect();
+    let position = args.iter().position(|arg| arg == "--seed")?;
+    let seed = args.get(position + 1).and_then(|hex| random::parse_seed(hex));
+    Some(seed.expect("--seed takes a seed in hex"))
+}
@@ -10,6 +10,9 @@ use std::sync::mpsc::{Receiver, Sender};
 use byteorder::{ByteOrder, BigEndian};
 
 
+// Every connected client with the sender for its records and its stream
+type ClientMap = Arc<Mutex<HashMap<String, (mpsc::Sender<Vec<u8>>, TcpStream)>>>;
+
 pub struct Server1 {
     listener: TcpListener,
 }
@@ -37,7 +40,7 @@ impl Server1 {
         // The client_map is wrapped in an Arc and Mutex to allow safe concurrent
         // access from multiple threads, ensuring that updates to the client connections
         // are coordinated across the main and client-handling threads.
-        let client_map: Arc<Mutex<HashMap<String, (mpsc::Sender<Vec<u8>>, TcpStream)>>> = Arc::new(Mutex::new(HashMap::new()));
+        let client_map: ClientMap = Arc::new(Mutex::new(HashMap::new()));
 
 
         // Thread for reading from stdin and sending those bytes to all clients
@@ -11,10 +11,14 @@ use byteorder::{ByteOrder, BigEndian};
 
 use aes_crypt;
 
+use crate::secret::SecretBytes;
+
+// Every connected client with the sender for its records and its stream
+type ClientMap = Arc<Mutex<HashMap<String, (mpsc::Sender<Vec<u8>>, TcpStream)>>>;
 
 pub struct Server2 {
     listener: TcpListener,
-    client_keys: Arc<Mutex<HashMap<String, Vec<u8>>>>,
+    client_keys: Arc<Mutex<HashMap<String, SecretBytes>>>,
 }
 
 
@@ -42,7 +46,7 @@ impl Server2 {
         // The client_map is wrapped in an Arc and Mutex to allow safe concurrent
         // access from multiple threads, ensuring that updates to the client connections
         // are coordinated across the main and client-handling threads.
-        let client_map: Arc<Mutex<HashMap<String, (mpsc::Sender<Vec<u8>>, TcpStream)>>> = Arc::new(Mutex::new(HashMap::new()));
+        let client_map: ClientMap = Arc::new(Mutex::new(HashMap::new()));
 
 
         // Thread for reading from stdin and sending those bytes to all clients
@@ -60,7 +64,7 @@ impl Server2 {
                 for (address, (client_tx, _)) in clients.iter() {
                     if let Some(key) = client_keys.get(address) {
                         // Encrypt the message using the client's key
-                        let encrypted_bytes = aes_crypt::encrypt_ecb(&temp_bytes, key);
+                        let encrypted_bytes = aes_crypt::encrypt_ecb(&temp_bytes, key.expose());
                         let message_length = encrypted_bytes.len() as u32;
                         let mut message_bytes = message_length.to_be_bytes().to_vec();
                         message_bytes.extend(encrypted_bytes);
@@ -115,7 +119,7 @@ impl Server2 {
                             // Decrypt response bytes here later on
 
                             if let Some(key) = client_keys_clone.lock().unwrap().get(&address_clone) {
-                                let decrypted_bytes = aes_crypt::decrypt_ecb(&response_bytes, key);
+                                let decrypted_bytes = aes_crypt::decrypt_ecb(&response_bytes, key.expose());
                                 let message = String::from_utf8_lossy(&decrypted_bytes);
                                 println!("{}", message);
                             }
@@ -132,7 +136,7 @@ impl Server2 {
         stdin_rx: Receiver<Vec<u8>>, 
         client_tx: Sender<Vec<u8>>, 
         address: String, 
-        client_keys: Arc<Mutex<HashMap<String, Vec<u8>>>>
+        client_keys: Arc<Mutex<HashMap<String, SecretBytes>>>
     ) -> Result<(), Error> {
 
         // To store entire messages sent from the client
@@ -188,8 +192,8 @@ impl Server2 {
                         if total_received >= length {
                             // If this is the first message, it is the client sending us the encryption key
                             if first_message {
-                                // Store the key
-                                client_keys.lock().unwrap().insert(address.clone(), dynamic_buffer.clone());
+                                // Store the key. The buffer is moved rather than copied so no stray copy of the key is left behind
+                                client_keys.lock().unwrap().insert(address.clone(), SecretBytes::new(std::mem::take(&mut dynamic_buffer)));
                                 first_message = false;
                             } else {
                                 // Send the complete message (excluding length prefix) to the main thread
@@ -13,22 +13,35 @@ use aes_crypt;
 use dh;
 use bernie_hmac;
 
+use crate::random::Random;
+use crate::sas::{self, Sas};
+use crate::secret::SecretBytes;
+
+
+// Every connected client with the sender for its records and its stream
+type ClientMap = Arc<Mutex<HashMap<String, (mpsc::Sender<Vec<u8>>, TcpStream)>>>;
 
 pub struct Server3 {
     listener: TcpListener,
-    client_keys: Arc<Mutex<HashMap<String, Vec<u8>>>>,
+    client_keys: Arc<Mutex<HashMap<String, SecretBytes>>>,
+    // Short authentication string of each client's session, keyed like client_keys
+    codes: Arc<Mutex<HashMap<String, Sas>>>,
+    random: Random,
 }
 
 
 impl Server3 {
-    pub fn new(port: usize) -> Self {
+    pub fn new(port: usize, random: Random) -> Self {
         let address = format!("0.0.0.0:{}", port);
         let listener = TcpListener::bind(address).expect("Could not bind");
         let client_keys = Arc::new(Mutex::new(HashMap::new()));
+        let codes = Arc::new(Mutex::new(HashMap::new()));
 
         Self {
             listener,
             client_keys,
+            codes,
+            random,
         }
     }
 
@@ -44,25 +57,32 @@ impl Server3 {
         // The client_map is wrapped in an Arc and Mutex to allow safe concurrent
         // access from multiple threads, ensuring that updates to the client connections
         // are coordinated across the main and client-handling threads.
-        let client_map: Arc<Mutex<HashMap<String, (mpsc::Sender<Vec<u8>>, TcpStream)>>> = Arc::new(Mutex::new(HashMap::new()));
+        let client_map: ClientMap = Arc::new(Mutex::new(HashMap::new()));
 
 
         // Thread for reading from stdin and sending those bytes to all clients
         let client_map_clone = Arc::clone(&client_map);
         let client_keys_clone = Arc::clone(&self.client_keys); 
+        let codes_clone = Arc::clone(&self.codes);
         thread::spawn(move || {
             loop {
                 let mut input = String::new();
                 std::io::stdin().read_line(&mut input).unwrap();
                 let temp_bytes = input.as_bytes().to_vec();
 
+                // /verify confirms a client's code rather than being sent to anyone
+                if let Some(target) = sas::parse_verify(&input) {
+                    sas::verify_client(&mut codes_clone.lock().unwrap(), target);
+                    continue;
+                }
+
                 let clients = client_map_clone.lock().unwrap();
                 let client_keys = client_keys_clone.lock().unwrap();
 
                 for (address, (client_tx, _)) in clients.iter() {
                     if let Some(key) = client_keys.get(address) {
                         // Encrypt the message using the client's key
-                        let mut encrypted_bytes = aes_crypt::encrypt_ecb(&temp_bytes, key);
+                        let mut encrypted_bytes = aes_crypt::encrypt_ecb(&temp_bytes, key.expose());
 
                         // Construct message with length header
                         let mut message_bytes = (4_u32 + encrypted_bytes.len() as u32).to_be_bytes().to_vec();
@@ -78,22 +98,17 @@ impl Server3 {
         // Continuously listen for new connections
         for stream in self.listener.incoming() {
             match stream {
-                Ok(mut stream) => {
+                Ok(stream) => {
                     let address = stream.peer_addr().unwrap().to_string();
                     let address_clone = address.clone();
 
                     println!("{} - Connected\n", address);
 
-                    // Generate key pair for this client and send client public key
+                    // Generate key pair for this client. The public key is only sent once the
+                    // client has committed to its own, see sas.rs
                     println!("--------------------------------------");
                     println!("[+] Generating key pair ...");
-                    let key_pair = dh::gen_key_pair();
-
-                    println!("[+] Sending public key to client ...");
-                    let key_length = key_pair.1.len() as u32;
-                    let mut key_message = key_length.to_be_bytes().to_vec();
-                    key_message.extend(key_pair.1.clone());
-                    stream.write_all(&key_message).expect("Failed to send client public key");
+                    let key_pair = self.random.dh_key_pair();
 
                     // Create a new sender for this client which will be used in the stdin thread
                     let (client_stdin_tx, client_stdin_rx) = mpsc::channel::<Vec<u8>>();
@@ -109,14 +124,16 @@ impl Server3 {
 
                     // Client handling thread
                     let client_keys_clone = self.client_keys.clone();
+                    let codes_clone = self.codes.clone();
                     thread::spawn(move || {
-                        if let Err(e) = Self::handle_client(stream, client_stdin_rx, client_tx, address.clone(), client_keys_clone.clone(), key_pair) {
+                        if let Err(e) = Self::handle_client(stream, client_stdin_rx, client_tx, address.clone(), client_keys_clone.clone(), codes_clone.clone(), key_pair) {
                             eprintln!("Error handling client: {:?}", e);
                         }
 
                         // Remove the client from the map upon disconnection as well as their key
                         client_map_clone.lock().unwrap().remove(&address);
                         client_keys_clone.lock().unwrap().remove(&address);
+                        codes_clone.lock().unwrap().remove(&address);
                         println!("{} - Disconnected", address);
                     });
 
@@ -128,7 +145,7 @@ impl Server3 {
                         while let Ok(response_bytes) = client_rx.recv() {
                             // This branch is taken after client sends it public key
                             if let Some(key) = client_keys_clone.lock().unwrap().get(&address_clone) {
-                                let decrypted_bytes = aes_crypt::decrypt_ecb(&response_bytes, key);
+                                let decrypted_bytes = aes_crypt::decrypt_ecb(&response_bytes, key.expose());
                                 let message = String::from_utf8_lossy(&decrypted_bytes);
                                 println!("{}", message);
                             }
@@ -145,8 +162,9 @@ impl Server3 {
         stdin_rx: Receiver<Vec<u8>>, 
         client_tx: Sender<Vec<u8>>, 
         address: String, 
-        client_keys: Arc<Mutex<HashMap<String, Vec<u8>>>>,
-        key_pair: (Vec<u8>, Vec<u8>)
+        client_keys: Arc<Mutex<HashMap<String, SecretBytes>>>,
+        codes: Arc<Mutex<HashMap<String, Sas>>>,
+        key_pair: (SecretBytes, Vec<u8>)
     ) -> Result<(), Error> {
 
         // To store entire messages sent from the client
@@ -161,6 +179,9 @@ impl Server3 {
         // To see if key is being sent 
         let mut first_message = true;
 
+        // Hash of the client's public key, which it sends before it gets to see ours
+        let mut commitment: Option<Vec<u8>> = None;
+
         loop {
             // Non-blocking attempt to receive message from stdin and send to client
             if let Ok(bytes) = stdin_rx.try_recv() {
@@ -202,23 +223,42 @@ impl Server3 {
                     // Check if the entire message has been received
                     if let Some(length) = expected_length {
                         if total_received >= length {
-                            // If this is the first message, it is the client sending us the encryption key
-                            if first_message {
-                                println!("[*] Received client's public key");
+                            // The client's first message commits to its public key, which we answer with ours
+                            if first_message && commitment.is_none() {
+                                println!("[*] Received client's commitment to its public key");
+                                commitment = Some(dynamic_buffer.clone());
+
+                                println!("[+] Sending public key to client ...");
+                                let key_length = key_pair.1.len() as u32;
+                                let mut key_message = key_length.to_be_bytes().to_vec();
+                                key_message.extend(key_pair.1.clone());
+                                stream.write_all(&key_message)?;
+                            } else if first_message {
+                                // The second reveals the public key it committed to
+                                if !sas::opens(commitment.as_ref().unwrap(), &dynamic_buffer) {
+                                    return Err(Error::new(std::io::ErrorKind::InvalidData, "Public key does not match the client's commitment"));
+                                }
+                                println!("[*] Received client's public key, it matches the commitment");
 
                                 // Use the client's public key to compute the shared secret
                                 println!("[+] Calculating shared secret ...");
                                 let modulus = dh::get_domain_params().0;
-                                let shared_secret = dh::get_secret(&dynamic_buffer.clone(), &key_pair.0, &modulus);
+                                let shared_secret = SecretBytes::new(dh::get_secret(&dynamic_buffer, key_pair.0.expose(), &modulus));
 
                                 // Use SHA-256 as the KDF to compute the final key
                                 println!("[+] Using SHA-256 as KDF to compute final key ...");
-                                let final_key = bernie_hmac::hash(&shared_secret);
+                                let final_key = SecretBytes::new(bernie_hmac::hash(shared_secret.expose()));
+
+                                // Both ends show a code from the key and the two public keys. The codes only
+                                // match if nobody in the middle ran a separate exchange with each end.
+                                let sas = Sas::new(&final_key, &sas::key_context(&key_pair.1, &dynamic_buffer));
 
                                 // Add the key to the client_keys HashMap
                                 client_keys.lock().unwrap().insert(address.clone(), final_key);
 
                                 println!("[*] DH Key Exchange Successful.");
+                                println!("[*] Short authentication string {}, type {} {} once the client shows the same", sas.code(), sas::VERIFY_COMMAND, address);
+                                codes.lock().unwrap().insert(address.clone(), sas);
                                 println!("--------------------------------------\n");
 
                                 first_message = false;
@@ -1,6 +1,6 @@
 use std::thread;
 use std::sync::mpsc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use std::sync::{Arc, Mutex};
 use std::io::{Read, Write, Error};
 use std::collections::HashMap;
@@ -13,26 +13,41 @@ use aes_crypt;
 use dh;
 use bernie_hmac;
 
+use crate::random::Random;
+use crate::sas::{self, Sas};
+use crate::secret::SecretBytes;
+use crate::sequence::{self, CLIENT_TO_SERVER, SERVER_TO_CLIENT, SEQUENCE_SIZE};
+use crate::verify;
+
 const MAC_TAG_SIZE: usize = 32;
 
+// Every connected client with the sender for its records and its stream
+type ClientMap = Arc<Mutex<HashMap<String, (mpsc::Sender<Vec<u8>>, TcpStream)>>>;
+
 pub struct Server4 {
     listener: TcpListener,
-    client_map: Arc<Mutex<HashMap<String, (mpsc::Sender<Vec<u8>>, TcpStream)>>>,
-    client_keys: Arc<Mutex<HashMap<String, Vec<u8>>>>,
+    client_map: ClientMap,
+    client_keys: Arc<Mutex<HashMap<String, (SecretBytes, u64)>>>,
+    // Short authentication string of each client's session, keyed like client_keys
+    codes: Arc<Mutex<HashMap<String, Sas>>>,
+    random: Random,
 }
 
 
 impl Server4 {
-    pub fn new(port: usize) -> Self {
+    pub fn new(port: usize, random: Random) -> Self {
         let address = format!("0.0.0.0:{}", port);
         let listener = TcpListener::bind(address).expect("Could not bind");
         let client_map = Arc::new(Mutex::new(HashMap::new()));
         let client_keys = Arc::new(Mutex::new(HashMap::new()));
+        let codes = Arc::new(Mutex::new(HashMap::new()));
 
         Self {
             listener,
             client_map,
             client_keys,
+            codes,
+            random,
         }
     }
 
@@ -53,25 +68,39 @@ impl Server4 {
         // Thread for reading from stdin and sending those bytes to all clients
         let client_map_clone = Arc::clone(&self.client_map);
         let client_keys_clone = Arc::clone(&self.client_keys); 
+        let codes_clone = Arc::clone(&self.codes);
         thread::spawn(move || {
             loop {
                 let mut input = String::new();
                 std::io::stdin().read_line(&mut input).unwrap();
                 let temp_bytes = input.as_bytes().to_vec();
 
+                // /verify confirms a client's code rather than being sent to anyone
+                if let Some(target) = sas::parse_verify(&input) {
+                    sas::verify_client(&mut codes_clone.lock().unwrap(), target);
+                    continue;
+                }
+
                 let clients = client_map_clone.lock().unwrap();
-                let client_keys = client_keys_clone.lock().unwrap();
+                let mut client_keys = client_keys_clone.lock().unwrap();
 
                 for (address, (client_tx, _)) in clients.iter() {
-                    if let Some(key) = client_keys.get(address) {
+                    if let Some((key, next_sequence)) = client_keys.get_mut(address) {
                         // Encrypt the message using the client's key
-                        let mut encrypted_bytes = aes_crypt::encrypt_ecb(&temp_bytes, key);
+                        let mut encrypted_bytes = aes_crypt::encrypt_ecb(&temp_bytes, key.expose());
 
-                        // Compute the MAC tag
-                        let mut mac_tag = bernie_hmac::hmac(&encrypted_bytes, key);
+                        // Take the next sequence number for messages sent to this client
+                        let sequence = *next_sequence;
+                        *next_sequence += 1;
+
+                        // Compute the MAC tag over the direction, sequence number and ciphertext
+                        let mut mac_tag = bernie_hmac::hmac(&sequence::mac_input(SERVER_TO_CLIENT, sequence, &encrypted_bytes), key.expose());
 
                         // Construct message with length header
-                        let mut message_bytes = (4_u32 + encrypted_bytes.len() as u32 + mac_tag.len() as u32).to_be_bytes().to_vec();
+                        let mut message_bytes = (4_u32 + SEQUENCE_SIZE as u32 + encrypted_bytes.len() as u32 + mac_tag.len() as u32).to_be_bytes().to_vec();
+
+                        // Append the sequence number to message
+                        message_bytes.extend_from_slice(&sequence.to_be_bytes());
 
                         // Append encrypted bytes to message
                         message_bytes.append(&mut encrypted_bytes);
@@ -89,22 +118,17 @@ impl Server4 {
         // Continuously listen for new connections
         for stream in self.listener.incoming() {
             match stream {
-                Ok(mut stream) => {
+                Ok(stream) => {
                     let address = stream.peer_addr().unwrap().to_string();
                     let address_clone = address.clone();
 
                     println!("{} - Connected\n", address);
 
-                    // Generate key pair for this client and send client public key
+                    // Generate key pair for this client. The public key is only sent once the
+                    // client has committed to its own, see sas.rs
                     println!("--------------------------------------");
                     println!("[+] Generating key pair ...");
-                    let key_pair = dh::gen_key_pair();
-
-                    println!("[+] Sending public key to client ...");
-                    let key_length = key_pair.1.len() as u32;
-                    let mut key_message = key_length.to_be_bytes().to_vec();
-                    key_message.extend(key_pair.1.clone());
-                    stream.write_all(&key_message).expect("Failed to send client public key");
+                    let key_pair = self.random.dh_key_pair();
 
                     // Create a new sender for this client which will be used in the stdin thread
                     let (client_stdin_tx, client_stdin_rx) = mpsc::channel::<Vec<u8>>();
@@ -120,14 +144,16 @@ impl Server4 {
 
                     // Client handling thread
                     let client_keys_clone = self.client_keys.clone();
+                    let codes_clone = self.codes.clone();
                     thread::spawn(move || {
-                        if let Err(e) = Self::handle_client(stream, client_stdin_rx, client_tx, address.clone(), client_keys_clone.clone(), key_pair) {
+                        if let Err(e) = Self::handle_client(stream, client_stdin_rx, client_tx, address.clone(), client_keys_clone.clone(), codes_clone.clone(), key_pair) {
                             eprintln!("Error handling client: {:?}", e);
                         }
 
                         // Remove the client from the map upon disconnection as well as their key
                         client_map_clone.lock().unwrap().remove(&address);
                         client_keys_clone.lock().unwrap().remove(&address);
+                        codes_clone.lock().unwrap().remove(&address);
                         println!("{} - Disconnected", address);
                     });
 
@@ -138,8 +164,8 @@ impl Server4 {
                         // Attempt to receive any messages sent from the client 
                         while let Ok(response_bytes) = client_rx.recv() {
                             // This branch is taken after client sends it public key
-                            if let Some(key) = client_keys_clone.lock().unwrap().get(&address_clone) {
-                                let decrypted_bytes = aes_crypt::decrypt_ecb(&response_bytes, key);
+                            if let Some((key, _)) = client_keys_clone.lock().unwrap().get(&address_clone) {
+                                let decrypted_bytes = aes_crypt::decrypt_ecb(&response_bytes, key.expose());
                                 let message = String::from_utf8_lossy(&decrypted_bytes);
                                 println!("Client > {}", message);
                             }
@@ -156,8 +182,9 @@ impl Server4 {
         stdin_rx: Receiver<Vec<u8>>, 
         client_tx: Sender<Vec<u8>>, 
         address: String, 
-        client_keys: Arc<Mutex<HashMap<String, Vec<u8>>>>,
-        key_pair: (Vec<u8>, Vec<u8>)
+        client_keys: Arc<Mutex<HashMap<String, (SecretBytes, u64)>>>,
+        codes: Arc<Mutex<HashMap<String, Sas>>>,
+        key_pair: (SecretBytes, Vec<u8>)
     ) -> Result<(), std::io::Error> {
 
         // To store entire messages sent from the client
@@ -172,6 +199,12 @@ impl Server4 {
         // To see if key is being sent 
         let mut first_message = true;
 
+        // Hash of the client's public key, which it sends before it gets to see ours
+        let mut commitment: Option<Vec<u8>> = None;
+
+        // Sequence number of the next message we expect from the client
+        let mut expected_sequence: u64 = 0;
+
         loop {
             // Non-blocking attempt to receive message from stdin and send to client
             if let Ok(bytes) = stdin_rx.try_recv() {
@@ -213,42 +246,75 @@ impl Server4 {
                     // Check if the entire message has been received
                     if let Some(length) = expected_length {
                         if total_received >= length {
-                            // If this is the first message, it is the client sending us the encryption key
-                            if first_message {
-                                println!("[*] Received client's public key");
+                            // A failure to verify is reported a fixed time after this point
+                            let received = Instant::now();
+
+                            // The client's first message commits to its public key, which we answer with ours
+                            if first_message && commitment.is_none() {
+                                println!("[*] Received client's commitment to its public key");
+                                commitment = Some(dynamic_buffer.clone());
+
+                                println!("[+] Sending public key to client ...");
+                                let key_length = key_pair.1.len() as u32;
+                                let mut key_message = key_length.to_be_bytes().to_vec();
+                                key_message.extend(key_pair.1.clone());
+                                stream.write_all(&key_message)?;
+                            } else if first_message {
+                                // The second reveals the public key it committed to
+                                if !sas::opens(commitment.as_ref().unwrap(), &dynamic_buffer) {
+                                    return Err(verify::authentication_failure(received));
+                                }
+                                println!("[*] Received client's public key, it matches the commitment");
 
                                 // Use the client's public key to compute the shared secret
                                 println!("[+] Calculating shared secret ...");
                                 let modulus = dh::get_domain_params().0;
-                                let shared_secret = dh::get_secret(&dynamic_buffer.clone(), &key_pair.0, &modulus);
+                                let shared_secret = SecretBytes::new(dh::get_secret(&dynamic_buffer, key_pair.0.expose(), &modulus));
 
                                 // Use SHA-256 as the KDF to compute the final key
                                 println!("[+] Using SHA-256 as KDF to compute final key ...");
-                                let final_key = bernie_hmac::hash(&shared_secret);
+                                let final_key = SecretBytes::new(bernie_hmac::hash(shared_secret.expose()));
+
+                                // Both ends show a code from the key and the two public keys. The codes only
+                                // match if nobody in the middle ran a separate exchange with each end.
+                                let sas = Sas::new(&final_key, &sas::key_context(&key_pair.1, &dynamic_buffer));
 
-                                // Add the key to the client_keys HashMap
-                                client_keys.lock().unwrap().insert(address.clone(), final_key);
+                                // Add the key to the client_keys HashMap along with the first sequence number to send
+                                client_keys.lock().unwrap().insert(address.clone(), (final_key, 0));
 
                                 println!("[*] DH Key Exchange Successful.");
+                                println!("[*] Short authentication string {}, type {} {} once the client shows the same", sas.code(), sas::VERIFY_COMMAND, address);
+                                codes.lock().unwrap().insert(address.clone(), sas);
                                 println!("--------------------------------------\n");
 
                                 first_message = false;
                             } else {
-                                // Separate the message from the MAC tag
-                                let (payload, received_mac_tag) = dynamic_buffer.split_at(dynamic_buffer.len() - MAC_TAG_SIZE);
-
-                                // Retrieve shared key to verify MAC tag
-                                let keys_lock = client_keys.lock().unwrap();
-                                if let Some(key) = keys_lock.get(&address.clone()) {
-                                    // Verify the MAC tag
-                                    if !bernie_hmac::verify_hmac(&payload, &received_mac_tag, &key) {
-                                        println!("[-] MAC verification failed!");
-                                        return Err(std::io::Error::new(std::io::ErrorKind::Other, "MAC verification failed"));
-                                    } else {
-                                        // Send the complete message (excluding length prefix and MAC tag) to the main thread
-                                        println!("\n\n[+] MAC tag verification successful.");
-                                        println!("--------------------------------------\n");
-                                        client_tx.send(payload.clone().to_vec()).unwrap();
+                                // Separate the sequence number and the message from the MAC tag
+                                if dynamic_buffer.len() < SEQUENCE_SIZE + MAC_TAG_SIZE {
+                                    return Err(verify::authentication_failure(received));
+                                }
+                                let (sequence_bytes, payload_with_tag) = dynamic_buffer.split_at(SEQUENCE_SIZE);
+                                let (payload, received_mac_tag) = payload_with_tag.split_at(payload_with_tag.len() - MAC_TAG_SIZE);
+                                let sequence = BigEndian::read_u64(sequence_bytes);
+
+                                // Retrieve shared key to verify MAC tag, outside the lock so that a
+                                // failure delay does not hold up the other clients
+                                let key = client_keys.lock().unwrap().get(&address).map(|(key, _)| key.duplicate());
+                                if let Some(key) = key {
+                                    // Verify the MAC tag, which also covers the direction and sequence number
+                                    if !verify::verify_hmac(&sequence::mac_input(CLIENT_TO_SERVER, sequence, payload), received_mac_tag, key.expose()) {
+                                        return Err(verify::authentication_failure(received));
+                                    }
+
+                                    // An authentic message is only accepted if it is the next one sent to us
+                                    match sequence::check(sequence, &mut expected_sequence) {
+                                        Ok(()) => {
+                                            // Send the complete message (excluding length prefix, sequence number and MAC tag) to the main thread
+                                            println!("\n\n[+] MAC tag verification successful.");
+                                            println!("--------------------------------------\n");
+                                            client_tx.send(payload.to_vec()).unwrap();
+                                        }
+                                        Err(e) => println!("[-] {}", e),
                                     }
                                 } 
                             }
@@ -266,4 +332,3 @@ impl Server4 {
         Ok(())
     }
 }
-
@@ -0,0 +1,386 @@
+use std::thread;
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+use std::sync::{Arc, Mutex};
+use std::io::{Read, Write, Error};
+use std::collections::HashMap;
+use std::net::{TcpListener, TcpStream};
+use std::sync::mpsc::{Receiver, Sender};
+
+use byteorder::{ByteOrder, BigEndian};
+
+use dh;
+use bernie_hmac;
+
+use crate::modes::{self, Mode, IV_SIZE, MAC_TAG_SIZE};
+use crate::random::Random;
+use crate::sas::{self, Sas};
+use crate::secret::SecretBytes;
+use crate::sequence::{self, CLIENT_TO_SERVER, SERVER_TO_CLIENT, SEQUENCE_SIZE};
+use crate::verify;
+
+// Every connected client with the sender for its records and its stream
+type ClientMap = Arc<Mutex<HashMap<String, (mpsc::Sender<Vec<u8>>, TcpStream)>>>;
+
+// Encryption and MAC keys for each client, with the next sequence number to send it
+type ClientKeys = Arc<Mutex<HashMap<String, (SecretBytes, SecretBytes, u64)>>>;
+
+// Stage 4 with ECB swapped out for CBC or CTR (SP 800-38A). Every message carries a fresh
+// IV and a sequence number, and the MAC is computed over the direction, the sequence
+// number, the length header, the IV and the ciphertext using a key separate from the
+// encryption key.
+pub struct Server4Etm {
+    listener: TcpListener,
+    client_map: ClientMap,
+    client_keys: ClientKeys,
+    // Short authentication string of each client's session, keyed like client_keys
+    codes: Arc<Mutex<HashMap<String, Sas>>>,
+    mode: Mode,
+    random: Random,
+}
+
+// One client's connection as its thread sees it: the stream, the records sealed for the
+// client by the stdin thread and the channel its messages go to the main thread on
+struct Connection {
+    stream: TcpStream,
+    stdin_rx: Receiver<Vec<u8>>,
+    client_tx: Sender<Vec<u8>>,
+    address: String,
+}
+
+
+impl Server4Etm {
+    pub fn new(port: usize, mode: Mode, random: Random) -> Self {
+        let address = format!("0.0.0.0:{}", port);
+        let listener = TcpListener::bind(address).expect("Could not bind");
+        let client_map = Arc::new(Mutex::new(HashMap::new()));
+        let client_keys = Arc::new(Mutex::new(HashMap::new()));
+        let codes = Arc::new(Mutex::new(HashMap::new()));
+
+        Self {
+            listener,
+            client_map,
+            client_keys,
+            codes,
+            mode,
+            random,
+        }
+    }
+
+    pub fn run(&mut self) {
+        println!("Listening for incoming connections...");
+
+        // The client_map is a hashmap that manages the active client connections.
+        // It is keyed by the client's address (as a String) and contains a tuple
+        // consisting of:
+        // - An mpsc::Sender<String> for the main-to-client channel, allowing the main
+        //   thread to send specific messages to individual clients.
+        // - A TcpStream, representing the connection to that specific client.
+        // The client_map is wrapped in an Arc and Mutex to allow safe concurrent
+        // access from multiple threads, ensuring that updates to the client connections
+        // are coordinated across the main and client-handling threads
+
+
+        // Thread for reading from stdin and sending those bytes to all clients
+        let client_map_clone = Arc::clone(&self.client_map);
+        let client_keys_clone = Arc::clone(&self.client_keys); 
+        let codes_clone = Arc::clone(&self.codes);
+        let mode = self.mode;
+        let random = self.random.clone();
+        thread::spawn(move || {
+            loop {
+                let mut input = String::new();
+                std::io::stdin().read_line(&mut input).unwrap();
+                let temp_bytes = input.as_bytes().to_vec();
+
+                // /verify confirms a client's code rather than being sent to anyone
+                if let Some(target) = sas::parse_verify(&input) {
+                    sas::verify_client(&mut codes_clone.lock().unwrap(), target);
+                    continue;
+                }
+
+                let clients = client_map_clone.lock().unwrap();
+                let mut client_keys = client_keys_clone.lock().unwrap();
+
+                for (address, (client_tx, _)) in clients.iter() {
+                    if let Some((encryption_key, mac_key, next_sequence)) = client_keys.get_mut(address) {
+                        // Generate a new IV for each message
+                        println!("\n--------------------------------------");
+                        println!("[+] Generating unique IV ...");
+                        let mut iv = modes::generate_iv(&random);
+
+                        // Encrypt the message using the client's encryption key
+                        println!("[+] Encrypting {} bytes with {} ...", temp_bytes.len(), mode.name());
+                        let mut encrypted_bytes = modes::encrypt(mode, &temp_bytes, &iv, encryption_key.expose());
+
+                        // Take the next sequence number for messages sent to this client
+                        let sequence = *next_sequence;
+                        *next_sequence += 1;
+
+                        // Construct the length header
+                        let header = (4_u32 + SEQUENCE_SIZE as u32 + iv.len() as u32 + encrypted_bytes.len() as u32 + MAC_TAG_SIZE as u32).to_be_bytes().to_vec();
+
+                        // Compute the MAC tag over the direction, sequence number, header, IV and ciphertext
+                        println!("[+] Computing HMAC-SHA256 over direction, record #{}, header, IV and ciphertext ...", sequence);
+                        println!("--------------------------------------");
+                        let mut authenticated = sequence::mac_prefix(SERVER_TO_CLIENT, sequence);
+                        authenticated.extend_from_slice(&header);
+                        let mut mac_tag = modes::compute_tag(&authenticated, &iv, &encrypted_bytes, mac_key.expose());
+
+                        // Construct message from the header
+                        let mut message_bytes = header;
+
+                        // Append the sequence number to message
+                        message_bytes.extend_from_slice(&sequence.to_be_bytes());
+
+                        // Append IV to message
+                        message_bytes.append(&mut iv);
+
+                        // Append encrypted bytes to message
+                        message_bytes.append(&mut encrypted_bytes);
+
+                        // Append MAC tag to message
+                        message_bytes.append(&mut mac_tag);
+
+                        // Send message_bytes through the stdin channel
+                        client_tx.send(message_bytes).unwrap();
+                    }
+                }
+            }
+        });
+
+        // Continuously listen for new connections
+        for stream in self.listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let address = stream.peer_addr().unwrap().to_string();
+
+                    println!("{} - Connected\n", address);
+
+                    // Generate key pair for this client. The public key is only sent once the
+                    // client has committed to its own, see sas.rs
+                    println!("--------------------------------------");
+                    println!("[+] Generating key pair ...");
+                    let key_pair = self.random.dh_key_pair();
+
+                    // Create a new sender for this client which will be used in the stdin thread
+                    let (client_stdin_tx, client_stdin_rx) = mpsc::channel::<Vec<u8>>();
+
+                    // Create channel for communicating from client thread to main thread
+                    let (client_tx, client_rx) = mpsc::channel::<Vec<u8>>();
+
+                    // Create reference to the shared client_map 
+                    let client_map_clone = Arc::clone(&self.client_map);
+
+                    // Add new entry to the hashmap including the sender for the command channel and the client's TcpStream
+                    client_map_clone.lock().unwrap().insert(address.clone(), (client_stdin_tx, stream.try_clone().unwrap()));
+
+                    // Client handling thread
+                    let client_keys_clone = self.client_keys.clone();
+                    let codes_clone = self.codes.clone();
+                    let mode = self.mode;
+                    thread::spawn(move || {
+                        let connection = Connection { stream, stdin_rx: client_stdin_rx, client_tx, address: address.clone() };
+                        if let Err(e) = Self::handle_client(connection, client_keys_clone.clone(), codes_clone.clone(), key_pair, mode) {
+                            eprintln!("Error handling client: {:?}", e);
+                        }
+
+                        // Remove the client from the map upon disconnection as well as their key
+                        client_map_clone.lock().unwrap().remove(&address);
+                        client_keys_clone.lock().unwrap().remove(&address);
+                        codes_clone.lock().unwrap().remove(&address);
+                        println!("{} - Disconnected", address);
+                    });
+
+                    // Creating a "Main" thread for each client. Creating the thread here inside 
+                    // the match statement allows each client to get its own dedicated comms thread
+                    thread::spawn(move || {
+                        // Attempt to receive any messages sent from the client 
+                        while let Ok(response_bytes) = client_rx.recv() {
+                            // Response bytes will already have been verified and decrypted in handle_client
+                            let message = String::from_utf8_lossy(&response_bytes);
+                            println!("Client > {}", message);
+                        }
+                    });
+                }
+                Err(e) => eprintln!("Failed to accept a client: {}", e),
+            }
+        }
+    }
+
+    fn handle_client(
+        connection: Connection,
+        client_keys: ClientKeys,
+        codes: Arc<Mutex<HashMap<String, Sas>>>,
+        key_pair: (SecretBytes, Vec<u8>),
+        mode: Mode
+    ) -> Result<(), std::io::Error> {
+        let Connection { mut stream, stdin_rx, client_tx, address } = connection;
+
+        // To store entire messages sent from the client
+        let mut dynamic_buffer = Vec::new();
+
+        // Client messages will be prepended with the total length of the message
+        let mut expected_length: Option<usize> = None;
+
+        // To keep track of how many bytes out of the expected lenth have been received
+        let mut total_received = 0;
+
+        // To see if key is being sent 
+        let mut first_message = true;
+
+        // Hash of the client's public key, which it sends before it gets to see ours
+        let mut commitment: Option<Vec<u8>> = None;
+
+        // Sequence number of the next message we expect from the client
+        let mut expected_sequence: u64 = 0;
+
+        loop {
+            // Non-blocking attempt to receive message from stdin and send to client
+            if let Ok(bytes) = stdin_rx.try_recv() {
+                // This method will continuously call write until there is no more data
+                // to be written or an ErrorKind::Iterrupted occurs.
+                stream.write_all(&bytes)?;
+            }
+
+            // Allows for 1 second of blocking while trying to read from the stream
+            stream.set_read_timeout(Some(Duration::new(1, 0)))?;
+
+            // The client will be sending a message whose total length will be prepended to the message
+            // as the first 4 bytes. If the client's message is larger than the segment size we are expecting
+            // we will continue to read in bytes until we have read in the message length's number of bytes
+
+            // Temporary buffer
+            let mut buffer = [0_u8; 512];
+
+            // Attempt to read bytes sent from client
+            match stream.read(&mut buffer) {
+                Ok(0) => break, // Connection closed by client
+                Ok(bytes_read) => {
+                    // Read the length prefix if it's not already set and we have enough bytes
+                    if expected_length.is_none() && bytes_read >= 4 {
+                        // read_u32 will only read in the first 4 bytes
+                        expected_length = Some(BigEndian::read_u32(&buffer) as usize);
+
+                        // Ensure only the part of the buffer after the length prefix is added
+                        if bytes_read > 4 {
+                            dynamic_buffer.extend_from_slice(&buffer[4..bytes_read]);
+                        }
+                    } else {
+                        // For subsequent segments, add the entire buffer content
+                        dynamic_buffer.extend_from_slice(&buffer[..bytes_read]);
+                    }
+                    
+                    total_received += bytes_read;
+
+                    // Check if the entire message has been received
+                    if let Some(length) = expected_length {
+                        if total_received >= length {
+                            // A failure to verify is reported a fixed time after this point
+                            let received = Instant::now();
+
+                            // The client's first message commits to its public key, which we answer with ours
+                            if first_message && commitment.is_none() {
+                                println!("[*] Received client's commitment to its public key");
+                                commitment = Some(dynamic_buffer.clone());
+
+                                println!("[+] Sending public key to client ...");
+                                let key_length = key_pair.1.len() as u32;
+                                let mut key_message = key_length.to_be_bytes().to_vec();
+                                key_message.extend(key_pair.1.clone());
+                                stream.write_all(&key_message)?;
+                            } else if first_message {
+                                // The second reveals the public key it committed to
+                                if !sas::opens(commitment.as_ref().unwrap(), &dynamic_buffer) {
+                                    return Err(verify::authentication_failure(received));
+                                }
+                                println!("[*] Received client's public key, it matches the commitment");
+
+                                // Use the client's public key to compute the shared secret
+                                println!("[+] Calculating shared secret ...");
+                                let modulus = dh::get_domain_params().0;
+                                let shared_secret = SecretBytes::new(dh::get_secret(&dynamic_buffer, key_pair.0.expose(), &modulus));
+
+                                // Use SHA-256 as the KDF to compute the final key
+                                println!("[+] Using SHA-256 as KDF to compute final key ...");
+                                let final_key = SecretBytes::new(bernie_hmac::hash(shared_secret.expose()));
+
+                                // Both ends show a code from the key and the two public keys. The codes only
+                                // match if nobody in the middle ran a separate exchange with each end.
+                                let sas = Sas::new(&final_key, &sas::key_context(&key_pair.1, &dynamic_buffer));
+
+                                // Split the final key into separate encryption and MAC keys
+                                println!("[+] Deriving separate encryption and MAC keys ...");
+                                let etm_keys = modes::derive_etm_keys(final_key.expose());
+
+                                // Add the keys to the client_keys HashMap along with the first sequence number to send
+                                let (encryption_key, mac_key) = etm_keys;
+                                client_keys.lock().unwrap().insert(address.clone(), (encryption_key, mac_key, 0));
+
+                                println!("[*] DH Key Exchange Successful.");
+                                println!("[*] Short authentication string {}, type {} {} once the client shows the same", sas.code(), sas::VERIFY_COMMAND, address);
+                                codes.lock().unwrap().insert(address.clone(), sas);
+                                println!("--------------------------------------\n");
+
+                                first_message = false;
+                            } else {
+                                // Separate the sequence number, the IV and the ciphertext from the MAC tag
+                                println!("--------------------------------------");
+                                println!("[+] {} bytes received.", total_received);
+                                if dynamic_buffer.len() < SEQUENCE_SIZE + IV_SIZE + MAC_TAG_SIZE {
+                                    return Err(verify::authentication_failure(received));
+                                }
+                                let (sequence_bytes, rest) = dynamic_buffer.split_at(SEQUENCE_SIZE);
+                                let (iv, payload_with_tag) = rest.split_at(IV_SIZE);
+                                let (payload, received_mac_tag) = payload_with_tag.split_at(payload_with_tag.len() - MAC_TAG_SIZE);
+                                let sequence = BigEndian::read_u64(sequence_bytes);
+
+                                // The MAC also covers the direction and the length header which was stripped off the buffer
+                                let mut authenticated = sequence::mac_prefix(CLIENT_TO_SERVER, sequence);
+                                authenticated.extend_from_slice(&(length as u32).to_be_bytes());
+
+                                // Retrieve shared keys to verify MAC tag. The lock is not held while a
+                                // failure is reported, so other clients are not kept waiting.
+                                let keys = client_keys.lock().unwrap().get(&address).map(|(encryption_key, mac_key, _)| (encryption_key.duplicate(), mac_key.duplicate()));
+                                if let Some((encryption_key, mac_key)) = keys {
+                                    // Verify the MAC tag before touching the ciphertext
+                                    if !modes::verify_tag(&authenticated, iv, payload, received_mac_tag, mac_key.expose()) {
+                                        return Err(verify::authentication_failure(received));
+                                    }
+                                    println!("[+] MAC tag verification successful.");
+
+                                    // An authentic message is only accepted if it is the next one sent to us
+                                    if let Err(e) = sequence::check(sequence, &mut expected_sequence) {
+                                        println!("[-] {}", e);
+                                        println!("--------------------------------------\n");
+                                    } else {
+                                        // Only now is the ciphertext decrypted
+                                        println!("[+] Decrypting record #{} ({} bytes) with {} ...", sequence, payload.len(), mode.name());
+                                        match modes::decrypt(mode, payload, iv, encryption_key.expose()) {
+                                            Some(message) => {
+                                                // Send the decrypted message to the main thread
+                                                println!("--------------------------------------\n");
+                                                client_tx.send(message).unwrap();
+                                            }
+                                            None => {
+                                                return Err(verify::authentication_failure(received));
+                                            }
+                                        }
+                                    }
+                                }
+                            }
+
+                            dynamic_buffer.clear();
+                            expected_length = None;
+                            total_received = 0;
+                        }
+                    }
+                }
+                Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => continue,
+                Err(_) => break, // Error or disconnection has occured
+            }
+        }
+        Ok(())
+    }
+}
+
@@ -1,42 +1,101 @@
 use std::thread;
 use std::sync::mpsc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use std::sync::{Arc, Mutex};
 use std::io::{Read, Write, Error};
 use std::collections::HashMap;
 use std::net::{TcpListener, TcpStream};
 use std::sync::mpsc::{Receiver, Sender};
 
-use byteorder::{ByteOrder, BigEndian};
-use rand::{Rng, thread_rng};
-
-use aes_crypt;
 use dh;
 
-const MAC_TAG_SIZE: usize = 16; // 16 bytes or 128 bits
-const IV_SIZE: usize = 12; // 12 bytes or 96 bits
+use crate::config::SessionConfig;
+use crate::handshake::{self, ClientHello, ServerHello, Transcript};
+use crate::mlkem;
+use crate::proof::{self, SignedMessage};
+use crate::ratchet::Ratchet;
+use crate::random::Random;
+use crate::secret::SecretBytes;
+use crate::sas::{self, Sas};
+use crate::record::{self, ContentType, PaddingPolicy, Record, RecordError, RecordLayer, Side};
+use crate::suites::{self, CipherSuite};
+use crate::ticket::{self, SessionTicket, TicketKeys};
+use crate::verify;
+
+// Every connected client with the sender for its records and its stream
+type ClientMap = Arc<Mutex<HashMap<String, (mpsc::Sender<Vec<u8>>, TcpStream)>>>;
 
 pub struct Server5 {
     listener: TcpListener,
-    client_map: Arc<Mutex<HashMap<String, (mpsc::Sender<Vec<u8>>, TcpStream)>>>,
-    client_keys: Arc<Mutex<HashMap<String, Vec<u8>>>>,
+    client_map: ClientMap,
+    client_keys: Arc<Mutex<HashMap<String, RecordLayer>>>,
+    // Short authentication string of each client's session, keyed like client_keys
+    codes: Arc<Mutex<HashMap<String, Sas>>>,
+    config: SessionConfig,
+    // Shared by every client thread so that tickets issued on one connection open on another
+    tickets: Arc<Mutex<TicketKeys>>,
+    random: Random,
+}
+
+// One client's connection as its thread sees it: the stream, the records sealed for the
+// client by the stdin thread and the channel its messages go to the main thread on
+struct Connection {
+    stream: TcpStream,
+    stdin_rx: Receiver<Vec<u8>>,
+    client_tx: Sender<Vec<u8>>,
+    address: String,
+}
+
+// A full handshake whose ServerHello has gone out but whose client key is still hidden
+// behind the commitment in the ClientHello
+struct PendingExchange {
+    suite: CipherSuite,
+    ratchet: bool,
+    // The ML-KEM secret encapsulated to the client, for the hybrid exchange
+    kem_secret: Option<SecretBytes>,
+    signed: bool,
+    gcm_tag_size: usize,
+    padded: bool,
+    key_pair: (SecretBytes, Vec<u8>),
+    commitment: Vec<u8>,
 }
 
 
 impl Server5 {
-    pub fn new(port: usize) -> Self {
+    pub fn new(port: usize, random: Random) -> Self {
+        Self::with_config(port, SessionConfig::default(), random)
+    }
+
+    // The policy lists the suites this server is willing to use, most preferred first
+    pub fn with_policy(port: usize, policy: Vec<CipherSuite>, random: Random) -> Self {
+        Self::with_config(port, SessionConfig { suites: policy, ..SessionConfig::default() }, random)
+    }
+
+    pub fn with_config(port: usize, config: SessionConfig, random: Random) -> Self {
         let address = format!("0.0.0.0:{}", port);
         let listener = TcpListener::bind(address).expect("Could not bind");
         let client_map = Arc::new(Mutex::new(HashMap::new()));
         let client_keys = Arc::new(Mutex::new(HashMap::new()));
+        let codes = Arc::new(Mutex::new(HashMap::new()));
+        let tickets = Arc::new(Mutex::new(TicketKeys::new(config.tickets, random.clone())));
 
         Self {
             listener,
             client_map,
             client_keys,
+            codes,
+            config,
+            tickets,
+            random,
         }
     }
 
+    // Keying material exported from the session with the client at this address, None
+    // if no handshake with it has finished
+    pub fn export_keying_material(&self, address: &str, label: &str, context: &[u8], length: usize) -> Option<SecretBytes> {
+        self.client_keys.lock().unwrap().get(address)?.export_keying_material(label, context, length)
+    }
+
     pub fn run(&mut self) {
         println!("Listening for incoming connections...");
 
@@ -54,44 +113,46 @@ impl Server5 {
         // Thread for reading from stdin and sending those bytes to all clients
         let client_map_clone = Arc::clone(&self.client_map);
         let client_keys_clone = Arc::clone(&self.client_keys); 
+        let codes_clone = Arc::clone(&self.codes);
+        let signing_key = self.config.signing_key.clone();
+        let random = self.random.clone();
         thread::spawn(move || {
             loop {
                 let mut input = String::new();
                 std::io::stdin().read_line(&mut input).unwrap();
                 let temp_bytes = input.as_bytes().to_vec();
 
+                // /verify confirms a client's code rather than being sent to anyone
+                if let Some(target) = sas::parse_verify(&input) {
+                    sas::verify_client(&mut codes_clone.lock().unwrap(), target);
+                    continue;
+                }
+
                 let clients = client_map_clone.lock().unwrap();
-                let client_keys = client_keys_clone.lock().unwrap();
+                let mut client_keys = client_keys_clone.lock().unwrap();
 
                 for (address, (client_tx, _)) in clients.iter() {
-                    if let Some(key) = client_keys.get(address) {
-                        // Generate a new IV for each message
+                    if let Some(record_layer) = client_keys.get_mut(address) {
+                        // Protect the message with the suite negotiated for this client
                         println!("\n--------------------------------------");
-                        println!("[+] Generating unique IV ...");
-                        let mut iv = generate_iv();
-
-                        // Initialize the additional authenticated data (if any)
-                        let aad: Vec<u8> = Vec::new();
-
-                        // Encrypt the message and retreive the ciphertext and authentication tag
-                        println!("[+] Encrypting {} bytes with AES-256-GCM ...", temp_bytes.len());
+                        let mut sequence = record_layer.write_sequence();
+                        if record_layer.update_due() {
+                            println!("[+] Sending KeyUpdate as record #{} and ratcheting sending key to epoch {} ...", sequence, record_layer.write_epoch() + 1);
+                            sequence += 1;
+                        }
+                        // Clients that asked for signed messages get the message signed for their record
+                        let mut message = temp_bytes.clone();
+                        if let Some(key_pair) = signing_key.as_ref().filter(|_| record_layer.signed_messages()) {
+                            println!("[+] Signing message for record #{} with key {} ...", sequence, proof::fingerprint(&key_pair.1));
+                            message = SignedMessage::sign(Side::Server, sequence, record_layer.channel_binding().unwrap().expose(), &temp_bytes, key_pair, &random).to_bytes();
+                        }
+                        println!("[+] Encrypting {} bytes as record #{} with {} ...", message.len(), sequence, record_layer.suite().name());
                         println!("--------------------------------------");
-                        let (mut encrypted_bytes, mut auth_tag) = aes_crypt::encrypt_gcm(&temp_bytes, &iv, &aad, key, MAC_TAG_SIZE * 8);
-
-                        // Construct message with length header
-                        let mut message_bytes = (4_u32 + encrypted_bytes.len() as u32 + auth_tag.len() as u32 + IV_SIZE as u32).to_be_bytes().to_vec();
-
-                        // Append encrypted bytes to message
-                        message_bytes.append(&mut encrypted_bytes);
-
-                        // Append authentication tag to message
-                        message_bytes.append(&mut auth_tag);
-
-                        // Append the IV to the the message
-                        message_bytes.append(&mut iv);
-
-                        // Send message_bytes through the stdin channel
-                        client_tx.send(message_bytes).unwrap();
+                        match record_layer.seal(&message) {
+                            // Send message_bytes through the stdin channel
+                            Ok(message_bytes) => client_tx.send(message_bytes).unwrap(),
+                            Err(e) => println!("[!] Could not send to {}: {}", address, e),
+                        }
                     }
                 }
             }
@@ -100,22 +161,11 @@ impl Server5 {
         // Continuously listen for new connections
         for stream in self.listener.incoming() {
             match stream {
-                Ok(mut stream) => {
+                Ok(stream) => {
                     let address = stream.peer_addr().unwrap().to_string();
 
                     println!("{} - Connected\n", address);
 
-                    // Generate key pair for this client and send client public key
-                    println!("--------------------------------------");
-                    println!("[+] Generating key pair ...");
-                    let key_pair = dh::gen_key_pair();
-
-                    println!("[+] Sending public key to client ...");
-                    let key_length = key_pair.1.len() as u32;
-                    let mut key_message = key_length.to_be_bytes().to_vec();
-                    key_message.extend(key_pair.1.clone());
-                    stream.write_all(&key_message).expect("Failed to send client public key");
-
                     // Create a new sender for this client which will be used in the stdin thread
                     let (client_stdin_tx, client_stdin_rx) = mpsc::channel::<Vec<u8>>();
 
@@ -130,14 +180,20 @@ impl Server5 {
 
                     // Client handling thread
                     let client_keys_clone = self.client_keys.clone();
+                    let codes_clone = self.codes.clone();
+                    let config = self.config.clone();
+                    let tickets = self.tickets.clone();
+                    let random = self.random.clone();
                     thread::spawn(move || {
-                        if let Err(e) = Self::handle_client(stream, client_stdin_rx, client_tx, address.clone(), client_keys_clone.clone(), key_pair) {
+                        let connection = Connection { stream, stdin_rx: client_stdin_rx, client_tx, address: address.clone() };
+                        if let Err(e) = Self::handle_client(connection, client_keys_clone.clone(), codes_clone.clone(), config, tickets, random) {
                             eprintln!("Error handling client: {:?}", e);
                         }
 
                         // Remove the client from the map upon disconnection as well as their key
                         client_map_clone.lock().unwrap().remove(&address);
                         client_keys_clone.lock().unwrap().remove(&address);
+                        codes_clone.lock().unwrap().remove(&address);
                         println!("{} - Disconnected", address);
                     });
 
@@ -158,25 +214,34 @@ impl Server5 {
     }
 
     fn handle_client(
-        mut stream: TcpStream, 
-        stdin_rx: Receiver<Vec<u8>>, 
-        client_tx: Sender<Vec<u8>>, 
-        address: String, 
-        client_keys: Arc<Mutex<HashMap<String, Vec<u8>>>>,
-        key_pair: (Vec<u8>, Vec<u8>)
+        connection: Connection,
+        client_keys: Arc<Mutex<HashMap<String, RecordLayer>>>,
+        codes: Arc<Mutex<HashMap<String, Sas>>>,
+        config: SessionConfig,
+        tickets: Arc<Mutex<TicketKeys>>,
+        random: Random
     ) -> Result<(), std::io::Error> {
+        let Connection { mut stream, stdin_rx, client_tx, address } = connection;
 
-        // To store entire messages sent from the client
+        // Bytes received from the client which have not yet been handled. Every frame is
+        // prepended with its total length, so a frame may arrive split across several
+        // reads or several frames may arrive in a single read
         let mut dynamic_buffer = Vec::new();
 
-        // Client messages will be prepended with the total length of the message
-        let mut expected_length: Option<usize> = None;
+        // Every handshake message sent or received, in order
+        let mut transcript = Transcript::new();
 
-        // To keep track of how many bytes out of the expected lenth have been received
-        let mut total_received = 0;
+        // Key the client signs its messages with, once the first one has arrived
+        let mut client_signing_key = None;
 
-        // To see if key is being sent 
-        let mut first_message = true;
+        // The client's Finished we are waiting for, which must arrive before any data
+        let mut expected_finished: Option<Vec<u8>> = None;
+
+        // Secret for the session ticket, which is only issued once that Finished checks out
+        let mut ticket_secret: Option<SecretBytes> = None;
+
+        // A full handshake waiting for the client to reveal its public key
+        let mut pending: Option<PendingExchange> = None;
 
         loop {
             // Non-blocking attempt to receive message from stdin and send to client
@@ -189,10 +254,6 @@ impl Server5 {
             // Allows for 1 second of blocking while trying to read from the stream
             stream.set_read_timeout(Some(Duration::new(1, 0)))?;
 
-            // The client will be sending a message whose total length will be prepended to the message
-            // as the first 4 bytes. If the client's message is larger than the segment size we are expecting
-            // we will continue to read in bytes until we have read in the message length's number of bytes
-
             // Temporary buffer
             let mut buffer = [0_u8; 512];
 
@@ -200,78 +261,304 @@ impl Server5 {
             match stream.read(&mut buffer) {
                 Ok(0) => break, // Connection closed by client
                 Ok(bytes_read) => {
-                    // Read the length prefix if it's not already set and we have enough bytes
-                    if expected_length.is_none() && bytes_read >= 4 {
-                        // read_u32 will only read in the first 4 bytes
-                        expected_length = Some(BigEndian::read_u32(&buffer) as usize);
-
-                        // Ensure only the part of the buffer after the length prefix is added
-                        if bytes_read > 4 {
-                            dynamic_buffer.extend_from_slice(&buffer[4..bytes_read]);
-                        }
-                    } else {
-                        // For subsequent segments, add the entire buffer content
-                        dynamic_buffer.extend_from_slice(&buffer[..bytes_read]);
-                    }
-                    
-                    total_received += bytes_read;
+                    dynamic_buffer.extend_from_slice(&buffer[..bytes_read]);
+
+                    // Handle every complete frame received so far
+                    loop {
+                        // A failure to verify is reported a fixed time after this point
+                        let received = Instant::now();
+
+                        // A frame that can not be parsed, too long or with an unknown type,
+                        // fails the same way as a record that does not verify
+                        let frame = match record::take_frame(&mut dynamic_buffer) {
+                            Ok(Some(frame)) => frame,
+                            Ok(None) => break,
+                            Err(_) => {
+                                let error = verify::authentication_failure(received);
+                                stream.write_all(&record::alert_frame())?;
+                                return Err(error);
+                            }
+                        };
+
+                        match frame.content_type {
+                            // The first message is the client listing its suites and committing to its public key
+                            ContentType::ClientHello => {
+                                if client_keys.lock().unwrap().contains_key(&address) || pending.is_some() {
+                                    return Err(std::io::Error::other("Unexpected ClientHello"));
+                                }
+
+                                let client_hello = ClientHello::from_bytes(&frame.body)
+                                    .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidData, "Malformed ClientHello"))?;
+                                transcript.add(&frame.body);
+                                println!("--------------------------------------");
+                                println!("[*] Received ClientHello offering: {}", suites::describe(&client_hello.suites));
+
+                                // A returning client can skip the DH exchange if we can open its ticket
+                                if let Some(session_ticket) = &client_hello.ticket {
+                                    println!("[*] Client presented a session ticket");
+                                    let opened = tickets.lock().unwrap().open(session_ticket)
+                                        .filter(|(suite, _)| config.allowed_suites().contains(suite) && client_hello.suites.contains(suite))
+                                        .filter(|_| client_hello.public_key.len() == ticket::NONCE_SIZE);
+                                    let (suite, resumption_secret) = match opened {
+                                        Some(opened) => opened,
+                                        None => {
+                                            // Unknown key, expired, tampered with or for a suite we no longer allow
+                                            println!("[!] Ticket can not be used, asking for a full handshake");
+                                            println!("--------------------------------------\n");
+                                            stream.write_all(&record::encode_frame(ContentType::TicketDeclined, 0, &[]))?;
+                                            transcript = Transcript::new();
+                                            continue;
+                                        }
+                                    };
+                                    println!("[+] Resuming session with {}", suite.name());
+                                    let signed = Self::accept_signed(&client_hello, &config);
+                                    let gcm_tag_size = Self::accept_gcm_tag_size(suite, &client_hello, &config);
+                                    let padded = Self::accept_padding(&client_hello, &config);
+
+                                    // Our nonce takes the place of the public key so the new keys are fresh
+                                    println!("[+] Sending ServerHello with nonce ...");
+                                    let nonce = random.bytes(ticket::NONCE_SIZE);
+                                    let server_hello = ServerHello { suite, ratchet: false, kem_ciphertext: None, resumed: true, signed, gcm_tag_size, padded, public_key: nonce }.to_bytes();
+                                    transcript.add(&server_hello);
+                                    stream.write_all(&record::encode_frame(ContentType::ServerHello, 0, &server_hello))?;
+
+                                    println!("[+] Using SHA-256 as KDF to compute final key from the resumption secret ...");
+                                    let final_key = handshake::derive_key(&resumption_secret, &transcript);
+                                    expected_finished = Some(Self::send_finished(&mut stream, &final_key, &transcript)?);
+                                    let mut record_layer = RecordLayer::new(suite, &final_key, Side::Server, config.rekey, random.clone())
+                                        .with_gcm_tag_size(gcm_tag_size);
+                                    if signed {
+                                        record_layer = record_layer.with_signed_messages();
+                                    }
+                                    if padded {
+                                        record_layer = record_layer.with_padding(config.padding.clone());
+                                    }
+                                    ticket_secret = Some(ticket::resumption_secret(&final_key));
+                                    println!("[*] Channel binding {}", record_layer.channel_binding().unwrap());
+                                    client_keys.lock().unwrap().insert(address.clone(), record_layer);
+
+                                    println!("[*] Session Resumed without a DH exchange. Final key {}", final_key);
+                                    Self::show_code(&codes, &address, &final_key, &transcript);
+                                    println!("--------------------------------------\n");
+                                    continue;
+                                }
+
+                                // Our key only goes out once the client is bound to its own, see sas.rs
+                                if !client_hello.committed || client_hello.public_key.len() != sas::COMMITMENT_SIZE {
+                                    println!("[!] ClientHello does not commit to the client's public key");
+                                    println!("--------------------------------------\n");
+                                    return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "ClientHello without a key commitment"));
+                                }
+
+                                // Pick the most preferred suite which the client also supports. Suites
+                                // below our minimum are never picked, even if they are all the client offered.
+                                let suite = match suites::select_suite(&config.allowed_suites(), &client_hello.suites) {
+                                    Some(suite) => suite,
+                                    None => {
+                                        println!("[!] No cipher suite at or above {} in common with the client.", config.minimum_suite.name());
+                                        println!("--------------------------------------\n");
+                                        return Err(std::io::Error::other("No shared cipher suite"));
+                                    }
+                                };
+                                println!("[+] Selected cipher suite: {}", suite.name());
+
+                                // The ratchet is only used when the client asks for it and we allow it
+                                let ratchet = client_hello.ratchet && config.ratchet;
+                                if ratchet {
+                                    println!("[+] Accepted Double Ratchet for per-message keys");
+                                }
+
+                                // Likewise the hybrid exchange. A fresh ML-KEM secret is encapsulated to
+                                // the client's key and the ciphertext goes out in the ServerHello
+                                let kem = match (&client_hello.kem_public_key, config.hybrid) {
+                                    (Some(kem_public_key), true) => {
+                                        println!("[+] Accepted hybrid key exchange, encapsulating to the client's ML-KEM-768 key ...");
+                                        let encapsulation = mlkem::encapsulate(kem_public_key, &random)
+                                            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e.to_string()))?;
+                                        Some(encapsulation)
+                                    }
+                                    _ => None,
+                                };
+                                let kem_ciphertext = kem.as_ref().map(|(_, ciphertext)| ciphertext.clone());
+                                let signed = Self::accept_signed(&client_hello, &config);
+                                let gcm_tag_size = Self::accept_gcm_tag_size(suite, &client_hello, &config);
+                                let padded = Self::accept_padding(&client_hello, &config);
+
+                                // Generate key pair for this client now that it is known to be needed,
+                                // unless the server was given one key pair for every client
+                                let key_pair = match &config.static_key {
+                                    Some(static_key) => {
+                                        println!("[!] Using the static DH key pair, this session has no forward secrecy");
+                                        (static_key.0.duplicate(), static_key.1.clone())
+                                    }
+                                    None => {
+                                        println!("[+] Generating key pair ...");
+                                        random.dh_key_pair()
+                                    }
+                                };
 
-                    // Check if the entire message has been received
-                    if let Some(length) = expected_length {
-                        if total_received >= length {
-                            // If this is the first message, it is the client sending us the encryption key
-                            if first_message {
-                                println!("[*] Received client's public key");
+                                println!("[+] Sending ServerHello with public key ...");
+                                let server_hello = ServerHello { suite, ratchet, kem_ciphertext, resumed: false, signed, gcm_tag_size, padded, public_key: key_pair.1.clone() }.to_bytes();
+                                transcript.add(&server_hello);
+                                stream.write_all(&record::encode_frame(ContentType::ServerHello, 0, &server_hello))?;
+
+                                println!("[*] Waiting for the client to reveal the public key it committed to ...");
+                                println!("--------------------------------------\n");
+                                let kem_secret = kem.map(|(kem_secret, _)| kem_secret);
+                                pending = Some(PendingExchange { suite, ratchet, kem_secret, signed, gcm_tag_size, padded, key_pair, commitment: client_hello.public_key });
+                            }
+                            // The client's public key, which must be the one its ClientHello committed to
+                            ContentType::KeyReveal => {
+                                let PendingExchange { suite, ratchet, kem_secret, signed, gcm_tag_size, padded, key_pair, commitment } = pending.take()
+                                    .ok_or_else(|| std::io::Error::other("Unexpected KeyReveal"))?;
+                                println!("--------------------------------------");
+                                if !sas::opens(&commitment, &frame.body) {
+                                    let error = verify::authentication_failure(received);
+                                    stream.write_all(&record::alert_frame())?;
+                                    return Err(error);
+                                }
+                                println!("[*] Received client's public key, it matches the commitment in its ClientHello");
+                                transcript.add(&frame.body);
 
                                 // Use the client's public key to compute the shared secret
                                 println!("[+] Calculating shared secret ...");
                                 let modulus = dh::get_domain_params().0;
-                                let shared_secret = dh::get_secret(&dynamic_buffer.clone(), &key_pair.0, &modulus);
+                                let mut shared_secret = SecretBytes::new(dh::get_secret(&frame.body, key_pair.0.expose(), &modulus));
+                                if let Some(kem_secret) = &kem_secret {
+                                    println!("[+] Combining DH and ML-KEM shared secrets ...");
+                                    shared_secret = handshake::hybrid_secret(&shared_secret, kem_secret);
+                                }
 
-                                // Use SHA-256 as the KDF to compute the final key
+                                // Use SHA-256 over the secret and the transcript as the KDF to compute the final key
                                 println!("[+] Using SHA-256 as KDF to compute final key ...");
-                                let final_key = bernie_hmac::hash(&shared_secret);
-
-                                // Add the key to the client_keys HashMap
-                                client_keys.lock().unwrap().insert(address.clone(), final_key);
-
-                                println!("[*] DH Key Exchange Successful.");
+                                let final_key = handshake::derive_key(&shared_secret, &transcript);
+                                expected_finished = Some(Self::send_finished(&mut stream, &final_key, &transcript)?);
+
+                                // Our handshake key pair becomes the first ratchet key pair
+                                let mut record_layer = RecordLayer::new(suite, &final_key, Side::Server, config.rekey, random.clone())
+                                    .with_gcm_tag_size(gcm_tag_size);
+                                if ratchet {
+                                    let ratchet_key_pair = (key_pair.0.duplicate(), key_pair.1.clone());
+                                    record_layer = record_layer.with_ratchet(Ratchet::respond(&final_key, ratchet_key_pair, random.clone()));
+                                }
+                                if signed {
+                                    record_layer = record_layer.with_signed_messages();
+                                }
+                                if padded {
+                                    record_layer = record_layer.with_padding(config.padding.clone());
+                                }
+                                ticket_secret = Some(ticket::resumption_secret(&final_key));
+
+                                // Add the record layer to the client_keys HashMap
+                                println!("[*] Channel binding {}", record_layer.channel_binding().unwrap());
+                                client_keys.lock().unwrap().insert(address.clone(), record_layer);
+
+                                // Only a fingerprint of the key is printed, so both ends can be compared by eye
+                                let exchange = if kem_secret.is_some() { "Hybrid ML-KEM-768 + DH" } else { "DH" };
+                                println!("[*] {} Key Exchange Successful. Final key {}", exchange, final_key);
+                                Self::show_code(&codes, &address, &final_key, &transcript);
                                 println!("--------------------------------------\n");
-
-                                first_message = false;
-                            } else {
-                                // Separate the message from the MAC tag and IV
+                            }
+                            // The client's Finished shows it saw the same hellos we did
+                            ContentType::Finished => {
+                                let expected = expected_finished.take()
+                                    .ok_or_else(|| std::io::Error::other("Unexpected Finished"))?;
+                                println!("--------------------------------------");
+                                if !verify::constant_time_eq(&expected, &frame.body) {
+                                    let error = verify::authentication_failure(received);
+                                    stream.write_all(&record::alert_frame())?;
+                                    return Err(error);
+                                }
+                                println!("[*] Client Finished verified, both sides saw the same offer and selection");
+
+                                // Only now has the client shown it holds the final key, so only now
+                                // does it get a ticket to resume from
+                                if let Some(resumption_secret) = ticket_secret.take() {
+                                    let mut keys_lock = client_keys.lock().unwrap();
+                                    let record_layer = keys_lock.get_mut(&address)
+                                        .ok_or_else(|| std::io::Error::other("Finished before handshake"))?;
+                                    Self::issue_ticket(&mut stream, record_layer, &tickets, &resumption_secret)?;
+                                }
+                                println!("--------------------------------------\n");
+                            }
+                            ContentType::ApplicationData | ContentType::KeyUpdate => {
+                                if expected_finished.is_some() {
+                                    return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "Application data before Finished"));
+                                }
                                 println!("--------------------------------------");
-                                println!("[+] {} bytes received.", total_received);
-                                let (payload_with_tag, iv) = dynamic_buffer.split_at(dynamic_buffer.len() - IV_SIZE);
-                                let (payload, auth_tag) = payload_with_tag.split_at(payload_with_tag.len() - MAC_TAG_SIZE);
-
-                                // Retrieve shared key
-                                let keys_lock = client_keys.lock().unwrap();
-                                if let Some(key) = keys_lock.get(&address.clone()) {
-                                    println!("[+] Decrypting {} bytes with AES-256-GCM ...", payload.len());
-
-                                    // Decrypt and verify the payload - Using empty AAD for now - Need to update later
-                                    let temp_aad: Vec<u8> = Vec::new();
-                                    let (message, result) = aes_crypt::decrypt_gcm(payload, iv, &temp_aad, auth_tag, &key);
-
-                                    // Check result 
-                                    if result {
-                                        // Send the complete message (excluding length prefix, MAC tag, and IV) to the main thread
-                                        println!("[*] AES-GCM authentication and decryption successful.");
+                                println!("[+] {} bytes received.", frame.body.len() + record::HEADER_SIZE);
+
+                                // Retrieve the record layer for this client
+                                let mut keys_lock = client_keys.lock().unwrap();
+                                let record_layer = keys_lock.get_mut(&address)
+                                    .ok_or_else(|| std::io::Error::other("Application data before handshake"))?;
+
+                                println!("[+] Decrypting record #{} ({} bytes) with {} ...", frame.sequence, frame.body.len(), record_layer.suite().name());
+                                let ratchet_steps = record_layer.ratchet_steps();
+                                match record_layer.open(&frame) {
+                                    Ok(Record::ApplicationData(message)) => {
+                                        if record_layer.ratchet_steps() != ratchet_steps {
+                                            println!("[*] Client sent a new ratchet key, DH ratchet step #{} replaced both chains", record_layer.ratchet_steps().unwrap());
+                                        }
+
+                                        // A signed message is only delivered once its signature checks out
+                                        let message = if record_layer.signed_messages() {
+                                            let signed_message = match proof::open_signed(&message, Side::Client, frame.sequence, record_layer.channel_binding().unwrap().expose(), &mut client_signing_key) {
+                                                Some(signed_message) => signed_message,
+                                                None => {
+                                                    // The other clients need the lock, so it is released before the failure delay
+                                                    drop(keys_lock);
+                                                    let error = verify::authentication_failure(received);
+                                                    stream.write_all(&record::alert_frame())?;
+                                                    return Err(error);
+                                                }
+                                            };
+                                            println!("[*] Message signed by client key {}", signed_message.signer());
+                                            if let Some(path) = &config.proof_file {
+                                                match signed_message.export(path) {
+                                                    Ok(()) => println!("[+] Proof appended to {}", path),
+                                                    Err(e) => println!("[!] Could not write proof to {}: {}", path, e),
+                                                }
+                                            }
+                                            signed_message.content
+                                        } else {
+                                            message
+                                        };
+
+                                        // Send the decrypted message to the main thread
+                                        println!("[*] Authentication and decryption successful.");
                                         println!("--------------------------------------\n");
-                                        client_tx.send(message.clone().to_vec()).unwrap(); 
-                                    } else {
-                                        println!("[!] AES-GCM authentication failed: Data integrity cannot be verified.");
+                                        client_tx.send(message).unwrap();
+                                    }
+                                    Ok(Record::KeyUpdate) => {
+                                        println!("[*] Client updated its sending key, receiving key ratcheted to epoch {}", record_layer.read_epoch());
                                         println!("--------------------------------------\n");
-                                        return Err(std::io::Error::new(std::io::ErrorKind::Other, "MAC verification failed"));
                                     }
-                                } 
+                                    // A replayed or reordered record is authentic but is dropped without being
+                                    // delivered. The connection itself is left open.
+                                    Err(e @ RecordError::Replay { .. }) | Err(e @ RecordError::OutOfOrder { .. }) => {
+                                        println!("[!] {}", e);
+                                        println!("--------------------------------------\n");
+                                    }
+                                    // Anything else gets the same alert after the same delay,
+                                    // whichever check the record failed
+                                    Err(_) => {
+                                        drop(keys_lock);
+                                        let error = verify::authentication_failure(received);
+                                        stream.write_all(&record::alert_frame())?;
+                                        return Err(error);
+                                    }
+                                }
+                            }
+                            ContentType::Alert => {
+                                println!("[!] Client sent an alert: {}", verify::AUTHENTICATION_ALERT);
+                                println!("--------------------------------------\n");
+                                return Err(std::io::Error::new(std::io::ErrorKind::ConnectionAborted, "Alert received"));
+                            }
+                            // Hellos meant for the other side and the SRP messages of the PAKE stage
+                            other => {
+                                return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, format!("Unexpected {:?}", other)));
                             }
-
-                            dynamic_buffer.clear();
-                            expected_length = None;
-                            total_received = 0;
                         }
                     }
                 }
@@ -281,12 +568,79 @@ impl Server5 {
         }
         Ok(())
     }
-}
 
-fn generate_iv() -> Vec<u8> {
-    let mut rng = thread_rng();
-    let mut iv = vec![0u8; IV_SIZE];
-    rng.fill(iv.as_mut_slice());
-    iv
-}
+    // Under GCM the tag is the longer of the shortest the client accepts and the shortest
+    // we accept. Other suites have no GCM tag, so the full length is sent back.
+    fn accept_gcm_tag_size(suite: CipherSuite, client_hello: &ClientHello, config: &SessionConfig) -> usize {
+        if suite != CipherSuite::Aes256Gcm {
+            return record::GCM_TAG_SIZE;
+        }
+        let gcm_tag_size = client_hello.gcm_tag_size.max(config.gcm_tag_size);
+        if gcm_tag_size < record::GCM_TAG_SIZE {
+            println!("[+] Using {}-bit GCM tags", gcm_tag_size * 8);
+        }
+        gcm_tag_size
+    }
+
+    // Padded records are used whenever the client asks. What we send is padded by our own
+    // policy, which may be no padding at all.
+    fn accept_padding(client_hello: &ClientHello, config: &SessionConfig) -> bool {
+        match (client_hello.padded, &config.padding) {
+            (true, padding) => println!("[+] Accepted record padding, sending records with {}", padding),
+            (false, PaddingPolicy::None) => {}
+            (false, _) => println!("[!] Client did not ask for record padding, records are sent unpadded"),
+        }
+        client_hello.padded
+    }
 
+    // The short authentication string comes from the final key and the hello transcript,
+    // so a man in the middle, who runs a separate handshake with each end, shows the two
+    // ends different codes
+    fn show_code(codes: &Arc<Mutex<HashMap<String, Sas>>>, address: &str, final_key: &SecretBytes, transcript: &Transcript) {
+        let sas = Sas::new(final_key, &transcript.hash());
+        println!("[*] Short authentication string {}, type {} {} once the client shows the same", sas.code(), sas::VERIFY_COMMAND, address);
+        codes.lock().unwrap().insert(address.to_string(), sas);
+    }
+
+    // Sends our Finished over the hello transcript and returns the one the client must send
+    fn send_finished(stream: &mut TcpStream, final_key: &SecretBytes, transcript: &Transcript) -> Result<Vec<u8>, std::io::Error> {
+        println!("[+] Sending Finished over the hello transcript ...");
+        let verify_data = handshake::finished(final_key, Side::Server, transcript);
+        stream.write_all(&record::encode_frame(ContentType::Finished, 0, &verify_data))?;
+        Ok(handshake::finished(final_key, Side::Client, transcript))
+    }
+
+    // Signed messages are only used when the client asks for them and we have a key to sign with
+    fn accept_signed(client_hello: &ClientHello, config: &SessionConfig) -> bool {
+        let signed = client_hello.signed && config.signing_key.is_some();
+        if signed {
+            println!("[+] Accepted signed messages");
+        }
+        signed
+    }
+
+    // Seals a ticket for the session that resumption_secret belongs to and sends it under
+    // the session's own record protection
+    fn issue_ticket(
+        stream: &mut TcpStream,
+        record_layer: &mut RecordLayer,
+        tickets: &Arc<Mutex<TicketKeys>>,
+        resumption_secret: &SecretBytes
+    ) -> Result<(), std::io::Error> {
+        let mut tickets = tickets.lock().unwrap();
+        let session_ticket = tickets.issue(record_layer.suite(), resumption_secret);
+        let lifetime = tickets.policy().lifetime;
+        let ticket_record = match record_layer.seal_ticket(&SessionTicket::new_session_ticket(lifetime, &session_ticket)) {
+            Ok(ticket_record) => ticket_record,
+            // A 32-bit GCM tag only covers records too short to hold a ticket
+            Err(e @ RecordError::GcmLimit { .. }) => {
+                println!("[!] No session ticket sent: {}", e);
+                return Ok(());
+            }
+            Err(e) => return Err(e.into()),
+        };
+        println!("[+] Sending session ticket under ticket key {}, valid for {} s ...", tickets.current_key_id(), lifetime.as_secs());
+        stream.write_all(&ticket_record)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,261 @@
+use std::thread;
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+use std::sync::{Arc, Mutex};
+use std::io::{Read, Write, Error};
+use std::collections::HashMap;
+use std::net::{TcpListener, TcpStream};
+use std::sync::mpsc::{Receiver, Sender};
+
+use crate::noise::{self, HandshakeState, NoiseConfig, NoiseError, TransportState};
+use crate::proof;
+use crate::random::Random;
+use crate::record::{self, ContentType};
+use crate::verify;
+
+// Every connected client with the sender for its records and its stream
+type ClientMap = Arc<Mutex<HashMap<String, (mpsc::Sender<Vec<u8>>, TcpStream)>>>;
+
+// Stage 5 with the hand-rolled DH handshake replaced by a Noise handshake, with the
+// pattern and static keys taken from the NoiseConfig. The server is always the
+// responder. Application data is sent under the two CipherStates the handshake ends with.
+pub struct Server5Noise {
+    listener: TcpListener,
+    client_map: ClientMap,
+    client_keys: Arc<Mutex<HashMap<String, TransportState>>>,
+    config: Arc<NoiseConfig>,
+    random: Random,
+}
+
+
+impl Server5Noise {
+    pub fn new(port: usize, config: NoiseConfig, random: Random) -> Self {
+        let address = format!("0.0.0.0:{}", port);
+        let listener = TcpListener::bind(address).expect("Could not bind");
+        let client_map = Arc::new(Mutex::new(HashMap::new()));
+        let client_keys = Arc::new(Mutex::new(HashMap::new()));
+
+        Self {
+            listener,
+            client_map,
+            client_keys,
+            config: Arc::new(config),
+            random,
+        }
+    }
+
+    pub fn run(&mut self) {
+        println!("Listening for incoming connections...");
+        println!("[*] Responding with {}", noise::protocol_name(self.config.pattern));
+        if let (true, Some((_, public_key))) = (self.config.pattern.needs_static_key(false), &self.config.static_key) {
+            println!("[*] Static public key {}", proof::fingerprint(public_key));
+        }
+
+        // Thread for reading from stdin and sending those bytes to all clients
+        let client_map_clone = Arc::clone(&self.client_map);
+        let client_keys_clone = Arc::clone(&self.client_keys);
+        thread::spawn(move || {
+            loop {
+                let mut input = String::new();
+                std::io::stdin().read_line(&mut input).unwrap();
+                let temp_bytes = input.as_bytes().to_vec();
+
+                let clients = client_map_clone.lock().unwrap();
+                let mut client_keys = client_keys_clone.lock().unwrap();
+
+                for (address, (client_tx, _)) in clients.iter() {
+                    if let Some(transport) = client_keys.get_mut(address) {
+                        println!("\n--------------------------------------");
+                        let nonce = transport.sending.nonce();
+                        println!("[+] Encrypting {} bytes with nonce {} ...", temp_bytes.len(), nonce);
+                        println!("--------------------------------------");
+                        match transport.sending.encrypt_with_ad(&[], &temp_bytes) {
+                            // Send message_bytes through the stdin channel
+                            Ok(ciphertext) => client_tx.send(record::encode_frame(ContentType::ApplicationData, nonce, &ciphertext)).unwrap(),
+                            Err(e) => println!("[!] Could not send to {}: {}", address, e),
+                        }
+                    }
+                }
+            }
+        });
+
+        // Continuously listen for new connections
+        for stream in self.listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let address = stream.peer_addr().unwrap().to_string();
+
+                    println!("{} - Connected\n", address);
+
+                    // Create a new sender for this client which will be used in the stdin thread
+                    let (client_stdin_tx, client_stdin_rx) = mpsc::channel::<Vec<u8>>();
+
+                    // Create channel for communicating from client thread to main thread
+                    let (client_tx, client_rx) = mpsc::channel::<Vec<u8>>();
+
+                    // Create reference to the shared client_map
+                    let client_map_clone = Arc::clone(&self.client_map);
+
+                    // Add new entry to the hashmap including the sender for the command channel and the client's TcpStream
+                    client_map_clone.lock().unwrap().insert(address.clone(), (client_stdin_tx, stream.try_clone().unwrap()));
+
+                    // Client handling thread
+                    let client_keys_clone = self.client_keys.clone();
+                    let config = self.config.clone();
+                    let random = self.random.clone();
+                    thread::spawn(move || {
+                        if let Err(e) = Self::handle_client(stream, client_stdin_rx, client_tx, address.clone(), client_keys_clone.clone(), config, random) {
+                            eprintln!("Error handling client: {:?}", e);
+                        }
+
+                        // Remove the client from the map upon disconnection as well as their key
+                        client_map_clone.lock().unwrap().remove(&address);
+                        client_keys_clone.lock().unwrap().remove(&address);
+                        println!("{} - Disconnected", address);
+                    });
+
+                    // Creating a "Main" thread for each client
+                    thread::spawn(move || {
+                        // Attempt to receive any messages sent from the client
+                        while let Ok(response_bytes) = client_rx.recv() {
+                            // Response bytes will already have been decrypted in handle_client
+                            let message = String::from_utf8_lossy(&response_bytes);
+                            println!("Client > {}", message);
+                        }
+                    });
+                }
+                Err(e) => eprintln!("Failed to accept a client: {}", e),
+            }
+        }
+    }
+
+    fn handle_client(
+        mut stream: TcpStream,
+        stdin_rx: Receiver<Vec<u8>>,
+        client_tx: Sender<Vec<u8>>,
+        address: String,
+        client_keys: Arc<Mutex<HashMap<String, TransportState>>>,
+        config: Arc<NoiseConfig>,
+        random: Random
+    ) -> Result<(), std::io::Error> {
+
+        // Bytes received from the client which have not yet been handled
+        let mut dynamic_buffer = Vec::new();
+
+        // Taken once the handshake has finished and the transport keys are in client_keys
+        let mut handshake = Some(HandshakeState::new(&config, false, random)?);
+
+        loop {
+            // Non-blocking attempt to receive message from stdin and send to client
+            if let Ok(bytes) = stdin_rx.try_recv() {
+                stream.write_all(&bytes)?;
+            }
+
+            // Allows for 1 second of blocking while trying to read from the stream
+            stream.set_read_timeout(Some(Duration::new(1, 0)))?;
+
+            // Temporary buffer
+            let mut buffer = [0_u8; 512];
+
+            // Attempt to read bytes sent from client
+            match stream.read(&mut buffer) {
+                Ok(0) => break, // Connection closed by client
+                Ok(bytes_read) => {
+                    dynamic_buffer.extend_from_slice(&buffer[..bytes_read]);
+
+                    // Handle every complete frame received so far
+                    loop {
+                        // A failure to verify is reported a fixed time after this point
+                        let received = Instant::now();
+
+                        // A frame that can not be parsed, too long or with an unknown type,
+                        // fails the same way as a record that does not verify
+                        let frame = match record::take_frame(&mut dynamic_buffer) {
+                            Ok(Some(frame)) => frame,
+                            Ok(None) => break,
+                            Err(_) => {
+                                let error = verify::authentication_failure(received);
+                                stream.write_all(&record::alert_frame())?;
+                                return Err(error);
+                            }
+                        };
+
+                        match frame.content_type {
+                            ContentType::NoiseHandshake => {
+                                let state = handshake.as_mut()
+                                    .ok_or_else(|| std::io::Error::other("Unexpected NoiseHandshake"))?;
+
+                                println!("--------------------------------------");
+                                println!("[*] Received {} handshake message ({} bytes)", state.pattern().name(), frame.body.len());
+                                match state.read_message(&frame.body) {
+                                    Ok(_) => {}
+                                    Err(NoiseError::OutOfTurn) => return Err(NoiseError::OutOfTurn.into()),
+                                    Err(_) => {
+                                        let error = verify::authentication_failure(received);
+                                        stream.write_all(&record::alert_frame())?;
+                                        return Err(error);
+                                    }
+                                }
+
+                                // Answer if the pattern has another message for us
+                                if !state.is_finished() {
+                                    println!("[+] Sending {} handshake message ...", state.pattern().name());
+                                    let message = state.write_message(&[])?;
+                                    stream.write_all(&record::encode_frame(ContentType::NoiseHandshake, 0, &message))?;
+                                }
+
+                                if state.is_finished() {
+                                    if let Some(remote_static) = state.remote_static() {
+                                        println!("[*] Client static key {}", proof::fingerprint(remote_static));
+                                    }
+                                    let transport = state.split()?;
+                                    println!("[*] Noise Handshake Successful. Handshake hash {}", proof::fingerprint(&transport.handshake_hash));
+                                    println!("--------------------------------------\n");
+                                    client_keys.lock().unwrap().insert(address.clone(), transport);
+                                    handshake = None;
+                                }
+                            }
+                            ContentType::ApplicationData => {
+                                println!("--------------------------------------");
+                                println!("[+] {} bytes received.", frame.body.len() + record::HEADER_SIZE);
+
+                                // Retrieve the transport keys for this client
+                                let mut keys_lock = client_keys.lock().unwrap();
+                                let transport = keys_lock.get_mut(&address)
+                                    .ok_or_else(|| std::io::Error::other("Application data before handshake"))?;
+
+                                // The nonce is implicit, so a replayed or reordered message fails to decrypt
+                                println!("[+] Decrypting message with nonce {} ...", transport.receiving.nonce());
+                                match transport.receiving.decrypt_with_ad(&[], &frame.body) {
+                                    Ok(message) => {
+                                        // Send the decrypted message to the main thread
+                                        println!("[*] Authentication and decryption successful.");
+                                        println!("--------------------------------------\n");
+                                        client_tx.send(message).unwrap();
+                                    }
+                                    Err(_) => {
+                                        drop(keys_lock);
+                                        let error = verify::authentication_failure(received);
+                                        stream.write_all(&record::alert_frame())?;
+                                        return Err(error);
+                                    }
+                                }
+                            }
+                            ContentType::Alert => {
+                                println!("[!] Client sent an alert: {}", verify::AUTHENTICATION_ALERT);
+                                println!("--------------------------------------\n");
+                                return Err(std::io::Error::new(std::io::ErrorKind::ConnectionAborted, "Alert received"));
+                            }
+                            other => {
+                                return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, format!("Unexpected {:?}", other)));
+                            }
+                        }
+                    }
+                }
+                Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => continue,
+                Err(_) => break, // Error or disconnection has occured
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,325 @@
+use std::thread;
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+use std::sync::{Arc, Mutex};
+use std::io::{Read, Write, Error};
+use std::collections::HashMap;
+use std::net::{TcpListener, TcpStream};
+use std::sync::mpsc::{Receiver, Sender};
+
+use crate::handshake::{self, Transcript};
+use crate::random::Random;
+use crate::secret::SecretBytes;
+use crate::record::{self, ContentType, Record, RecordError, RecordLayer, RekeyPolicy, Side};
+use crate::srp::{SrpClientHello, SrpServer, SrpServerHello, SrpSession, Verifier};
+use crate::suites::CipherSuite;
+use crate::verify;
+
+// Every connected client with the sender for its records and its stream
+type ClientMap = Arc<Mutex<HashMap<String, (mpsc::Sender<Vec<u8>>, TcpStream)>>>;
+
+// Stage 5 with the DH handshake replaced by SRP-6a, for pairing a client and server that
+// share nothing but a passphrase. The server holds a verifier for each identity rather
+// than the passphrase. Once both sides have proven they derived the same session key,
+// it is turned into the final key for the AES-GCM record layer of stage 5.
+pub struct Server5Pake {
+    listener: TcpListener,
+    client_map: ClientMap,
+    client_keys: Arc<Mutex<HashMap<String, RecordLayer>>>,
+    verifiers: Arc<HashMap<String, Verifier>>,
+    // Key for the decoy salts and verifiers handed out for unknown identities
+    decoy_key: Arc<SecretBytes>,
+    random: Random,
+}
+
+// One client's connection as its thread sees it: the stream, the records sealed for the
+// client by the stdin thread and the channel its messages go to the main thread on
+struct Connection {
+    stream: TcpStream,
+    stdin_rx: Receiver<Vec<u8>>,
+    client_tx: Sender<Vec<u8>>,
+    address: String,
+}
+
+
+impl Server5Pake {
+    pub fn new(port: usize, verifiers: Vec<Verifier>, random: Random) -> Self {
+        let address = format!("0.0.0.0:{}", port);
+        let listener = TcpListener::bind(address).expect("Could not bind");
+        let client_map = Arc::new(Mutex::new(HashMap::new()));
+        let client_keys = Arc::new(Mutex::new(HashMap::new()));
+        let verifiers = Arc::new(verifiers.into_iter().map(|verifier| (verifier.identity.clone(), verifier)).collect());
+        let decoy_key = Arc::new(random.secret(32));
+
+        Self {
+            listener,
+            client_map,
+            client_keys,
+            verifiers,
+            decoy_key,
+            random,
+        }
+    }
+
+    pub fn run(&mut self) {
+        println!("Listening for incoming connections...");
+
+        // Thread for reading from stdin and sending those bytes to all clients
+        let client_map_clone = Arc::clone(&self.client_map);
+        let client_keys_clone = Arc::clone(&self.client_keys);
+        thread::spawn(move || {
+            loop {
+                let mut input = String::new();
+                std::io::stdin().read_line(&mut input).unwrap();
+                let temp_bytes = input.as_bytes().to_vec();
+
+                let clients = client_map_clone.lock().unwrap();
+                let mut client_keys = client_keys_clone.lock().unwrap();
+
+                for (address, (client_tx, _)) in clients.iter() {
+                    if let Some(record_layer) = client_keys.get_mut(address) {
+                        println!("\n--------------------------------------");
+                        let mut sequence = record_layer.write_sequence();
+                        if record_layer.update_due() {
+                            println!("[+] Sending KeyUpdate as record #{} and ratcheting sending key to epoch {} ...", sequence, record_layer.write_epoch() + 1);
+                            sequence += 1;
+                        }
+                        println!("[+] Encrypting {} bytes as record #{} with {} ...", temp_bytes.len(), sequence, record_layer.suite().name());
+                        println!("--------------------------------------");
+                        match record_layer.seal(&temp_bytes) {
+                            // Send message_bytes through the stdin channel
+                            Ok(message_bytes) => client_tx.send(message_bytes).unwrap(),
+                            Err(e) => println!("[!] Could not send to {}: {}", address, e),
+                        }
+                    }
+                }
+            }
+        });
+
+        // Continuously listen for new connections
+        for stream in self.listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let address = stream.peer_addr().unwrap().to_string();
+
+                    println!("{} - Connected\n", address);
+
+                    // Create a new sender for this client which will be used in the stdin thread
+                    let (client_stdin_tx, client_stdin_rx) = mpsc::channel::<Vec<u8>>();
+
+                    // Create channel for communicating from client thread to main thread
+                    let (client_tx, client_rx) = mpsc::channel::<Vec<u8>>();
+
+                    // Create reference to the shared client_map
+                    let client_map_clone = Arc::clone(&self.client_map);
+
+                    // Add new entry to the hashmap including the sender for the command channel and the client's TcpStream
+                    client_map_clone.lock().unwrap().insert(address.clone(), (client_stdin_tx, stream.try_clone().unwrap()));
+
+                    // Client handling thread
+                    let client_keys_clone = self.client_keys.clone();
+                    let verifiers = self.verifiers.clone();
+                    let decoy_key = self.decoy_key.clone();
+                    let random = self.random.clone();
+                    thread::spawn(move || {
+                        let connection = Connection { stream, stdin_rx: client_stdin_rx, client_tx, address: address.clone() };
+                        if let Err(e) = Self::handle_client(connection, client_keys_clone.clone(), verifiers, decoy_key, random) {
+                            eprintln!("Error handling client: {:?}", e);
+                        }
+
+                        // Remove the client from the map upon disconnection as well as their key
+                        client_map_clone.lock().unwrap().remove(&address);
+                        client_keys_clone.lock().unwrap().remove(&address);
+                        println!("{} - Disconnected", address);
+                    });
+
+                    // Creating a "Main" thread for each client
+                    thread::spawn(move || {
+                        // Attempt to receive any messages sent from the client
+                        while let Ok(response_bytes) = client_rx.recv() {
+                            // Response bytes will already have been decrypted in handle_client
+                            let message = String::from_utf8_lossy(&response_bytes);
+                            println!("Client > {}", message);
+                        }
+                    });
+                }
+                Err(e) => eprintln!("Failed to accept a client: {}", e),
+            }
+        }
+    }
+
+    fn handle_client(
+        connection: Connection,
+        client_keys: Arc<Mutex<HashMap<String, RecordLayer>>>,
+        verifiers: Arc<HashMap<String, Verifier>>,
+        decoy_key: Arc<SecretBytes>,
+        random: Random
+    ) -> Result<(), std::io::Error> {
+        let Connection { mut stream, stdin_rx, client_tx, address } = connection;
+
+        // Bytes received from the client which have not yet been handled
+        let mut dynamic_buffer = Vec::new();
+
+        // Both hello messages, in order
+        let mut transcript = Transcript::new();
+
+        // Set once the hellos have been exchanged, until the client's proof has been checked
+        let mut pending_session: Option<SrpSession> = None;
+
+        loop {
+            // Non-blocking attempt to receive message from stdin and send to client
+            if let Ok(bytes) = stdin_rx.try_recv() {
+                stream.write_all(&bytes)?;
+            }
+
+            // Allows for 1 second of blocking while trying to read from the stream
+            stream.set_read_timeout(Some(Duration::new(1, 0)))?;
+
+            // Temporary buffer
+            let mut buffer = [0_u8; 512];
+
+            // Attempt to read bytes sent from client
+            match stream.read(&mut buffer) {
+                Ok(0) => break, // Connection closed by client
+                Ok(bytes_read) => {
+                    dynamic_buffer.extend_from_slice(&buffer[..bytes_read]);
+
+                    // Handle every complete frame received so far
+                    loop {
+                        // A failure to verify is reported a fixed time after this point
+                        let received = Instant::now();
+
+                        // A frame that can not be parsed, too long or with an unknown type,
+                        // fails the same way as a record that does not verify
+                        let frame = match record::take_frame(&mut dynamic_buffer) {
+                            Ok(Some(frame)) => frame,
+                            Ok(None) => break,
+                            Err(_) => {
+                                let error = verify::authentication_failure(received);
+                                stream.write_all(&record::alert_frame())?;
+                                return Err(error);
+                            }
+                        };
+
+                        match frame.content_type {
+                            // The client names its identity and sends A = g^a
+                            ContentType::SrpClientHello => {
+                                if pending_session.is_some() || client_keys.lock().unwrap().contains_key(&address) {
+                                    return Err(std::io::Error::other("Unexpected SrpClientHello"));
+                                }
+
+                                let client_hello = SrpClientHello::from_bytes(&frame.body)
+                                    .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidData, "Malformed SrpClientHello"))?;
+                                transcript.add(&frame.body);
+                                println!("--------------------------------------");
+                                println!("[*] Received SRP ClientHello for identity '{}'", client_hello.identity);
+
+                                // An unknown identity gets a decoy so that the client can not tell it
+                                // apart from a wrong password
+                                let decoy;
+                                let verifier = match verifiers.get(&client_hello.identity) {
+                                    Some(verifier) => verifier,
+                                    None => {
+                                        println!("[!] Unknown identity, continuing with a decoy verifier");
+                                        decoy = Verifier::decoy(&client_hello.identity, &decoy_key);
+                                        &decoy
+                                    }
+                                };
+
+                                // B = k * v + g^b
+                                println!("[+] Generating ephemeral key and sending salt with B ...");
+                                let server = SrpServer::new(verifier, &random);
+                                let server_hello = SrpServerHello { salt: server.salt().to_vec(), public_key: server.public_key().to_vec() }.to_bytes();
+                                transcript.add(&server_hello);
+                                stream.write_all(&record::encode_frame(ContentType::SrpServerHello, 0, &server_hello))?;
+
+                                // S = (A * v^u)^b
+                                println!("[+] Calculating shared secret from A and the verifier ...");
+                                match server.finish(&client_hello.public_key) {
+                                    Some(session) => pending_session = Some(session),
+                                    None => {
+                                        let error = verify::authentication_failure(received);
+                                        stream.write_all(&record::alert_frame())?;
+                                        return Err(error);
+                                    }
+                                }
+                            }
+                            // M1 shows the client derived the same session key, which it could only
+                            // have done knowing the password
+                            ContentType::SrpClientProof => {
+                                let session = pending_session.take()
+                                    .ok_or_else(|| std::io::Error::other("Unexpected SrpClientProof"))?;
+
+                                println!("[+] Verifying the client's proof ...");
+                                if !session.verify_client_proof(&frame.body) {
+                                    let error = verify::authentication_failure(received);
+                                    stream.write_all(&record::alert_frame())?;
+                                    return Err(error);
+                                }
+
+                                // M2 shows the client that we hold the verifier
+                                println!("[+] Client proof verified, sending server proof ...");
+                                stream.write_all(&record::encode_frame(ContentType::SrpServerProof, 0, session.server_proof()))?;
+
+                                // The session key and the transcript give the final key for the record layer
+                                println!("[+] Using SHA-256 as KDF to compute final key ...");
+                                let final_key = handshake::derive_key(&session.session_key, &transcript);
+                                let record_layer = RecordLayer::new(CipherSuite::Aes256Gcm, &final_key, Side::Server, RekeyPolicy::default(), random.clone());
+                                client_keys.lock().unwrap().insert(address.clone(), record_layer);
+
+                                println!("[*] SRP Key Exchange Successful. Final key {}", final_key);
+                                println!("--------------------------------------\n");
+                            }
+                            ContentType::ApplicationData | ContentType::KeyUpdate => {
+                                println!("--------------------------------------");
+                                println!("[+] {} bytes received.", frame.body.len() + record::HEADER_SIZE);
+
+                                // Retrieve the record layer for this client
+                                let mut keys_lock = client_keys.lock().unwrap();
+                                let record_layer = keys_lock.get_mut(&address)
+                                    .ok_or_else(|| std::io::Error::other("Application data before handshake"))?;
+
+                                println!("[+] Decrypting record #{} ({} bytes) with {} ...", frame.sequence, frame.body.len(), record_layer.suite().name());
+                                match record_layer.open(&frame) {
+                                    Ok(Record::ApplicationData(message)) => {
+                                        // Send the decrypted message to the main thread
+                                        println!("[*] Authentication and decryption successful.");
+                                        println!("--------------------------------------\n");
+                                        client_tx.send(message).unwrap();
+                                    }
+                                    Ok(Record::KeyUpdate) => {
+                                        println!("[*] Client updated its sending key, receiving key ratcheted to epoch {}", record_layer.read_epoch());
+                                        println!("--------------------------------------\n");
+                                    }
+                                    // A replayed or reordered record is authentic but is dropped without being
+                                    // delivered. The connection itself is left open.
+                                    Err(e @ RecordError::Replay { .. }) | Err(e @ RecordError::OutOfOrder { .. }) => {
+                                        println!("[!] {}", e);
+                                        println!("--------------------------------------\n");
+                                    }
+                                    Err(_) => {
+                                        drop(keys_lock);
+                                        let error = verify::authentication_failure(received);
+                                        stream.write_all(&record::alert_frame())?;
+                                        return Err(error);
+                                    }
+                                }
+                            }
+                            ContentType::Alert => {
+                                println!("[!] Client sent an alert: {}", verify::AUTHENTICATION_ALERT);
+                                println!("--------------------------------------\n");
+                                return Err(std::io::Error::new(std::io::ErrorKind::ConnectionAborted, "Alert received"));
+                            }
+                            other => {
+                                return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, format!("Unexpected {:?}", other)));
+                            }
+                        }
+                    }
+                }
+                Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => continue,
+                Err(_) => break, // Error or disconnection has occured
+            }
+        }
+        Ok(())
+    }
+}
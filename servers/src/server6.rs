@@ -0,0 +1,530 @@
+use std::thread;
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+use std::sync::{Arc, Mutex};
+use std::io::{Read, Write, Error};
+use std::collections::HashMap;
+use std::net::{TcpListener, TcpStream};
+use std::sync::mpsc::{Receiver, Sender};
+
+use dh;
+
+use crate::certificate::{self, Certificate};
+use crate::config::SessionConfig;
+use crate::handshake::{ClientHello, ServerHello, Transcript};
+use crate::identity::ClientAuth;
+use crate::proof;
+use crate::random::Random;
+use crate::secret::SecretBytes;
+use crate::record::{self, ContentType, Record, RecordError, RecordLayer, Side};
+use crate::suites;
+use crate::tls::{self, HandshakeType, KeySchedule};
+use crate::verify;
+
+// Every connected client with the sender for its records and its stream
+type ClientMap = Arc<Mutex<HashMap<String, (mpsc::Sender<Vec<u8>>, TcpStream)>>>;
+
+// Record layer of each client whose handshake has finished, under the same key as in ClientMap
+type ClientKeys = Arc<Mutex<HashMap<String, RecordLayer>>>;
+
+// Stage 5 grown into a TLS 1.3-style handshake. The server proves who it is with a
+// certificate and a signature over the transcript, both sent encrypted, and the two
+// Finished messages confirm that each side saw the same handshake. Handshake messages
+// and application data are protected under separate keys from the key schedule in tls.rs.
+//
+// With client authentication (identity.rs) the server also asks for the client's
+// certificate and a signature over the transcript, and a client is known by the identity
+// it proved from then on. Without it, clients are known by their socket address.
+//
+// Only the suites, the minimum suite and the rekey policy of the SessionConfig apply
+// here. A request for the ratchet or the hybrid exchange is declined.
+pub struct Server6 {
+    listener: TcpListener,
+    client_map: ClientMap,
+    client_keys: ClientKeys,
+    config: SessionConfig,
+    credentials: Arc<ServerCredentials>,
+    client_auth: ClientAuth,
+    random: Random,
+}
+
+// What the server authenticates with
+struct ServerCredentials {
+    certificate: Certificate,
+    // Private key matching the certificate's public key, used for CertificateVerify
+    signing_key: (SecretBytes, Vec<u8>),
+}
+
+// One client's connection as its thread sees it
+struct Connection {
+    stream: TcpStream,
+    // Where the client's messages go to the main thread
+    client_tx: Sender<Vec<u8>>,
+    address: String,
+    // Ephemeral key pair for this client, sent in the ServerHello
+    key_pair: (SecretBytes, Vec<u8>),
+    // The key the client is kept under, see identity_key, once its handshake has finished
+    identity: Option<String>,
+}
+
+// A client whose Finished has not arrived yet
+struct PendingHandshake {
+    // Under the handshake traffic keys, for reading the client's flight
+    handshake_layer: RecordLayer,
+    // Secret the client's Finished is keyed with
+    client_secret: SecretBytes,
+    // The next message the client must send
+    expected: HandshakeType,
+    // The identity and key from the client's Certificate once it has been accepted
+    client_identity: Option<(String, Vec<u8>)>,
+    // Moved into client_keys once the client's Finished verifies
+    application_layer: RecordLayer,
+}
+
+
+impl Server6 {
+    pub fn new(port: usize, certificate: Certificate, signing_key: (SecretBytes, Vec<u8>), random: Random) -> Self {
+        Self::with_config(port, SessionConfig::default(), certificate, signing_key, random)
+    }
+
+    pub fn with_config(port: usize, config: SessionConfig, certificate: Certificate, signing_key: (SecretBytes, Vec<u8>), random: Random) -> Self {
+        let address = format!("0.0.0.0:{}", port);
+        let listener = TcpListener::bind(address).expect("Could not bind");
+        let client_map = Arc::new(Mutex::new(HashMap::new()));
+        let client_keys = Arc::new(Mutex::new(HashMap::new()));
+
+        Self {
+            listener,
+            client_map,
+            client_keys,
+            config,
+            credentials: Arc::new(ServerCredentials { certificate, signing_key }),
+            client_auth: ClientAuth::None,
+            random,
+        }
+    }
+
+    // Only keep clients that authenticate with a key or certificate the policy accepts
+    pub fn with_client_auth(mut self, client_auth: ClientAuth) -> Self {
+        self.client_auth = client_auth;
+        self
+    }
+
+    // Keying material exported from the session with this client, named by its identity
+    // or, without client authentication, its address. None if no handshake with it has
+    // finished.
+    pub fn export_keying_material(&self, client: &str, label: &str, context: &[u8], length: usize) -> Option<SecretBytes> {
+        let client_keys = self.client_keys.lock().unwrap();
+        let record_layer = if self.client_auth.required() {
+            client_keys.get(&Self::identity_key(client))
+        } else {
+            client_keys.get(&Self::address_key(client))
+        };
+        record_layer?.export_keying_material(label, context, length)
+    }
+
+    // Keys of client_map and client_keys. Identities and addresses get different prefixes
+    // so that a client can never be mistaken for one known the other way, such as a
+    // certificate whose subject is another client's address.
+    fn identity_key(identity: &str) -> String {
+        format!("id:{}", identity)
+    }
+
+    fn address_key(address: &str) -> String {
+        format!("addr:{}", address)
+    }
+
+    pub fn run(&mut self) {
+        println!("Listening for incoming connections...");
+        println!("[*] Serving certificate for \"{}\" issued by \"{}\"", self.credentials.certificate.subject, self.credentials.certificate.issuer);
+        if self.client_auth.required() {
+            println!("[*] Client authentication required, accepting {}", self.client_auth);
+        }
+
+        // Thread for reading from stdin and sending those bytes to all clients
+        let client_map_clone = Arc::clone(&self.client_map);
+        let client_keys_clone = Arc::clone(&self.client_keys);
+        thread::spawn(move || {
+            loop {
+                let mut input = String::new();
+                std::io::stdin().read_line(&mut input).unwrap();
+                let temp_bytes = input.as_bytes().to_vec();
+
+                let clients = client_map_clone.lock().unwrap();
+                let mut client_keys = client_keys_clone.lock().unwrap();
+
+                for (client, (client_tx, _)) in clients.iter() {
+                    if let Some(record_layer) = client_keys.get_mut(client) {
+                        // Protect the message under the application traffic keys for this client
+                        println!("\n--------------------------------------");
+                        let mut sequence = record_layer.write_sequence();
+                        if record_layer.update_due() {
+                            println!("[+] Sending KeyUpdate as record #{} and ratcheting sending key to epoch {} ...", sequence, record_layer.write_epoch() + 1);
+                            sequence += 1;
+                        }
+                        println!("[+] Encrypting {} bytes as record #{} with {} ...", temp_bytes.len(), sequence, record_layer.suite().name());
+                        println!("--------------------------------------");
+                        match record_layer.seal(&temp_bytes) {
+                            // Send message_bytes through the stdin channel
+                            Ok(message_bytes) => client_tx.send(message_bytes).unwrap(),
+                            Err(e) => println!("[!] Could not send to {}: {}", client, e),
+                        }
+                    }
+                }
+            }
+        });
+
+        // Continuously listen for new connections
+        for stream in self.listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let address = stream.peer_addr().unwrap().to_string();
+
+                    println!("{} - Connected\n", address);
+
+                    // Ephemeral key pair for this client, sent in the ServerHello
+                    println!("--------------------------------------");
+                    println!("[+] Generating key pair ...");
+                    let key_pair = self.random.dh_key_pair();
+
+                    // Create channel for communicating from client thread to main thread
+                    let (client_tx, client_rx) = mpsc::channel::<Vec<u8>>();
+
+                    // Create reference to the shared client_map. The client is only added to
+                    // it, under its identity, once its handshake has finished.
+                    let client_map_clone = Arc::clone(&self.client_map);
+
+                    // Client handling thread
+                    let client_keys_clone = self.client_keys.clone();
+                    let config = self.config.clone();
+                    let credentials = self.credentials.clone();
+                    let client_auth = self.client_auth.clone();
+                    let random = self.random.clone();
+                    thread::spawn(move || {
+                        let mut connection = Connection { stream, client_tx, address: address.clone(), key_pair, identity: None };
+                        if let Err(e) = Self::handle_client(&mut connection, client_map_clone.clone(), client_keys_clone.clone(), config, credentials, client_auth, random) {
+                            eprintln!("Error handling client: {:?}", e);
+                        }
+
+                        // Remove the client from the map upon disconnection as well as their key
+                        if let Some(identity) = connection.identity {
+                            client_map_clone.lock().unwrap().remove(&identity);
+                            client_keys_clone.lock().unwrap().remove(&identity);
+                        }
+                        println!("{} - Disconnected", address);
+                    });
+
+                    // Creating a "Main" thread for each client
+                    thread::spawn(move || {
+                        // Attempt to receive any messages sent from the client
+                        while let Ok(response_bytes) = client_rx.recv() {
+                            // Response bytes will already have been decrypted in handle_client
+                            let message = String::from_utf8_lossy(&response_bytes);
+                            println!("Client > {}", message);
+                        }
+                    });
+                }
+                Err(e) => eprintln!("Failed to accept a client: {}", e),
+            }
+        }
+    }
+
+    // Sets the connection's identity once the client's handshake has finished
+    fn handle_client(
+        connection: &mut Connection,
+        client_map: ClientMap,
+        client_keys: ClientKeys,
+        config: SessionConfig,
+        credentials: Arc<ServerCredentials>,
+        client_auth: ClientAuth,
+        random: Random
+    ) -> Result<(), std::io::Error> {
+        let Connection { stream, client_tx, address, key_pair, identity } = connection;
+
+        // Channel the stdin thread sends this client's records through once it is in client_map
+        let (stdin_tx, stdin_rx) = mpsc::channel::<Vec<u8>>();
+
+        // Bytes received from the client which have not yet been handled
+        let mut dynamic_buffer = Vec::new();
+
+        // Every handshake message sent or received, in order
+        let mut transcript = Transcript::new();
+
+        // Set between our Finished and the client's
+        let mut pending: Option<PendingHandshake> = None;
+
+        loop {
+            // Non-blocking attempt to receive message from stdin and send to client
+            if let Ok(bytes) = stdin_rx.try_recv() {
+                stream.write_all(&bytes)?;
+            }
+
+            // Allows for 1 second of blocking while trying to read from the stream
+            stream.set_read_timeout(Some(Duration::new(1, 0)))?;
+
+            // Temporary buffer
+            let mut buffer = [0_u8; 512];
+
+            // Attempt to read bytes sent from client
+            match stream.read(&mut buffer) {
+                Ok(0) => break, // Connection closed by client
+                Ok(bytes_read) => {
+                    dynamic_buffer.extend_from_slice(&buffer[..bytes_read]);
+
+                    // Handle every complete frame received so far
+                    loop {
+                        // A failure to verify is reported a fixed time after this point
+                        let received = Instant::now();
+
+                        // A frame that can not be parsed, too long or with an unknown type,
+                        // fails the same way as a record that does not verify
+                        let frame = match record::take_frame(&mut dynamic_buffer) {
+                            Ok(Some(frame)) => frame,
+                            Ok(None) => break,
+                            Err(_) => {
+                                let error = verify::authentication_failure(received);
+                                stream.write_all(&record::alert_frame())?;
+                                return Err(error);
+                            }
+                        };
+
+                        match frame.content_type {
+                            // The first message is the client listing its suites and sending its public key
+                            ContentType::ClientHello => {
+                                if pending.is_some() || identity.is_some() {
+                                    return Err(std::io::Error::other("Unexpected ClientHello"));
+                                }
+
+                                // Our certificate authenticates the exchange, so there is no short
+                                // authentication string for a key commitment to protect
+                                let client_hello = ClientHello::from_bytes(&frame.body)
+                                    .filter(|client_hello| !client_hello.committed)
+                                    .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidData, "Malformed ClientHello"))?;
+                                transcript.add(&frame.body);
+                                println!("[*] Received ClientHello offering: {}", suites::describe(&client_hello.suites));
+
+                                // Pick the most preferred suite which the client also supports
+                                let suite = match suites::select_suite(&config.allowed_suites(), &client_hello.suites) {
+                                    Some(suite) => suite,
+                                    None => {
+                                        println!("[!] No cipher suite at or above {} in common with the client.", config.minimum_suite.name());
+                                        println!("--------------------------------------\n");
+                                        return Err(std::io::Error::other("No shared cipher suite"));
+                                    }
+                                };
+                                println!("[+] Selected cipher suite: {}", suite.name());
+
+                                println!("[+] Sending ServerHello with public key ...");
+                                let server_hello = ServerHello { suite, ratchet: false, kem_ciphertext: None, resumed: false, signed: false, gcm_tag_size: record::GCM_TAG_SIZE, padded: false, public_key: key_pair.1.clone() }.to_bytes();
+                                transcript.add(&server_hello);
+                                stream.write_all(&record::encode_frame(ContentType::ServerHello, 0, &server_hello))?;
+
+                                // Use the client's public key to compute the shared secret
+                                println!("[+] Calculating shared secret ...");
+                                let modulus = dh::get_domain_params().0;
+                                let shared_secret = SecretBytes::new(dh::get_secret(&client_hello.public_key, key_pair.0.expose(), &modulus));
+
+                                // Everything after the hellos is encrypted under the handshake traffic keys
+                                println!("[+] Deriving handshake traffic keys ...");
+                                let schedule = KeySchedule::new(&shared_secret);
+                                let handshake_secrets = schedule.handshake_traffic(&transcript.hash());
+                                let mut handshake_layer = RecordLayer::from_traffic_secrets(
+                                    suite,
+                                    handshake_secrets.client.duplicate(),
+                                    handshake_secrets.server.duplicate(),
+                                    Side::Server,
+                                    config.rekey,
+                                    random.clone(),
+                                );
+
+                                // Ask for the client's certificate before sending our own, as in TLS 1.3
+                                if client_auth.required() {
+                                    println!("[+] Sending encrypted CertificateRequest ...");
+                                    let message = tls::encode_message(HandshakeType::CertificateRequest, &[]);
+                                    transcript.add(&message);
+                                    stream.write_all(&handshake_layer.seal(&message)?)?;
+                                }
+
+                                println!("[+] Sending encrypted Certificate ...");
+                                let message = tls::encode_message(HandshakeType::Certificate, &credentials.certificate.to_bytes());
+                                transcript.add(&message);
+                                stream.write_all(&handshake_layer.seal(&message)?)?;
+
+                                // Signing the transcript shows we hold the certificate's private key
+                                println!("[+] Signing transcript and sending encrypted CertificateVerify ...");
+                                let signature = certificate::sign(&credentials.signing_key, &tls::certificate_verify_input(Side::Server, &transcript.hash()), &random);
+                                let message = tls::encode_message(HandshakeType::CertificateVerify, &signature);
+                                transcript.add(&message);
+                                stream.write_all(&handshake_layer.seal(&message)?)?;
+
+                                println!("[+] Sending encrypted Finished ...");
+                                let verify_data = tls::finished(&handshake_secrets.server, &transcript.hash());
+                                let message = tls::encode_message(HandshakeType::Finished, &verify_data);
+                                transcript.add(&message);
+                                stream.write_all(&handshake_layer.seal(&message)?)?;
+
+                                // The application keys cover the transcript up to our Finished
+                                println!("[+] Deriving application traffic keys ...");
+                                let transcript_hash = transcript.hash();
+                                let application_secrets = schedule.application_traffic(&transcript_hash);
+                                println!("[*] Application traffic keys: client {} server {}", application_secrets.client, application_secrets.server);
+                                pending = Some(PendingHandshake {
+                                    handshake_layer,
+                                    client_secret: handshake_secrets.client,
+                                    expected: if client_auth.required() { HandshakeType::Certificate } else { HandshakeType::Finished },
+                                    client_identity: None,
+                                    application_layer: RecordLayer::from_traffic_secrets(
+                                        suite,
+                                        application_secrets.client,
+                                        application_secrets.server,
+                                        Side::Server,
+                                        config.rekey,
+                                        random.clone(),
+                                    ).with_exporter_secret(schedule.exporter_master(&transcript_hash)),
+                                });
+                                if client_auth.required() {
+                                    println!("[*] Waiting for the client's Certificate, CertificateVerify and Finished ...");
+                                } else {
+                                    println!("[*] Waiting for the client's Finished ...");
+                                }
+                                println!("--------------------------------------\n");
+                            }
+                            // The client's Certificate, CertificateVerify and Finished, under the
+                            // handshake traffic keys
+                            ContentType::ApplicationData if pending.is_some() => {
+                                println!("--------------------------------------");
+                                let handshake = pending.as_mut().unwrap();
+
+                                let message = match handshake.handshake_layer.open(&frame) {
+                                    Ok(Record::ApplicationData(message)) => message,
+                                    _ => Vec::new(),
+                                };
+
+                                // Signatures and Finished cover the transcript before the message itself
+                                let transcript_hash = transcript.hash();
+                                let decoded = tls::decode_message(&message).filter(|(handshake_type, _)| *handshake_type == handshake.expected);
+                                let verified = match decoded {
+                                    Some((HandshakeType::Certificate, body)) => {
+                                        println!("[*] Received encrypted client Certificate");
+                                        match Certificate::from_bytes(body) {
+                                            Some(client_certificate) => match client_auth.identify(&client_certificate) {
+                                                Some(client_identity) => {
+                                                    println!("[*] Client key {} accepted as \"{}\"", proof::fingerprint(&client_certificate.public_key), client_identity);
+                                                    handshake.client_identity = Some((client_identity, client_certificate.public_key));
+                                                    handshake.expected = HandshakeType::CertificateVerify;
+                                                    true
+                                                }
+                                                None => {
+                                                    println!("[!] Client key {} is not authorized", proof::fingerprint(&client_certificate.public_key));
+                                                    false
+                                                }
+                                            },
+                                            // An empty Certificate from a client without a key ends up here too
+                                            None => false,
+                                        }
+                                    }
+                                    Some((HandshakeType::CertificateVerify, body)) => {
+                                        println!("[*] Received encrypted client CertificateVerify");
+                                        let (_, client_public_key) = handshake.client_identity.as_ref().unwrap();
+                                        handshake.expected = HandshakeType::Finished;
+                                        certificate::verify(client_public_key, &tls::certificate_verify_input(Side::Client, &transcript_hash), body)
+                                    }
+                                    Some((HandshakeType::Finished, body)) => tls::verify_finished(&handshake.client_secret, &transcript_hash, body),
+                                    _ => false,
+                                };
+
+                                // An unauthorized key, a bad signature, a bad Finished and a message out
+                                // of order all get the same alert
+                                if !verified {
+                                    let error = verify::authentication_failure(received);
+                                    stream.write_all(&record::alert_frame())?;
+                                    return Err(error);
+                                }
+                                if !matches!(decoded, Some((HandshakeType::Finished, _))) {
+                                    transcript.add(&message);
+                                    println!("--------------------------------------\n");
+                                    continue;
+                                }
+                                let handshake = pending.take().unwrap();
+
+                                println!("[*] Client Finished verified. Handshake Successful.");
+                                println!("[*] Channel binding {}", handshake.application_layer.channel_binding().unwrap());
+
+                                // From here on the client is known by the identity it proved, or by its
+                                // address if it did not have to prove one
+                                let (name, key) = match handshake.client_identity {
+                                    Some((client_identity, _)) => (client_identity.clone(), Self::identity_key(&client_identity)),
+                                    None => (address.clone(), Self::address_key(address)),
+                                };
+                                let mut clients = client_map.lock().unwrap();
+                                let mut keys_lock = client_keys.lock().unwrap();
+                                if keys_lock.contains_key(&key) {
+                                    println!("[!] \"{}\" is already connected, closing this connection", name);
+                                    println!("--------------------------------------\n");
+                                    return Err(std::io::Error::new(std::io::ErrorKind::AlreadyExists, "Client already connected"));
+                                }
+                                if client_auth.required() {
+                                    println!("[*] Client authenticated as \"{}\"", name);
+                                }
+                                println!("--------------------------------------\n");
+                                clients.insert(key.clone(), (stdin_tx.clone(), stream.try_clone()?));
+                                keys_lock.insert(key.clone(), handshake.application_layer);
+                                *identity = Some(key);
+                            }
+                            ContentType::ApplicationData | ContentType::KeyUpdate => {
+                                println!("--------------------------------------");
+                                println!("[+] {} bytes received.", frame.body.len() + record::HEADER_SIZE);
+
+                                // Retrieve the record layer for this client
+                                let mut keys_lock = client_keys.lock().unwrap();
+                                let record_layer = identity.as_ref().and_then(|identity| keys_lock.get_mut(identity))
+                                    .ok_or_else(|| std::io::Error::other("Application data before handshake"))?;
+
+                                println!("[+] Decrypting record #{} ({} bytes) with {} ...", frame.sequence, frame.body.len(), record_layer.suite().name());
+                                match record_layer.open(&frame) {
+                                    Ok(Record::ApplicationData(message)) => {
+                                        // Send the decrypted message to the main thread
+                                        println!("[*] Authentication and decryption successful.");
+                                        println!("--------------------------------------\n");
+                                        client_tx.send(message).unwrap();
+                                    }
+                                    Ok(Record::KeyUpdate) => {
+                                        println!("[*] Client updated its sending key, receiving key ratcheted to epoch {}", record_layer.read_epoch());
+                                        println!("--------------------------------------\n");
+                                    }
+                                    // A replayed or reordered record is authentic but is dropped without being
+                                    // delivered. The connection itself is left open.
+                                    Err(e @ RecordError::Replay { .. })
+                                    | Err(e @ RecordError::OutOfOrder { .. }) => {
+                                        println!("[!] {}", e);
+                                        println!("--------------------------------------\n");
+                                    }
+                                    // Anything else gets the same alert after the same delay,
+                                    // whichever check the record failed
+                                    Err(_) => {
+                                        drop(keys_lock);
+                                        let error = verify::authentication_failure(received);
+                                        stream.write_all(&record::alert_frame())?;
+                                        return Err(error);
+                                    }
+                                }
+                            }
+                            ContentType::Alert => {
+                                println!("[!] Client sent an alert: {}", verify::AUTHENTICATION_ALERT);
+                                println!("--------------------------------------\n");
+                                return Err(std::io::Error::new(std::io::ErrorKind::ConnectionAborted, "Alert received"));
+                            }
+                            // Hellos meant for the other side and the messages of the other handshakes
+                            other => {
+                                return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, format!("Unexpected {:?}", other)));
+                            }
+                        }
+                    }
+                }
+                Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => continue,
+                Err(_) => break, // Error or disconnection has occured
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,407 @@
+// A passive eavesdropper and the tool for what it records, for the forward secrecy demo.
+//
+// `wiretap <listen address> <server address> <capture file>` relays one stage-5
+// connection, changing nothing, and appends every frame to the capture file as a line of
+// hex starting with the sender, C for the client and S for the server.
+//
+// `decrypt <capture file> <key file>` plays the attacker who later steals the server's
+// long-term private key. The DH secret of the recorded session is the client's public key
+// from its KeyReveal raised to the server's private key. With a static DH key (the
+// static_key of SessionConfig) the stolen key is that private key, so the final key, and
+// with it every record, can be recovered from the capture. With an ephemeral key the
+// private half only existed for the handshake and was thrown away. The stolen key gives
+// a different secret and the recording stays sealed.
+//
+// Either way the thief can impersonate the server from then on. Forward secrecy is only
+// about the past, and only the static key gives that up as well.
+//
+// `impersonate <listen address> <server address> <key file> <message>` shows what else
+// the static key gives up: key compromise impersonation, where the stolen key is used
+// against its owner. It relays a live connection like the wiretap, but works out the
+// session key from the stolen key as decrypt does and, once the client's Finished shows
+// it has the right one, rewrites the client's first message before it reaches the server.
+// Nothing in the handshake is changed, so both ends show the same SAS and the server takes
+// the message as the client's. Signed messages do not help, since the server learns the
+// client's signing key from its first message: every message is signed again with a key
+// of the attacker's. Against an ephemeral server the candidate key fails the client's
+// Finished and the relay can only pass the connection through.
+//
+// Key files hold a private key as one line of hex, unprotected so that the demo can leak
+// them. Real identity keys belong in a keystore.
+
+use std::fs::OpenOptions;
+use std::io::{Error, ErrorKind, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+
+use dh;
+
+use crate::handshake::{self, ClientHello, ServerHello, Transcript};
+use crate::hex;
+use crate::proof::{self, SignedMessage};
+use crate::random::Random;
+use crate::sas;
+use crate::record::{self, ContentType, Frame, PaddingPolicy, Record, RecordLayer, RekeyPolicy, Side};
+use crate::secret::SecretBytes;
+
+// Reads the private key in a key file, or makes a new one there if the file does not exist
+pub fn demo_key(path: &str, random: &Random) -> Result<(SecretBytes, Vec<u8>), Error> {
+    if !std::path::Path::new(path).exists() {
+        let key_pair = random.dh_key_pair();
+        std::fs::write(path, format!("{}\n", hex::to_hex(key_pair.0.expose())))?;
+        println!("[*] New long-term key {} written to {}", proof::fingerprint(&key_pair.1), path);
+        return Ok(key_pair);
+    }
+    let private_key = hex::from_hex(std::fs::read_to_string(path)?.trim())
+        .filter(|private_key| !private_key.is_empty())
+        .map(SecretBytes::new)
+        .ok_or_else(|| Error::new(ErrorKind::InvalidData, format!("{} does not hold a private key in hex", path)))?;
+    let (modulus, generator) = dh::get_domain_params();
+    let public_key = dh::get_secret(&generator, private_key.expose(), &modulus);
+    Ok((private_key, public_key))
+}
+
+// Relays one connection from listen_address to server_address, recording it as it goes
+pub fn record(listen_address: &str, server_address: &str, capture_path: &str) -> Result<(), Error> {
+    let listener = TcpListener::bind(listen_address)?;
+    println!("[*] Waiting for a client on {} to relay to {} ...", listen_address, server_address);
+    let (client, address) = listener.accept()?;
+    let server = TcpStream::connect(server_address)?;
+    println!("[*] Relaying {} to {}, recording to {}", address, server_address, capture_path);
+
+    let (capture_tx, capture_rx) = mpsc::channel::<(Side, Frame)>();
+    let relays = [
+        relay(client.try_clone()?, server.try_clone()?, Side::Client, capture_tx.clone()),
+        relay(server, client, Side::Server, capture_tx),
+    ];
+
+    // Frames from both directions, in the order they were seen
+    let mut capture = OpenOptions::new().create(true).append(true).open(capture_path)?;
+    let mut frames = 0;
+    for (sender, frame) in capture_rx {
+        let direction = match sender {
+            Side::Client => "C",
+            Side::Server => "S",
+        };
+        println!("[*] {} {:?} #{} ({} bytes)", direction, frame.content_type, frame.sequence, frame.body.len());
+        writeln!(capture, "{} {}", direction, hex::to_hex(&record::encode_frame(frame.content_type, frame.sequence, &frame.body)))?;
+        frames += 1;
+    }
+    for relay in relays {
+        let _ = relay.join();
+    }
+    println!("[*] Connection closed, {} frames recorded", frames);
+    Ok(())
+}
+
+// Copies everything one side sends to the other and passes each complete frame on for
+// the capture. When either side closes, the other is closed too.
+fn relay(mut from: TcpStream, mut to: TcpStream, sender: Side, capture_tx: Sender<(Side, Frame)>) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        let mut dynamic_buffer = Vec::new();
+        let mut buffer = [0_u8; 512];
+        while let Ok(bytes_read) = from.read(&mut buffer) {
+            if bytes_read == 0 || to.write_all(&buffer[..bytes_read]).is_err() {
+                break;
+            }
+            dynamic_buffer.extend_from_slice(&buffer[..bytes_read]);
+            while let Ok(Some(frame)) = record::take_frame(&mut dynamic_buffer) {
+                let _ = capture_tx.send((sender, frame));
+            }
+        }
+        let _ = to.shutdown(Shutdown::Both);
+        let _ = from.shutdown(Shutdown::Both);
+    })
+}
+
+// Decrypts a recorded session with a stolen private key. Returns false if it could not.
+pub fn decrypt(capture_path: &str, key_path: &str) -> bool {
+    match decrypt_capture(capture_path, key_path) {
+        Ok(decrypted) => decrypted,
+        Err(e) => {
+            println!("[!] {}", e);
+            false
+        }
+    }
+}
+
+fn decrypt_capture(capture_path: &str, key_path: &str) -> Result<bool, Error> {
+    let frames = read_capture(capture_path)?;
+    let (private_key, leaked_public_key) = demo_key_from_file(key_path)?;
+    println!("[*] Read {} frames from {}", frames.len(), capture_path);
+    println!("[*] Leaked private key belongs to public key {}", proof::fingerprint(&leaked_public_key));
+
+    // The ServerHello answers the last ClientHello before it, which differs from the
+    // first one only when a ticket was declined
+    let server_hello_index = frames.iter()
+        .position(|(sender, frame)| *sender == Side::Server && frame.content_type == ContentType::ServerHello)
+        .ok_or_else(|| Error::new(ErrorKind::InvalidData, "No ServerHello in the capture"))?;
+    let client_hello_frame = frames[..server_hello_index].iter()
+        .rfind(|(sender, frame)| *sender == Side::Client && frame.content_type == ContentType::ClientHello)
+        .map(|(_, frame)| frame)
+        .ok_or_else(|| Error::new(ErrorKind::InvalidData, "No ClientHello before the ServerHello"))?;
+    let server_hello_frame = &frames[server_hello_index].1;
+    let client_hello = ClientHello::from_bytes(&client_hello_frame.body)
+        .ok_or_else(|| Error::new(ErrorKind::InvalidData, "Malformed ClientHello"))?;
+    let server_hello = ServerHello::from_bytes(&server_hello_frame.body)
+        .ok_or_else(|| Error::new(ErrorKind::InvalidData, "Malformed ServerHello"))?;
+
+    println!("--------------------------------------");
+    println!("[*] ServerHello: {} with server DH key {}", server_hello.suite.name(), proof::fingerprint(&server_hello.public_key));
+    if server_hello.resumed {
+        println!("[!] The session was resumed from a ticket, there is no DH exchange to attack");
+        return Ok(false);
+    }
+    if server_hello.kem_ciphertext.is_some() {
+        println!("[!] The session used the hybrid exchange, its ML-KEM secret is not in the leaked key");
+        return Ok(false);
+    }
+    if server_hello.ratchet {
+        println!("[!] The session used the Double Ratchet, whose fresh DH keys the leaked key does not cover");
+        return Ok(false);
+    }
+
+    // The ClientHello only commits to the client's key, which follows the ServerHello
+    let client_public_key = frames[server_hello_index..].iter()
+        .find(|(sender, frame)| *sender == Side::Client && frame.content_type == ContentType::KeyReveal)
+        .map(|(_, frame)| frame.body.clone())
+        .filter(|public_key| client_hello.committed && sas::opens(&client_hello.public_key, public_key))
+        .ok_or_else(|| Error::new(ErrorKind::InvalidData, "No KeyReveal matching the ClientHello's commitment"))?;
+    println!("[*] KeyReveal: client DH key {}", proof::fingerprint(&client_public_key));
+
+    if server_hello.public_key == leaked_public_key {
+        println!("[*] The ServerHello carries the leaked key, so the server used a static DH key");
+    } else {
+        println!("[!] The ServerHello carries a different key. It was ephemeral and its private");
+        println!("    half was discarded after the handshake. Trying the leaked key anyway ...");
+    }
+
+    // Everything the server computed, redone from the capture and the stolen key
+    let (final_key, transcript) = candidate_key(&private_key, &client_hello_frame.body, &server_hello_frame.body, &client_public_key);
+
+    // The server's Finished is an HMAC under the final key, so it tells whether the
+    // candidate is right before any record is tried
+    let server_finished = frames[server_hello_index..].iter()
+        .find(|(sender, frame)| *sender == Side::Server && frame.content_type == ContentType::Finished)
+        .map(|(_, frame)| frame.body.as_slice());
+    if server_finished != Some(handshake::finished(&final_key, Side::Server, &transcript).as_slice()) {
+        println!("[!] The server's Finished does not match the candidate key. The recording stays sealed.");
+        println!("--------------------------------------\n");
+        return Ok(false);
+    }
+    println!("[*] The server's Finished matches, the final key is recovered");
+    println!("--------------------------------------\n");
+
+    // A layer for each direction, the same as the receiving end had
+    let mut from_client = session_layer(&server_hello, &final_key, Side::Server);
+    let mut from_server = session_layer(&server_hello, &final_key, Side::Client);
+
+    let mut decrypted = 0;
+    for (sender, frame) in &frames[server_hello_index + 1..] {
+        let (layer, name) = match sender {
+            Side::Client => (&mut from_client, "Client"),
+            Side::Server => (&mut from_server, "Server"),
+        };
+        match frame.content_type {
+            ContentType::ApplicationData | ContentType::KeyUpdate => match layer.open(frame) {
+                Ok(Record::ApplicationData(message)) => {
+                    let content = if server_hello.signed {
+                        SignedMessage::from_bytes(&message).map(|signed_message| signed_message.content).unwrap_or(message)
+                    } else {
+                        message
+                    };
+                    println!("{} > {}", name, String::from_utf8_lossy(&content).trim_end());
+                    decrypted += 1;
+                }
+                Ok(Record::KeyUpdate) => println!("[*] {} updated its sending key", name),
+                Err(e) => println!("[!] {} record #{}: {}", name, frame.sequence, e),
+            },
+            ContentType::NewSessionTicket => match layer.open_ticket(frame) {
+                // The ticket's resumption secret comes from the final key, so sessions
+                // resumed from it are exposed as well
+                Ok(ticket) => println!("[*] Server issued a {} byte session ticket, resumptions from it are exposed too", ticket.len()),
+                Err(e) => println!("[!] Session ticket: {}", e),
+            },
+            _ => {}
+        }
+    }
+    println!("\n[*] Decrypted {} messages with the leaked key", decrypted);
+    Ok(true)
+}
+
+// The final key and hello transcript the server would have, if the stolen private key is
+// the one it used for this exchange
+fn candidate_key(private_key: &SecretBytes, client_hello: &[u8], server_hello: &[u8], client_public_key: &[u8]) -> (SecretBytes, Transcript) {
+    println!("[+] Calculating shared secret from the client's public key and the leaked private key ...");
+    let modulus = dh::get_domain_params().0;
+    let shared_secret = SecretBytes::new(dh::get_secret(client_public_key, private_key.expose(), &modulus));
+    let mut transcript = Transcript::new();
+    transcript.add(client_hello);
+    transcript.add(server_hello);
+    transcript.add(client_public_key);
+    let final_key = handshake::derive_key(&shared_secret, &transcript);
+    println!("[*] Candidate final key {}", final_key);
+    (final_key, transcript)
+}
+
+// A record layer for the session, as the given side set it up
+fn session_layer(server_hello: &ServerHello, final_key: &SecretBytes, side: Side) -> RecordLayer {
+    let mut layer = RecordLayer::new(server_hello.suite, final_key, side, RekeyPolicy::default(), Random::new())
+        .with_gcm_tag_size(server_hello.gcm_tag_size);
+    if server_hello.padded {
+        layer = layer.with_padding(PaddingPolicy::None);
+    }
+    layer
+}
+
+// Relays one connection from listen_address to server_address and, if the stolen key
+// opens the session, delivers message to the server in place of the client's first one
+pub fn impersonate(listen_address: &str, server_address: &str, key_path: &str, message: &str) -> Result<(), Error> {
+    let (private_key, leaked_public_key) = demo_key_from_file(key_path)?;
+    println!("[*] Leaked private key belongs to public key {}", proof::fingerprint(&leaked_public_key));
+    let listener = TcpListener::bind(listen_address)?;
+    println!("[*] Waiting for a client on {} to relay to {} ...", listen_address, server_address);
+    let (client, address) = listener.accept()?;
+    let server = TcpStream::connect(server_address)?;
+    println!("[*] Relaying {} to {}", address, server_address);
+
+    // The server's side only needs passing on, apart from the ServerHello, which the
+    // client's side waits for before it can work out the key
+    let (server_hello_tx, server_hello_rx) = mpsc::channel::<Frame>();
+    let (capture_tx, capture_rx) = mpsc::channel::<(Side, Frame)>();
+    let server_relay = relay(server.try_clone()?, client.try_clone()?, Side::Server, capture_tx);
+    thread::spawn(move || {
+        for (_, frame) in capture_rx {
+            if frame.content_type == ContentType::ServerHello {
+                let _ = server_hello_tx.send(frame);
+            }
+        }
+    });
+
+    let result = rewrite_client(client.try_clone()?, server.try_clone()?, server_hello_rx, &private_key, message);
+    let _ = server.shutdown(Shutdown::Both);
+    let _ = client.shutdown(Shutdown::Both);
+    let _ = server_relay.join();
+    println!("[*] Connection closed");
+    result
+}
+
+// The client's side of impersonate. Frames are passed on one at a time, and once the
+// session key is known every application data record is opened and sealed again.
+fn rewrite_client(mut client: TcpStream, mut server: TcpStream, server_hello_rx: Receiver<Frame>, private_key: &SecretBytes, message: &str) -> Result<(), Error> {
+    let random = Random::new();
+    let attacker_key = random.dh_key_pair();
+    let mut client_hello = None;
+    let mut candidate = None;
+    // Layers reading the client's records and sealing ours, once the key is known
+    let mut layers: Option<(RecordLayer, RecordLayer, bool)> = None;
+    let mut forged = false;
+
+    let mut dynamic_buffer = Vec::new();
+    let mut buffer = [0_u8; 512];
+    loop {
+        let bytes_read = client.read(&mut buffer)?;
+        if bytes_read == 0 {
+            return Ok(());
+        }
+        dynamic_buffer.extend_from_slice(&buffer[..bytes_read]);
+        while let Some(frame) = record::take_frame(&mut dynamic_buffer).map_err(|e| Error::new(ErrorKind::InvalidData, e.to_string()))? {
+            match frame.content_type {
+                ContentType::ClientHello => client_hello = Some(frame.body.clone()),
+                ContentType::KeyReveal => {
+                    let server_hello_frame = server_hello_rx.recv()
+                        .map_err(|_| Error::new(ErrorKind::UnexpectedEof, "The server closed before its ServerHello"))?;
+                    let server_hello = ServerHello::from_bytes(&server_hello_frame.body)
+                        .ok_or_else(|| Error::new(ErrorKind::InvalidData, "Malformed ServerHello"))?;
+                    let client_hello = client_hello.as_ref()
+                        .ok_or_else(|| Error::new(ErrorKind::InvalidData, "KeyReveal before any ClientHello"))?;
+                    println!("--------------------------------------");
+                    println!("[*] ServerHello: {} with server DH key {}", server_hello.suite.name(), proof::fingerprint(&server_hello.public_key));
+                    println!("[*] KeyReveal: client DH key {}", proof::fingerprint(&frame.body));
+                    if server_hello.kem_ciphertext.is_some() || server_hello.ratchet {
+                        println!("[!] The session goes beyond a plain DH exchange, relaying it untouched");
+                    } else {
+                        let (final_key, transcript) = candidate_key(private_key, client_hello, &server_hello_frame.body, &frame.body);
+                        candidate = Some((server_hello, final_key, transcript));
+                    }
+                    println!("--------------------------------------\n");
+                }
+                // The client only sends its Finished once it has checked the server's, so
+                // a match means the candidate is the key both ends are using
+                ContentType::Finished => if let Some((server_hello, final_key, transcript)) = candidate.take() {
+                    println!("--------------------------------------");
+                    if frame.body == handshake::finished(&final_key, Side::Client, &transcript) {
+                        println!("[*] The client's Finished matches, the final key is recovered");
+                        println!("[*] Both ends will show the same SAS, nothing they exchanged was changed");
+                        let from_client = session_layer(&server_hello, &final_key, Side::Server);
+                        let to_server = session_layer(&server_hello, &final_key, Side::Client);
+                        layers = Some((from_client, to_server, server_hello.signed));
+                    } else {
+                        println!("[!] The client's Finished does not match the candidate key. The server's DH");
+                        println!("    key was ephemeral, so the session can only be relayed untouched.");
+                    }
+                    println!("--------------------------------------\n");
+                },
+                ContentType::ApplicationData => if let Some((from_client, to_server, signed)) = layers.as_mut() {
+                    let content = match from_client.open(&frame) {
+                        Ok(Record::ApplicationData(content)) => content,
+                        _ => return Err(Error::new(ErrorKind::InvalidData, format!("Could not open client record #{}", frame.sequence))),
+                    };
+                    let mut content = if *signed {
+                        SignedMessage::from_bytes(&content).map(|signed_message| signed_message.content).unwrap_or(content)
+                    } else {
+                        content
+                    };
+                    println!("--------------------------------------");
+                    println!("[*] Client record #{}: {}", frame.sequence, String::from_utf8_lossy(&content).trim_end());
+                    if !forged {
+                        content = format!("{}\n", message).into_bytes();
+                        forged = true;
+                        println!("[+] Replacing it with: {}", message);
+                    }
+                    if *signed {
+                        println!("[+] Signing it for record #{} with our own key {} ...", to_server.write_sequence(), proof::fingerprint(&attacker_key.1));
+                        content = SignedMessage::sign(Side::Client, to_server.write_sequence(), to_server.channel_binding().unwrap().expose(), &content, &attacker_key, &random).to_bytes();
+                    }
+                    println!("--------------------------------------\n");
+                    let sealed = to_server.seal(&content).map_err(|e| Error::new(ErrorKind::InvalidData, e.to_string()))?;
+                    server.write_all(&sealed)?;
+                    continue;
+                },
+                // The demo sessions are far too short for a key update, which the layers
+                // here would have to follow on both sides
+                ContentType::KeyUpdate if layers.is_some() => {
+                    return Err(Error::new(ErrorKind::Unsupported, "The client updated its key, which the impersonation does not follow"));
+                }
+                _ => {}
+            }
+            server.write_all(&record::encode_frame(frame.content_type, frame.sequence, &frame.body))?;
+        }
+    }
+}
+
+// Like demo_key, but a missing file is an error rather than a reason to make a key
+fn demo_key_from_file(path: &str) -> Result<(SecretBytes, Vec<u8>), Error> {
+    if !std::path::Path::new(path).exists() {
+        return Err(Error::new(ErrorKind::NotFound, format!("No key file at {}", path)));
+    }
+    demo_key(path, &Random::new())
+}
+
+fn read_capture(path: &str) -> Result<Vec<(Side, Frame)>, Error> {
+    let mut frames = Vec::new();
+    for (number, line) in std::fs::read_to_string(path)?.lines().enumerate() {
+        let malformed = || Error::new(ErrorKind::InvalidData, format!("{} line {} is not a recorded frame", path, number + 1));
+        let (direction, hex) = line.split_once(' ').ok_or_else(malformed)?;
+        let sender = match direction {
+            "C" => Side::Client,
+            "S" => Side::Server,
+            _ => return Err(malformed()),
+        };
+        let mut bytes = hex::from_hex(hex).ok_or_else(malformed)?;
+        let frame = record::take_frame(&mut bytes).ok().flatten().ok_or_else(malformed)?;
+        frames.push((sender, frame));
+    }
+    Ok(frames)
+}